@@ -0,0 +1,159 @@
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Desync counter of every actor, shared with the monitors so they can be exported
+static DESYNCS: Lazy<Mutex<BTreeMap<String, Arc<AtomicU64>>>> = Lazy::new(Default::default);
+
+/// Number of mismatching device reports seen so far, by actor
+pub fn desync_counts() -> BTreeMap<String, u64> {
+    DESYNCS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(actor, desyncs)| (actor.clone(), desyncs.load(Ordering::Relaxed)))
+        .collect()
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// What an actor does when its device reports a state different from the one bffh last applied
+pub enum DesyncPolicy {
+    /// Send the state bffh expects to the device again
+    Reassert,
+    /// Only log and count the mismatch
+    Alert,
+}
+
+impl FromStr for DesyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reassert" => Ok(Self::Reassert),
+            "alert" => Ok(Self::Alert),
+            other => Err(format!(
+                "unknown desync policy '{}', expected 'reassert' or 'alert'",
+                other
+            )),
+        }
+    }
+}
+
+impl DesyncPolicy {
+    /// Read the policy from the `desync` actor parameter
+    pub fn from_params(actor: &str, params: &HashMap<String, String>) -> Option<Self> {
+        let value = params.get("desync")?;
+        match value.parse() {
+            Ok(policy) => Some(policy),
+            Err(error) => {
+                tracing::error!(%actor, %error, "ignoring invalid desync policy");
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Action to take after a device report didn't match the expected state
+pub enum Reconcile {
+    /// Apply the contained state to the device again
+    Reassert(bool),
+    Alert,
+}
+
+#[derive(Debug)]
+/// Compares states reported by a device against the state last applied to it
+pub struct DesyncMonitor {
+    actor: String,
+    policy: DesyncPolicy,
+    expected: Mutex<Option<bool>>,
+    desyncs: Arc<AtomicU64>,
+}
+
+impl DesyncMonitor {
+    pub fn new(actor: String, policy: DesyncPolicy) -> Self {
+        let desyncs = DESYNCS
+            .lock()
+            .unwrap()
+            .entry(actor.clone())
+            .or_default()
+            .clone();
+        Self {
+            actor,
+            policy,
+            expected: Mutex::new(None),
+            desyncs,
+        }
+    }
+
+    /// Record the state that was just sent to the device
+    pub fn expect(&self, on: bool) {
+        *self.expected.lock().unwrap() = Some(on);
+    }
+
+    /// Check a state reported by the device
+    ///
+    /// Reports arriving before any state was applied are not considered a desync.
+    pub fn report(&self, on: bool) -> Option<Reconcile> {
+        let expected = (*self.expected.lock().unwrap())?;
+        if expected == on {
+            return None;
+        }
+
+        let desyncs = self.desyncs.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.policy {
+            DesyncPolicy::Reassert => {
+                tracing::info!(actor = %self.actor, expected, reported = on, desyncs,
+                    "device state out of sync, reasserting");
+                Some(Reconcile::Reassert(expected))
+            }
+            DesyncPolicy::Alert => {
+                tracing::warn!(actor = %self.actor, expected, reported = on, desyncs,
+                    "device state out of sync");
+                Some(Reconcile::Alert)
+            }
+        }
+    }
+
+    /// Number of mismatching reports seen so far
+    pub fn desyncs(&self) -> u64 {
+        self.desyncs.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conflicting_report_triggers_policy() {
+        let monitor = DesyncMonitor::new("shelly-reassert".to_string(), DesyncPolicy::Reassert);
+        assert_eq!(monitor.report(true), None);
+
+        monitor.expect(false);
+        assert_eq!(monitor.report(false), None);
+        assert_eq!(monitor.report(true), Some(Reconcile::Reassert(false)));
+        assert_eq!(monitor.desyncs(), 1);
+
+        let monitor = DesyncMonitor::new("shelly-alert".to_string(), DesyncPolicy::Alert);
+        monitor.expect(true);
+        assert_eq!(monitor.report(false), Some(Reconcile::Alert));
+        assert_eq!(monitor.desyncs(), 1);
+        assert_eq!(desync_counts().get("shelly-alert"), Some(&1));
+    }
+
+    #[test]
+    fn policy_from_params() {
+        let mut params = HashMap::new();
+        assert_eq!(DesyncPolicy::from_params("a", &params), None);
+        params.insert("desync".to_string(), "alert".to_string());
+        assert_eq!(
+            DesyncPolicy::from_params("a", &params),
+            Some(DesyncPolicy::Alert)
+        );
+        params.insert("desync".to_string(), "yes".to_string());
+        assert_eq!(DesyncPolicy::from_params("a", &params), None);
+    }
+}
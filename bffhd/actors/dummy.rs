@@ -18,6 +18,10 @@ impl Dummy {
 }
 
 impl Actor for Dummy {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
     fn apply(&mut self, state: ArchivedValue<State>) -> BoxFuture<'static, ()> {
         tracing::info!(name=%self.name, params=?self.params, ?state, "dummy actor updating state");
         Box::pin(future::ready(()))
@@ -0,0 +1,251 @@
+//! Actor driving a GPIO line of the host, e.g. a relay wired directly to the bffh machine
+//!
+//! Uses the GPIO character device (`/dev/gpiochipN`), the same interface libgpiod is built on, so
+//! it's only available on Linux and only with the `gpio` feature enabled.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use async_io::Timer;
+use futures_util::future::BoxFuture;
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+
+use crate::actors::Actor;
+use crate::db::ArchivedValue;
+use crate::resources::modules::fabaccess::ArchivedStatus;
+use crate::resources::state::State;
+use crate::utils::ratelimit::LogLimiter;
+
+/// Label the line is requested with, shown as consumer by `gpioinfo`
+const CONSUMER: &str = "bffh";
+/// Time between attempts to request a line that isn't available
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Which line to drive and how
+struct LineConfig {
+    chip: String,
+    line: u32,
+    active_low: bool,
+}
+
+impl LineConfig {
+    fn from_params(name: &str, params: &HashMap<String, String>) -> Option<Self> {
+        let chip = match params.get("chip") {
+            // Allow the short name `gpioinfo` prints as well as the full path
+            Some(chip) if chip.starts_with('/') => chip.clone(),
+            Some(chip) => format!("/dev/{}", chip),
+            None => {
+                tracing::error!(%name, "`Gpio` actor needs a `chip` parameter");
+                return None;
+            }
+        };
+        let line = match params.get("line").map(|line| line.parse()) {
+            Some(Ok(line)) => line,
+            Some(Err(error)) => {
+                tracing::error!(%name, %error, "invalid `line` for `Gpio` actor");
+                return None;
+            }
+            None => {
+                tracing::error!(%name, "`Gpio` actor needs a `line` parameter");
+                return None;
+            }
+        };
+        let active_low = match params.get("active_low").map(|low| low.parse()) {
+            None => false,
+            Some(Ok(low)) => low,
+            Some(Err(error)) => {
+                tracing::error!(%name, %error, "invalid `active_low` for `Gpio` actor");
+                return None;
+            }
+        };
+        Some(Self {
+            chip,
+            line,
+            active_low,
+        })
+    }
+
+    fn request(&self, powered: bool) -> Result<LineHandle, gpio_cdev::Error> {
+        let mut flags = LineRequestFlags::OUTPUT;
+        if self.active_low {
+            flags |= LineRequestFlags::ACTIVE_LOW;
+        }
+        Chip::new(&self.chip)?
+            .get_line(self.line)?
+            .request(flags, powered as u8, CONSUMER)
+    }
+}
+
+#[derive(Debug)]
+struct Line {
+    /// The requested line, `None` until requesting it succeeded
+    handle: Option<LineHandle>,
+    /// State the line should be in, applied as soon as it could be requested
+    powered: bool,
+    limiter: LogLimiter,
+}
+
+/// An actor switching a GPIO line of the host, powering it while the machine is in use
+///
+/// The line is given by `chip` (e.g. `gpiochip0` or `/dev/gpiochip0`) and its offset `line`.
+/// With `active_low = "true"` the line is driven low to power the device.
+///
+/// The line is requested once and held until the actor is dropped. If it's not available, e.g.
+/// because another process is holding it, requesting it is retried in the background and the
+/// latest state is applied once that succeeds.
+pub struct Gpio {
+    name: String,
+    config: Arc<LineConfig>,
+    line: Arc<Mutex<Line>>,
+}
+
+impl Gpio {
+    /// The actor and the task retrying to request its line, which finishes once the actor is
+    /// dropped
+    pub fn new(
+        name: String,
+        params: &HashMap<String, String>,
+    ) -> Option<(Self, impl Future<Output = ()> + Send + 'static)> {
+        let config = Arc::new(LineConfig::from_params(&name, params)?);
+        let line = Arc::new(Mutex::new(Line {
+            handle: None,
+            powered: false,
+            limiter: LogLimiter::default(),
+        }));
+        Self::try_request(&name, &config, &mut line.lock().unwrap());
+
+        tracing::debug!(%name, chip=%config.chip, line=config.line, "Starting Gpio module");
+        let retry = Self::retry(name.clone(), config.clone(), Arc::downgrade(&line));
+        Some((Self { name, config, line }, retry))
+    }
+
+    fn try_request(name: &str, config: &LineConfig, line: &mut Line) {
+        if line.handle.is_some() {
+            return;
+        }
+        match config.request(line.powered) {
+            Ok(handle) => {
+                tracing::info!(%name, chip=%config.chip, line=config.line, "requested GPIO line");
+                line.handle = Some(handle);
+            }
+            Err(error) => {
+                let key = error.to_string();
+                crate::log_limited!(line.limiter, &key, error, %error, %name,
+                    chip=%config.chip, line=config.line,
+                    "`Gpio` actor failed to request line, retrying");
+            }
+        }
+    }
+
+    async fn retry(name: String, config: Arc<LineConfig>, line: Weak<Mutex<Line>>) {
+        loop {
+            Timer::after(RETRY_INTERVAL).await;
+            match line.upgrade() {
+                Some(line) => Self::try_request(&name, &config, &mut line.lock().unwrap()),
+                None => return,
+            }
+        }
+    }
+}
+
+impl Actor for Gpio {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn apply(&mut self, state: ArchivedValue<State>) -> BoxFuture<'static, ()> {
+        let powered = matches!(state.as_ref().inner.state, ArchivedStatus::InUse(_));
+        let mut line = self.line.lock().unwrap();
+        line.powered = powered;
+        let line = &mut *line;
+        if let Some(handle) = line.handle.as_ref() {
+            tracing::debug!(name=%self.name, powered, "Gpio actor changing state");
+            if let Err(error) = handle.set_value(powered as u8) {
+                let key = error.to_string();
+                crate::log_limited!(line.limiter, &key, error, %error, name=%self.name,
+                    chip=%self.config.chip, line=self.config.line,
+                    "`Gpio` actor failed to update state");
+                // Request the line anew, the chip may have gone away
+                line.handle = None;
+            }
+        }
+        Box::pin(async {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::modules::fabaccess::MachineState;
+    use crate::users::UserRef;
+    use rkyv::ser::serializers::AllocSerializer;
+    use rkyv::ser::Serializer;
+
+    fn state(state: MachineState) -> ArchivedValue<State> {
+        let mut serializer = AllocSerializer::<1024>::default();
+        serializer.serialize_value(&state.to_state()).unwrap();
+        ArchivedValue::new(serializer.into_serializer().into_inner())
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn line_is_read_from_params() {
+        assert_eq!(
+            LineConfig::from_params("relay", &params(&[("chip", "gpiochip0"), ("line", "17")])),
+            Some(LineConfig {
+                chip: "/dev/gpiochip0".to_string(),
+                line: 17,
+                active_low: false,
+            })
+        );
+        assert_eq!(
+            LineConfig::from_params(
+                "relay",
+                &params(&[
+                    ("chip", "/dev/gpiochip1"),
+                    ("line", "4"),
+                    ("active_low", "true")
+                ])
+            ),
+            Some(LineConfig {
+                chip: "/dev/gpiochip1".to_string(),
+                line: 4,
+                active_low: true,
+            })
+        );
+        assert_eq!(
+            LineConfig::from_params("relay", &params(&[("chip", "gpiochip0")])),
+            None
+        );
+        assert_eq!(
+            LineConfig::from_params("relay", &params(&[("chip", "gpiochip0"), ("line", "-1")])),
+            None
+        );
+    }
+
+    #[test]
+    fn missing_chip_does_not_stop_the_actor() {
+        let (mut actor, _retry) = Gpio::new(
+            "relay".to_string(),
+            &params(&[("chip", "/nonexistent/gpiochip"), ("line", "0")]),
+        )
+        .unwrap();
+        assert!(actor.line.lock().unwrap().handle.is_none());
+
+        // Only remembers the state to apply once the line is available
+        let user = UserRef::new("user".to_string());
+        async_io::block_on(actor.apply(state(MachineState::used(user, None))));
+        let line = actor.line.lock().unwrap();
+        assert!(line.handle.is_none());
+        assert!(line.powered);
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use async_io::Timer;
+use futures_lite::FutureExt;
+use futures_signals::signal::SignalExt;
+use futures_util::stream::{self, BoxStream};
+use futures_util::{Stream, StreamExt};
+use rumqttc::{AsyncClient, QoS};
+
+use crate::resources::modules::fabaccess::ArchivedStatus;
+use crate::resources::search::ResourcesHandle;
+
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(200);
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// Colour shown for positions without a machine
+const OFF: &str = "#000000";
+
+fn colour(status: &ArchivedStatus) -> &'static str {
+    match status {
+        ArchivedStatus::Free => "#00ff00",
+        ArchivedStatus::InUse(_) => "#0000ff",
+        ArchivedStatus::ToCheck(_) => "#ff8000",
+        ArchivedStatus::Blocked(_) => "#ff0000",
+        ArchivedStatus::Disabled => "#404040",
+        ArchivedStatus::Reserved(_) => "#ffff00",
+    }
+}
+
+/// Combined state of all positions on the board
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Board {
+    colours: Vec<&'static str>,
+}
+
+impl Board {
+    fn new(size: usize) -> Self {
+        Self {
+            colours: vec![OFF; size],
+        }
+    }
+
+    fn set(&mut self, (position, colour): (usize, &'static str)) {
+        self.colours[position] = colour;
+    }
+
+    /// JSON array with one colour per position
+    fn payload(&self) -> String {
+        serde_json::to_string(&self.colours).expect("serializing a list of strings can't fail")
+    }
+}
+
+/// An actor showing the state of several machines on a LED matrix
+///
+/// Instead of being connected to a single machine it watches all machines given in the
+/// `machines` parameter as comma-separated `machine=position` pairs. Changes arriving within
+/// `debounce_ms` (default 200) of each other are combined into a single publish to `topic`, but
+/// no change waits longer than `max_delay_ms` (default 5000) even if machines keep changing.
+pub struct LedBoard {
+    name: String,
+    topic: String,
+    debounce: Duration,
+    max_delay: Duration,
+    size: usize,
+    updates: BoxStream<'static, (usize, &'static str)>,
+    client: AsyncClient,
+}
+
+impl LedBoard {
+    pub fn new(
+        name: String,
+        params: &HashMap<String, String>,
+        resources: &ResourcesHandle,
+        client: AsyncClient,
+    ) -> Option<Self> {
+        let topic = params
+            .get("topic")
+            .cloned()
+            .unwrap_or_else(|| format!("ledboard/{}", name));
+        let debounce = match params.get("debounce_ms").map(|ms| ms.parse()) {
+            None => DEFAULT_DEBOUNCE,
+            Some(Ok(ms)) => Duration::from_millis(ms),
+            Some(Err(error)) => {
+                tracing::error!(%name, %error, "invalid `debounce_ms` for LedBoard actor");
+                return None;
+            }
+        };
+        let max_delay = match params.get("max_delay_ms").map(|ms| ms.parse()) {
+            None => DEFAULT_MAX_DELAY,
+            Some(Ok(ms)) => Duration::from_millis(ms),
+            Some(Err(error)) => {
+                tracing::error!(%name, %error, "invalid `max_delay_ms` for LedBoard actor");
+                return None;
+            }
+        };
+
+        let mut size = 0;
+        let mut updates = Vec::new();
+        for entry in params.get("machines")?.split(',') {
+            let (machine, position) = match entry.trim().split_once('=') {
+                Some((machine, position)) => (machine, position.parse::<usize>().ok()),
+                None => (entry, None),
+            };
+            let position = if let Some(position) = position {
+                position
+            } else {
+                tracing::error!(%name, %entry, "LedBoard machines must be given as `machine=position`");
+                return None;
+            };
+            let resource = if let Some(resource) = resources.get_by_id(machine) {
+                resource
+            } else {
+                tracing::error!(%name, %machine, "Machine configured for LedBoard not found!");
+                continue;
+            };
+
+            size = size.max(position + 1);
+            updates.push(
+                resource
+                    .get_signal()
+                    .to_stream()
+                    .map(move |state| (position, colour(&state.as_ref().inner.state)))
+                    .boxed(),
+            );
+        }
+
+        Some(Self {
+            name,
+            topic,
+            debounce,
+            max_delay,
+            size,
+            updates: stream::select_all(updates).boxed(),
+            client,
+        })
+    }
+
+    pub async fn run(self) {
+        let Self {
+            name,
+            topic,
+            debounce,
+            max_delay,
+            size,
+            updates,
+            client,
+        } = self;
+        tracing::debug!(%name, %topic, size, "starting LedBoard actor");
+
+        run(updates, Board::new(size), debounce, max_delay, |payload| {
+            tracing::trace!(%name, %payload, "updating LedBoard");
+            if let Err(error) = client.try_publish(topic.as_str(), QoS::AtLeastOnce, true, payload)
+            {
+                tracing::error!(?error, %name, "`LedBoard` actor failed to publish state");
+            }
+        })
+        .await
+    }
+}
+
+/// Apply `updates` to `board`, publishing it once no update arrived for `debounce`
+///
+/// A change is published at the latest `max_delay` after it arrived, so the board still follows
+/// machines changing faster than `debounce`.
+async fn run<S>(
+    mut updates: S,
+    mut board: Board,
+    debounce: Duration,
+    max_delay: Duration,
+    mut publish: impl FnMut(String),
+) where
+    S: Stream<Item = (usize, &'static str)> + Unpin,
+{
+    while let Some(update) = updates.next().await {
+        board.set(update);
+        let deadline = Instant::now() + max_delay;
+        loop {
+            let wait_until = deadline.min(Instant::now() + debounce);
+            let next = async { Some(updates.next().await) }
+                .or(async {
+                    Timer::at(wait_until).await;
+                    None
+                })
+                .await;
+            match next {
+                Some(Some(update)) => board.set(update),
+                Some(None) => {
+                    publish(board.payload());
+                    return;
+                }
+                None => break,
+            }
+        }
+        publish(board.payload());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_are_combined_into_one_payload() {
+        let updates = stream::iter(vec![(2, "#0000ff"), (0, "#ff0000"), (2, "#00ff00")]);
+        let mut published = Vec::new();
+
+        async_io::block_on(run(
+            updates,
+            Board::new(3),
+            Duration::from_millis(50),
+            Duration::from_secs(5),
+            |payload| published.push(payload),
+        ));
+
+        assert_eq!(published, vec![r##"["#ff0000","#000000","#00ff00"]"##]);
+    }
+
+    #[test]
+    fn changes_after_debounce_are_published_separately() {
+        let updates = stream::iter(vec![(0, "#ff0000")]).chain(stream::once(async {
+            Timer::after(Duration::from_millis(100)).await;
+            (1, "#0000ff")
+        }));
+        let mut published = Vec::new();
+
+        async_io::block_on(run(
+            Box::pin(updates),
+            Board::new(2),
+            Duration::from_millis(20),
+            Duration::from_secs(5),
+            |payload| published.push(payload),
+        ));
+
+        assert_eq!(
+            published,
+            vec![r##"["#ff0000","#000000"]"##, r##"["#ff0000","#0000ff"]"##]
+        );
+    }
+
+    #[test]
+    fn continuous_changes_are_published_after_max_delay() {
+        // Changes every 5ms never leave the 20ms debounce quiet
+        let updates = stream::iter(0..60).then(|i| async move {
+            Timer::after(Duration::from_millis(5)).await;
+            (0, if i % 2 == 0 { "#ff0000" } else { "#0000ff" })
+        });
+        let mut published = Vec::new();
+
+        async_io::block_on(run(
+            Box::pin(updates),
+            Board::new(1),
+            Duration::from_millis(20),
+            Duration::from_millis(50),
+            |payload| published.push(payload),
+        ));
+
+        // About 300ms of changes, one publish every 50ms and the last state at the end
+        assert!(published.len() >= 3, "{:?}", published);
+        assert_eq!(published.last().unwrap(), r##"["#0000ff"]"##);
+    }
+}
@@ -1,54 +1,329 @@
 use crate::actors::shelly::Shelly;
+use crate::config::Secret;
 use crate::resources::state::State;
+use crate::shutdown::ShutdownSignal;
+use crate::utils::http;
+use crate::utils::ratelimit::LogLimiter;
 use crate::{Config, ResourcesHandle};
 use async_compat::CompatExt;
+use async_io::Timer;
 use executor::pool::Executor;
-use futures_signals::signal::Signal;
+use futures_lite::FutureExt;
+use futures_signals::signal::{MutableSignal, Signal};
 use futures_util::future::BoxFuture;
-use rumqttc::{AsyncClient, ConnectionError, Event, Incoming, MqttOptions};
+use rumqttc::{AsyncClient, ClientError, ConnectionError, Event, Incoming, MqttOptions, QoS};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::future::Future;
 
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 use miette::Diagnostic;
 use std::task::{Context, Poll};
 use std::time::Duration;
 use thiserror::Error;
 
-use once_cell::sync::Lazy;
 use rumqttc::ConnectReturnCode::Success;
 
 use crate::actors::dummy::Dummy;
+use crate::actors::ledboard::LedBoard;
+use crate::actors::modbus::Modbus;
 use crate::actors::process::Process;
+use crate::actors::webhook::Webhook;
 use crate::db::ArchivedValue;
-use rustls::RootCertStore;
+use lightproc::recoverable_handle::RecoverableHandle;
 use url::Url;
 
+mod desync;
 mod dummy;
+#[cfg(feature = "gpio")]
+mod gpio;
+mod ledboard;
+mod modbus;
+mod payload;
 mod process;
 mod shelly;
+mod topic;
+mod webhook;
+
+pub use desync::desync_counts;
 
 pub trait Actor {
+    /// Id of the actor in the config, to tell actors apart in logs
+    fn name(&self) -> &str;
+
     fn apply(&mut self, state: ArchivedValue<State>) -> BoxFuture<'static, ()>;
 }
 
+pub type MessageHandler = Box<dyn Fn(&[u8]) + Send + Sync>;
+
+#[derive(Clone, Default)]
+/// Handlers for incoming MQTT messages, keyed by topic
+struct Subscriptions {
+    handlers: Arc<Mutex<HashMap<String, Vec<MessageHandler>>>>,
+}
+
+impl Subscriptions {
+    /// Call `handler` with the payload of every message published to `topic`
+    ///
+    /// Returns whether `topic` is new, i.e. whether the broker still has to be asked for it.
+    fn register(&self, topic: String, handler: MessageHandler) -> bool {
+        let mut handlers = self.handlers.lock().unwrap();
+        let registered = handlers.entry(topic).or_default();
+        registered.push(handler);
+        registered.len() == 1
+    }
+
+    fn topics(&self) -> Vec<String> {
+        self.handlers.lock().unwrap().keys().cloned().collect()
+    }
+
+    fn dispatch(&self, topic: &str, payload: &[u8]) {
+        if let Some(handlers) = self.handlers.lock().unwrap().get(topic) {
+            for handler in handlers {
+                handler(payload)
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+/// The MQTT connection, shared with everything else receiving messages from the broker
+pub struct MqttClient {
+    client: AsyncClient,
+    subscriptions: Subscriptions,
+}
+
+impl MqttClient {
+    /// Subscribe to `topic`, calling `handler` with the payload of every message published to it
+    ///
+    /// Several handlers can subscribe to the same topic, all of them are called for every message.
+    /// Handlers are called from the MQTT event loop, so they must not block.
+    pub fn subscribe(&self, topic: String, handler: MessageHandler) -> Result<(), ClientError> {
+        if self.subscriptions.register(topic.clone(), handler) {
+            self.client.try_subscribe(topic, QoS::AtLeastOnce)?;
+        }
+        Ok(())
+    }
+
+    /// Subscribe to all topics again, the broker forgets them when the connection is lost
+    fn resubscribe(&self) {
+        for topic in self.subscriptions.topics() {
+            if let Err(error) = self.client.try_subscribe(topic.clone(), QoS::AtLeastOnce) {
+                tracing::error!(%topic, ?error, "failed to subscribe to MQTT topic again");
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct StatusInner {
+    applying: AtomicBool,
+    queued: AtomicUsize,
+}
+
+#[derive(Clone, Debug, Default)]
+/// What an [`ActorDriver`] is busy with, observable after the driver has been spawned
+pub struct DriverStatus(Arc<StatusInner>);
+
+impl DriverStatus {
+    /// Whether the actor is currently applying a state
+    pub fn is_applying(&self) -> bool {
+        self.0.applying.load(Ordering::Relaxed)
+    }
+
+    /// Number of states waiting for the running `apply` to finish
+    pub fn queued(&self) -> usize {
+        self.0.queued.load(Ordering::Relaxed)
+    }
+}
+
+/// Future finishing once the given time passed
+type Sleep = Arc<dyn Fn(Duration) -> BoxFuture<'static, ()> + Send + Sync>;
+
 pub struct ActorDriver<S: 'static> {
     signal: S,
 
     actor: Box<dyn Actor + Send + Sync>,
     future: Option<BoxFuture<'static, ()>>,
+
+    /// Time an `apply` may take before it's dropped to continue with the next state
+    timeout: Option<Duration>,
+    deadline: Option<Timer>,
+
+    /// States that changed while an `apply` was running, oldest first
+    queue: VecDeque<ArchivedValue<State>>,
+    queue_depth: usize,
+    status: DriverStatus,
+
+    /// Time a new state has to stay unchanged before it's applied
+    settle: Option<Duration>,
+    /// State waiting for the settle delay to pass
+    settling: Option<(ArchivedValue<State>, BoxFuture<'static, ()>)>,
+    /// Waits for the settle delay, replaced by tests to control time
+    sleep: Sleep,
+    /// Last state applied, to tell when a state reverted while settling
+    applied: Option<ArchivedValue<State>>,
+
+    shutdown: MutableSignal<bool>,
+    stopping: bool,
 }
 
 impl<S: Signal<Item = ArchivedValue<State>>> ActorDriver<S> {
-    pub fn new(signal: S, actor: Box<dyn Actor + Send + Sync>) -> Self {
+    pub fn new(signal: S, actor: Box<dyn Actor + Send + Sync>, shutdown: &ShutdownSignal) -> Self {
         Self {
             signal,
             actor,
             future: None,
+            timeout: None,
+            deadline: None,
+            queue: VecDeque::new(),
+            queue_depth: 0,
+            status: DriverStatus::default(),
+            settle: None,
+            settling: None,
+            sleep: Arc::new(|delay| {
+                Box::pin(async move {
+                    Timer::after(delay).await;
+                })
+            }),
+            applied: None,
+            shutdown: shutdown.signal(),
+            stopping: false,
         }
     }
+
+    /// Give up on an `apply` that didn't finish within `timeout`
+    ///
+    /// This keeps a single unresponsive device from blocking all later state changes.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Keep up to `depth` states that change while an `apply` is running, to apply them in order
+    ///
+    /// Without a queue only the latest of those states is applied. Once the queue is full the
+    /// oldest queued state is dropped. Changes made faster than the driver gets woken up are still
+    /// merged into the latest one by the signal.
+    pub fn with_queue(mut self, depth: usize) -> Self {
+        self.queue_depth = depth;
+        self
+    }
+
+    /// Only apply a state once it stayed unchanged for `delay`
+    ///
+    /// States replaced within the delay are skipped, so hardware isn't switched on and off by
+    /// momentary changes. This is independent of the machine's own debounce. States queued while
+    /// an `apply` is running are applied in order without waiting.
+    pub fn with_settle_delay(mut self, delay: Duration) -> Self {
+        self.settle = Some(delay);
+        self
+    }
+
+    /// Wait for the settle delay using `sleep` instead of a timer
+    #[cfg(test)]
+    fn with_sleep(
+        mut self,
+        sleep: impl Fn(Duration) -> BoxFuture<'static, ()> + Send + Sync + 'static,
+    ) -> Self {
+        self.sleep = Arc::new(sleep);
+        self
+    }
+
+    /// Handle to observe the driver with once it's spawned
+    pub fn status(&self) -> DriverStatus {
+        self.status.clone()
+    }
+
+    fn start(&mut self, state: ArchivedValue<State>) {
+        if self.settle.is_some() {
+            self.applied = Some(ArchivedValue::build(state.as_slice()));
+        }
+        // This future MUST be polled before we exit from the Actor::poll because if we
+        // do not do that it will not register the dependency and thus NOT BE POLLED.
+        let f = self.actor.apply(state);
+        self.future.replace(f);
+        self.deadline = self.timeout.map(Timer::after);
+        self.status.0.applying.store(true, Ordering::Relaxed);
+    }
+
+    fn finish(&mut self) {
+        self.future = None;
+        self.deadline = None;
+        self.status.0.applying.store(false, Ordering::Relaxed);
+    }
+
+    /// Wait for the settle delay before applying `state`, skipping any state still waiting
+    fn settle(&mut self, state: ArchivedValue<State>, delay: Duration) {
+        let same = |other: &ArchivedValue<State>| other.as_slice() == state.as_slice();
+        if self
+            .settling
+            .as_ref()
+            .map_or(false, |(settling, _)| same(settling))
+        {
+            return;
+        }
+        if self.settling.take().is_some() {
+            tracing::debug!(
+                actor = self.actor.name(),
+                "state changed within settle delay, skipping it"
+            );
+        }
+        // Reverted to the state the device is already in
+        if self.applied.as_ref().map_or(false, same) {
+            return;
+        }
+        self.settling = Some((state, (self.sleep)(delay)));
+    }
+
+    /// Whether the state waiting for the settle delay can be applied
+    fn poll_settled(&mut self, cx: &mut Context) -> bool {
+        self.settling
+            .as_mut()
+            .map_or(false, |(_, timer)| timer.as_mut().poll(cx).is_ready())
+    }
+
+    /// Queue the states that changed while an `apply` is running
+    fn poll_queue(&mut self, cx: &mut Context)
+    where
+        S: Unpin,
+    {
+        if self.queue_depth == 0 {
+            return;
+        }
+        while let Poll::Ready(Some(state)) = Pin::new(&mut self.signal).poll_change(cx) {
+            if self.queue.len() >= self.queue_depth {
+                tracing::warn!(
+                    actor = self.actor.name(),
+                    depth = self.queue_depth,
+                    "actor queue is full, dropping the oldest queued state"
+                );
+                self.queue.pop_front();
+            }
+            self.queue.push_back(state);
+        }
+        self.status
+            .0
+            .queued
+            .store(self.queue.len(), Ordering::Relaxed);
+    }
+
+    /// Whether the running `apply` took longer than allowed
+    fn poll_deadline(&mut self, cx: &mut Context) -> bool {
+        self.deadline
+            .as_mut()
+            .map_or(false, |deadline| Pin::new(deadline).poll(cx).is_ready())
+    }
+
+    fn poll_shutdown(&mut self, cx: &mut Context) -> bool {
+        while let Poll::Ready(Some(stopping)) = Pin::new(&mut self.shutdown).poll_change(cx) {
+            self.stopping = stopping;
+        }
+        self.stopping
+    }
 }
 
 impl<S> Future for ActorDriver<S>
@@ -70,48 +345,58 @@ where
                 None => {}
 
                 // This apply future is done, get a new one
-                Some(Poll::Ready(_)) => self.future = None,
+                Some(Poll::Ready(_)) => self.finish(),
+
+                Some(Poll::Pending) if self.poll_deadline(cx) => {
+                    tracing::warn!(
+                        actor = self.actor.name(),
+                        timeout = ?self.timeout,
+                        "actor did not apply state in time, continuing with the next state"
+                    );
+                    self.finish();
+                }
 
                 // This future would block so we return to continue work another time
-                Some(Poll::Pending) => return Poll::Pending,
+                Some(Poll::Pending) => {
+                    self.poll_queue(cx);
+                    return Poll::Pending;
+                }
+            }
+
+            if let Some(state) = self.queue.pop_front() {
+                self.status
+                    .0
+                    .queued
+                    .store(self.queue.len(), Ordering::Relaxed);
+                self.start(state);
+                continue;
+            }
+
+            if self.poll_settled(cx) {
+                if let Some((state, _)) = self.settling.take() {
+                    self.start(state);
+                    continue;
+                }
             }
 
             // Poll the signal and apply any change that happen to the inner Actuator
             match Pin::new(&mut self.signal).poll_change(cx) {
+                // Only stop once all changes made so far have been applied
+                Poll::Pending if self.poll_shutdown(cx) => match self.settling.take() {
+                    Some((state, _)) => self.start(state),
+                    None => return Poll::Ready(()),
+                },
                 Poll::Pending => return Poll::Pending,
                 Poll::Ready(None) => return Poll::Ready(()),
-                Poll::Ready(Some(state)) => {
-                    // This future MUST be polled before we exit from the Actor::poll because if we
-                    // do not do that it will not register the dependency and thus NOT BE POLLED.
-                    let f = self.actor.apply(state);
-                    self.future.replace(f);
-                }
+                Poll::Ready(Some(state)) => match self.settle {
+                    Some(delay) => self.settle(state, delay),
+                    None => self.start(state),
+                },
             }
         }
     }
 }
 
-static ROOT_CERTS: Lazy<RootCertStore> = Lazy::new(|| {
-    let span = tracing::info_span!("loading system certificates");
-    let _guard = span.enter();
-    let mut store = RootCertStore::empty();
-    match rustls_native_certs::load_native_certs() {
-        Ok(certs) => {
-            let certs: Vec<Vec<u8>> = certs.into_iter().map(|c| c.0).collect();
-            let (loaded, ignored) = store.add_parsable_certificates(&certs[..]);
-            if ignored != 0 {
-                tracing::info!(loaded, ignored, "certificates loaded, some ignored");
-            } else {
-                tracing::info!(loaded, "certificates loaded");
-            }
-        }
-        Err(error) => {
-            tracing::error!(%error, "failed to load system certificates");
-        }
-    }
-    store
-});
-
 #[derive(Debug, Error, Diagnostic)]
 pub enum ActorError {
     #[error("failed to parse MQTT url")]
@@ -130,11 +415,22 @@ pub enum ActorError {
     ),
 }
 
+/// Tasks started by [`load`]
+pub struct ActorTasks {
+    /// One task per actor, finishing once the actor applied all pending state after shutdown
+    pub actors: Vec<RecoverableHandle<()>>,
+    /// The MQTT client, which has to keep running until all actors are stopped
+    pub mqtt: RecoverableHandle<()>,
+    /// Connection to subscribe to further topics with, e.g. for initiators
+    pub client: MqttClient,
+}
+
 pub fn load(
     executor: Executor,
     config: &Config,
     resources: ResourcesHandle,
-) -> Result<(), ActorError> {
+    shutdown: &ShutdownSignal,
+) -> Result<ActorTasks, ActorError> {
     let span = tracing::info_span!("loading actors");
     let _guard = span;
 
@@ -144,7 +440,7 @@ pub fn load(
             rumqttc::Transport::tls_with_config(
                 rumqttc::ClientConfig::builder()
                     .with_safe_defaults()
-                    .with_root_certificates(ROOT_CERTS.clone())
+                    .with_root_certificates(http::ROOT_CERTS.clone())
                     .with_no_client_auth()
                     .into(),
             ),
@@ -171,7 +467,13 @@ pub fn load(
         .set_keep_alive(Duration::from_secs(20));
 
     if !mqtt_url.username().is_empty() {
-        mqttoptions.set_credentials(mqtt_url.username(), mqtt_url.password().unwrap_or_default());
+        let password = config
+            .mqtt_password
+            .as_ref()
+            .map(Secret::expose)
+            .or_else(|| mqtt_url.password())
+            .unwrap_or_default();
+        mqttoptions.set_credentials(mqtt_url.username(), password);
     }
 
     let (mqtt, mut eventloop) = AsyncClient::new(mqttoptions, 256);
@@ -200,14 +502,31 @@ pub fn load(
         .compat(),
     )?;
 
-    executor.spawn(
+    let client = MqttClient {
+        client: mqtt.clone(),
+        subscriptions: Subscriptions::default(),
+    };
+    let incoming = client.clone();
+    let mqtt_task = executor.spawn_named(
+        "mqtt:eventloop",
         async move {
             let mut fault = false;
+            let mut limiter = LogLimiter::default();
             loop {
                 match eventloop.poll().compat().await {
+                    Ok(Event::Incoming(Incoming::Publish(publish))) => {
+                        fault = false;
+                        incoming
+                            .subscriptions
+                            .dispatch(&publish.topic, &publish.payload);
+                    }
+                    Ok(Event::Incoming(Incoming::ConnAck(connack))) => {
+                        fault = false;
+                        tracing::info!(?connack, "MQTT connection re-established");
+                        incoming.resubscribe();
+                    }
                     Ok(_) => {
                         fault = false;
-                        // TODO: Handle incoming MQTT messages
                     }
                     Err(ConnectionError::Cancel)
                     | Err(ConnectionError::StreamDone)
@@ -217,9 +536,12 @@ pub fn load(
                         return;
                     }
                     Err(ConnectionError::Timeout(_)) => {
-                        tracing::error!("MQTT operation timed out!");
-                        tracing::warn!(
-                            "MQTT client will continue, but messages may have been lost."
+                        crate::log_limited!(
+                            limiter,
+                            "timeout",
+                            error,
+                            "MQTT operation timed out! \
+                            MQTT client will continue, but messages may have been lost."
                         )
                         // Timeout does not close the client
                     }
@@ -230,7 +552,14 @@ pub fn load(
                     }
                     Err(ConnectionError::Io(error)) => {
                         fault = true;
-                        tracing::error!(?error, "MQTT encountered IO error");
+                        let key = error.to_string();
+                        crate::log_limited!(
+                            limiter,
+                            &key,
+                            error,
+                            ?error,
+                            "MQTT encountered IO error"
+                        );
                         // *First* IO error does not close the client.
                     }
                     Err(error) => {
@@ -248,7 +577,7 @@ pub fn load(
         .iter()
         .filter_map(|(k, v)| {
             if let Some(resource) = resources.get_by_id(v) {
-                Some((k.clone(), resource.get_signal()))
+                Some((k.clone(), (v.clone(), resource.get_signal())))
             } else {
                 tracing::error!(actor=%k, machine=%v, "Machine configured for actor not found!");
                 None
@@ -256,12 +585,58 @@ pub fn load(
         })
         .collect();
 
+    let mut tasks = Vec::new();
     for (name, cfg) in config.actors.iter() {
-        if let Some(sig) = actor_map.remove(name) {
-            if let Some(actor) = load_single(name, &cfg.module, &cfg.params, mqtt.clone()) {
-                let driver = ActorDriver::new(sig, actor);
+        // Status boards watch several machines and are configured entirely through their params
+        if cfg.module == "LedBoard" {
+            if let Some(board) = LedBoard::new(name.clone(), &cfg.params, &resources, mqtt.clone())
+            {
+                let shutdown = shutdown.clone();
+                let task = board.run().or(async move { shutdown.wait().await });
+                tasks.push(executor.spawn_named(&format!("actor:{}", name), task));
+            } else {
+                tracing::error!(%name, "LedBoard actor is misconfigured. Skipping!");
+            }
+            continue;
+        }
+
+        if let Some((machine, sig)) = actor_map.remove(name) {
+            let timeout = match cfg.params.get("apply_timeout_ms").map(|ms| ms.parse()) {
+                None => None,
+                Some(Ok(ms)) => Some(Duration::from_millis(ms)),
+                Some(Err(error)) => {
+                    tracing::error!(%name, %error, "invalid `apply_timeout_ms` for actor. Skipping!");
+                    continue;
+                }
+            };
+            let queue_depth = match cfg.params.get("queue_depth").map(|n| n.parse()) {
+                None => 0,
+                Some(Ok(n)) => n,
+                Some(Err(error)) => {
+                    tracing::error!(%name, %error, "invalid `queue_depth` for actor. Skipping!");
+                    continue;
+                }
+            };
+            let settle = match cfg.params.get("settle_ms").map(|ms| ms.parse()) {
+                None => None,
+                Some(Ok(ms)) => Some(Duration::from_millis(ms)),
+                Some(Err(error)) => {
+                    tracing::error!(%name, %error, "invalid `settle_ms` for actor. Skipping!");
+                    continue;
+                }
+            };
+            if let Some(actor) =
+                load_single(&executor, name, machine, &cfg.module, &cfg.params, &client)
+            {
+                let mut driver = ActorDriver::new(sig, actor, shutdown).with_queue(queue_depth);
+                if let Some(timeout) = timeout {
+                    driver = driver.with_timeout(timeout);
+                }
+                if let Some(settle) = settle {
+                    driver = driver.with_settle_delay(settle);
+                }
                 tracing::debug!(module_name=%cfg.module, %name, "starting actor task");
-                executor.spawn(driver);
+                tasks.push(executor.spawn_named(&format!("actor:{}", name), driver));
             } else {
                 tracing::error!(module_name=%cfg.module, %name, "Actor module type not found");
             }
@@ -270,20 +645,262 @@ pub fn load(
         }
     }
 
-    Ok(())
+    Ok(ActorTasks {
+        actors: tasks,
+        mqtt: mqtt_task,
+        client,
+    })
 }
 
 fn load_single(
+    executor: &Executor,
     name: &String,
+    machine: String,
     module_name: &String,
     params: &HashMap<String, String>,
-    client: AsyncClient,
+    mqtt: &MqttClient,
 ) -> Option<Box<dyn Actor + Sync + Send>> {
     tracing::info!(%name, %module_name, ?params, "Loading actor");
     match module_name.as_ref() {
         "Dummy" => Some(Box::new(Dummy::new(name.clone(), params.clone()))),
         "Process" => Process::new(name.clone(), params).map(|a| a.into_boxed_actuator()),
-        "Shelly" => Some(Box::new(Shelly::new(name.clone(), client, params))),
+        "Modbus" => {
+            Modbus::new(name.clone(), params).map(|a| Box::new(a) as Box<dyn Actor + Sync + Send>)
+        }
+        "Shelly" => Shelly::new(name.clone(), machine, mqtt, params)
+            .map(|a| Box::new(a) as Box<dyn Actor + Sync + Send>),
+        "Webhook" => Webhook::new(name.clone(), machine, params).map(|(actor, task)| {
+            executor.spawn_named(&format!("webhook:{}", name), task);
+            Box::new(actor) as Box<dyn Actor + Sync + Send>
+        }),
+        #[cfg(feature = "gpio")]
+        "Gpio" => gpio::Gpio::new(name.clone(), params).map(|(actor, task)| {
+            executor.spawn_named(&format!("gpio:{}", name), task);
+            Box::new(actor) as Box<dyn Actor + Sync + Send>
+        }),
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::modules::fabaccess::{ArchivedStatus, MachineState};
+    use crate::users::UserRef;
+    use futures_signals::signal::{Mutable, SignalExt};
+    use rkyv::ser::serializers::AllocSerializer;
+    use rkyv::ser::Serializer;
+
+    fn state(state: MachineState) -> ArchivedValue<State> {
+        let mut serializer = AllocSerializer::<1024>::default();
+        serializer.serialize_value(&state.to_state()).unwrap();
+        ArchivedValue::new(serializer.into_serializer().into_inner())
+    }
+
+    /// Never finishes applying the first state it is given
+    struct Stuck(Arc<Mutex<Vec<ArchivedValue<State>>>>);
+    impl Actor for Stuck {
+        fn name(&self) -> &str {
+            "Stuck"
+        }
+
+        fn apply(&mut self, state: ArchivedValue<State>) -> BoxFuture<'static, ()> {
+            let mut applied = self.0.lock().unwrap();
+            applied.push(state);
+            if applied.len() == 1 {
+                Box::pin(std::future::pending())
+            } else {
+                Box::pin(async {})
+            }
+        }
+    }
+
+    /// Takes a moment to apply every state
+    struct Slow(Arc<Mutex<Vec<ArchivedValue<State>>>>);
+    impl Actor for Slow {
+        fn name(&self) -> &str {
+            "Slow"
+        }
+
+        fn apply(&mut self, state: ArchivedValue<State>) -> BoxFuture<'static, ()> {
+            self.0.lock().unwrap().push(state);
+            Box::pin(async {
+                Timer::after(Duration::from_millis(20)).await;
+            })
+        }
+    }
+
+    /// Applies every state right away
+    struct Record(Arc<Mutex<Vec<ArchivedValue<State>>>>);
+    impl Actor for Record {
+        fn name(&self) -> &str {
+            "Record"
+        }
+
+        fn apply(&mut self, state: ArchivedValue<State>) -> BoxFuture<'static, ()> {
+            self.0.lock().unwrap().push(state);
+            Box::pin(async {})
+        }
+    }
+
+    #[test]
+    fn name_is_kept_when_boxed() {
+        let actor: Box<dyn Actor + Send + Sync> =
+            Box::new(Dummy::new("Dummy1".to_string(), HashMap::new()));
+        assert_eq!(actor.name(), "Dummy1");
+    }
+
+    #[test]
+    fn stuck_apply_times_out() {
+        let signal = Mutable::new(state(MachineState::free(None)));
+        let applied = Arc::new(Mutex::new(Vec::new()));
+        let mut driver = ActorDriver::new(
+            signal.signal_cloned(),
+            Box::new(Stuck(applied.clone())),
+            &ShutdownSignal::new(),
+        )
+        .with_timeout(Duration::from_millis(50));
+
+        async_io::block_on(async {
+            futures_lite::future::poll_once(&mut driver).await;
+            signal.set(state(MachineState::disabled(None, None)));
+            Timer::after(Duration::from_millis(100)).await;
+            futures_lite::future::poll_once(&mut driver).await;
+        });
+
+        let applied = applied.lock().unwrap();
+        assert_eq!(applied.len(), 2);
+        assert_eq!(applied[1].as_ref().inner.state, ArchivedStatus::Disabled);
+    }
+
+    #[test]
+    fn changes_during_apply_are_queued_in_order() {
+        let signal = Mutable::new(state(MachineState::free(None)));
+        let applied = Arc::new(Mutex::new(Vec::new()));
+        let mut driver = ActorDriver::new(
+            signal.signal_cloned(),
+            Box::new(Slow(applied.clone())),
+            &ShutdownSignal::new(),
+        )
+        .with_queue(4);
+        let status = driver.status();
+        let user = UserRef::new("user".to_string());
+
+        async_io::block_on(async {
+            futures_lite::future::poll_once(&mut driver).await;
+            assert!(status.is_applying());
+            signal.set(state(MachineState::used(user, None)));
+            futures_lite::future::poll_once(&mut driver).await;
+            signal.set(state(MachineState::disabled(None, None)));
+            futures_lite::future::poll_once(&mut driver).await;
+            assert_eq!(status.queued(), 2);
+
+            (&mut driver)
+                .or(async {
+                    Timer::after(Duration::from_millis(200)).await;
+                })
+                .await;
+        });
+
+        assert!(!status.is_applying());
+        assert_eq!(status.queued(), 0);
+        let applied = applied.lock().unwrap();
+        assert_eq!(applied.len(), 3);
+        assert!(matches!(
+            applied[1].as_ref().inner.state,
+            ArchivedStatus::InUse(_)
+        ));
+        assert_eq!(applied[2].as_ref().inner.state, ArchivedStatus::Disabled);
+    }
+
+    #[test]
+    fn only_settled_states_are_applied() {
+        // Milliseconds passed, only moving when the test advances it
+        let clock = Mutable::new(0u64);
+        let sleep = {
+            let clock = clock.clone();
+            move |delay: Duration| -> BoxFuture<'static, ()> {
+                let deadline = clock.get() + delay.as_millis() as u64;
+                let mut now = clock.signal().to_stream();
+                Box::pin(async move {
+                    futures_lite::StreamExt::find(&mut now, |now| *now >= deadline).await;
+                })
+            }
+        };
+        let signal = Mutable::new(state(MachineState::free(None)));
+        let applied = Arc::new(Mutex::new(Vec::new()));
+        let mut driver = ActorDriver::new(
+            signal.signal_cloned(),
+            Box::new(Record(applied.clone())),
+            &ShutdownSignal::new(),
+        )
+        .with_settle_delay(Duration::from_millis(50))
+        .with_sleep(sleep);
+        let user = UserRef::new("user".to_string());
+        let applied_count = || applied.lock().unwrap().len();
+
+        let mut advance = |ms| {
+            clock.replace_with(|now| *now + ms);
+            async_io::block_on(futures_lite::future::poll_once(&mut driver));
+        };
+
+        advance(0);
+        advance(49);
+        assert_eq!(applied_count(), 0);
+        advance(1);
+        assert_eq!(applied_count(), 1);
+
+        // Reverts before the delay passed, the device never sees it
+        signal.set(state(MachineState::used(user.clone(), None)));
+        advance(0);
+        advance(10);
+        signal.set(state(MachineState::free(None)));
+        advance(0);
+        advance(100);
+        assert_eq!(applied_count(), 1);
+
+        // Replaced before the delay passed, only the latest state is applied, a full delay after
+        // it was set
+        signal.set(state(MachineState::disabled(None, None)));
+        advance(0);
+        advance(10);
+        signal.set(state(MachineState::used(user, None)));
+        advance(0);
+        advance(49);
+        assert_eq!(applied_count(), 1);
+        advance(1);
+
+        let applied = applied.lock().unwrap();
+        assert_eq!(applied.len(), 2);
+        assert_eq!(applied[0].as_ref().inner.state, ArchivedStatus::Free);
+        assert!(matches!(
+            applied[1].as_ref().inner.state,
+            ArchivedStatus::InUse(_)
+        ));
+    }
+
+    #[test]
+    fn every_handler_of_a_topic_is_called() {
+        let subscriptions = Subscriptions::default();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let handler = |name: &'static str| -> MessageHandler {
+            let received = received.clone();
+            Box::new(move |payload| received.lock().unwrap().push((name, payload.to_vec())))
+        };
+
+        assert!(subscriptions.register("a".to_string(), handler("first")));
+        // Only the first handler of a topic has to subscribe at the broker
+        assert!(!subscriptions.register("a".to_string(), handler("second")));
+        assert!(subscriptions.register("b".to_string(), handler("other")));
+
+        subscriptions.dispatch("a", b"on");
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![("first", b"on".to_vec()), ("second", b"on".to_vec())]
+        );
+
+        let mut topics = subscriptions.topics();
+        topics.sort();
+        assert_eq!(topics, vec!["a".to_string(), "b".to_string()]);
+    }
+}
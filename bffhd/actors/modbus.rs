@@ -0,0 +1,315 @@
+//! Actor switching a coil or holding register of a device over Modbus TCP
+
+use futures_lite::{AsyncReadExt, AsyncWriteExt, FutureExt};
+use futures_util::future::BoxFuture;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_io::Timer;
+use async_net::TcpStream;
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::actors::Actor;
+use crate::db::ArchivedValue;
+use crate::resources::modules::fabaccess::ArchivedStatus;
+use crate::resources::state::State;
+use crate::utils::ratelimit::LogLimiter;
+
+/// Port Modbus TCP devices listen on unless configured otherwise
+const DEFAULT_PORT: u16 = 502;
+/// Time connecting to the device and getting its answer may take
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum ModbusError {
+    #[error("failed to talk to the Modbus device")]
+    #[diagnostic(code(bffh::actors::modbus::io))]
+    Io(#[from] io::Error),
+    #[error("Modbus device answered with exception code {0:#04x}")]
+    #[diagnostic(
+        code(bffh::actors::modbus::exception),
+        help("Check that the `unit_id` and the coil or register address are right")
+    )]
+    Exception(u8),
+    #[error("Modbus device answer does not match the request")]
+    #[diagnostic(code(bffh::actors::modbus::mismatch))]
+    Mismatch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What is written to switch the device
+enum Target {
+    Coil(u16),
+    Register { address: u16, on: u16, off: u16 },
+}
+
+impl Target {
+    fn from_params(params: &HashMap<String, String>) -> Option<Self> {
+        let number = |key: &str, default: Option<u16>| match params.get(key) {
+            Some(value) => value.parse().ok(),
+            None => default,
+        };
+        match (params.get("coil"), params.get("register")) {
+            (Some(_), None) => Some(Self::Coil(number("coil", None)?)),
+            (None, Some(_)) => Some(Self::Register {
+                address: number("register", None)?,
+                on: number("on_value", Some(1))?,
+                off: number("off_value", Some(0))?,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Function code, address and value to write for the given power state
+    fn write(&self, powered: bool) -> (u8, u16, u16) {
+        match *self {
+            Self::Coil(address) => (
+                WRITE_SINGLE_COIL,
+                address,
+                if powered { 0xFF00 } else { 0x0000 },
+            ),
+            Self::Register { address, on, off } => (
+                WRITE_SINGLE_REGISTER,
+                address,
+                if powered { on } else { off },
+            ),
+        }
+    }
+}
+
+/// Modbus TCP request frame: MBAP header followed by the PDU
+fn frame(transaction: u16, unit_id: u8, function: u8, address: u16, value: u16) -> [u8; 12] {
+    let mut frame = [0; 12];
+    frame[0..2].copy_from_slice(&transaction.to_be_bytes());
+    // Bytes 2..4 are the protocol id, always 0 for Modbus
+    frame[4..6].copy_from_slice(&6u16.to_be_bytes());
+    frame[6] = unit_id;
+    frame[7] = function;
+    frame[8..10].copy_from_slice(&address.to_be_bytes());
+    frame[10..12].copy_from_slice(&value.to_be_bytes());
+    frame
+}
+
+/// Send a single write request on a fresh connection and check the device's answer
+async fn write(addr: &str, request: [u8; 12]) -> Result<(), ModbusError> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(&request).await?;
+
+        // MBAP header and function code, which has the high bit set for exceptions
+        let mut header = [0; 8];
+        stream.read_exact(&mut header).await?;
+        if header[7] == request[7] | 0x80 {
+            let mut code = [0; 1];
+            stream.read_exact(&mut code).await?;
+            return Err(ModbusError::Exception(code[0]));
+        }
+        // Successful writes are answered with an echo of the request
+        let mut body = [0; 4];
+        stream.read_exact(&mut body).await?;
+        if header[..] != request[..8] || body[..] != request[8..] {
+            return Err(ModbusError::Mismatch);
+        }
+        Ok::<(), ModbusError>(())
+    };
+    exchange
+        .or(async {
+            Timer::after(TIMEOUT).await;
+            Err(ModbusError::Io(io::ErrorKind::TimedOut.into()))
+        })
+        .await
+}
+
+/// An actuator switching a device over Modbus TCP
+///
+/// The device at `host` and `port` (default 502) is powered while the machine is in use. With
+/// `coil` set that coil is switched; with `register` set the holding register is written
+/// `on_value` (default 1) or `off_value` (default 0). `unit_id` defaults to 1.
+///
+/// Writes only happen when the power state changes. A new connection is opened for every write.
+pub struct Modbus {
+    name: String,
+    addr: String,
+    unit_id: u8,
+    target: Target,
+    transaction: u16,
+    /// Last power state the device confirmed, so failed writes are retried on the next state
+    written: Arc<Mutex<Option<bool>>>,
+    /// Keeps an unreachable device from flooding the log with failed writes
+    limiter: Arc<Mutex<LogLimiter>>,
+}
+
+impl Modbus {
+    pub fn new(name: String, params: &HashMap<String, String>) -> Option<Self> {
+        let host = match params.get("host") {
+            Some(host) => host,
+            None => {
+                tracing::error!(%name, "`Modbus` actor needs a `host` parameter");
+                return None;
+            }
+        };
+        let port = match params.get("port").map(|port| port.parse()) {
+            None => DEFAULT_PORT,
+            Some(Ok(port)) => port,
+            Some(Err(error)) => {
+                tracing::error!(%name, %error, "invalid `port` for `Modbus` actor");
+                return None;
+            }
+        };
+        let unit_id = match params.get("unit_id").map(|id| id.parse()) {
+            None => 1,
+            Some(Ok(id)) => id,
+            Some(Err(error)) => {
+                tracing::error!(%name, %error, "invalid `unit_id` for `Modbus` actor");
+                return None;
+            }
+        };
+        let target = match Target::from_params(params) {
+            Some(target) => target,
+            None => {
+                tracing::error!(%name,
+                    "`Modbus` actor needs exactly one of `coil` or `register` and numeric values");
+                return None;
+            }
+        };
+
+        tracing::debug!(%name, %host, port, unit_id, ?target, "Starting Modbus module");
+        Some(Self {
+            name,
+            addr: format!("{}:{}", host, port),
+            unit_id,
+            target,
+            transaction: 0,
+            written: Arc::default(),
+            limiter: Arc::default(),
+        })
+    }
+}
+
+impl Actor for Modbus {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn apply(&mut self, state: ArchivedValue<State>) -> BoxFuture<'static, ()> {
+        let powered = matches!(state.as_ref().inner.state, ArchivedStatus::InUse(_));
+        if *self.written.lock().unwrap() == Some(powered) {
+            return Box::pin(async {});
+        }
+        tracing::debug!(?state, name=%self.name, powered, "Modbus actor changing state");
+
+        self.transaction = self.transaction.wrapping_add(1);
+        let (function, address, value) = self.target.write(powered);
+        let request = frame(self.transaction, self.unit_id, function, address, value);
+
+        let name = self.name.clone();
+        let addr = self.addr.clone();
+        let written = self.written.clone();
+        let limiter = self.limiter.clone();
+        Box::pin(async move {
+            match write(&addr, request).await {
+                Ok(()) => *written.lock().unwrap() = Some(powered),
+                Err(error) => {
+                    // Don't skip the next write just because an earlier one got through
+                    *written.lock().unwrap() = None;
+                    let mut limiter = limiter.lock().unwrap();
+                    let key = error.to_string();
+                    crate::log_limited!(limiter, &key, error, %error, %name, %addr,
+                        "`Modbus` actor failed to update state");
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::modules::fabaccess::MachineState;
+    use crate::users::UserRef;
+    use async_net::TcpListener;
+    use rkyv::ser::serializers::AllocSerializer;
+    use rkyv::ser::Serializer;
+
+    fn state(state: MachineState) -> ArchivedValue<State> {
+        let mut serializer = AllocSerializer::<1024>::default();
+        serializer.serialize_value(&state.to_state()).unwrap();
+        ArchivedValue::new(serializer.into_serializer().into_inner())
+    }
+
+    /// Answer one request with `answer`, or echo it if `None`, returning the request
+    async fn device(listener: &TcpListener, answer: Option<&[u8]>) -> [u8; 12] {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = [0; 12];
+        stream.read_exact(&mut request).await.unwrap();
+        stream
+            .write_all(answer.unwrap_or(&request[..]))
+            .await
+            .unwrap();
+        request
+    }
+
+    #[test]
+    fn coil_is_written_on_power_change() {
+        async_io::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let params = HashMap::from([
+                ("host".to_string(), "127.0.0.1".to_string()),
+                (
+                    "port".to_string(),
+                    listener.local_addr().unwrap().port().to_string(),
+                ),
+                ("unit_id".to_string(), "3".to_string()),
+                ("coil".to_string(), "16".to_string()),
+            ]);
+            let mut actor = Modbus::new("Modbus1".to_string(), &params).unwrap();
+            let user = UserRef::new("user".to_string());
+
+            let apply = actor.apply(state(MachineState::used(user.clone(), None)));
+            let (request, ()) = futures_lite::future::zip(device(&listener, None), apply).await;
+            assert_eq!(
+                request,
+                [0, 1, 0, 0, 0, 6, 3, WRITE_SINGLE_COIL, 0, 16, 0xFF, 0x00]
+            );
+
+            // Still powered, nothing to write
+            actor.apply(state(MachineState::used(user, None))).await;
+
+            // A failed write is tried again with the next state
+            let exception = [0, 2, 0, 0, 0, 3, 3, WRITE_SINGLE_COIL | 0x80, 0x02];
+            let apply = actor.apply(state(MachineState::free(None)));
+            futures_lite::future::zip(device(&listener, Some(&exception)), apply).await;
+            assert_eq!(*actor.written.lock().unwrap(), None);
+
+            let apply = actor.apply(state(MachineState::free(None)));
+            let (request, ()) = futures_lite::future::zip(device(&listener, None), apply).await;
+            assert_eq!(&request[8..], [0, 16, 0x00, 0x00]);
+            assert_eq!(*actor.written.lock().unwrap(), Some(false));
+        });
+    }
+
+    #[test]
+    fn invalid_params_are_rejected() {
+        let params = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        assert!(Modbus::new("m".into(), &params(&[("host", "plc"), ("coil", "1")])).is_some());
+        assert!(Modbus::new("m".into(), &params(&[("coil", "1")])).is_none());
+        assert!(Modbus::new("m".into(), &params(&[("host", "plc")])).is_none());
+        assert!(Modbus::new(
+            "m".into(),
+            &params(&[("host", "plc"), ("coil", "1"), ("register", "2")])
+        )
+        .is_none());
+        assert!(Modbus::new("m".into(), &params(&[("host", "plc"), ("register", "x")])).is_none());
+    }
+}
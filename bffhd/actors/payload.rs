@@ -0,0 +1,177 @@
+//! Encodings of the on/off state MQTT actors publish
+
+use std::collections::HashMap;
+
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::resources::modules::fabaccess::ArchivedStatus;
+
+#[derive(Debug, Error, Diagnostic, PartialEq, Eq)]
+pub enum PayloadFormatError {
+    #[error("unknown payload format `{0}`")]
+    #[diagnostic(
+        code(bffh::actors::payload::unknown),
+        help("Known formats are onoff, numeric, bool, json and custom")
+    )]
+    Unknown(String),
+    #[error("payload format `custom` needs both `payload_on` and `payload_off`")]
+    #[diagnostic(code(bffh::actors::payload::incomplete))]
+    Incomplete,
+    #[error("`payload_on` and `payload_off` are only used with payload format `custom`")]
+    #[diagnostic(
+        code(bffh::actors::payload::unused),
+        help("Set `payload_format` to \"custom\" or remove them")
+    )]
+    Unused,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// How a device expects to be switched on and off
+///
+/// Read from the `payload_format` actor parameter: `onoff` (the default) sends `on`/`off`,
+/// `numeric` `1`/`0`, `bool` `true`/`false` and `json` `{"state":"ON"}`/`{"state":"OFF"}`.
+/// With `custom` the payloads are taken verbatim from `payload_on` and `payload_off`.
+pub enum PayloadFormat {
+    #[default]
+    OnOff,
+    Numeric,
+    Bool,
+    Json,
+    Custom {
+        on: String,
+        off: String,
+    },
+}
+
+impl PayloadFormat {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, PayloadFormatError> {
+        let on = params.get("payload_on");
+        let off = params.get("payload_off");
+        let format = match params.get("payload_format").map(String::as_str) {
+            None | Some("onoff") => Self::OnOff,
+            Some("numeric") => Self::Numeric,
+            Some("bool") => Self::Bool,
+            Some("json") => Self::Json,
+            Some("custom") => {
+                return match (on, off) {
+                    (Some(on), Some(off)) => Ok(Self::Custom {
+                        on: on.clone(),
+                        off: off.clone(),
+                    }),
+                    _ => Err(PayloadFormatError::Incomplete),
+                }
+            }
+            Some(unknown) => return Err(PayloadFormatError::Unknown(unknown.to_string())),
+        };
+        if on.is_some() || off.is_some() {
+            return Err(PayloadFormatError::Unused);
+        }
+        Ok(format)
+    }
+
+    pub fn encode(&self, on: bool) -> &str {
+        match (self, on) {
+            (Self::OnOff, true) => "on",
+            (Self::OnOff, false) => "off",
+            (Self::Numeric, true) => "1",
+            (Self::Numeric, false) => "0",
+            (Self::Bool, true) => "true",
+            (Self::Bool, false) => "false",
+            (Self::Json, true) => r#"{"state":"ON"}"#,
+            (Self::Json, false) => r#"{"state":"OFF"}"#,
+            (Self::Custom { on, .. }, true) => on.as_str(),
+            (Self::Custom { off, .. }, false) => off.as_str(),
+        }
+    }
+
+    /// Payload switching the device on for machines in use and off otherwise
+    pub fn for_status(&self, status: &ArchivedStatus) -> &str {
+        self.encode(matches!(status, ArchivedStatus::InUse(_)))
+    }
+
+    /// Read back a state reported by the device, `None` if `payload` is neither on nor off
+    pub fn decode(&self, payload: &[u8]) -> Option<bool> {
+        if payload == self.encode(true).as_bytes() {
+            Some(true)
+        } else if payload == self.encode(false).as_bytes() {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::ArchivedValue;
+    use crate::resources::modules::fabaccess::MachineState;
+    use crate::resources::state::State;
+    use crate::users::UserRef;
+    use rkyv::ser::serializers::AllocSerializer;
+    use rkyv::ser::Serializer;
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn powered_state_sends_configured_on_payload() {
+        let mut serializer = AllocSerializer::<1024>::default();
+        let in_use = MachineState::used(UserRef::new("user".to_string()), None);
+        serializer.serialize_value(&in_use.to_state()).unwrap();
+        let in_use: ArchivedValue<State> =
+            ArchivedValue::new(serializer.into_serializer().into_inner());
+        let in_use = &in_use.as_ref().inner.state;
+
+        for (format, on, off) in [
+            (params(&[]), "on", "off"),
+            (params(&[("payload_format", "onoff")]), "on", "off"),
+            (params(&[("payload_format", "numeric")]), "1", "0"),
+            (params(&[("payload_format", "bool")]), "true", "false"),
+            (
+                params(&[("payload_format", "json")]),
+                r#"{"state":"ON"}"#,
+                r#"{"state":"OFF"}"#,
+            ),
+            (
+                params(&[
+                    ("payload_format", "custom"),
+                    ("payload_on", "POWER ON"),
+                    ("payload_off", "POWER OFF"),
+                ]),
+                "POWER ON",
+                "POWER OFF",
+            ),
+        ] {
+            let format = PayloadFormat::from_params(&format).unwrap();
+            assert_eq!(format.for_status(in_use), on);
+            assert_eq!(format.for_status(&ArchivedStatus::Free), off);
+            assert_eq!(format.decode(on.as_bytes()), Some(true));
+            assert_eq!(format.decode(off.as_bytes()), Some(false));
+        }
+    }
+
+    #[test]
+    fn invalid_formats_are_rejected() {
+        assert_eq!(
+            PayloadFormat::from_params(&params(&[("payload_format", "yaml")])),
+            Err(PayloadFormatError::Unknown("yaml".to_string()))
+        );
+        assert_eq!(
+            PayloadFormat::from_params(&params(&[
+                ("payload_format", "custom"),
+                ("payload_on", "up")
+            ])),
+            Err(PayloadFormatError::Incomplete)
+        );
+        assert_eq!(
+            PayloadFormat::from_params(&params(&[("payload_on", "up"), ("payload_off", "down")])),
+            Err(PayloadFormatError::Unused)
+        );
+    }
+}
@@ -30,6 +30,10 @@ impl Process {
 }
 
 impl Actor for Process {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
     fn apply(&mut self, state: ArchivedValue<State>) -> BoxFuture<'static, ()> {
         tracing::debug!(name=%self.name, cmd=%self.cmd, ?state,
             "Process actor updating state");
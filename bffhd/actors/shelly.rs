@@ -1,10 +1,15 @@
 use futures_util::future::BoxFuture;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
-use crate::actors::Actor;
+use crate::actors::desync::{DesyncMonitor, DesyncPolicy, Reconcile};
+use crate::actors::payload::PayloadFormat;
+use crate::actors::topic::{TemplateError, TopicTemplate};
+use crate::actors::{Actor, MqttClient};
 use crate::db::ArchivedValue;
 use crate::resources::modules::fabaccess::ArchivedStatus;
 use crate::resources::state::State;
+use crate::utils::ratelimit::LogLimiter;
 use rumqttc::{AsyncClient, QoS};
 
 /// An actuator for a Shellie connected listening on one MQTT broker
@@ -12,27 +17,98 @@ use rumqttc::{AsyncClient, QoS};
 /// This actuator will toggle the shellie with the given `name`.
 /// If you need to toggle shellies on multiple brokers you need multiple instanced of this
 /// actuator with different clients.
+///
+/// If the `desync` parameter is set to `reassert` or `alert` the relay state reported by the
+/// shelly is compared against the last state applied to it.
+///
+/// Devices using a different topic layout can be driven by setting `topic_template`, see
+/// [`TopicTemplate`] for the placeholders available, and switched with payloads other than
+/// `on`/`off` by setting `payload_format`, see [`PayloadFormat`]. The relay state is then expected
+/// one level above the command topic unless `status_topic` says otherwise.
 pub struct Shelly {
     name: String,
+    machine: String,
     client: AsyncClient,
-    topic: String,
+    topics: Arc<Topics>,
+    format: Arc<PayloadFormat>,
+    monitor: Option<Arc<DesyncMonitor>>,
+    /// Keeps a broker outage from flooding the log with failed publishes
+    limiter: Arc<Mutex<LogLimiter>>,
 }
 
 impl Shelly {
-    pub fn new(name: String, client: AsyncClient, params: &HashMap<String, String>) -> Self {
-        let topic = if let Some(topic) = params.get("topic") {
-            format!("shellies/{}/relay/0/command", topic)
-        } else {
-            format!("shellies/{}/relay/0/command", name)
+    pub fn new(
+        name: String,
+        machine: String,
+        mqtt: &MqttClient,
+        params: &HashMap<String, String>,
+    ) -> Option<Self> {
+        let client = mqtt.client.clone();
+        let topics = match Topics::from_params(&name, params) {
+            Ok(topics) => Arc::new(topics),
+            Err(error) => {
+                tracing::error!(%name, %error, "invalid `topic_template` for Shelly actor");
+                return None;
+            }
         };
+        let format = match PayloadFormat::from_params(params) {
+            Ok(format) => Arc::new(format),
+            Err(error) => {
+                tracing::error!(%name, %error, "invalid payload format for Shelly actor");
+                return None;
+            }
+        };
+
+        let topic = topics.command(&name, &machine, "free");
+        tracing::debug!(%name, %topic, "Starting shelly module");
 
-        tracing::debug!(%name,%topic,"Starting shelly module");
+        let monitor = DesyncPolicy::from_params(&name, params)
+            .map(|policy| Arc::new(DesyncMonitor::new(name.clone(), policy)));
+        if let Some(ref monitor) = monitor {
+            let status = match topics.status(&name, &machine, params) {
+                Ok(status) => status,
+                Err(error) => {
+                    tracing::error!(%name, %error, "no status topic for Shelly actor");
+                    return None;
+                }
+            };
+            let monitor = monitor.clone();
+            let reassert = client.clone();
+            let topics = topics.clone();
+            let (actor, machine) = (name.clone(), machine.clone());
+            let format = format.clone();
+            let subscribed = mqtt.subscribe(
+                status,
+                Box::new(move |payload| {
+                    let reported = match format.decode(payload) {
+                        Some(on) => on,
+                        None => return,
+                    };
+                    if let Some(Reconcile::Reassert(on)) = monitor.report(reported) {
+                        let pl = format.encode(on).to_string();
+                        let command = topics.command_for(&actor, &machine, on);
+                        if let Err(error) =
+                            reassert.try_publish(command, QoS::AtLeastOnce, false, pl)
+                        {
+                            tracing::error!(?error, "`Shelly` actor failed to reassert state");
+                        }
+                    }
+                }),
+            );
+            if let Err(error) = subscribed {
+                tracing::error!(?error, %name, "`Shelly` actor failed to subscribe to relay state");
+            }
+        }
 
-        Shelly {
+        Some(Shelly {
             name,
+            machine,
             client,
-            topic,
-        }
+            topics,
+            format,
+            monitor,
+            limiter: Arc::default(),
+        })
     }
 
     /// Set the name to a new one. This changes the shelly that will be activated
@@ -43,25 +119,162 @@ impl Shelly {
 }
 
 impl Actor for Shelly {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
     fn apply(&mut self, state: ArchivedValue<State>) -> BoxFuture<'static, ()> {
         tracing::debug!(?state, name=%self.name,
             "Shelly changing state"
         );
-        let pl = match state.as_ref().inner.state {
-            ArchivedStatus::InUse(_) => "on",
-            _ => "off",
-        };
+        let status = &state.as_ref().inner.state;
+        let pl = self.format.for_status(status).to_string();
+
+        if let Some(ref monitor) = self.monitor {
+            monitor.expect(matches!(status, ArchivedStatus::InUse(_)));
+        }
 
         let name = self.name.clone();
         let client = self.client.clone();
-        let topic = self.topic.clone();
+        let limiter = self.limiter.clone();
+        let topic = self
+            .topics
+            .command(&self.name, &self.machine, status.as_str());
         let f = async move {
             let res = client.publish(topic, QoS::AtLeastOnce, false, pl).await;
             if let Err(error) = res {
-                tracing::error!(?error, %name, "`Shelly` actor failed to update state");
+                let mut limiter = limiter.lock().unwrap();
+                let key = error.to_string();
+                crate::log_limited!(limiter, &key, error, ?error, %name,
+                    "`Shelly` actor failed to update state");
             }
         };
 
         return Box::pin(f);
     }
 }
+
+/// Where a shelly takes commands and reports its relay state
+#[derive(Debug)]
+struct Topics {
+    /// Topic of the default layout, `shellies/<topic>/relay/0`
+    base: String,
+    template: Option<TopicTemplate>,
+}
+
+impl Topics {
+    fn from_params(name: &str, params: &HashMap<String, String>) -> Result<Self, TemplateError> {
+        let topic = params.get("topic").map(String::as_str).unwrap_or(name);
+        let template = params
+            .get("topic_template")
+            .map(|t| TopicTemplate::parse(t))
+            .transpose()?;
+        Ok(Self {
+            base: format!("shellies/{}/relay/0", topic),
+            template,
+        })
+    }
+
+    /// Topic to send the command switching to `state` to
+    fn command(&self, name: &str, machine: &str, state: &str) -> String {
+        match self.template {
+            Some(ref template) => template.expand(name, machine, state),
+            None => format!("{}/command", self.base),
+        }
+    }
+
+    /// Topic to send the command switching the relay on or off to when reasserting its state
+    fn command_for(&self, name: &str, machine: &str, on: bool) -> String {
+        self.command(name, machine, if on { "inuse" } else { "free" })
+    }
+
+    /// Topic the shelly reports its relay state on
+    ///
+    /// `status_topic` if set, otherwise the level above the command topic, like the default
+    /// layout reporting on `shellies/<topic>/relay/0` and taking commands on `.../command`.
+    fn status(
+        &self,
+        name: &str,
+        machine: &str,
+        params: &HashMap<String, String>,
+    ) -> Result<String, TemplateError> {
+        let status = match (params.get("status_topic"), &self.template) {
+            (Some(status), _) => TopicTemplate::parse(status)?,
+            (None, Some(template)) => template.parent().ok_or(TemplateError::NoParent)?,
+            (None, None) => return Ok(self.base.clone()),
+        };
+        if status.uses_state() {
+            return Err(TemplateError::StatefulStatus);
+        }
+        Ok(status.expand(name, machine, ""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn default_layout() {
+        let params = config(&[("topic", "shelly1-ABC")]);
+        let topics = Topics::from_params("Shelly1234", &params).unwrap();
+        assert_eq!(
+            topics.command_for("Shelly1234", "Testmachine", true),
+            "shellies/shelly1-ABC/relay/0/command"
+        );
+        assert_eq!(
+            topics.status("Shelly1234", "Testmachine", &params).unwrap(),
+            "shellies/shelly1-ABC/relay/0"
+        );
+    }
+
+    #[test]
+    fn reassert_and_status_follow_the_template() {
+        let params = config(&[("topic_template", "devices/{machine_id}/relay/1/{state}")]);
+        let topics = Topics::from_params("Shelly1234", &params).unwrap();
+        assert_eq!(
+            topics.command_for("Shelly1234", "Testmachine", true),
+            "devices/Testmachine/relay/1/inuse"
+        );
+        assert_eq!(
+            topics.command_for("Shelly1234", "Testmachine", false),
+            "devices/Testmachine/relay/1/free"
+        );
+        // The status topic can't be the parent of a topic depending on the state
+        assert_eq!(
+            topics.status("Shelly1234", "Testmachine", &params),
+            Err(TemplateError::StatefulStatus)
+        );
+
+        let params = config(&[("topic_template", "{actor}/rpc/Switch.Set")]);
+        let topics = Topics::from_params("gen2", &params).unwrap();
+        assert_eq!(
+            topics.command_for("gen2", "Testmachine", true),
+            "gen2/rpc/Switch.Set"
+        );
+        assert_eq!(
+            topics.status("gen2", "Testmachine", &params).unwrap(),
+            "gen2/rpc"
+        );
+    }
+
+    #[test]
+    fn status_topic_can_be_set() {
+        let params = config(&[
+            ("topic_template", "devices/{machine_id}/relay/1/{state}"),
+            ("status_topic", "devices/{machine_id}/status/switch:1"),
+        ]);
+        let topics = Topics::from_params("Shelly1234", &params).unwrap();
+        assert_eq!(
+            topics.status("Shelly1234", "Testmachine", &params).unwrap(),
+            "devices/Testmachine/status/switch:1"
+        );
+    }
+}
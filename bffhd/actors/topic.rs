@@ -0,0 +1,163 @@
+//! Templates for the MQTT topics actors publish to
+
+use miette::Diagnostic;
+use thiserror::Error;
+
+#[derive(Debug, Error, Diagnostic, PartialEq, Eq)]
+pub enum TemplateError {
+    #[error("unknown placeholder `{{{0}}}` in topic template")]
+    #[diagnostic(
+        code(bffh::actors::topic::placeholder),
+        help("Known placeholders are {{actor}}, {{machine_id}} and {{state}}")
+    )]
+    UnknownPlaceholder(String),
+    #[error("unclosed `{{` in topic template")]
+    #[diagnostic(code(bffh::actors::topic::unclosed))]
+    Unclosed,
+    #[error("topic template has no level above it to derive the status topic from")]
+    #[diagnostic(
+        code(bffh::actors::topic::no_parent),
+        help("Set `status_topic` to where the device reports its state")
+    )]
+    NoParent,
+    #[error("status topic can't depend on the state")]
+    #[diagnostic(
+        code(bffh::actors::topic::stateful_status),
+        help("Set `status_topic` to a topic without {{state}}")
+    )]
+    StatefulStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Actor,
+    MachineId,
+    State,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// MQTT topic with placeholders filled in on every publish
+///
+/// `{actor}` is replaced with the id of the actor, `{machine_id}` with the id of the machine it is
+/// connected to and `{state}` with the new state, e.g. `inuse` or `free`.
+pub struct TopicTemplate {
+    parts: Vec<Part>,
+}
+
+impl TopicTemplate {
+    pub fn parse(template: &str) -> Result<Self, TemplateError> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or(TemplateError::Unclosed)? + start;
+            parts.push(match &rest[start + 1..end] {
+                "actor" => Part::Actor,
+                "machine_id" => Part::MachineId,
+                "state" => Part::State,
+                unknown => return Err(TemplateError::UnknownPlaceholder(unknown.to_string())),
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Self { parts })
+    }
+
+    /// Fill in the placeholders, `state` being e.g. `inuse` or `free`
+    pub fn expand(&self, actor: &str, machine_id: &str, state: &str) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.as_str(),
+                Part::Actor => actor,
+                Part::MachineId => machine_id,
+                Part::State => state,
+            })
+            .collect()
+    }
+
+    /// Whether the topic contains `{state}`, i.e. is different for every state
+    pub fn uses_state(&self) -> bool {
+        self.parts.contains(&Part::State)
+    }
+
+    /// The topic one level up, e.g. `devices/{machine_id}/relay` for `.../relay/set`
+    ///
+    /// `None` if the template has only a single level.
+    pub fn parent(&self) -> Option<Self> {
+        let (i, slash) = self
+            .parts
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(i, part)| match part {
+                Part::Text(text) => text.rfind('/').map(|slash| (i, slash)),
+                _ => None,
+            })?;
+        let mut parts = self.parts[..i].to_vec();
+        if let Part::Text(ref text) = self.parts[i] {
+            if slash > 0 {
+                parts.push(Part::Text(text[..slash].to_string()));
+            }
+        }
+        if parts.is_empty() {
+            return None;
+        }
+        Some(Self { parts })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_expands_placeholders() {
+        let template = TopicTemplate::parse("devices/{machine_id}/{state}/set").unwrap();
+        assert_eq!(
+            template.expand("Shelly1234", "Testmachine", "disabled"),
+            "devices/Testmachine/disabled/set"
+        );
+        let template = TopicTemplate::parse("{actor}").unwrap();
+        assert_eq!(
+            template.expand("Shelly1234", "Testmachine", "free"),
+            "Shelly1234"
+        );
+    }
+
+    #[test]
+    fn parent_drops_the_last_level() {
+        let template = TopicTemplate::parse("devices/{machine_id}/relay/set").unwrap();
+        let parent = template.parent().unwrap();
+        assert_eq!(
+            parent.expand("Shelly1234", "Testmachine", "free"),
+            "devices/Testmachine/relay"
+        );
+        assert!(!parent.uses_state());
+
+        let template = TopicTemplate::parse("{actor}/rpc").unwrap();
+        assert_eq!(
+            template.parent().unwrap().expand("Shelly1234", "", ""),
+            "Shelly1234"
+        );
+        assert_eq!(TopicTemplate::parse("{actor}").unwrap().parent(), None);
+        assert_eq!(TopicTemplate::parse("/{actor}").unwrap().parent(), None);
+    }
+
+    #[test]
+    fn unknown_placeholders_are_rejected() {
+        assert_eq!(
+            TopicTemplate::parse("devices/{machine}/set"),
+            Err(TemplateError::UnknownPlaceholder("machine".to_string()))
+        );
+        assert_eq!(
+            TopicTemplate::parse("devices/{state"),
+            Err(TemplateError::Unclosed)
+        );
+    }
+}
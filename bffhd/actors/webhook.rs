@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use async_channel::{Receiver, Sender};
+use async_io::Timer;
+use futures_lite::FutureExt;
+use futures_util::future::{self, BoxFuture};
+use rkyv::{Deserialize, Infallible};
+use url::Url;
+
+use crate::actors::Actor;
+use crate::config::Secret;
+use crate::db::ArchivedValue;
+use crate::resources::state::State;
+use crate::utils::http;
+
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(500);
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(5);
+const DEFAULT_RETRIES: u32 = 2;
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An actor POSTing the state of its machine as JSON to a HTTP endpoint, e.g. a status dashboard
+///
+/// The body is `{"machine": <id>, "state": <state>}`. Changes arriving within `debounce_ms`
+/// (default 500) of each other are sent once with the latest state, but no change waits longer
+/// than `max_delay_ms` (default 5000) even if the machine keeps changing. Failed requests are
+/// retried `retries` times (default 2) with doubling delay before the state is dropped. If `token`
+/// is given it's sent as bearer token, it may be `file:<path>` or `env:<name>` like secrets in the
+/// config. Both `http://` and `https://` URLs are supported.
+///
+/// Requests are sent from a separate task so a slow endpoint never holds up the machine.
+pub struct Webhook {
+    name: String,
+    machine: String,
+    updates: Sender<String>,
+}
+
+impl Webhook {
+    /// The actor and the task sending its requests, which finishes once the actor is dropped
+    pub fn new(
+        name: String,
+        machine: String,
+        params: &HashMap<String, String>,
+    ) -> Option<(Self, impl Future<Output = ()> + Send + 'static)> {
+        let url = match params.get("url").map(|url| Url::parse(url)) {
+            Some(Ok(url)) => match http::check_url(&url) {
+                Ok(()) => url,
+                Err(error) => {
+                    tracing::error!(%name, %error, "invalid `url` for Webhook actor");
+                    return None;
+                }
+            },
+            Some(Err(error)) => {
+                tracing::error!(%name, %error, "invalid `url` for Webhook actor");
+                return None;
+            }
+            None => {
+                tracing::error!(%name, "Webhook actor needs an `url`");
+                return None;
+            }
+        };
+        let token = match params.get("token").cloned().map(Secret::resolve) {
+            None => None,
+            Some(Ok(token)) => Some(token),
+            Some(Err(error)) => {
+                tracing::error!(%name, %error, "invalid `token` for Webhook actor");
+                return None;
+            }
+        };
+        let debounce = match params.get("debounce_ms").map(|ms| ms.parse()) {
+            None => DEFAULT_DEBOUNCE,
+            Some(Ok(ms)) => Duration::from_millis(ms),
+            Some(Err(error)) => {
+                tracing::error!(%name, %error, "invalid `debounce_ms` for Webhook actor");
+                return None;
+            }
+        };
+        let max_delay = match params.get("max_delay_ms").map(|ms| ms.parse()) {
+            None => DEFAULT_MAX_DELAY,
+            Some(Ok(ms)) => Duration::from_millis(ms),
+            Some(Err(error)) => {
+                tracing::error!(%name, %error, "invalid `max_delay_ms` for Webhook actor");
+                return None;
+            }
+        };
+        let retries = match params.get("retries").map(|n| n.parse()) {
+            None => DEFAULT_RETRIES,
+            Some(Ok(n)) => n,
+            Some(Err(error)) => {
+                tracing::error!(%name, %error, "invalid `retries` for Webhook actor");
+                return None;
+            }
+        };
+
+        let endpoint = Endpoint {
+            url,
+            token,
+            retries,
+            backoff: DEFAULT_BACKOFF,
+        };
+        let (updates, pending) = async_channel::unbounded();
+        let task = run(name.clone(), endpoint, pending, debounce, max_delay);
+        Some((
+            Self {
+                name,
+                machine,
+                updates,
+            },
+            task,
+        ))
+    }
+}
+
+impl Actor for Webhook {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn apply(&mut self, state: ArchivedValue<State>) -> BoxFuture<'static, ()> {
+        let state: State = state.as_ref().deserialize(&mut Infallible).unwrap();
+        let payload = serde_json::json!({ "machine": self.machine, "state": state }).to_string();
+        tracing::trace!(name = %self.name, %payload, "queueing webhook request");
+        if self.updates.try_send(payload).is_err() {
+            tracing::error!(name = %self.name, "webhook task stopped, dropping state");
+        }
+        Box::pin(future::ready(()))
+    }
+}
+
+struct Endpoint {
+    url: Url,
+    token: Option<Secret>,
+    retries: u32,
+    /// Delay before the first retry, doubled for every further one
+    backoff: Duration,
+}
+
+/// Send every payload in `pending`, only sending the latest one of those within `debounce`
+///
+/// A payload is sent at the latest `max_delay` after it arrived, so a machine flapping faster than
+/// `debounce` still gets its state through.
+async fn run(
+    name: String,
+    endpoint: Endpoint,
+    pending: Receiver<String>,
+    debounce: Duration,
+    max_delay: Duration,
+) {
+    while let Ok(mut payload) = pending.recv().await {
+        let deadline = Instant::now() + max_delay;
+        loop {
+            let wait_until = deadline.min(Instant::now() + debounce);
+            let next = async { Some(pending.recv().await) }
+                .or(async {
+                    Timer::at(wait_until).await;
+                    None
+                })
+                .await;
+            match next {
+                Some(Ok(newer)) => payload = newer,
+                // Closed: the last payload is still sent, then the outer loop ends
+                Some(Err(_)) | None => break,
+            }
+        }
+        deliver(&name, &endpoint, &payload).await;
+    }
+}
+
+async fn deliver(name: &str, endpoint: &Endpoint, payload: &str) {
+    let mut backoff = endpoint.backoff;
+    for attempt in 0..=endpoint.retries {
+        let mut request =
+            http::Request::post(&endpoint.url, "application/json", payload.as_bytes())
+                .timeout(REQUEST_TIMEOUT);
+        if let Some(token) = &endpoint.token {
+            request = request.bearer(token.expose());
+        }
+        match request.send().await {
+            Ok(_) => {
+                tracing::trace!(%name, "webhook request sent");
+                return;
+            }
+            Err(error) if attempt < endpoint.retries => {
+                tracing::warn!(%name, %error, attempt, ?backoff, "webhook request failed, retrying");
+                Timer::after(backoff).await;
+                backoff *= 2;
+            }
+            Err(error) => {
+                tracing::error!(%name, %error, "webhook request failed, dropping state");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_net::TcpListener;
+    use futures_lite::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    /// Answer `statuses.len()` requests with the given status codes, returning their
+    /// authorization headers and bodies
+    async fn serve(listener: TcpListener, statuses: &[u16]) -> Vec<(String, String)> {
+        let mut requests = Vec::new();
+        for status in statuses {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(stream.clone());
+            let mut authorization = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                } else if let Some(value) = line.strip_prefix("Authorization: ") {
+                    authorization = value.to_string();
+                } else if let Some(value) = line.strip_prefix("Content-Length: ") {
+                    length = value.parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).await.unwrap();
+            requests.push((authorization, String::from_utf8(body).unwrap()));
+
+            let response = format!("HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+        requests
+    }
+
+    #[test]
+    fn rapid_changes_are_sent_once_and_retried() {
+        async_io::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let endpoint = Endpoint {
+                url: Url::parse(&format!("http://{}/status", address)).unwrap(),
+                token: Some(Secret::resolve("hunter2".to_string()).unwrap()),
+                retries: 2,
+                backoff: Duration::from_millis(10),
+            };
+            let (updates, pending) = async_channel::unbounded();
+            for payload in ["first", "second", "third"] {
+                updates.try_send(payload.to_string()).unwrap();
+            }
+            drop(updates);
+
+            let ((), requests) = futures_lite::future::zip(
+                run(
+                    "test".to_string(),
+                    endpoint,
+                    pending,
+                    Duration::from_millis(20),
+                    Duration::from_secs(5),
+                ),
+                serve(listener, &[500, 200]),
+            )
+            .await;
+
+            let expected = ("Bearer hunter2".to_string(), "third".to_string());
+            assert_eq!(requests, vec![expected.clone(), expected]);
+        });
+    }
+
+    #[test]
+    fn flapping_machines_are_still_sent() {
+        async_io::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let endpoint = Endpoint {
+                url: Url::parse(&format!("http://{}/status", address)).unwrap(),
+                token: None,
+                retries: 0,
+                backoff: Duration::from_millis(10),
+            };
+            let (updates, pending) = async_channel::unbounded();
+            // Changes every 5ms never leave the 20ms debounce quiet
+            let flapping = async move {
+                for i in 0..60 {
+                    updates.send(i.to_string()).await.unwrap();
+                    Timer::after(Duration::from_millis(5)).await;
+                }
+            };
+
+            let (((), ()), requests) = futures_lite::future::zip(
+                futures_lite::future::zip(
+                    run(
+                        "test".to_string(),
+                        endpoint,
+                        pending,
+                        Duration::from_millis(20),
+                        Duration::from_millis(50),
+                    ),
+                    flapping,
+                ),
+                serve(listener, &[200, 200]),
+            )
+            .await;
+
+            assert_eq!(requests.len(), 2);
+            assert_ne!(
+                requests[0].1, "59",
+                "first state was only sent after the flapping"
+            );
+        });
+    }
+}
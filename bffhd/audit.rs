@@ -1,21 +1,67 @@
 use miette::Diagnostic;
 use once_cell::sync::OnceCell;
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::{LineWriter, Write};
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
 use std::sync::Mutex;
+use std::time::Duration;
 use thiserror::Error;
+use url::Url;
 
+use crate::config::{AuditFormat, AuditSinkConfig};
+use crate::resources::modules::fabaccess::ArchivedMachineState;
+use crate::utils::http;
+use crate::utils::ratelimit::LogLimiter;
 use crate::Config;
+use rkyv::option::ArchivedOption;
 use serde::{Deserialize, Serialize};
-use serde_json::Serializer;
 
 pub static AUDIT: OnceCell<AuditLog> = OnceCell::new();
 
+/// Kinds of sinks that can be configured in `audit_sinks`
+pub const SINK_KINDS: &[&str] = &["file", "syslog", "http"];
+
+/// Entries waiting to be sent by a HTTP sink before new ones are dropped
+const HTTP_QUEUE: usize = 1024;
+/// Time a HTTP collector may take to connect or answer
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
 // TODO: Make the audit log a tracing layer
 #[derive(Debug)]
 pub struct AuditLog {
-    writer: Mutex<LineWriter<File>>,
+    sinks: Vec<Box<dyn AuditSink>>,
+    format: AuditFormat,
+    /// Keeps a sink that is down from flooding the log with one error per entry
+    limiter: Mutex<LogLimiter>,
+}
+
+/// A destination audit log lines are written to
+pub trait AuditSink: fmt::Debug + Send + Sync {
+    /// Where the sink writes to, to tell sinks apart in error messages
+    fn name(&self) -> &str;
+
+    /// Write a single entry, serialized as JSON without trailing newline
+    fn write(&self, line: &str) -> io::Result<()>;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// What caused a state change
+pub enum Source {
+    /// A user using the machine through the API
+    User,
+    /// An initiator, e.g. a card reader or a process
+    Initiator,
+    /// A sensor, e.g. a door contact
+    Sensor,
+    /// Someone with manage or admin permissions overriding the state
+    Admin,
+    /// bffh itself, e.g. when reconciling state
+    System,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,43 +69,378 @@ pub struct AuditLogLine<'a> {
     timestamp: i64,
     machine: &'a str,
     state: &'a str,
+    source: Source,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A state change in [`AuditFormat::Structured`]
+pub struct AuditStateLine<'a> {
+    timestamp: i64,
+    resource_id: &'a str,
+    status: &'a str,
+    /// Who the machine is used, reserved etc. by
+    user: Option<&'a str>,
+    previous_user: Option<&'a str>,
+    source: Source,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditAdminLine<'a> {
+    timestamp: i64,
+    action: &'a str,
+    target: &'a str,
+    admin: &'a str,
 }
 
 #[derive(Debug, Error, Diagnostic)]
-#[error(transparent)]
-#[repr(transparent)]
-pub struct Error(#[from] pub io::Error);
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid {kind} audit sink '{target}': {reason}")]
+    #[diagnostic(
+        code(bffh::audit::sink),
+        help("Audit sinks are `file` with a path, `syslog` with host:port or `http` with a http:// or https:// URL")
+    )]
+    InvalidSink {
+        kind: String,
+        target: String,
+        reason: String,
+    },
+}
 
 impl AuditLog {
     pub fn new(config: &Config) -> Result<&'static Self, Error> {
         AUDIT.get_or_try_init(|| {
             tracing::debug!(path = %config.auditlog_path.display(), "Initializing audit log");
-            let fd = OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(&config.auditlog_path)?;
-            let writer = Mutex::new(LineWriter::new(fd));
-            Ok(Self { writer })
+            let mut sinks: Vec<Box<dyn AuditSink>> =
+                vec![Box::new(FileSink::open(&config.auditlog_path)?)];
+            for sink in config.audit_sinks.iter() {
+                tracing::debug!(kind = %sink.kind, target = %sink.target, "Adding audit sink");
+                sinks.push(open_sink(sink)?);
+            }
+            Ok(Self::with_sinks(sinks).with_format(config.audit_format))
         })
     }
 
-    pub fn log(&self, machine: &str, state: &str) -> io::Result<()> {
+    pub(crate) fn open(path: &Path) -> Result<Self, Error> {
+        Ok(Self::with_sinks(vec![Box::new(FileSink::open(path)?)]))
+    }
+
+    pub(crate) fn with_sinks(sinks: Vec<Box<dyn AuditSink>>) -> Self {
+        Self {
+            sinks,
+            format: AuditFormat::default(),
+            limiter: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn with_format(mut self, format: AuditFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Write an entry to every sink
+    ///
+    /// A failing sink doesn't keep the entry from the others. Fails if the audit log file, the
+    /// first sink, couldn't take it; failures of the other sinks are only logged.
+    pub fn log(
+        &self,
+        machine: &str,
+        state: &ArchivedMachineState,
+        source: Source,
+    ) -> io::Result<()> {
+        let timestamp = chrono::Utc::now().timestamp();
+        let line = match self.format {
+            AuditFormat::Simple => {
+                let state = state.to_string();
+                let line = AuditLogLine {
+                    timestamp,
+                    machine,
+                    state: &state,
+                    source,
+                };
+                tracing::debug!(?line, "writing audit log line");
+                serde_json::to_string(&line)
+            }
+            AuditFormat::Structured => {
+                let previous_user = match &state.previous {
+                    ArchivedOption::Some(user) => Some(user.id.as_str()),
+                    ArchivedOption::None => None,
+                };
+                let line = AuditStateLine {
+                    timestamp,
+                    resource_id: machine,
+                    status: state.state.as_str(),
+                    user: state.state.user().map(|user| user.id.as_str()),
+                    previous_user,
+                    source,
+                };
+                tracing::debug!(?line, "writing audit log line");
+                serde_json::to_string(&line)
+            }
+        };
+        let line = line.expect("failed to serialize audit log line");
+        self.write(&line)
+    }
+
+    /// Write an entry for an admin action that doesn't change the state of a machine, such as
+    /// disconnecting a session
+    pub fn log_admin(&self, action: &str, target: &str, admin: &str) -> io::Result<()> {
         let timestamp = chrono::Utc::now().timestamp();
-        let line = AuditLogLine {
+        let line = AuditAdminLine {
             timestamp,
-            machine,
-            state,
+            action,
+            target,
+            admin,
         };
 
         tracing::debug!(?line, "writing audit log line");
+        let line = serde_json::to_string(&line).expect("failed to serialize audit log line");
+        self.write(&line)
+    }
+
+    fn write(&self, line: &str) -> io::Result<()> {
+        let mut primary = Ok(());
+        for (i, sink) in self.sinks.iter().enumerate() {
+            if let Err(error) = sink.write(line) {
+                let mut limiter = self.limiter.lock().unwrap();
+                let key = format!("{}: {}", sink.name(), error);
+                crate::log_limited!(limiter, &key, error, %error, sink = sink.name(),
+                    "writing to audit sink failed");
+                if i == 0 {
+                    primary = Err(error);
+                }
+            }
+        }
+        primary
+    }
+}
+
+fn open_sink(config: &AuditSinkConfig) -> Result<Box<dyn AuditSink>, Error> {
+    let invalid = |reason: String| Error::InvalidSink {
+        kind: config.kind.clone(),
+        target: config.target.clone(),
+        reason,
+    };
+    let sink: Box<dyn AuditSink> = match config.kind.as_str() {
+        "file" => Box::new(FileSink::open(Path::new(&config.target))?),
+        "syslog" => Box::new(SyslogSink::connect(&config.target)?),
+        "http" => {
+            let url = Url::parse(&config.target).map_err(|error| invalid(error.to_string()))?;
+            http::check_url(&url).map_err(|error| invalid(error.to_string()))?;
+            Box::new(HttpSink::spawn(url)?)
+        }
+        kind => return Err(invalid(format!("unknown kind `{}`", kind))),
+    };
+    Ok(sink)
+}
+
+#[derive(Debug)]
+/// Appends entries to a local file, one per line
+struct FileSink {
+    path: String,
+    writer: Mutex<LineWriter<File>>,
+}
+
+impl FileSink {
+    fn open(path: &Path) -> io::Result<Self> {
+        let fd = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.display().to_string(),
+            writer: Mutex::new(LineWriter::new(fd)),
+        })
+    }
+}
+
+impl AuditSink for FileSink {
+    fn name(&self) -> &str {
+        &self.path
+    }
+
+    fn write(&self, line: &str) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        // Flushed by `LineWriter` once the newline is written, so a crash loses no complete entry
+        writer.write_all(line.as_bytes())?;
+        writer.write_all("\n".as_bytes())
+    }
+}
+
+#[derive(Debug)]
+/// Sends entries to a syslog server as RFC 5424 messages over UDP
+struct SyslogSink {
+    address: String,
+    socket: UdpSocket,
+}
+
+impl SyslogSink {
+    fn connect(address: &str) -> io::Result<Self> {
+        let target = address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "address resolved to nothing")
+        })?;
+        let local = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(target)?;
+        Ok(Self {
+            address: address.to_string(),
+            socket,
+        })
+    }
+}
+
+impl AuditSink for SyslogSink {
+    fn name(&self) -> &str {
+        &self.address
+    }
+
+    fn write(&self, line: &str) -> io::Result<()> {
+        // Facility 13 (log audit), severity 6 (informational); the timestamp is part of the entry
+        let message = format!("<110>1 - - bffhd - audit - {}", line);
+        self.socket.send(message.as_bytes()).map(|_| ())
+    }
+}
+
+#[derive(Debug)]
+/// POSTs entries to a HTTP collector
+///
+/// Requests are sent from a separate thread so a slow collector doesn't hold up state changes.
+/// If the collector falls too far behind new entries are dropped and reported as failed.
+struct HttpSink {
+    url: String,
+    queue: SyncSender<String>,
+}
+
+impl HttpSink {
+    fn spawn(url: Url) -> io::Result<Self> {
+        let (queue, entries) = sync_channel::<String>(HTTP_QUEUE);
+        let name = url.to_string();
+        std::thread::Builder::new()
+            .name("bffhd-audit-http".to_string())
+            .spawn(move || {
+                let mut limiter = LogLimiter::default();
+                for entry in entries {
+                    let request = http::Request::post(&url, "application/json", entry.as_bytes())
+                        .timeout(HTTP_TIMEOUT);
+                    if let Err(error) = async_io::block_on(request.send()) {
+                        let key = error.to_string();
+                        crate::log_limited!(limiter, &key, error, %error, %url,
+                            "sending audit entry failed");
+                    }
+                }
+            })?;
+        Ok(Self { url: name, queue })
+    }
+}
+
+impl AuditSink for HttpSink {
+    fn name(&self) -> &str {
+        &self.url
+    }
+
+    fn write(&self, line: &str) -> io::Result<()> {
+        match self.queue.try_send(line.to_string()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "too many entries waiting to be sent",
+            )),
+            Err(TrySendError::Disconnected(_)) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::modules::fabaccess::MachineState;
+    use crate::users::UserRef;
+    use rkyv::ser::serializers::AllocSerializer;
+    use rkyv::ser::Serializer;
+    use rkyv::AlignedVec;
+    use std::sync::Arc;
+
+    fn archive(state: &MachineState) -> AlignedVec {
+        let mut serializer = AllocSerializer::<1024>::default();
+        serializer.serialize_value(state).unwrap();
+        serializer.into_serializer().into_inner()
+    }
+
+    #[derive(Debug, Default)]
+    struct Recording(Arc<Mutex<Vec<String>>>);
+    impl AuditSink for Recording {
+        fn name(&self) -> &str {
+            "recording"
+        }
+
+        fn write(&self, line: &str) -> io::Result<()> {
+            self.0.lock().unwrap().push(line.to_string());
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    struct Failing;
+    impl AuditSink for Failing {
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        fn write(&self, _: &str) -> io::Result<()> {
+            Err(io::ErrorKind::ConnectionRefused.into())
+        }
+    }
+
+    #[test]
+    fn failing_sink_does_not_affect_others() {
+        let first = Arc::new(Mutex::new(Vec::new()));
+        let second = Arc::new(Mutex::new(Vec::new()));
+        let log = AuditLog::with_sinks(vec![
+            Box::new(Recording(first.clone())),
+            Box::new(Failing),
+            Box::new(Recording(second.clone())),
+        ]);
+
+        let free = archive(&MachineState::free(None));
+        let free = unsafe { rkyv::archived_root::<MachineState>(&free) };
+        log.log("Testmachine", free, Source::Admin).unwrap();
+
+        for received in [first, second] {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            let entry: serde_json::Value = serde_json::from_str(&received[0]).unwrap();
+            assert_eq!(entry["machine"], "Testmachine");
+            assert_eq!(entry["state"], "free");
+        }
+
+        // Entries missing from the audit log file are reported, even if other sinks got them
+        let lost =
+            AuditLog::with_sinks(vec![Box::new(Failing), Box::new(Recording(Arc::default()))]);
+        assert!(lost.log("Testmachine", free, Source::Admin).is_err());
+    }
+
+    #[test]
+    fn structured_format_has_separate_fields() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = AuditLog::with_sinks(vec![Box::new(Recording(received.clone()))])
+            .with_format(AuditFormat::Structured);
 
-        let mut guard = self.writer.lock().unwrap();
-        let mut writer: &mut LineWriter<File> = &mut *guard;
+        let used = MachineState::used(
+            UserRef::new("Testuser".to_string()),
+            Some(UserRef::new("Previous".to_string())),
+        );
+        let used = archive(&used);
+        let used = unsafe { rkyv::archived_root::<MachineState>(&used) };
+        log.log("Testmachine", used, Source::User).unwrap();
 
-        let mut ser = Serializer::new(&mut writer);
-        line.serialize(&mut ser)
-            .expect("failed to serialize audit log line");
-        writer.write_all("\n".as_bytes())?;
-        Ok(())
+        let received = received.lock().unwrap();
+        let entry: serde_json::Value = serde_json::from_str(&received[0]).unwrap();
+        assert_eq!(entry["resource_id"], "Testmachine");
+        assert_eq!(entry["status"], "inuse");
+        assert_eq!(entry["user"], "Testuser");
+        assert_eq!(entry["previous_user"], "Previous");
+        assert_eq!(entry["source"], "user");
     }
 }
@@ -14,6 +14,7 @@ use std::fmt::{Debug, Display, Formatter};
 use std::io::Write;
 
 use crate::authentication::fabfire::FabFireCardKey;
+use crate::utils::truncate::Truncated;
 
 enum FabFireError {
     ParseError,
@@ -192,7 +193,10 @@ impl Authentication for FabFire {
                         self.card_info = match serde_json::from_slice(cardinfo) {
                             Ok(card_info) => Some(card_info),
                             Err(e) => {
-                                tracing::error!("Deserializing card_info failed: {:?}", e);
+                                tracing::error!(
+                                    "Deserializing card_info failed: {:?}",
+                                    Truncated(&e)
+                                );
                                 return Err(FabFireError::DeserializationError(e).into());
                             }
                         };
@@ -242,7 +246,10 @@ impl Authentication for FabFire {
                     {
                         Ok(response) => response,
                         Err(e) => {
-                            tracing::error!("Deserializing data from card failed: {:?}", e);
+                            tracing::error!(
+                                "Deserializing data from card failed: {:?}",
+                                Truncated(&e)
+                            );
                             return Err(e.into());
                         }
                     },
@@ -251,7 +258,7 @@ impl Authentication for FabFire {
                 let apdu_response = match response {
                     CardCommand::readPICC { data } => APDUResponse::new(&*data),
                     _ => {
-                        tracing::error!("Unexpected response: {:?}", response);
+                        tracing::error!("Unexpected response: {:?}", Truncated(&response));
                         return Err(FabFireError::ParseError.into());
                     }
                 };
@@ -306,7 +313,10 @@ impl Authentication for FabFire {
                     {
                         Ok(response) => response,
                         Err(e) => {
-                            tracing::error!("Deserializing data from card failed: {:?}", e);
+                            tracing::error!(
+                                "Deserializing data from card failed: {:?}",
+                                Truncated(&e)
+                            );
                             return Err(e.into());
                         }
                     },
@@ -315,7 +325,7 @@ impl Authentication for FabFire {
                 let apdu_response = match response {
                     CardCommand::readPICC { data } => APDUResponse::new(&*data),
                     _ => {
-                        tracing::error!("Unexpected response: {:?}", response);
+                        tracing::error!("Unexpected response: {:?}", Truncated(&response));
                         return Err(FabFireError::ParseError.into());
                     }
                 };
@@ -389,7 +399,10 @@ impl Authentication for FabFire {
                     {
                         Ok(response) => response,
                         Err(e) => {
-                            tracing::error!("Deserializing data from card failed: {:?}", e);
+                            tracing::error!(
+                                "Deserializing data from card failed: {:?}",
+                                Truncated(&e)
+                            );
                             return Err(e.into());
                         }
                     },
@@ -398,7 +411,7 @@ impl Authentication for FabFire {
                 let apdu_response = match response {
                     CardCommand::readPICC { data } => APDUResponse::new(&*data),
                     _ => {
-                        tracing::error!("Unexpected response: {:?}", response);
+                        tracing::error!("Unexpected response: {:?}", Truncated(&response));
                         return Err(FabFireError::ParseError.into());
                     }
                 };
@@ -411,7 +424,7 @@ impl Authentication for FabFire {
                                 if received_urn != self.local_urn {
                                     tracing::error!(
                                         "URN mismatch: {:?} != {:?}",
-                                        received_urn,
+                                        Truncated(&received_urn),
                                         self.local_urn
                                     );
                                     return Err(FabFireError::ParseError.into());
@@ -476,7 +489,10 @@ impl Authentication for FabFire {
                     {
                         Ok(response) => response,
                         Err(e) => {
-                            tracing::error!("Deserializing data from card failed: {:?}", e);
+                            tracing::error!(
+                                "Deserializing data from card failed: {:?}",
+                                Truncated(&e)
+                            );
                             return Err(e.into());
                         }
                     },
@@ -485,7 +501,7 @@ impl Authentication for FabFire {
                 let apdu_response = match response {
                     CardCommand::readPICC { data } => APDUResponse::new(&*data),
                     _ => {
-                        tracing::error!("Unexpected response: {:?}", response);
+                        tracing::error!("Unexpected response: {:?}", Truncated(&response));
                         return Err(FabFireError::ParseError.into());
                     }
                 };
@@ -563,7 +579,7 @@ impl Authentication for FabFire {
                     {
                         Ok(response) => response,
                         Err(e) => {
-                            tracing::error!("Failed to deserialize response: {:?}", e);
+                            tracing::error!("Failed to deserialize response: {:?}", Truncated(&e));
                             return Err(e.into());
                         }
                     },
@@ -572,7 +588,7 @@ impl Authentication for FabFire {
                 let apdu_response = match response {
                     CardCommand::readPICC { data } => APDUResponse::new(&*data),
                     _ => {
-                        tracing::error!("Unexpected response: {:?}", response);
+                        tracing::error!("Unexpected response: {:?}", Truncated(&response));
                         return Err(FabFireError::ParseError.into());
                     }
                 };
@@ -623,7 +639,10 @@ impl Authentication for FabFire {
                                 };
                             }
                             None => {
-                                tracing::error!("Got invalid response: {:?}", apdu_response);
+                                tracing::error!(
+                                    "Got invalid response: {:?}",
+                                    Truncated(&apdu_response)
+                                );
                                 return Err(FabFireError::ParseError.into());
                             }
                         };
@@ -645,7 +664,7 @@ impl Authentication for FabFire {
                     {
                         Ok(response) => response,
                         Err(e) => {
-                            tracing::error!("Failed to deserialize response: {:?}", e);
+                            tracing::error!("Failed to deserialize response: {:?}", Truncated(&e));
                             return Err(e.into());
                         }
                     },
@@ -654,7 +673,7 @@ impl Authentication for FabFire {
                 let apdu_response = match response {
                     CardCommand::readPICC { data } => APDUResponse::new(&*data),
                     _ => {
-                        tracing::error!("Got invalid response: {:?}", response);
+                        tracing::error!("Got invalid response: {:?}", Truncated(&response));
                         return Err(FabFireError::ParseError.into());
                     }
                 };
@@ -728,10 +747,12 @@ impl Authentication for FabFire {
                         };
                     }
                     Err(_e) => {
-                        tracing::error!("Got invalid response: {:?}", apdu_response);
-                        return Err(
-                            FabFireError::InvalidCredentials(format!("{}", apdu_response)).into(),
-                        );
+                        tracing::error!("Got invalid response: {:?}", Truncated(&apdu_response));
+                        return Err(FabFireError::InvalidCredentials(format!(
+                            "{}",
+                            Truncated(&apdu_response)
+                        ))
+                        .into());
                     }
                 }
             }
@@ -740,3 +761,204 @@ impl Authentication for FabFire {
         return Ok(State::Finished(MessageSent::No));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::AuthenticationHandle;
+    use crate::resources::state::db::StateDB;
+    use crate::users::db::User;
+    use crate::users::Users;
+    use desfire::crypto::cipher::aes::AES;
+    use desfire::crypto::cipher::Cipher;
+    use rsasl::prelude::Mechname;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    const CARD_KEY: [u8; 16] = *b"0123456789abcdef";
+    const LOCAL_URN: &str = "urn:fabaccess:lab:innovisionlab";
+
+    /// A DESFire card with a FabFire application, answering APDUs the way the reader relays them
+    struct MockCard {
+        key: [u8; 16],
+        files: HashMap<u8, Vec<u8>>,
+        rnd_b: [u8; 16],
+        /// The encrypted RndB sent to the host, needed to decrypt its answer
+        challenge: Option<Vec<u8>>,
+    }
+
+    impl MockCard {
+        fn new(key: [u8; 16], authid: &str) -> Self {
+            let mut token = authid.as_bytes().to_vec();
+            token.resize(MAX_BYTES_PER_TRANSACTION, 0);
+            let files = HashMap::from([
+                (0x01, MAGIC.as_bytes().to_vec()),
+                (0x02, LOCAL_URN.as_bytes().to_vec()),
+                (0x03, token),
+            ]);
+            Self {
+                key,
+                files,
+                rnd_b: *b"fixed card rnd_b",
+                challenge: None,
+            }
+        }
+
+        fn transmit(&mut self, apdu: &[u8]) -> Vec<u8> {
+            // cla, ins, p1, p2, lc, data, le
+            let ins = apdu[1];
+            let data = &apdu[5..5 + apdu[4] as usize];
+            let (mut response, status) = match ins {
+                // SelectApplication
+                0x5A if data == [0x42, 0x41, 0x46] => (Vec::new(), 0x00),
+                0x5A => (Vec::new(), 0xA0),
+                // ReadData
+                0xBD => {
+                    let le =
+                        |bytes: &[u8]| bytes.iter().rev().fold(0, |n, b| (n << 8) | *b as usize);
+                    let (offset, length) = (le(&data[1..4]), le(&data[4..7]));
+                    match self.files.get(&data[0]) {
+                        Some(file) => {
+                            let end = file.len().min(offset + length);
+                            (file[offset..end].to_vec(), 0x00)
+                        }
+                        None => (Vec::new(), 0xF0),
+                    }
+                }
+                // AuthenticateAes
+                0xAA => {
+                    let challenge = AES::encrypt(&self.rnd_b, &self.key, &[0; 16]).unwrap();
+                    self.challenge = Some(challenge.clone());
+                    (challenge, 0xAF)
+                }
+                // Continue, answering the challenge with RndA || RndB rotated left
+                0xAF => {
+                    let challenge = self.challenge.take().unwrap();
+                    let rnd_ab = AES::decrypt(data, &self.key, &challenge).unwrap();
+                    let (rnd_a, rnd_b) = rnd_ab.split_at(16);
+                    let mut rnd_b = rnd_b.to_vec();
+                    rnd_b.rotate_right(1);
+                    if rnd_b != self.rnd_b {
+                        (Vec::new(), 0xAE)
+                    } else {
+                        let mut rnd_a = rnd_a.to_vec();
+                        rnd_a.rotate_left(1);
+                        let iv = &data[data.len() - 16..];
+                        (AES::encrypt(&rnd_a, &self.key, iv).unwrap(), 0x00)
+                    }
+                }
+                _ => (Vec::new(), 0x1C),
+            };
+            response.extend([0x91, status]);
+            response
+        }
+    }
+
+    fn card_info() -> Vec<u8> {
+        let card_info = CardInfo {
+            uid: [0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66],
+            key_old: None,
+            key_new: None,
+        };
+        serde_json::to_vec(&card_info).unwrap()
+    }
+
+    /// Authentication handle knowing the user `cardholder` with the key [`CARD_KEY`]
+    fn authentication(dir: &tempfile::TempDir) -> AuthenticationHandle {
+        let env = StateDB::open_env(dir.path().join("db")).unwrap();
+        let users = Users::new(env).unwrap();
+        let mut user = User::new_with_plain_pw("cardholder", "secret");
+        user.userdata
+            .kv
+            .insert("cardkey".to_string(), hex::encode(CARD_KEY));
+        users.put_user("cardholder", &user).unwrap();
+        AuthenticationHandle::new(users)
+    }
+
+    /// Run a whole FabFire authentication of `card` until the mechanism stops
+    fn authenticate(card: &mut MockCard) -> (Result<State, SessionError>, Option<User>) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = authentication(&dir)
+            .start(Mechname::parse(b"X-FABFIRE").unwrap())
+            .unwrap();
+
+        let mut input = card_info();
+        loop {
+            let mut out = Vec::new();
+            match session.step(Some(&input), &mut out) {
+                Ok(State::Running) => {}
+                result => return (result, session.validation()),
+            }
+            let apdu = match serde_json::from_slice(&out).unwrap() {
+                CardCommand::sendPICC { data } => data,
+                cmd => panic!("unexpected command to the card: {:?}", cmd),
+            };
+            let data = card.transmit(&apdu);
+            input = serde_json::to_vec(&CardCommand::readPICC { data }).unwrap();
+        }
+    }
+
+    #[test]
+    fn card_with_known_key_authenticates() {
+        let mut card = MockCard::new(CARD_KEY, "cardholder");
+        let (result, user) = authenticate(&mut card);
+
+        assert!(matches!(result, Ok(State::Finished(MessageSent::Yes))));
+        assert_eq!(user.unwrap().id, "cardholder");
+    }
+
+    #[test]
+    fn card_with_wrong_key_is_rejected() {
+        let mut card = MockCard::new(*b"not the card key", "cardholder");
+        let (result, user) = authenticate(&mut card);
+
+        assert!(result.is_err());
+        assert!(user.is_none());
+    }
+
+    #[derive(Clone)]
+    struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn large_card_data_is_truncated_in_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = authentication(&dir)
+            .start(Mechname::parse(b"X-FABFIRE").unwrap())
+            .unwrap();
+        let mut out = Vec::new();
+        assert!(matches!(
+            session.step(Some(&card_info()), &mut out),
+            Ok(State::Running)
+        ));
+
+        // The card is expected to answer with `readPICC`, so this is logged as unexpected
+        let response = CardCommand::sendPICC {
+            data: vec![0xAB; 4096],
+        };
+        let response = serde_json::to_vec(&response).unwrap();
+        let log = LogBuffer(Arc::default());
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(session.step(Some(&response), &mut out).is_err());
+        });
+
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert!(log.contains("Unexpected response: sendPICC"), "{}", log);
+        assert!(log.contains("more)"), "{}", log);
+        assert!(log.len() < 1024, "{}", log);
+    }
+}
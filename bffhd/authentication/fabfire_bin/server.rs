@@ -14,6 +14,7 @@ use std::fmt::{Debug, Display, Formatter};
 use std::io::Write;
 
 use crate::authentication::fabfire::FabFireCardKey;
+use crate::utils::truncate::Truncated;
 use crate::CONFIG;
 
 enum FabFireError {
@@ -302,7 +303,7 @@ impl Authentication for FabFire {
                                 if received_urn != self.local_urn {
                                     tracing::error!(
                                         "URN mismatch: {:?} != {:?}",
-                                        received_urn,
+                                        Truncated(&received_urn),
                                         self.local_urn
                                     );
                                     return Err(FabFireError::ParseError.into());
@@ -454,7 +455,10 @@ impl Authentication for FabFire {
                                 }
                             }
                             None => {
-                                tracing::error!("Got invalid response: {:?}", apdu_response);
+                                tracing::error!(
+                                    "Got invalid response: {:?}",
+                                    Truncated(&apdu_response)
+                                );
                                 Err(FabFireError::ParseError.into())
                             }
                         }
@@ -518,10 +522,12 @@ impl Authentication for FabFire {
                         };
                     }
                     Err(_e) => {
-                        tracing::error!("Got invalid response: {:?}", apdu_response);
-                        return Err(
-                            FabFireError::InvalidCredentials(format!("{}", apdu_response)).into(),
-                        );
+                        tracing::error!("Got invalid response: {:?}", Truncated(&apdu_response));
+                        return Err(FabFireError::InvalidCredentials(format!(
+                            "{}",
+                            Truncated(&apdu_response)
+                        ))
+                        .into());
                     }
                 }
             }
@@ -2,6 +2,7 @@ use crate::users::Users;
 use miette::{IntoDiagnostic, WrapErr};
 use rsasl::callback::{CallbackError, Context, Request, SessionCallback, SessionData};
 use rsasl::mechanism::SessionError;
+use rsasl::mechanisms::scram::properties::ScramStoredPassword;
 use rsasl::prelude::{Mechname, SASLConfig, SASLServer, Session, Validation};
 use rsasl::property::{AuthId, AuthzId, Password};
 use rsasl::validate::{Validate, ValidationError};
@@ -9,10 +10,18 @@ use std::sync::Arc;
 
 use crate::authentication::fabfire::FabFireCardKey;
 use crate::users::db::User;
+use crate::users::invites;
+use crate::users::invites::{InviteDB, RegistrationError};
+use crate::users::scram::ScramCredentials;
 
 mod fabfire;
 mod fabfire_bin;
 
+/// Mechanisms accepted by [`Callback::validate`]
+///
+/// rsasl also offers SCRAM-SHA-256-PLUS, which needs channel binding data that isn't available.
+const MECHANISMS: &[&str] = &["PLAIN", "X-FABFIRE", "X-FABFIRE-BIN", "SCRAM-SHA-256"];
+
 struct Callback {
     users: Users,
     span: tracing::Span,
@@ -43,10 +52,40 @@ impl SessionCallback for Callback {
                         .map_err(|_| CallbackError::NoValue)?;
                 Ok(card_key)
             })?;
+            if request.is::<ScramStoredPassword>() {
+                // Users that never authenticated with PLAIN since SCRAM was added have no
+                // credentials yet, so SCRAM fails for them
+                let credentials = self
+                    .users
+                    .get_user(authid)
+                    .and_then(|user| user.userdata.scram_credentials())
+                    .ok_or(CallbackError::NoValue)?;
+                request.satisfy::<ScramStoredPassword>(&ScramStoredPassword::new(
+                    credentials.iterations,
+                    &credentials.salt,
+                    &credentials.stored_key,
+                    &credentials.server_key,
+                ))?;
+            }
         }
         Ok(())
     }
 
+    /// Store SCRAM credentials for `user` if they are missing, now that its password is known
+    fn upgrade_to_scram(&self, user: &mut User, password: &[u8]) {
+        if user.userdata.scram_credentials().is_some() {
+            return;
+        }
+        user.userdata
+            .set_scram_credentials(&ScramCredentials::derive(password));
+        match self.users.put_user(&user.id, user) {
+            Ok(()) => tracing::info!(authid=%user.id, "derived SCRAM credentials"),
+            Err(error) => {
+                tracing::warn!(authid=%user.id, %error, "failed to store SCRAM credentials")
+            }
+        }
+    }
+
     fn validate(
         &self,
         session_data: &SessionData,
@@ -72,9 +111,16 @@ impl SessionCallback for Callback {
                         return Ok(());
                     }
 
-                    if let Some(user) = self.users.get_user(authcid) {
+                    if let Some(mut user) = self.users.get_user(authcid) {
+                        if let Some(reason) = user.userdata.suspension_reason() {
+                            tracing::warn!(authid=%authcid, reason, "AUTH FAILED: account suspended");
+                            return Ok(());
+                        }
                         match user.check_password(password) {
-                            Ok(true) => validate.finalize::<V>(user),
+                            Ok(true) => {
+                                self.upgrade_to_scram(&mut user, password);
+                                validate.finalize::<V>(user)
+                            }
                             Ok(false) => {
                                 tracing::warn!(authid=%authcid, "AUTH FAILED: bad password");
                             }
@@ -86,12 +132,17 @@ impl SessionCallback for Callback {
                         tracing::warn!(authid=%authcid, "AUTH FAILED: no such user");
                     }
                 }
-                "X-FABFIRE" | "X-FABFIRE-BIN" => {
+                // SCRAM only gets here after the client proved knowing the password
+                "X-FABFIRE" | "X-FABFIRE-BIN" | "SCRAM-SHA-256" => {
                     let authcid = context
                         .get_ref::<AuthId>()
                         .ok_or(ValidationError::MissingRequiredProperty)?;
                     if let Some(user) = self.users.get_user(authcid) {
-                        validate.finalize::<V>(user)
+                        if let Some(reason) = user.userdata.suspension_reason() {
+                            tracing::warn!(authid=%authcid, reason, "AUTH FAILED: account suspended");
+                        } else {
+                            validate.finalize::<V>(user)
+                        }
                     }
                 }
                 _ => {}
@@ -119,6 +170,29 @@ impl Inner {
 #[derive(Clone)]
 pub struct AuthenticationHandle {
     inner: Inner,
+    users: Users,
+    invites: Option<InviteDB>,
+}
+
+/// Registers users with invite tokens, see [`invites::MECHANISM`]
+#[derive(Clone)]
+pub struct Registration {
+    users: Users,
+    invites: InviteDB,
+}
+
+impl Registration {
+    /// Create the user described by `data`, the token, username and password separated by NUL
+    pub fn register(&self, data: &[u8]) -> Result<User, RegistrationError> {
+        let mut parts = data.split(|byte| *byte == 0).map(std::str::from_utf8);
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(token)), Some(Ok(username)), Some(Ok(password)), None) => {
+                self.users
+                    .register(&self.invites, token, username, password)
+            }
+            _ => Err(RegistrationError::Malformed),
+        }
+    }
 }
 
 impl AuthenticationHandle {
@@ -131,20 +205,50 @@ impl AuthenticationHandle {
             .with_callback(Callback::new(userdb))
             .unwrap();
 
-        let mechs: Vec<&'static str> = SASLServer::<V>::new(config.clone())
-            .get_available()
-            .into_iter()
-            .map(|m| m.mechanism.as_str())
-            .collect();
+        let handle = Self {
+            inner: Inner::new(config),
+            users: userdb,
+            invites: None,
+        };
+
+        let mechs = handle.mechanisms();
         tracing::info!(available_mechs = mechs.len(), "initialized sasl backend");
         tracing::debug!(?mechs, "available mechs");
 
-        Self {
-            inner: Inner::new(config),
-        }
+        handle
+    }
+
+    /// Offer registering with tokens from `invites`, if self-registration is enabled
+    pub fn with_invites(mut self, invites: Option<InviteDB>) -> Self {
+        self.invites = invites;
+        self
+    }
+
+    /// Registration with invite tokens, `None` if self-registration is disabled
+    pub fn registration(&self) -> Option<Registration> {
+        self.invites.clone().map(|invites| Registration {
+            users: self.users,
+            invites,
+        })
+    }
+
+    /// Names of the mechanisms users can authenticate with
+    pub fn mechanisms(&self) -> Vec<&'static str> {
+        self.sess()
+            .get_available()
+            .into_iter()
+            .map(|m| m.mechanism.as_str())
+            .filter(|m| MECHANISMS.contains(m))
+            .collect()
     }
 
     pub fn start(&self, mechanism: &Mechname) -> miette::Result<Session<V>> {
+        if !MECHANISMS.contains(&mechanism.as_str()) {
+            return Err(miette::miette!(
+                "SASL mechanism {} is not supported",
+                mechanism.as_str()
+            ));
+        }
         Ok(SASLServer::new(self.inner.rsasl.clone())
             .start_suggested(mechanism)
             .into_diagnostic()
@@ -155,3 +259,109 @@ impl AuthenticationHandle {
         SASLServer::new(self.inner.rsasl.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::state::db::StateDB;
+    use crate::users::scram::SCRAM_KEY;
+    use rsasl::mechanism::State;
+    use rsasl::prelude::SASLClient;
+
+    const SCRAM_SHA_256: &Mechname = Mechname::const_new_unchecked(b"SCRAM-SHA-256");
+
+    /// Run a whole SCRAM-SHA-256 exchange between an rsasl client and the server
+    fn scram(authentication: &AuthenticationHandle, authid: &str, password: &str) -> Option<User> {
+        let config =
+            SASLConfig::with_credentials(None, authid.to_string(), password.to_string()).unwrap();
+        let mut client = SASLClient::new(config)
+            .start_suggested(&[SCRAM_SHA_256])
+            .unwrap();
+        let mut server = authentication.start(SCRAM_SHA_256).unwrap();
+
+        let mut client_first = Vec::new();
+        client.step(None, &mut client_first).unwrap();
+        let mut server_first = Vec::new();
+        server.step(Some(&client_first), &mut server_first).ok()?;
+        let mut client_final = Vec::new();
+        client.step(Some(&server_first), &mut client_final).unwrap();
+        let mut server_final = Vec::new();
+        let state = server.step(Some(&client_final), &mut server_final).ok()?;
+        assert!(matches!(state, State::Finished(_)));
+        // The client checks the server knew the credentials as well
+        client.step(Some(&server_final), &mut Vec::new()).unwrap();
+        server.validation()
+    }
+
+    #[test]
+    fn only_accepted_mechanisms_are_offered() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = StateDB::open_env(dir.path().join("db")).unwrap();
+        let authentication = AuthenticationHandle::new(Users::new(env).unwrap());
+
+        let mechanisms = authentication.mechanisms();
+        assert!(mechanisms.contains(&"PLAIN"));
+        assert!(mechanisms.contains(&"SCRAM-SHA-256"));
+        assert!(!mechanisms.contains(&"SCRAM-SHA-256-PLUS"));
+        assert!(authentication
+            .start(Mechname::parse(b"SCRAM-SHA-256-PLUS").unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn scram_uses_credentials_upgraded_by_plain() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = StateDB::open_env(dir.path().join("db")).unwrap();
+        let users = Users::new(env).unwrap();
+
+        // A record from before SCRAM was supported only has the argon2 hash
+        let mut user = User::new_with_plain_pw("legacy", "secret");
+        user.userdata.kv.remove(SCRAM_KEY);
+        users.put_user("legacy", &user).unwrap();
+
+        let authentication = AuthenticationHandle::new(users);
+        assert!(scram(&authentication, "legacy", "secret").is_none());
+
+        let mut session = authentication
+            .start(Mechname::parse(b"PLAIN").unwrap())
+            .unwrap();
+        let mut out = Vec::new();
+        session.step(Some(b"\0legacy\0secret"), &mut out).unwrap();
+        assert!(session.validation().is_some());
+        assert!(users
+            .get_user("legacy")
+            .unwrap()
+            .userdata
+            .scram_credentials()
+            .is_some());
+
+        let user = scram(&authentication, "legacy", "secret").unwrap();
+        assert_eq!(user.id, "legacy");
+        assert!(scram(&authentication, "legacy", "wrong").is_none());
+    }
+
+    #[test]
+    fn suspended_user_fails_plain() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = StateDB::open_env(dir.path().join("db")).unwrap();
+        let users = Users::new(env).unwrap();
+
+        let mut user = User::new_with_plain_pw("suspended", "secret");
+        user.userdata.roles.push("member".to_string());
+        user.userdata.suspend("unpaid fees");
+        users.put_user("suspended", &user).unwrap();
+
+        let authentication = AuthenticationHandle::new(users);
+        let mut session = authentication
+            .start(Mechname::parse(b"PLAIN").unwrap())
+            .unwrap();
+        let mut out = Vec::new();
+        let _ = session.step(Some(b"\0suspended\0secret"), &mut out);
+        assert!(session.validation().is_none());
+
+        // The record itself stays intact
+        let stored = users.get_user("suspended").unwrap();
+        assert_eq!(stored, user);
+        assert!(stored.check_password(b"secret").unwrap());
+    }
+}
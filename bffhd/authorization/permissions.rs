@@ -208,7 +208,8 @@ pub enum PermRule {
     /// The permissions is for the subtree marked by the node
     ///
     /// i.e. `Children("bffh.perm")` grants bffh.perm.sub, bffh.perm.sub.two and also bffh.perm
-    /// itself.
+    /// itself. It does not grant permissions with empty segments below the node such as
+    /// bffh.perm..sub. An asterisk anywhere but in a trailing `.*` is matched literally.
     Subtree(PermissionBuf),
     // This lacks what LDAP calls "ONELEVEL": The ability to grant the exact children but not several
     // levels deep, i.e. `Onelevel("bffh.perm")` grants bffh.perm.sub *BUT NOT* bffh.perm.sub.two or
@@ -222,7 +223,11 @@ impl PermRule {
         match self {
             PermRule::Base(ref base) => base.as_permission() == perm.as_ref(),
             PermRule::Children(ref parent) => parent.as_permission() > perm.as_ref(),
-            PermRule::Subtree(ref parent) => parent.as_permission() >= perm.as_ref(),
+            PermRule::Subtree(ref parent) => {
+                let perm = perm.as_ref();
+                let depth = parent.as_permission().iter().count();
+                parent.as_permission() >= perm && !perm.iter().skip(depth).any(str::is_empty)
+            }
         }
     }
 }
@@ -246,7 +251,7 @@ impl Into<String> for PermRule {
                 perm.into_string()
             }
             PermRule::Subtree(mut perm) => {
-                perm.push(Permission::new("+"));
+                perm.push(Permission::new("*"));
                 perm.into_string()
             }
         }
@@ -262,20 +267,19 @@ impl TryFrom<String> for PermRule {
         if len <= 2 {
             Err("Input string for PermRule is too short")
         } else {
-            match &input[len - 2..len] {
-                ".+" => {
-                    input.truncate(len - 2);
-                    Ok(PermRule::Children(PermissionBuf::from_string_unchecked(
-                        input,
-                    )))
-                }
-                ".*" => {
-                    input.truncate(len - 2);
-                    Ok(PermRule::Subtree(PermissionBuf::from_string_unchecked(
-                        input,
-                    )))
-                }
-                _ => Ok(PermRule::Base(PermissionBuf::from_string_unchecked(input))),
+            // Slicing off the last two bytes could split a multi-byte char, so compare instead
+            if input.ends_with(".+") {
+                input.truncate(len - 2);
+                Ok(PermRule::Children(PermissionBuf::from_string_unchecked(
+                    input,
+                )))
+            } else if input.ends_with(".*") {
+                input.truncate(len - 2);
+                Ok(PermRule::Subtree(PermissionBuf::from_string_unchecked(
+                    input,
+                )))
+            } else {
+                Ok(PermRule::Base(PermissionBuf::from_string_unchecked(input)))
             }
         }
     }
@@ -354,5 +358,49 @@ mod tests {
     fn rules_from_string_edgecases_test() {
         assert!(PermRule::try_from("*".to_string()).is_err());
         assert!(PermRule::try_from("+".to_string()).is_err());
+        assert!(PermRule::try_from("b€".to_string()).is_ok());
+    }
+
+    #[test]
+    fn rules_roundtrip_through_string() {
+        for rule in ["bffh.perm", "bffh.perm.+", "bffh.perm.*"] {
+            let parsed = PermRule::try_from(rule.to_string()).unwrap();
+            assert_eq!(parsed.to_string(), rule);
+            let string: String = parsed.into();
+            assert_eq!(string, rule);
+        }
+    }
+
+    #[test]
+    fn permission_subtree_skips_empty_segments() {
+        let rule = PermRule::try_from("lab.laser.*".to_string()).unwrap();
+
+        assert!(rule.match_perm(Permission::new("lab.laser")));
+        assert!(rule.match_perm(Permission::new("lab.laser.write")));
+        assert!(!rule.match_perm(Permission::new("lab.lasercutter")));
+
+        // The wildcard never expands into empty segments
+        assert!(!rule.match_perm(Permission::new("lab.laser.")));
+        assert!(!rule.match_perm(Permission::new("lab.laser..write")));
+        assert!(!rule.match_perm(Permission::new("lab.laser.cutter.")));
+    }
+
+    #[test]
+    fn permission_inner_asterisk_is_literal() {
+        let rule = PermRule::try_from("lab.*.write".to_string()).unwrap();
+        assert_eq!(
+            rule,
+            PermRule::Base(PermissionBuf::from_string_unchecked(
+                "lab.*.write".to_string()
+            ))
+        );
+        assert!(rule.match_perm(Permission::new("lab.*.write")));
+        assert!(!rule.match_perm(Permission::new("lab.lasercutter.write")));
+
+        let rule = PermRule::try_from("lab.laser*".to_string()).unwrap();
+        assert!(rule.match_perm(Permission::new("lab.laser*")));
+        assert!(!rule.match_perm(Permission::new("lab.laser")));
+        assert!(!rule.match_perm(Permission::new("lab.laser.write")));
+        assert!(!rule.match_perm(Permission::new("lab.lasercutter")));
     }
 }
@@ -1,8 +1,9 @@
 use crate::authorization::permissions::{PermRule, Permission};
 use crate::users::db::UserData;
 use once_cell::sync::OnceCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
+use std::time::Duration;
 
 static ROLES: OnceCell<HashMap<String, Role>> = OnceCell::new();
 
@@ -23,6 +24,14 @@ impl Roles {
         Self { roles: this }
     }
 
+    /// Roles not shared through the global used by [`Roles::new`], so tests can use their own
+    #[cfg(test)]
+    pub(crate) fn leak(roles: HashMap<String, Role>) -> Self {
+        Self {
+            roles: Box::leak(Box::new(roles)),
+        }
+    }
+
     pub fn get(self, roleid: &str) -> Option<&Role> {
         self.roles.get(roleid)
     }
@@ -31,6 +40,29 @@ impl Roles {
         self.roles.keys()
     }
 
+    /// All defined roles with the permissions each one grants, including inherited ones
+    pub fn catalog(&self) -> BTreeMap<String, Vec<PermRule>> {
+        self.roles
+            .keys()
+            .map(|role_id| (role_id.clone(), self.resolved_permissions(role_id)))
+            .collect()
+    }
+
+    /// Permissions granted by a role and all of its ancestors, sorted and without duplicates
+    pub fn resolved_permissions(&self, role_id: &String) -> Vec<PermRule> {
+        let mut roleset = HashMap::new();
+        self.tally_role(&mut roleset, role_id);
+
+        let mut output: Vec<PermRule> = Vec::new();
+        for rule in roleset.values().flat_map(|role| role.permissions.iter()) {
+            if !output.contains(rule) {
+                output.push(rule.clone());
+            }
+        }
+        output.sort_by_cached_key(PermRule::to_string);
+        output
+    }
+
     /// Tally a role dependency tree into a set
     ///
     /// A Default implementation exists which adapter may overwrite with more efficient
@@ -97,6 +129,19 @@ impl Roles {
         false
     }
 
+    /// Longest a session of `user` may stay open
+    ///
+    /// The shortest lifetime of the roles assigned to the user applies. Lifetimes of parent roles
+    /// are not inherited, so e.g. a `member` role inheriting from `guest` isn't limited to the
+    /// lifetime of guest sessions.
+    pub fn session_ttl(&self, user: &UserData) -> Option<Duration> {
+        user.roles
+            .iter()
+            .filter_map(|role_id| self.get(role_id)?.session_ttl)
+            .min()
+            .map(Duration::from_secs)
+    }
+
     pub fn is_permitted(&self, user: &UserData, perm: impl AsRef<Permission>) -> bool {
         let perm = perm.as_ref();
         tracing::debug!(perm = perm.as_str(), "Checking permission");
@@ -136,6 +181,10 @@ pub struct Role {
     // If a role doesn't define permissions, default to an empty Vec.
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     permissions: Vec<PermRule>,
+
+    /// Seconds after which sessions of users with this role end
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    session_ttl: Option<u64>,
 }
 
 impl Role {
@@ -143,8 +192,14 @@ impl Role {
         Self {
             parents,
             permissions,
+            session_ttl: None,
         }
     }
+
+    pub fn with_session_ttl(mut self, ttl: Duration) -> Self {
+        self.session_ttl = Some(ttl.as_secs());
+        self
+    }
 }
 
 impl fmt::Display for Role {
@@ -171,3 +226,44 @@ impl fmt::Display for Role {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Vec<PermRule> {
+        rules
+            .iter()
+            .map(|rule| PermRule::try_from(rule.to_string()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn catalog_includes_inherited_permissions() {
+        let roles = Roles::leak(HashMap::from([
+            (
+                "guest".to_string(),
+                Role::new(Vec::new(), rules(&["lab.door.open"])),
+            ),
+            (
+                "member".to_string(),
+                Role::new(
+                    vec!["guest".to_string()],
+                    rules(&["lab.printer.*", "lab.door.open"]),
+                ),
+            ),
+            (
+                "admin".to_string(),
+                Role::new(vec!["member".to_string()], rules(&["bffh.users.+"])),
+            ),
+        ]));
+
+        let catalog = roles.catalog();
+        assert_eq!(catalog.len(), 3);
+        assert_eq!(catalog["guest"], rules(&["lab.door.open"]));
+        assert_eq!(
+            catalog["admin"],
+            rules(&["bffh.users.+", "lab.door.open", "lab.printer.*"])
+        );
+    }
+}
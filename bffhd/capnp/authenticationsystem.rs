@@ -6,11 +6,15 @@ use rsasl::prelude::State as SaslState;
 use rsasl::prelude::{MessageSent, Session};
 use std::fmt;
 use std::fmt::{Formatter, Write};
+use std::net::IpAddr;
 use tracing::Span;
 
-use crate::authentication::V;
+use crate::authentication::{Registration, V};
+use crate::capnp::limits::ConnectionSlot;
 use crate::capnp::session::APISession;
 use crate::session::SessionManager;
+use crate::shutdown::ShutdownSignal;
+use crate::users::invites;
 use api::authenticationsystem_capnp::authentication::{
     AbortParams, AbortResults, Server as AuthenticationSystem, StepParams, StepResults,
 };
@@ -29,6 +33,9 @@ impl Authentication {
         mechanism: &Mechname, /* TODO: this is stored in session as well, get it out of there. */
         session: Session<V>,
         sessionmanager: SessionManager,
+        slot: ConnectionSlot,
+        peer: IpAddr,
+        close: ShutdownSignal,
     ) -> Self {
         let span = tracing::info_span!(
             target: TARGET,
@@ -43,7 +50,33 @@ impl Authentication {
         );
         Self {
             span,
-            state: State::Running(session, sessionmanager),
+            state: State::Running(session, sessionmanager, slot, Connection { peer, close }),
+        }
+    }
+
+    /// Registering a user with an invite token, see [`invites::MECHANISM`]
+    pub fn registration(
+        parent: &Span,
+        registration: Registration,
+        sessionmanager: SessionManager,
+        slot: ConnectionSlot,
+        peer: IpAddr,
+        close: ShutdownSignal,
+    ) -> Self {
+        let span = tracing::info_span!(
+            target: TARGET,
+            parent: parent,
+            "Authentication",
+            mechanism = invites::MECHANISM
+        );
+        Self {
+            span,
+            state: State::Registering(
+                registration,
+                sessionmanager,
+                slot,
+                Connection { peer, close },
+            ),
         }
     }
 
@@ -61,7 +94,7 @@ impl Authentication {
     }
 
     fn build_error(&self, response: response::Builder) {
-        if let State::Running(_, _) = self.state {
+        if let State::Running(..) | State::Registering(..) = self.state {
             return;
         }
 
@@ -82,7 +115,8 @@ impl fmt::Display for Authentication {
             State::InvalidMechanism => f.write_str("invalid mechanism")?,
             State::Finished => f.write_str("finished")?,
             State::Aborted => f.write_str("aborted")?,
-            State::Running(_, _) => f.write_str("running")?,
+            State::Running(..) => f.write_str("running")?,
+            State::Registering(..) => f.write_str("registering")?,
         }
         f.write_char(')')
     }
@@ -92,7 +126,14 @@ enum State {
     InvalidMechanism,
     Finished,
     Aborted,
-    Running(Session<V>, SessionManager),
+    Running(Session<V>, SessionManager, ConnectionSlot, Connection),
+    Registering(Registration, SessionManager, ConnectionSlot, Connection),
+}
+
+/// The connection a session will be opened for
+struct Connection {
+    peer: IpAddr,
+    close: ShutdownSignal,
 }
 
 impl AuthenticationSystem for Authentication {
@@ -116,29 +157,103 @@ impl AuthenticationSystem for Authentication {
         let response;
 
         let mut builder = results.get();
-        if let State::Running(mut session, manager) =
-            std::mem::replace(&mut self.state, State::Aborted)
-        {
-            let data: &[u8] = pry!(pry!(params.get()).get_data());
-
-            let mut out = Vec::new();
-            match session.step(Some(data), &mut out) {
-                Ok(SaslState::Finished(sent)) => {
-                    self.state = State::Finished;
-
-                    if let Some(user) = session.validation() {
-                        let session = manager.open(&self.span, user);
+        match std::mem::replace(&mut self.state, State::Aborted) {
+            State::Running(mut session, manager, slot, connection) => {
+                let data: &[u8] = pry!(pry!(params.get()).get_data());
+
+                let mut out = Vec::new();
+                match session.step(Some(data), &mut out) {
+                    Ok(SaslState::Finished(sent)) => {
+                        self.state = State::Finished;
+
+                        let user = session.validation();
+                        if user.is_some() && !slot.authenticate() {
+                            tracing::warn!(
+                                parent: &self.span,
+                                "too many authenticated connections, refusing login"
+                            );
+                            let mut builder = builder.init_failed();
+                            builder.set_code(ErrorCode::Aborted);
+
+                            response = Response {
+                                union_field: "error",
+                            };
+                        } else if let Some(user) = user {
+                            let session = manager.open_with_peer(
+                                &self.span,
+                                user,
+                                connection.peer,
+                                connection.close,
+                            );
+                            response = Response {
+                                union_field: "successful",
+                            };
+
+                            let mut builder = builder.init_successful();
+                            if sent == MessageSent::Yes {
+                                builder.set_additional_data(out.as_slice());
+                            }
+
+                            APISession::build(session, builder)
+                        } else {
+                            let mut builder = builder.init_failed();
+                            builder.set_code(ErrorCode::InvalidCredentials);
+
+                            response = Response {
+                                union_field: "error",
+                            };
+                        }
+                    }
+                    Ok(SaslState::Running) => {
+                        self.state = State::Running(session, manager, slot, connection);
+                        builder.set_challenge(out.as_slice());
+
+                        response = Response {
+                            union_field: "challenge",
+                        };
+                    }
+                    Err(_) => {
+                        self.state = State::Aborted;
+                        self.build_error(builder);
+
+                        response = Response {
+                            union_field: "error",
+                        };
+                    }
+                }
+            }
+            State::Registering(registration, manager, slot, connection) => {
+                self.state = State::Finished;
+                let data: &[u8] = pry!(pry!(params.get()).get_data());
+
+                match registration.register(data) {
+                    Ok(user) if slot.authenticate() => {
+                        let session = manager.open_with_peer(
+                            &self.span,
+                            user,
+                            connection.peer,
+                            connection.close,
+                        );
                         response = Response {
                             union_field: "successful",
                         };
 
-                        let mut builder = builder.init_successful();
-                        if sent == MessageSent::Yes {
-                            builder.set_additional_data(out.as_slice());
-                        }
+                        APISession::build(session, builder.init_successful())
+                    }
+                    Ok(_) => {
+                        tracing::warn!(
+                            parent: &self.span,
+                            "too many authenticated connections, refusing login after registration"
+                        );
+                        let mut builder = builder.init_failed();
+                        builder.set_code(ErrorCode::Aborted);
 
-                        APISession::build(session, builder)
-                    } else {
+                        response = Response {
+                            union_field: "error",
+                        };
+                    }
+                    Err(error) => {
+                        tracing::info!(parent: &self.span, %error, "registration refused");
                         let mut builder = builder.init_failed();
                         builder.set_code(ErrorCode::InvalidCredentials);
 
@@ -147,28 +262,13 @@ impl AuthenticationSystem for Authentication {
                         };
                     }
                 }
-                Ok(SaslState::Running) => {
-                    self.state = State::Running(session, manager);
-                    builder.set_challenge(out.as_slice());
-
-                    response = Response {
-                        union_field: "challenge",
-                    };
-                }
-                Err(_) => {
-                    self.state = State::Aborted;
-                    self.build_error(builder);
-
-                    response = Response {
-                        union_field: "error",
-                    };
-                }
             }
-        } else {
-            self.build_error(builder);
-            response = Response {
-                union_field: "error",
-            };
+            _ => {
+                self.build_error(builder);
+                response = Response {
+                    union_field: "error",
+                };
+            }
         }
 
         tracing::trace!(
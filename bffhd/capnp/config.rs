@@ -1,6 +1,10 @@
 use std::fmt::Formatter;
 use std::net::ToSocketAddrs;
+use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
+use std::time::Duration;
+
+use nix::sys::socket::{setsockopt, sockopt};
 
 use serde::{Deserialize, Serialize};
 
@@ -57,7 +61,96 @@ pub struct TlsListen {
     pub tls_min_version: Option<String>,
     #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
     pub protocols: Vec<String>,
+    /// Seconds a client has to complete the TLS handshake before the connection is dropped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handshake_timeout: Option<u64>,
+}
+
+impl TlsListen {
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT))
+    }
+}
+
+pub const DEFAULT_HANDSHAKE_TIMEOUT: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// Keepalive settings for API connections
+///
+/// TCP keepalive probes keep NAT mappings of idle connections alive and allow the kernel to
+/// detect peers that went away without saying goodbye. On top of that peers are pinged over RPC,
+/// which also notices clients that stopped answering.
+pub struct Keepalive {
+    /// Seconds of inactivity after which probes are sent, and the interval between probes and pings
+    pub interval: u32,
+    /// Seconds after which a peer not answering probes or a ping is considered dead
+    pub timeout: u32,
+}
+
+impl Keepalive {
+    /// Number of unanswered probes after which the connection is closed
+    pub fn probes(&self) -> u32 {
+        (self.timeout / self.interval.max(1)).max(1)
+    }
+
+    pub fn apply(&self, socket: &impl AsRawFd) -> nix::Result<()> {
+        let fd = socket.as_raw_fd();
+        setsockopt(fd, sockopt::KeepAlive, &true)?;
+        setsockopt(fd, sockopt::TcpKeepIdle, &self.interval)?;
+        setsockopt(fd, sockopt::TcpKeepInterval, &self.interval)?;
+        setsockopt(fd, sockopt::TcpKeepCount, &self.probes())?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// Rate at which a single IP address may open new API connections
+///
+/// Every address has a bucket of `burst` connections that is refilled by `per_minute`
+/// connections per minute. Connections arriving while the bucket is empty are dropped before the
+/// TLS handshake.
+pub struct ConnectionRate {
+    pub burst: u32,
+    pub per_minute: u32,
 }
 
 // The default port in the non-assignable i.e. free-use area
 pub const DEFAULT_PORT: u16 = 59661;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keepalive_probes_cover_timeout() {
+        let keepalive = Keepalive {
+            interval: 30,
+            timeout: 120,
+        };
+        assert_eq!(keepalive.probes(), 4);
+
+        let keepalive = Keepalive {
+            interval: 60,
+            timeout: 10,
+        };
+        assert_eq!(keepalive.probes(), 1);
+    }
+
+    #[test]
+    fn keepalive_is_applied_to_socket() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let keepalive = Keepalive {
+            interval: 15,
+            timeout: 45,
+        };
+        keepalive.apply(&stream).unwrap();
+
+        let fd = stream.as_raw_fd();
+        assert!(nix::sys::socket::getsockopt(fd, sockopt::KeepAlive).unwrap());
+        assert_eq!(
+            nix::sys::socket::getsockopt(fd, sockopt::TcpKeepCount).unwrap(),
+            3
+        );
+    }
+}
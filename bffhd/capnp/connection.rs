@@ -6,7 +6,12 @@ use std::net::SocketAddr;
 
 use crate::authentication::AuthenticationHandle;
 use crate::capnp::authenticationsystem::Authentication;
+use crate::capnp::limits::ConnectionSlot;
+use crate::capnp::version;
+use crate::capnp::version::{ApiVersion, VersionMismatch, API_VERSION};
 use crate::session::SessionManager;
+use crate::shutdown::ShutdownSignal;
+use crate::users::invites;
 use capnp::capability::Promise;
 use capnp_rpc::pry;
 use rsasl::mechname::Mechname;
@@ -17,7 +22,11 @@ pub struct BootCap {
     peer_addr: SocketAddr,
     authentication: AuthenticationHandle,
     sessionmanager: SessionManager,
+    slot: ConnectionSlot,
+    close: ShutdownSignal,
     span: Span,
+    /// Set once the client announced a version this server can't talk to
+    mismatch: Option<VersionMismatch>,
 }
 
 impl BootCap {
@@ -25,22 +34,43 @@ impl BootCap {
         peer_addr: SocketAddr,
         authentication: AuthenticationHandle,
         sessionmanager: SessionManager,
+        slot: ConnectionSlot,
+        close: ShutdownSignal,
         span: Span,
     ) -> Self {
         Self {
             peer_addr,
             authentication,
             sessionmanager,
+            slot,
+            close,
             span,
+            mismatch: None,
         }
     }
+
+    /// Agree on the API version to use with a client announcing `client`
+    ///
+    /// Not part of the API schema yet, clients can't announce their version over RPC. After a
+    /// mismatch no session can be created on this connection.
+    pub fn negotiate(&mut self, client: ApiVersion) -> Result<ApiVersion, VersionMismatch> {
+        let negotiated = version::negotiate(client);
+        match &negotiated {
+            Ok(version) => {
+                tracing::debug!(parent: &self.span, %client, %version, "negotiated API version")
+            }
+            Err(error) => tracing::info!(parent: &self.span, %error, "refusing client"),
+        }
+        self.mismatch = negotiated.clone().err();
+        negotiated
+    }
 }
 
 impl bootstrap::Server for BootCap {
     fn get_a_p_i_version(
         &mut self,
         _: bootstrap::GetAPIVersionParams,
-        _: bootstrap::GetAPIVersionResults,
+        mut result: bootstrap::GetAPIVersionResults,
     ) -> Promise<(), ::capnp::Error> {
         let _guard = self.span.enter();
         let _span = tracing::trace_span!(
@@ -50,6 +80,12 @@ impl bootstrap::Server for BootCap {
         )
         .entered();
         tracing::trace!("method call");
+
+        let mut builder = result.get();
+        builder.set_major(API_VERSION.major as i32);
+        builder.set_minor(API_VERSION.minor as i32);
+
+        tracing::trace!(results.version = %API_VERSION, "method return");
         Promise::ok(())
     }
 
@@ -93,13 +129,10 @@ impl bootstrap::Server for BootCap {
         tracing::trace!(target: "bffh::api", "method call");
 
         let builder = result.get();
-        let mechs: Vec<_> = self
-            .authentication
-            .sess()
-            .get_available()
-            .into_iter()
-            .map(|m| m.mechanism.as_str())
-            .collect();
+        let mut mechs = self.authentication.mechanisms();
+        if self.authentication.registration().is_some() {
+            mechs.push(invites::MECHANISM);
+        }
         let mut mechbuilder = builder.init_mechs(mechs.len() as u32);
         for (i, m) in mechs.iter().enumerate() {
             mechbuilder.set(i as u32, m);
@@ -147,10 +180,32 @@ impl bootstrap::Server for BootCap {
 
         tracing::trace!(params.mechanism = mechanism, "method call");
 
+        if let Some(mismatch) = &self.mismatch {
+            return Promise::err(::capnp::Error::failed(mismatch.to_string()));
+        }
+
         let mechname = Mechname::parse(mechanism.as_bytes());
-        let auth = if let Ok(mechname) = mechname {
+        let registration = self.authentication.registration();
+        let auth = if let (invites::MECHANISM, Some(registration)) = (mechanism, registration) {
+            Authentication::registration(
+                &self.span,
+                registration,
+                self.sessionmanager.clone(),
+                self.slot.clone(),
+                self.peer_addr.ip(),
+                self.close.clone(),
+            )
+        } else if let Ok(mechname) = mechname {
             if let Ok(session) = self.authentication.start(mechname) {
-                Authentication::new(&self.span, mechname, session, self.sessionmanager.clone())
+                Authentication::new(
+                    &self.span,
+                    mechname,
+                    session,
+                    self.sessionmanager.clone(),
+                    self.slot.clone(),
+                    self.peer_addr.ip(),
+                    self.close.clone(),
+                )
             } else {
                 Authentication::invalid_mechanism()
             }
@@ -0,0 +1,175 @@
+//! Conversions between internal types and their API counterparts
+
+use std::convert::TryFrom;
+
+use api::machine_capnp::machine::MachineState as APIMState;
+use api::user_capnp::user;
+use thiserror::Error;
+
+use crate::resources::modules::fabaccess::{
+    ArchivedMachineState, ArchivedStatus, MachineState, Status,
+};
+use crate::session::{Maintenance, Overloaded, SessionExpired};
+use crate::users::UserRef;
+
+#[derive(Debug, Error)]
+#[error("machine state {0:?} is not supported")]
+pub struct UnsupportedState(pub APIMState);
+
+impl From<UnsupportedState> for capnp::Error {
+    fn from(e: UnsupportedState) -> Self {
+        capnp::Error::unimplemented(e.to_string())
+    }
+}
+
+impl From<Maintenance> for capnp::Error {
+    fn from(e: Maintenance) -> Self {
+        capnp::Error::failed(e.to_string())
+    }
+}
+
+impl From<crate::users::Error> for capnp::Error {
+    fn from(e: crate::users::Error) -> Self {
+        match e {
+            crate::users::Error::Db(e) => e.into(),
+            crate::users::Error::Maintenance(e) => e.into(),
+        }
+    }
+}
+
+impl From<Overloaded> for capnp::Error {
+    fn from(e: Overloaded) -> Self {
+        capnp::Error::overloaded(e.to_string())
+    }
+}
+
+impl From<SessionExpired> for capnp::Error {
+    fn from(e: SessionExpired) -> Self {
+        capnp::Error::failed(e.to_string())
+    }
+}
+
+impl From<&Status> for APIMState {
+    fn from(status: &Status) -> Self {
+        match status {
+            Status::Free => APIMState::Free,
+            Status::InUse(_) => APIMState::InUse,
+            Status::ToCheck(_) => APIMState::ToCheck,
+            Status::Blocked(_) => APIMState::Blocked,
+            Status::Disabled => APIMState::Disabled,
+            Status::Reserved(_) => APIMState::Reserved,
+        }
+    }
+}
+
+impl From<&ArchivedStatus> for APIMState {
+    fn from(status: &ArchivedStatus) -> Self {
+        match status {
+            ArchivedStatus::Free => APIMState::Free,
+            ArchivedStatus::InUse(_) => APIMState::InUse,
+            ArchivedStatus::ToCheck(_) => APIMState::ToCheck,
+            ArchivedStatus::Blocked(_) => APIMState::Blocked,
+            ArchivedStatus::Disabled => APIMState::Disabled,
+            ArchivedStatus::Reserved(_) => APIMState::Reserved,
+        }
+    }
+}
+
+impl From<&MachineState> for APIMState {
+    fn from(state: &MachineState) -> Self {
+        (&state.state).into()
+    }
+}
+
+impl From<&ArchivedMachineState> for APIMState {
+    fn from(state: &ArchivedMachineState) -> Self {
+        (&state.state).into()
+    }
+}
+
+/// The API only transmits the kind of state, the user it applies to is supplied separately
+impl TryFrom<(APIMState, UserRef)> for Status {
+    type Error = UnsupportedState;
+
+    fn try_from((state, user): (APIMState, UserRef)) -> Result<Self, Self::Error> {
+        Ok(match state {
+            APIMState::Free => Status::Free,
+            APIMState::InUse => Status::InUse(user),
+            APIMState::ToCheck => Status::ToCheck(user),
+            APIMState::Blocked => Status::Blocked(user),
+            APIMState::Disabled => Status::Disabled,
+            APIMState::Reserved => Status::Reserved(user),
+            APIMState::Totakeover => return Err(UnsupportedState(state)),
+        })
+    }
+}
+
+impl TryFrom<user::Reader<'_>> for UserRef {
+    type Error = capnp::Error;
+
+    fn try_from(reader: user::Reader<'_>) -> Result<Self, Self::Error> {
+        Ok(UserRef::new(reader.get_username()?.to_string()))
+    }
+}
+
+/// Write the identifying part of `user` into `builder`
+///
+/// Capabilities are not set since they depend on the session the user is sent to.
+pub fn build_user_ref(user: &UserRef, mut builder: user::Builder) {
+    builder.set_username(user.get_username());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api::machine_capnp::machine;
+
+    fn roundtrip(status: Status) -> Status {
+        let user = match &status {
+            Status::Free | Status::Disabled => UserRef::new("unused".to_string()),
+            Status::InUse(user)
+            | Status::ToCheck(user)
+            | Status::Blocked(user)
+            | Status::Reserved(user) => user.clone(),
+        };
+
+        let mut message = capnp::message::Builder::new_default();
+        let mut builder = message.init_root::<machine::Builder>();
+        builder.set_state((&status).into());
+
+        let state = builder.into_reader().get_state().unwrap();
+        Status::try_from((state, user)).unwrap()
+    }
+
+    #[test]
+    fn status_roundtrips() {
+        let user = UserRef::new("testuser".to_string());
+        for status in [
+            Status::Free,
+            Status::InUse(user.clone()),
+            Status::ToCheck(user.clone()),
+            Status::Blocked(user.clone()),
+            Status::Disabled,
+            Status::Reserved(user),
+        ] {
+            assert_eq!(roundtrip(status.clone()), status);
+        }
+    }
+
+    #[test]
+    fn totakeover_is_unsupported() {
+        let user = UserRef::new("testuser".to_string());
+        assert!(Status::try_from((APIMState::Totakeover, user)).is_err());
+    }
+
+    #[test]
+    fn user_ref_roundtrips() {
+        let user = UserRef::new("testuser".to_string());
+
+        let mut message = capnp::message::Builder::new_default();
+        let mut builder = message.init_root::<user::Builder>();
+        build_user_ref(&user, builder.reborrow());
+
+        assert_eq!(UserRef::try_from(builder.into_reader()).unwrap(), user);
+    }
+}
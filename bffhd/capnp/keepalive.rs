@@ -0,0 +1,138 @@
+//! Noticing peers that went away without closing their connection
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_io::Timer;
+use capnp::capability::Promise;
+use capnp_rpc::rpc_twoparty_capnp::Side;
+use capnp_rpc::RpcSystem;
+
+use crate::capnp::Keepalive;
+
+/// Serves an RPC connection, pinging the peer every now and then
+///
+/// A ping asks the peer for its bootstrap capability. Any answer, even an error because it
+/// doesn't export one, shows the peer is still there. The connection ends with an error once an
+/// answer takes longer than the timeout. TCP keepalive alone can't tell, the kernel of a client
+/// that hangs still answers its probes.
+pub struct Pinged {
+    rpc: RpcSystem<Side>,
+    /// Interval between pings and time the peer has to answer, never pinging if `None`
+    ping: Option<(Duration, Duration)>,
+    next: Timer,
+    answer: Option<(Promise<(), capnp::Error>, Timer)>,
+}
+
+impl Pinged {
+    pub fn new(rpc: RpcSystem<Side>, keepalive: Option<Keepalive>) -> Self {
+        let ping = keepalive.map(|keepalive| {
+            (
+                Duration::from_secs(keepalive.interval.max(1).into()),
+                Duration::from_secs(keepalive.timeout.into()),
+            )
+        });
+        Self::with_ping(rpc, ping)
+    }
+
+    fn with_ping(rpc: RpcSystem<Side>, ping: Option<(Duration, Duration)>) -> Self {
+        let next = match ping {
+            Some((interval, _)) => Timer::after(interval),
+            None => Timer::never(),
+        };
+        Self {
+            rpc,
+            ping,
+            next,
+            answer: None,
+        }
+    }
+}
+
+impl Future for Pinged {
+    type Output = Result<(), capnp::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Poll::Ready(result) = Pin::new(&mut this.rpc).poll(cx) {
+            return Poll::Ready(result);
+        }
+        let (interval, timeout) = match this.ping {
+            Some(ping) => ping,
+            None => return Poll::Pending,
+        };
+
+        if let Some((answer, deadline)) = this.answer.as_mut() {
+            if Pin::new(answer).poll(cx).is_pending() {
+                if Pin::new(deadline).poll(cx).is_ready() {
+                    return Poll::Ready(Err(capnp::Error::disconnected(format!(
+                        "peer did not answer a ping within {:?}",
+                        timeout
+                    ))));
+                }
+                return Poll::Pending;
+            }
+            this.answer = None;
+            this.next = Timer::after(interval);
+        }
+
+        if Pin::new(&mut this.next).poll(cx).is_ready() {
+            let peer: capnp::capability::Client = this.rpc.bootstrap(Side::Client);
+            this.answer = Some((peer.when_resolved(), Timer::after(timeout)));
+            // The request is only sent once the RPC system is polled again
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_io::Async;
+    use capnp_rpc::twoparty::VatNetwork;
+    use futures_lite::FutureExt;
+    use std::os::unix::net::UnixStream;
+    use std::time::Instant;
+
+    const PING: Option<(Duration, Duration)> =
+        Some((Duration::from_millis(20), Duration::from_millis(100)));
+
+    fn rpc(stream: UnixStream, side: Side) -> RpcSystem<Side> {
+        let (rx, tx) = futures_lite::io::split(Async::new(stream).unwrap());
+        RpcSystem::new(
+            Box::new(VatNetwork::new(rx, tx, side, Default::default())),
+            None,
+        )
+    }
+
+    #[test]
+    fn silent_peer_is_disconnected() {
+        let (server, _client) = UnixStream::pair().unwrap();
+        let start = Instant::now();
+
+        // The client never reads or answers anything, like a peer that is gone
+        let result = async_io::block_on(Pinged::with_ping(rpc(server, Side::Server), PING));
+        assert!(result.is_err());
+        assert!(start.elapsed() >= Duration::from_millis(120));
+    }
+
+    #[test]
+    fn answering_peer_stays_connected() {
+        let (server, client) = UnixStream::pair().unwrap();
+        let served = Pinged::with_ping(rpc(server, Side::Server), PING);
+        let peer = rpc(client, Side::Client);
+
+        let result = async_io::block_on(
+            async {
+                Timer::after(Duration::from_millis(500)).await;
+                Ok(())
+            }
+            .or(served)
+            .or(peer),
+        );
+        assert!(result.is_ok(), "{:?}", result);
+    }
+}
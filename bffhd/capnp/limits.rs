@@ -0,0 +1,249 @@
+//! Caps on the number of concurrent API connections and the rate new ones are opened at
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::ConnectionRate;
+
+#[derive(Debug, Default)]
+struct Counts {
+    anonymous: usize,
+    authenticated: usize,
+}
+
+/// Cap on anonymous connections if none is configured
+pub const DEFAULT_MAX_ANONYMOUS: usize = 64;
+
+#[derive(Clone, Debug)]
+/// Number of API connections allowed, counted separately before and after authentication
+///
+/// Anybody can open connections that never authenticate, so those usually get a much tighter
+/// cap than connections of authenticated users. A connection counts as anonymous from the moment
+/// it is accepted, including the TLS handshake, until its first successful authentication.
+pub struct ConnectionLimits {
+    max_anonymous: Option<usize>,
+    max_authenticated: Option<usize>,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl ConnectionLimits {
+    pub fn new(max_anonymous: Option<usize>, max_authenticated: Option<usize>) -> Self {
+        Self {
+            max_anonymous,
+            max_authenticated,
+            counts: Arc::default(),
+        }
+    }
+
+    /// Take a slot for a newly accepted connection, failing if too many are unauthenticated
+    ///
+    /// The slot is given back once the returned handle and all its clones are dropped.
+    pub fn try_connect(&self) -> Option<ConnectionSlot> {
+        let mut counts = self.counts.lock().unwrap();
+        if counts.anonymous >= self.max_anonymous.unwrap_or(usize::MAX) {
+            return None;
+        }
+        counts.anonymous += 1;
+        Some(ConnectionSlot(Arc::new(Slot {
+            limits: self.clone(),
+            authenticated: AtomicBool::new(false),
+        })))
+    }
+
+    /// Number of (anonymous, authenticated) connections currently open
+    pub fn open(&self) -> (usize, usize) {
+        let counts = self.counts.lock().unwrap();
+        (counts.anonymous, counts.authenticated)
+    }
+}
+
+#[derive(Debug)]
+struct Slot {
+    limits: ConnectionLimits,
+    /// Only changed with the lock on the counts held
+    authenticated: AtomicBool,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut counts = self.limits.counts.lock().unwrap();
+        if self.authenticated.load(Ordering::Acquire) {
+            counts.authenticated -= 1;
+        } else {
+            counts.anonymous -= 1;
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Slot of a single open connection
+pub struct ConnectionSlot(Arc<Slot>);
+
+impl ConnectionSlot {
+    /// Count the connection as authenticated from now on
+    ///
+    /// Fails if the cap on authenticated connections is reached, in which case the connection
+    /// stays anonymous. Authenticating an already authenticated connection again always succeeds.
+    pub fn authenticate(&self) -> bool {
+        let slot = &self.0;
+        let mut counts = slot.limits.counts.lock().unwrap();
+        if slot.authenticated.load(Ordering::Acquire) {
+            return true;
+        }
+        if counts.authenticated >= slot.limits.max_authenticated.unwrap_or(usize::MAX) {
+            return false;
+        }
+        counts.anonymous -= 1;
+        counts.authenticated += 1;
+        slot.authenticated.store(true, Ordering::Release);
+        true
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.0.authenticated.load(Ordering::Acquire)
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    by_ip: HashMap<IpAddr, Bucket>,
+    pruned: Instant,
+}
+
+#[derive(Clone, Debug)]
+/// Token bucket per IP address limiting how fast new connections are accepted
+///
+/// Addresses that were idle long enough for their bucket to fill up again are forgotten, so the
+/// map only holds addresses that connected recently.
+pub struct ConnectionRateLimiter {
+    rate: ConnectionRate,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl ConnectionRateLimiter {
+    pub fn new(rate: ConnectionRate) -> Self {
+        Self {
+            rate,
+            buckets: Arc::new(Mutex::new(Buckets {
+                by_ip: HashMap::new(),
+                pruned: Instant::now(),
+            })),
+        }
+    }
+
+    /// Take a token for a new connection from `ip`, failing if its bucket is empty
+    pub fn check(&self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let burst = f64::from(self.rate.burst);
+        let per_second = f64::from(self.rate.per_minute) / 60.0;
+        // Time an empty bucket takes to fill up, after which it's the same as having none
+        let idle = Duration::from_secs_f64((burst / per_second).min(86400.0));
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.saturating_duration_since(buckets.pruned) >= idle {
+            buckets
+                .by_ip
+                .retain(|_, bucket| now.saturating_duration_since(bucket.updated) < idle);
+            buckets.pruned = now;
+        }
+
+        let bucket = buckets.by_ip.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_open_connections_hit_anonymous_cap_first() {
+        let limits = ConnectionLimits::new(Some(2), Some(10));
+
+        let authenticated: Vec<_> = (0..5)
+            .map(|_| {
+                let slot = limits.try_connect().unwrap();
+                assert!(slot.authenticate());
+                slot
+            })
+            .collect();
+
+        let mut half_open: Vec<_> = std::iter::from_fn(|| limits.try_connect())
+            .take(100)
+            .collect();
+        assert_eq!(half_open.len(), 2);
+        assert_eq!(limits.open(), (2, 5));
+
+        // Authenticated users are not locked out by the half-open connections
+        half_open.pop();
+        let slot = limits.try_connect().unwrap();
+        assert!(slot.authenticate());
+        assert_eq!(limits.open(), (1, 6));
+
+        drop(authenticated);
+        drop(slot);
+        assert_eq!(limits.open(), (1, 0));
+    }
+
+    #[test]
+    fn authenticated_cap_keeps_connection_anonymous() {
+        let limits = ConnectionLimits::new(None, Some(1));
+        let first = limits.try_connect().unwrap();
+        let second = limits.try_connect().unwrap();
+        assert!(first.authenticate());
+        assert!(first.authenticate());
+        assert!(!second.authenticate());
+        assert!(first.is_authenticated());
+        assert!(!second.is_authenticated());
+        assert_eq!(limits.open(), (1, 1));
+    }
+
+    #[test]
+    fn rate_is_limited_per_ip_and_idle_entries_are_pruned() {
+        let limiter = ConnectionRateLimiter::new(ConnectionRate {
+            burst: 2,
+            per_minute: 60,
+        });
+        let now = Instant::now();
+        let flooding: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        assert!(limiter.check_at(flooding, now));
+        assert!(limiter.check_at(flooding, now));
+        assert!(!limiter.check_at(flooding, now));
+        assert!(limiter.check_at(other, now));
+
+        // One connection per second is refilled
+        assert!(!limiter.check_at(flooding, now + Duration::from_millis(500)));
+        assert!(limiter.check_at(flooding, now + Duration::from_millis(1500)));
+        assert_eq!(limiter.buckets.lock().unwrap().by_ip.len(), 2);
+
+        // Both buckets are full again after two seconds and get forgotten
+        let later = now + Duration::from_secs(10);
+        assert!(limiter.check_at("192.0.2.3".parse().unwrap(), later));
+        assert_eq!(limiter.buckets.lock().unwrap().by_ip.len(), 1);
+    }
+}
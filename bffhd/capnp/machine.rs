@@ -1,4 +1,5 @@
 use crate::capnp::user::User;
+use crate::config::MachineDescription;
 use crate::resources::modules::fabaccess::{ArchivedStatus, Status};
 use crate::resources::Resource;
 use crate::session::SessionHandle;
@@ -10,6 +11,40 @@ use api::machine_capnp::machine::{
 };
 use capnp::capability::Promise;
 use capnp_rpc::pry;
+use futures_signals::signal::SignalExt;
+use futures_util::StreamExt;
+use std::future::Future;
+
+/// Property key under which the reason for a disabled machine is exposed
+const DISABLED_REASON: &str = "disabled_reason";
+/// Property key under which the number of uses since the last reset is exposed
+///
+/// Setting it to `0` or removing it resets the counter, e.g. after the machine was serviced.
+const USAGE_COUNT: &str = "usage_count";
+/// Property key under which the configured icon is exposed
+const ICON: &str = "icon";
+
+/// Configured presentation data, passed to clients unchanged
+fn presentation_properties(desc: &MachineDescription) -> Vec<(&str, &str)> {
+    desc.icon
+        .iter()
+        .map(|icon| (ICON, icon.as_str()))
+        .chain(
+            desc.metadata
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        )
+        .collect()
+}
+
+/// Receiver of the state changes pushed by [`Machine::subscribe`]
+///
+/// Implemented by the callback capability a client passes in.
+pub trait StateCallback {
+    /// Send `state` to the client. Failing, e.g. because the client disconnected, ends the
+    /// subscription.
+    fn push(&self, state: MachineState) -> Promise<(), ::capnp::Error>;
+}
 
 #[derive(Clone)]
 pub struct Machine {
@@ -23,15 +58,17 @@ impl Machine {
     }
 
     pub fn build_into(self, mut builder: machine::Builder) {
+        // Everything below describes the machine as of this one instant
+        let snapshot = self.resource.snapshot();
         builder.set_id(self.resource.get_id());
-        builder.set_name(self.resource.get_name());
-        if let Some(ref desc) = self.resource.get_description().description {
+        builder.set_name(&snapshot.description.name);
+        if let Some(ref desc) = snapshot.description.description {
             builder.set_description(desc);
         }
-        if let Some(ref wiki) = self.resource.get_description().wiki {
+        if let Some(ref wiki) = snapshot.description.wiki {
             builder.set_wiki(wiki);
         }
-        if let Some(ref category) = self.resource.get_description().category {
+        if let Some(ref category) = snapshot.description.category {
             builder.set_category(category);
         }
         builder.set_urn(&format!(
@@ -41,8 +78,7 @@ impl Machine {
 
         {
             let user = self.session.get_user_ref();
-            let state = self.resource.get_state_ref();
-            let state = state.as_ref();
+            let state = snapshot.state.as_ref();
 
             if self.session.has_write(&self.resource)
                 && match &state.inner.state {
@@ -60,27 +96,76 @@ impl Machine {
 
             // TODO: admin perm
 
-            let s = match &state.inner.state {
-                ArchivedStatus::Free => MachineState::Free,
-                ArchivedStatus::Disabled => MachineState::Disabled,
-                ArchivedStatus::Blocked(_) => MachineState::Blocked,
-                ArchivedStatus::InUse(owner) => {
-                    if owner == &user {
-                        builder.set_inuse(capnp_rpc::new_client(self.clone()));
-                    }
-                    MachineState::InUse
+            if let ArchivedStatus::InUse(owner) = &state.inner.state {
+                if owner == &user {
+                    builder.set_inuse(capnp_rpc::new_client(self.clone()));
                 }
-                ArchivedStatus::Reserved(_) => MachineState::Reserved,
-                ArchivedStatus::ToCheck(_) => MachineState::ToCheck,
-            };
+            }
             if self.session.has_read(&self.resource) {
-                builder.set_state(s);
+                builder.set_state(MachineState::from(&state.inner));
             }
         }
 
         builder.set_info(capnp_rpc::new_client(self));
     }
 
+    /// Push the state of the machine to `callback`, first the current one and then every change
+    ///
+    /// Changes made while a push is still in flight are coalesced, so a slow client only gets the
+    /// latest state instead of a growing backlog. States are only pushed while the machine is
+    /// visible to the session. The returned future ends once `callback` fails or the machine is
+    /// dropped, e.g. because it was removed or replaced on a configuration reload. Dropping the
+    /// future ends the subscription as well.
+    pub fn subscribe(&self, callback: impl StateCallback) -> impl Future<Output = ()> {
+        let session = self.session.clone();
+        let resource = self.resource.downgrade();
+        let id = self.resource.get_id().to_string();
+        // Doesn't keep the machine alive, the stream ends with the last handle to the state
+        let mut states = self.resource.get_signal().to_stream();
+
+        async move {
+            // Only taking the next state once the push finished is what coalesces bursts
+            while let Some(state) = states.next().await {
+                match resource.upgrade() {
+                    Some(resource) if resource.visible(&session) => {}
+                    Some(_) => continue,
+                    None => break,
+                }
+                let state = MachineState::from(&state.as_ref().inner);
+                if let Err(error) = callback.push(state).await {
+                    tracing::debug!(%id, %error, "pushing state failed, ending subscription");
+                    return;
+                }
+            }
+            tracing::debug!(%id, "machine dropped, ending subscription");
+        }
+    }
+
+    /// Run `f` as a state-changing call counting against the in-flight limit of the session
+    fn limited<F>(&self, f: F) -> Promise<(), ::capnp::Error>
+    where
+        F: Future<Output = Result<(), ::capnp::Error>> + 'static,
+    {
+        pry!(self.session.check_active());
+        let permit = pry!(self.session.start_call());
+        Promise::from_future(async move {
+            let result = f.await;
+            drop(permit);
+            result
+        })
+    }
+
+    /// Reset the usage counter of the machine, needs the manage privilege
+    fn reset_usage(&self) -> Promise<(), ::capnp::Error> {
+        let resource = self.resource.clone();
+        let session = self.session.clone();
+        self.limited(async move {
+            resource
+                .reset_usage(&session)
+                .map_err(|reason| ::capnp::Error::failed(reason.to_string()))
+        })
+    }
+
     /// Builds a machine into the given builder. Re
     pub fn build(session: SessionHandle, resource: Resource, builder: machine::Builder) {
         let this = Self::new(session.clone(), resource.clone());
@@ -104,11 +189,30 @@ impl InfoServer for Machine {
     fn get_property_list(
         &mut self,
         _: info::GetPropertyListParams,
-        _: info::GetPropertyListResults,
+        mut result: info::GetPropertyListResults,
     ) -> Promise<(), ::capnp::Error> {
-        Promise::err(::capnp::Error::unimplemented(
-            "method not implemented".to_string(),
-        ))
+        let (reason, usage) = if self.session.has_read(&self.resource) {
+            let usage = self.resource.get_usage().to_string();
+            (self.resource.get_reason(), Some(usage))
+        } else {
+            (None, None)
+        };
+        let desc = self.resource.get_description();
+        let mut properties = presentation_properties(&desc);
+        if let Some(ref reason) = reason {
+            properties.push((DISABLED_REASON, reason.as_str()));
+        }
+        if let Some(ref usage) = usage {
+            properties.push((USAGE_COUNT, usage.as_str()));
+        }
+
+        let mut builder = result.get().init_property_list(properties.len() as u32);
+        for (i, (key, value)) in properties.iter().enumerate() {
+            let mut kv = builder.reborrow().get(i as u32);
+            kv.set_key(key);
+            kv.set_value(value);
+        }
+        Promise::ok(())
     }
     fn get_reservation_list(
         &mut self,
@@ -125,10 +229,12 @@ impl UseServer for Machine {
     fn use_(&mut self, _: use_::UseParams, _: use_::UseResults) -> Promise<(), ::capnp::Error> {
         let resource = self.resource.clone();
         let session = self.session.clone();
-        Promise::from_future(async move {
+        self.limited(async move {
             let user = session.get_user_ref();
-            resource.try_update(session, Status::InUse(user)).await;
-            Ok(())
+            resource
+                .try_update(session, Status::InUse(user))
+                .await
+                .map_err(|reason| ::capnp::Error::failed(reason.to_string()))
         })
     }
 
@@ -139,10 +245,12 @@ impl UseServer for Machine {
     ) -> Promise<(), ::capnp::Error> {
         let resource = self.resource.clone();
         let session = self.session.clone();
-        Promise::from_future(async move {
+        self.limited(async move {
             let user = session.get_user_ref();
-            resource.try_update(session, Status::Reserved(user)).await;
-            Ok(())
+            resource
+                .try_update(session, Status::Reserved(user))
+                .await
+                .map_err(|reason| ::capnp::Error::failed(reason.to_string()))
         })
     }
 
@@ -165,9 +273,11 @@ impl InUseServer for Machine {
     ) -> Promise<(), ::capnp::Error> {
         let resource = self.resource.clone();
         let session = self.session.clone();
-        Promise::from_future(async move {
-            resource.give_back(session.clone()).await;
-            Ok(())
+        self.limited(async move {
+            resource
+                .give_back(session.clone())
+                .await
+                .map_err(|reason| ::capnp::Error::failed(reason.to_string()))
         })
     }
 
@@ -225,21 +335,70 @@ impl ManageServer for Machine {
     }
     fn set_property(
         &mut self,
-        _: manage::SetPropertyParams,
+        params: manage::SetPropertyParams,
         _: manage::SetPropertyResults,
     ) -> Promise<(), ::capnp::Error> {
-        Promise::err(::capnp::Error::unimplemented(
-            "method not implemented".to_string(),
-        ))
+        let property = pry!(pry!(params.get()).get_property());
+        let key = pry!(property.get_key());
+        if key == USAGE_COUNT {
+            if pry!(property.get_value()) != "0" {
+                return Promise::err(::capnp::Error::failed(format!(
+                    "{} can only be reset to 0",
+                    USAGE_COUNT
+                )));
+            }
+            return self.reset_usage();
+        }
+        if key != DISABLED_REASON {
+            return Promise::err(::capnp::Error::unimplemented(format!(
+                "property {} not implemented",
+                key
+            )));
+        }
+        let reason = pry!(property.get_value());
+        let reason = if reason.is_empty() {
+            None
+        } else {
+            Some(reason.to_string())
+        };
+
+        let resource = self.resource.clone();
+        let session = self.session.clone();
+        self.limited(async move {
+            resource
+                .disable(&session, reason)
+                .await
+                .map_err(|reason| ::capnp::Error::failed(reason.to_string()))
+        })
     }
     fn remove_property(
         &mut self,
-        _: manage::RemovePropertyParams,
+        params: manage::RemovePropertyParams,
         _: manage::RemovePropertyResults,
     ) -> Promise<(), ::capnp::Error> {
-        Promise::err(::capnp::Error::unimplemented(
-            "method not implemented".to_string(),
-        ))
+        let property = pry!(pry!(params.get()).get_property());
+        let key = pry!(property.get_key());
+        if key == USAGE_COUNT {
+            return self.reset_usage();
+        }
+        if key != DISABLED_REASON {
+            return Promise::err(::capnp::Error::unimplemented(format!(
+                "property {} not implemented",
+                key
+            )));
+        }
+
+        let resource = self.resource.clone();
+        let session = self.session.clone();
+        self.limited(async move {
+            if resource.get_reason().is_some() {
+                resource
+                    .disable(&session, None)
+                    .await
+                    .map_err(|reason| ::capnp::Error::failed(reason.to_string()))?;
+            }
+            Ok(())
+        })
     }
 
     fn force_use(
@@ -249,11 +408,11 @@ impl ManageServer for Machine {
     ) -> Promise<(), ::capnp::Error> {
         let resource = self.resource.clone();
         let session = self.session.clone();
-        Promise::from_future(async move {
+        self.limited(async move {
             resource
-                .force_set(Status::InUse(session.get_user_ref()))
-                .await;
-            Ok(())
+                .force_set(&session, Status::InUse(session.get_user_ref()))
+                .await
+                .map_err(|reason| ::capnp::Error::failed(reason.to_string()))
         })
     }
 
@@ -263,10 +422,12 @@ impl ManageServer for Machine {
         _: manage::ForceFreeResults,
     ) -> Promise<(), ::capnp::Error> {
         let resource = self.resource.clone();
-        let _session = self.session.clone();
-        Promise::from_future(async move {
-            resource.force_set(Status::Free).await;
-            Ok(())
+        let session = self.session.clone();
+        self.limited(async move {
+            resource
+                .force_set(&session, Status::Free)
+                .await
+                .map_err(|reason| ::capnp::Error::failed(reason.to_string()))
         })
     }
     fn force_transfer(
@@ -286,11 +447,11 @@ impl ManageServer for Machine {
     ) -> Promise<(), ::capnp::Error> {
         let resource = self.resource.clone();
         let session = self.session.clone();
-        Promise::from_future(async move {
+        self.limited(async move {
             resource
-                .force_set(Status::Blocked(session.get_user_ref()))
-                .await;
-            Ok(())
+                .force_set(&session, Status::Blocked(session.get_user_ref()))
+                .await
+                .map_err(|reason| ::capnp::Error::failed(reason.to_string()))
         })
     }
     fn disabled(
@@ -299,9 +460,12 @@ impl ManageServer for Machine {
         _: manage::DisabledResults,
     ) -> Promise<(), ::capnp::Error> {
         let resource = self.resource.clone();
-        Promise::from_future(async move {
-            resource.force_set(Status::Disabled).await;
-            Ok(())
+        let session = self.session.clone();
+        self.limited(async move {
+            resource
+                .disable(&session, None)
+                .await
+                .map_err(|reason| ::capnp::Error::failed(reason.to_string()))
         })
     }
 }
@@ -312,25 +476,16 @@ impl AdminServer for Machine {
         params: admin::ForceSetStateParams,
         _: admin::ForceSetStateResults,
     ) -> Promise<(), ::capnp::Error> {
-        use api::schema::machine_capnp::machine::MachineState as APIMState;
         let user = self.session.get_user_ref();
-        let state = match pry!(pry!(params.get()).get_state()) {
-            APIMState::Free => Status::Free,
-            APIMState::Blocked => Status::Blocked(user),
-            APIMState::Disabled => Status::Disabled,
-            APIMState::InUse => Status::InUse(user),
-            APIMState::Reserved => Status::Reserved(user),
-            APIMState::ToCheck => Status::ToCheck(user),
-            APIMState::Totakeover => {
-                return Promise::err(::capnp::Error::unimplemented(
-                    "totakeover not implemented".to_string(),
-                ))
-            }
-        };
+        let state = pry!(pry!(params.get()).get_state());
+        let state = pry!(Status::try_from((state, user)));
         let resource = self.resource.clone();
-        Promise::from_future(async move {
-            resource.force_set(state).await;
-            Ok(())
+        let session = self.session.clone();
+        self.limited(async move {
+            resource
+                .force_set(&session, state)
+                .await
+                .map_err(|reason| ::capnp::Error::failed(reason.to_string()))
         })
     }
 
@@ -372,3 +527,36 @@ impl AdminServer for Machine {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presentation_data_is_passed_through_unchanged() {
+        let desc: MachineDescription = serde_dhall::from_str(
+            r##"{
+                name = "Testmachine",
+                icon = "https://example.org/icons/lasercutter.svg",
+                metadata = { colour = "#ff8000", position = "Room 2, left of the door" },
+                disclose = "lab.test.read",
+                read = "lab.test.read",
+                write = "lab.test.write",
+                manage = "lab.test.admin",
+            }"##,
+        )
+        .parse()
+        .unwrap();
+
+        let mut properties = presentation_properties(&desc);
+        properties.sort();
+        assert_eq!(
+            properties,
+            [
+                ("colour", "#ff8000"),
+                ("icon", "https://example.org/icons/lasercutter.svg"),
+                ("position", "Room 2, left of the door"),
+            ]
+        );
+    }
+}
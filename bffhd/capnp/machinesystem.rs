@@ -1,15 +1,42 @@
 use crate::capnp::machine::Machine;
+use crate::resources::group::GroupRequest;
 use crate::resources::search::ResourcesHandle;
+use crate::resources::state::value;
+use crate::resources::state::value::ValueType;
 use crate::resources::Resource;
 use crate::session::SessionHandle;
 use crate::RESOURCES;
 use api::machinesystem_capnp::machine_system::info;
 use capnp::capability::Promise;
 use capnp_rpc::pry;
+use std::collections::HashMap;
 use tracing::Span;
 
 const TARGET: &str = "bffh::api::machinesystem";
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The parts of a machine that only change when the config is reloaded
+pub struct MachineInfo {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub wiki: Option<String>,
+    pub category: Option<String>,
+    pub icon: Option<String>,
+    pub metadata: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Descriptions of all machines visible to a user, for clients to cache
+///
+/// As long as `generation` stays the same the descriptions haven't changed. It does not track
+/// changes of the user's roles, which can make machines appear or disappear.
+pub struct MachineCatalog {
+    pub generation: u64,
+    /// Sorted by id
+    pub machines: Vec<MachineInfo>,
+}
+
 #[derive(Clone)]
 pub struct Machines {
     span: Span,
@@ -19,18 +46,79 @@ pub struct Machines {
 
 impl Machines {
     pub fn new(session: SessionHandle) -> Self {
+        // FIXME no unwrap bad
+        Self::with_resources(session, RESOURCES.get().unwrap().clone())
+    }
+
+    pub fn with_resources(session: SessionHandle, resources: ResourcesHandle) -> Self {
         let span = tracing::info_span!(
             target: TARGET,
             parent: &session.span,
             "MachineSystem",
         );
-        // FIXME no unwrap bad
         Self {
             span,
             session,
-            resources: RESOURCES.get().unwrap().clone(),
+            resources,
         }
     }
+
+    /// Static descriptions of the visible machines
+    ///
+    /// Not part of the API schema yet, clients can't call this over RPC.
+    pub fn catalog(&self) -> MachineCatalog {
+        let (generation, resources) = self.resources.list_all_with_generation();
+        let mut machines: Vec<MachineInfo> = resources
+            .into_iter()
+            .filter(|resource| resource.visible(&self.session))
+            .map(|resource| {
+                let desc = resource.get_description();
+                MachineInfo {
+                    id: resource.get_id().to_string(),
+                    name: desc.name.clone(),
+                    description: desc.description.clone(),
+                    wiki: desc.wiki.clone(),
+                    category: desc.category.clone(),
+                    icon: desc.icon.clone(),
+                    metadata: desc.metadata.clone(),
+                }
+            })
+            .collect();
+        machines.sort_by(|a, b| a.id.cmp(&b.id));
+        MachineCatalog {
+            generation,
+            machines,
+        }
+    }
+
+    /// Types of the values machine states are made of, so clients can decode any state
+    ///
+    /// Not part of the API schema yet, clients can't call this over RPC.
+    pub fn value_types(&self) -> Vec<ValueType> {
+        value::value_types()
+    }
+
+    /// Put the user on a free member of the group `id`, or queue them for it
+    ///
+    /// Not part of the API schema yet, clients can't call this over RPC. Groups without any
+    /// member visible to the session are reported like unknown ones.
+    pub async fn request_group(&self, id: &str) -> Result<GroupRequest, capnp::Error> {
+        self.session.check_active()?;
+        let group = self
+            .resources
+            .get_group(id)
+            .filter(|group| {
+                group
+                    .members()
+                    .iter()
+                    .any(|member| member.visible(&self.session))
+            })
+            .ok_or_else(|| capnp::Error::failed(format!("no such group {}", id)))?;
+        group
+            .request(self.session.clone())
+            .await
+            .map_err(|reason| capnp::Error::failed(reason.to_string()))
+    }
 }
 
 impl info::Server for Machines {
@@ -49,7 +137,7 @@ impl info::Server for Machines {
 
         tracing::trace!("method call");
 
-        let machine_list: Vec<(usize, &Resource)> = self
+        let machine_list: Vec<(usize, Resource)> = self
             .resources
             .list_all()
             .into_iter()
@@ -57,8 +145,7 @@ impl info::Server for Machines {
             .enumerate()
             .collect();
         let mut builder = result.get().init_machine_list(machine_list.len() as u32);
-        for (i, m) in machine_list {
-            let resource = m.clone();
+        for (i, resource) in machine_list {
             let mbuilder = builder.reborrow().get(i as u32);
             Machine::build(self.session.clone(), resource, mbuilder);
         }
@@ -90,7 +177,7 @@ impl info::Server for Machines {
         if let Some(resource) = self.resources.get_by_id(id) {
             tracing::trace!(results = "Just", results.inner = id, "method return");
             let builder = result.get();
-            Machine::optional_build(self.session.clone(), resource.clone(), builder);
+            Machine::optional_build(self.session.clone(), resource, builder);
         } else {
             tracing::trace!(results = "Nothing", "method return");
         }
@@ -123,7 +210,7 @@ impl info::Server for Machines {
                 "method return"
             );
             let builder = result.get();
-            Machine::optional_build(self.session.clone(), resource.clone(), builder);
+            Machine::optional_build(self.session.clone(), resource, builder);
         } else {
             tracing::trace!(results = "Nothing", "method return");
         }
@@ -1,44 +1,102 @@
 use miette::Diagnostic;
 use thiserror::Error;
 
-use async_net::TcpListener;
+use async_net::{TcpListener, TcpStream};
 use capnp_rpc::rpc_twoparty_capnp::Side;
 use capnp_rpc::twoparty::VatNetwork;
 use capnp_rpc::RpcSystem;
 use executor::prelude::{Executor, SupervisionRegistry};
-use futures_rustls::server::TlsStream;
-use futures_rustls::TlsAcceptor;
-use futures_util::stream::FuturesUnordered;
-use futures_util::{stream, AsyncRead, AsyncWrite, StreamExt};
+use futures_util::stream::{BoxStream, FuturesUnordered};
+use futures_util::{stream, StreamExt};
+use lightproc::recoverable_handle::RecoverableHandle;
 
+use async_io::Timer;
+use futures_lite::{future, FutureExt};
 use std::future::Future;
 use std::io;
+use std::time::Duration;
 
 use std::net::{IpAddr, SocketAddr};
 
 use crate::authentication::AuthenticationHandle;
-use crate::session::SessionManager;
+use crate::config::Config;
+use crate::session::{Notification, SessionManager};
+use crate::shutdown::ShutdownSignal;
+use crate::tls::ReloadableAcceptor;
 
 mod config;
-pub use config::{Listen, TlsListen};
+pub use config::{ConnectionRate, Keepalive, Listen, TlsListen};
 
 mod authenticationsystem;
 mod connection;
+mod interop;
+mod keepalive;
+use keepalive::Pinged;
+mod limits;
+pub use limits::{ConnectionLimits, ConnectionRateLimiter, DEFAULT_MAX_ANONYMOUS};
 mod machine;
 mod machinesystem;
 mod permissionsystem;
+mod proxy;
+mod resolve;
 mod session;
 mod user;
 mod user_system;
+pub mod version;
+
+#[cfg(test)]
+mod tests;
 
 pub struct APIServer {
     executor: Executor<'static>,
     sockets: Vec<TcpListener>,
-    acceptor: TlsAcceptor,
+    acceptor: ReloadableAcceptor,
     sessionmanager: SessionManager,
     authentication: AuthenticationHandle,
+    keepalive: Option<Keepalive>,
+    handshake_timeout: Duration,
+    trusted_proxies: Vec<IpAddr>,
+    limits: ConnectionLimits,
+    /// Time a connection has to authenticate in before it is closed
+    auth_timeout: Duration,
+    /// Throttles how fast a single address may open connections, unlimited if `None`
+    rate: Option<ConnectionRateLimiter>,
+    /// Time open connections get to finish on shutdown before they are cancelled
+    drain_timeout: Duration,
+    /// Triggered on shutdown, connections close once they have no calls outstanding
+    draining: ShutdownSignal,
+    /// Sockets opened on addresses that listens given by hostname resolve to later on
+    watch: Option<BoxStream<'static, TcpListener>>,
+}
+
+/// Stream of connections accepted on `socket`, owning it so sockets can be added while running
+fn accept(socket: TcpListener) -> BoxStream<'static, io::Result<TcpStream>> {
+    stream::unfold(socket, |socket| async move {
+        let stream = socket.accept().await.map(|(stream, _)| stream);
+        Some((stream, socket))
+    })
+    .boxed()
+}
+
+/// Await `f`, giving up after `timeout` has elapsed
+async fn with_timeout<T>(f: impl Future<Output = T>, timeout: Duration) -> Option<T> {
+    async move { Some(f.await) }
+        .or(async {
+            Timer::after(timeout).await;
+            None
+        })
+        .await
 }
 
+/// Time open connections get to finish on shutdown if not configured otherwise
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often sessions are checked for outstanding calls while draining
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time new connections get to authenticate if not configured otherwise
+pub const DEFAULT_AUTH_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Debug, Error, Diagnostic)]
 #[error("Reached Void error, this should not be possible")]
 pub enum Error {}
@@ -47,9 +105,13 @@ impl APIServer {
     pub fn new(
         executor: Executor<'static>,
         sockets: Vec<TcpListener>,
-        acceptor: TlsAcceptor,
+        acceptor: ReloadableAcceptor,
         sessionmanager: SessionManager,
         authentication: AuthenticationHandle,
+        keepalive: Option<Keepalive>,
+        handshake_timeout: Duration,
+        trusted_proxies: Vec<IpAddr>,
+        limits: ConnectionLimits,
     ) -> Self {
         Self {
             executor,
@@ -57,13 +119,22 @@ impl APIServer {
             acceptor,
             sessionmanager,
             authentication,
+            keepalive,
+            handshake_timeout,
+            trusted_proxies,
+            limits,
+            auth_timeout: DEFAULT_AUTH_TIMEOUT,
+            rate: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            draining: ShutdownSignal::new(),
+            watch: None,
         }
     }
 
     pub async fn bind(
         executor: Executor<'static>,
-        listens: impl IntoIterator<Item = &Listen>,
-        acceptor: TlsAcceptor,
+        config: &Config,
+        acceptor: ReloadableAcceptor,
         sessionmanager: SessionManager,
         authentication: AuthenticationHandle,
     ) -> Result<Self, Error> {
@@ -72,9 +143,10 @@ impl APIServer {
 
         let sockets = FuturesUnordered::new();
 
-        listens
-            .into_iter()
-            .map(|a| async move { (async_net::resolve(a.to_tuple()).await, a) })
+        config
+            .listens
+            .iter()
+            .map(|a| async move { (resolve::resolve(a, resolve::Retry::default()).await, a) })
             .collect::<FuturesUnordered<_>>()
             .filter_map(|(res, addr)| async move {
                 match res {
@@ -114,43 +186,167 @@ impl APIServer {
             tracing::warn!("No usable listen addresses configured for the API server!");
         }
 
-        Ok(Self::new(
+        let watch = config.listen_reresolve_interval.and_then(|secs| {
+            let hostnames: Vec<Listen> = config
+                .listens
+                .iter()
+                .filter(|listen| resolve::is_hostname(listen))
+                .cloned()
+                .collect();
+            if hostnames.is_empty() {
+                return None;
+            }
+            let known = sockets
+                .iter()
+                .filter_map(|socket| socket.local_addr().ok())
+                .collect();
+            Some(resolve::watch(hostnames, known, Duration::from_secs(secs)))
+        });
+
+        let mut server = Self::new(
             executor,
             sockets,
             acceptor,
             sessionmanager,
             authentication,
-        ))
+            config.keepalive,
+            config.tlsconfig.handshake_timeout(),
+            config.trusted_proxies.clone(),
+            ConnectionLimits::new(
+                config
+                    .max_anonymous_connections
+                    .or(Some(DEFAULT_MAX_ANONYMOUS)),
+                config.max_authenticated_connections,
+            ),
+        );
+        server.watch = watch;
+        if let Some(secs) = config.auth_timeout {
+            server.auth_timeout = Duration::from_secs(secs);
+        }
+        server.rate = config.connection_rate.map(ConnectionRateLimiter::new);
+        if let Some(secs) = config.drain_timeout {
+            server.drain_timeout = Duration::from_secs(secs);
+        }
+        Ok(server)
     }
 
-    pub async fn handle_until(self, stop: impl Future) {
-        stream::select_all(
-            self.sockets
-                .iter()
-                .map(|tcplistener| tcplistener.incoming()),
-        )
-        .take_until(stop)
-        .for_each(|stream| async {
-            match stream {
-                Ok(stream) => {
+    pub async fn handle_until(mut self, stop: impl Future) {
+        enum Event {
+            Accepted(io::Result<TcpStream>),
+            Listening(TcpListener),
+            Closed,
+            Stop,
+        }
+
+        let mut incoming = stream::select_all(self.sockets.drain(..).map(accept));
+        let mut new_sockets = self
+            .watch
+            .take()
+            .unwrap_or_else(|| stream::pending().boxed());
+        let mut connections = FuturesUnordered::new();
+        let mut stop = Box::pin(stop);
+        loop {
+            let event = async {
+                match incoming.next().await {
+                    Some(stream) => Event::Accepted(stream),
+                    // Only happens while there is no socket yet, wait for re-resolving to add one
+                    None => future::pending().await,
+                }
+            }
+            .or(async {
+                match new_sockets.next().await {
+                    Some(socket) => Event::Listening(socket),
+                    None => future::pending().await,
+                }
+            })
+            .or(async {
+                match connections.next().await {
+                    Some(_) => Event::Closed,
+                    None => future::pending().await,
+                }
+            })
+            .or(async {
+                (&mut stop).await;
+                Event::Stop
+            })
+            .await;
+
+            match event {
+                Event::Accepted(Ok(stream)) => {
+                    if let Some(keepalive) = self.keepalive {
+                        if let Err(error) = keepalive.apply(&stream) {
+                            tracing::warn!(%error, "failed to enable TCP keepalive");
+                        }
+                    }
                     if let Ok(peer_addr) = stream.peer_addr() {
-                        self.handle(peer_addr, self.acceptor.accept(stream))
+                        if !self.admit(peer_addr.ip()) {
+                            tracing::debug!(
+                                peer.ip = %peer_addr.ip(),
+                                peer.port = peer_addr.port(),
+                                "connection rate exceeded, dropping connection"
+                            );
+                        } else if let Some(connection) = self.handle(peer_addr, stream) {
+                            connections.push(connection);
+                        }
                     } else {
                         tracing::error!(?stream, "failing a TCP connection with no peer addr");
                     }
                 }
-                Err(e) => tracing::warn!("Failed to accept stream: {}", e),
+                Event::Accepted(Err(e)) => tracing::warn!("Failed to accept stream: {}", e),
+                Event::Listening(socket) => incoming.push(accept(socket)),
+                Event::Closed => {}
+                Event::Stop => break,
             }
-        })
+        }
+        drop(incoming);
+
+        tracing::info!(
+            connections = connections.len(),
+            timeout = ?self.drain_timeout,
+            "closing down API handler, waiting for open connections"
+        );
+        self.draining.trigger();
+        self.sessionmanager.notify_all(Notification::GoingAway);
+        let sessionmanager = &self.sessionmanager;
+        let drained = with_timeout(
+            async { while connections.next().await.is_some() {} }.or(async {
+                // Sessions may still be opened by connections authenticating right now
+                loop {
+                    sessionmanager.close_idle();
+                    Timer::after(DRAIN_POLL_INTERVAL).await;
+                }
+            }),
+            self.drain_timeout,
+        )
         .await;
-        tracing::info!("closing down API handler");
+        if drained.is_none() {
+            tracing::warn!(
+                connections = connections.len(),
+                "connections still open after the drain timeout, cancelling them"
+            );
+            for connection in connections {
+                connection.cancel();
+            }
+        }
     }
 
-    fn handle<IO: 'static + Unpin + AsyncRead + AsyncWrite>(
+    /// Whether `ip` may open another connection right now
+    ///
+    /// Trusted proxies are exempt, all clients behind them would share a single budget otherwise.
+    /// The clients they forward are checked by [`admitted_client`] once their address is known.
+    fn admit(&self, ip: IpAddr) -> bool {
+        match &self.rate {
+            Some(rate) if !self.trusted_proxies.contains(&ip) => rate.check(ip),
+            _ => true,
+        }
+    }
+
+    /// Serve the API on `stream`, returning the task doing so unless the connection was refused
+    fn handle(
         &self,
         peer_addr: SocketAddr,
-        stream: impl Future<Output = io::Result<TlsStream<IO>>>,
-    ) {
+        mut stream: TcpStream,
+    ) -> Option<RecoverableHandle<()>> {
         let span = tracing::trace_span!("api.handle");
         let _guard = span.enter();
 
@@ -169,32 +365,126 @@ impl APIServer {
             "spawning api handler"
         );
 
+        let slot = if let Some(slot) = self.limits.try_connect() {
+            slot
+        } else {
+            tracing::warn!(
+                %peer.ip,
+                peer.port,
+                "too many unauthenticated connections, dropping connection"
+            );
+            return None;
+        };
+
         let connection_span = tracing::info_span!(
             target: "bffh::api",
             "connection",
             %peer.ip,
             peer.port,
+            proxy = tracing::field::Empty,
         );
+        let handshake_timeout = self.handshake_timeout;
+        let auth_timeout = self.auth_timeout;
+        let trusted_proxies = self.trusted_proxies.clone();
+        let rate = self.rate.clone();
+        let draining = self.draining.clone();
+        let acceptor = self.acceptor.acceptor();
+        let keepalive = self.keepalive;
         let f = async move {
-            tracing::trace!(parent: &connection_span, "starting tls exchange");
-            let stream = match stream.await {
-                Ok(stream) => stream,
-                Err(error) => {
-                    tracing::error!(parent: &connection_span, %error, "TLS handshake failed");
+            let handshake = async {
+                let admitted =
+                    admitted_client(&mut stream, peer_addr, &trusted_proxies, rate.as_ref());
+                let client_addr = match admitted.await? {
+                    Some(client_addr) => client_addr,
+                    None => {
+                        tracing::debug!(parent: &connection_span,
+                            "connection rate exceeded, dropping connection");
+                        return Ok(None);
+                    }
+                };
+                if client_addr != peer_addr {
+                    connection_span.record("proxy", &tracing::field::display(peer_addr));
+                    connection_span.record("peer.ip", &tracing::field::display(client_addr.ip()));
+                    connection_span.record("peer.port", &client_addr.port());
+                } else if !trusted_proxies.is_empty() {
+                    let mut prefix = [0u8; 12];
+                    let n = stream.peek(&mut prefix).await?;
+                    if proxy::looks_like_header(&prefix[..n]) {
+                        tracing::warn!(parent: &connection_span,
+                            "PROXY header sent by untrusted peer, dropping connection");
+                        return Ok(None);
+                    }
+                }
+
+                tracing::trace!(parent: &connection_span, "starting tls exchange");
+                let stream = acceptor.accept(stream).await?;
+                Ok::<_, Box<dyn std::error::Error>>(Some((client_addr, stream)))
+            };
+            let (client_addr, stream) = match with_timeout(handshake, handshake_timeout).await {
+                Some(Ok(Some(accepted))) => accepted,
+                Some(Ok(None)) => return,
+                Some(Err(error)) => {
+                    tracing::error!(parent: &connection_span, %error, "connection handshake failed");
+                    return;
+                }
+                None => {
+                    tracing::warn!(
+                        parent: &connection_span,
+                        timeout = ?handshake_timeout,
+                        "TLS handshake timed out, dropping connection"
+                    );
                     return;
                 }
             };
             let (rx, tx) = futures_lite::io::split(stream);
             let vat = VatNetwork::new(rx, tx, Side::Server, Default::default());
 
+            // Triggered when an admin disconnects the session opened on this connection, or on
+            // shutdown once the session has no calls outstanding
+            let close = ShutdownSignal::new();
             let bootstrap: connection::Client = capnp_rpc::new_client(connection::BootCap::new(
-                peer_addr,
+                client_addr,
                 self.authentication.clone(),
                 self.sessionmanager.clone(),
+                slot.clone(),
+                close.clone(),
                 connection_span.clone(),
             ));
 
-            if let Err(error) = RpcSystem::new(Box::new(vat), Some(bootstrap.client)).await {
+            let rpc = RpcSystem::new(Box::new(vat), Some(bootstrap.client));
+            let rpc = Pinged::new(rpc, keepalive);
+            let disconnected = async {
+                close.wait().await;
+                if draining.is_triggered() {
+                    tracing::debug!(parent: &connection_span, "closing connection for shutdown");
+                } else {
+                    tracing::info!(parent: &connection_span, "session disconnected by an admin");
+                }
+                Ok(())
+            };
+            // Connections without a session have no calls worth waiting for on shutdown
+            let drained = async {
+                draining.wait().await;
+                if slot.is_authenticated() {
+                    return futures_lite::future::pending().await;
+                }
+                tracing::debug!(parent: &connection_span, "closing anonymous connection for shutdown");
+                Ok(())
+            };
+            // Anonymous connections must not hold on to their slot forever
+            let unauthenticated = async {
+                Timer::after(auth_timeout).await;
+                if slot.is_authenticated() {
+                    return futures_lite::future::pending().await;
+                }
+                tracing::warn!(
+                    parent: &connection_span,
+                    timeout = ?auth_timeout,
+                    "connection did not authenticate in time, dropping it"
+                );
+                Ok(())
+            };
+            if let Err(error) = rpc.or(disconnected).or(unauthenticated).or(drained).await {
                 tracing::error!(
                     parent: &connection_span,
                     %error,
@@ -203,6 +493,94 @@ impl APIServer {
             }
         };
         let cgroup = SupervisionRegistry::with(SupervisionRegistry::new_group);
-        self.executor.spawn_local_cgroup(f, cgroup);
+        Some(
+            self.executor
+                .spawn_local_cgroup_named(&format!("rpc:{}", peer_addr), f, cgroup),
+        )
+    }
+}
+
+/// Determine the client at the other end of `stream`, unless it is over its connection rate
+///
+/// Direct connections were already checked when they were accepted, connections forwarded by a
+/// trusted proxy are checked here against the client named in their PROXY header.
+async fn admitted_client<R: futures_util::AsyncRead + Unpin>(
+    stream: &mut R,
+    peer_addr: SocketAddr,
+    trusted_proxies: &[IpAddr],
+    rate: Option<&ConnectionRateLimiter>,
+) -> Result<Option<SocketAddr>, proxy::ProxyError> {
+    let client_addr = proxy::client_addr(stream, peer_addr, trusted_proxies).await?;
+    match rate {
+        Some(rate) if client_addr != peer_addr && !rate.check(client_addr.ip()) => Ok(None),
+        _ => Ok(Some(client_addr)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::io::Cursor;
+    use std::time::Instant;
+
+    /// A PROXY v2 header for a TCP connection from `client` to `10.0.0.1:59661`
+    fn forwarded(client: SocketAddr) -> Cursor<Vec<u8>> {
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        match client {
+            SocketAddr::V4(client) => header.extend_from_slice(&client.ip().octets()),
+            SocketAddr::V6(_) => unimplemented!(),
+        }
+        header.extend_from_slice(&[10, 0, 0, 1]);
+        header.extend_from_slice(&client.port().to_be_bytes());
+        header.extend_from_slice(&59661u16.to_be_bytes());
+        Cursor::new(header)
+    }
+
+    #[test]
+    fn clients_behind_trusted_proxy_are_rate_limited() {
+        let proxy: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let flooding: SocketAddr = "192.0.2.7:51234".parse().unwrap();
+        let other: SocketAddr = "192.0.2.8:51234".parse().unwrap();
+        let rate = ConnectionRateLimiter::new(ConnectionRate {
+            burst: 3,
+            per_minute: 1,
+        });
+        let admitted = |client| {
+            let mut stream = forwarded(client);
+            async_io::block_on(admitted_client(
+                &mut stream,
+                proxy,
+                &[proxy.ip()],
+                Some(&rate),
+            ))
+            .unwrap()
+        };
+
+        for _ in 0..3 {
+            assert_eq!(admitted(flooding), Some(flooding));
+        }
+        for _ in 0..10 {
+            assert_eq!(admitted(flooding), None);
+        }
+        // Neither the proxy nor the other clients behind it are blocked by one of them
+        assert_eq!(admitted(other), Some(other));
+        assert!(rate.check(proxy.ip()));
+    }
+
+    #[test]
+    fn stalled_handshake_is_abandoned() {
+        let start = Instant::now();
+        let result = async_io::block_on(with_timeout(
+            futures_lite::future::pending::<()>(),
+            Duration::from_millis(50),
+        ));
+        assert!(result.is_none());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn completed_handshake_is_returned() {
+        let result = async_io::block_on(with_timeout(async { 42 }, Duration::from_secs(10)));
+        assert_eq!(result, Some(42));
     }
 }
@@ -1,4 +1,6 @@
+use crate::resources::search::ResourcesHandle;
 use crate::Roles;
+use crate::RESOURCES;
 use api::permissionsystem_capnp::permission_system::info::{
     GetRoleListParams, GetRoleListResults, Server as PermissionSystem,
 };
@@ -6,21 +8,51 @@ use capnp::capability::Promise;
 use capnp::Error;
 use tracing::Span;
 
-use crate::session::SessionHandle;
+use crate::session::{EffectivePermissions, SessionHandle};
 
 const TARGET: &str = "bffh::api::permissionsystem";
 
 pub struct Permissions {
     span: Span,
     roles: Roles,
+    session: SessionHandle,
+    resources: ResourcesHandle,
 }
 
 impl Permissions {
     pub fn new(session: SessionHandle) -> Self {
+        Self::with_resources(session, RESOURCES.get().unwrap().clone())
+    }
+
+    pub fn with_resources(session: SessionHandle, resources: ResourcesHandle) -> Self {
         let span = tracing::info_span!(target: TARGET, "PermissionSystem",);
         Self {
             span,
             roles: session.roles,
+            session,
+            resources,
+        }
+    }
+
+    /// What the session may do with machine `id`, e.g. to grey out buttons in one round-trip
+    ///
+    /// Machines the session may not see fail the same way as unknown ones so their existence
+    /// isn't leaked.
+    pub fn effective_permissions(&self, id: &str) -> Result<EffectivePermissions, Error> {
+        let _guard = self.span.enter();
+        let _span = tracing::trace_span!(target: TARGET, "effectivePermissions").entered();
+        tracing::trace!(params.id = id, "method call");
+
+        match self.resources.get_by_id(id) {
+            Some(resource) if resource.visible(&self.session) => {
+                let permissions = self.session.effective_permissions(&resource);
+                tracing::trace!(results = ?permissions, "method return");
+                Ok(permissions)
+            }
+            _ => {
+                tracing::trace!("method return: no such machine");
+                Err(Error::failed(format!("no machine with id `{}`", id)))
+            }
         }
     }
 }
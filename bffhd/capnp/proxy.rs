@@ -0,0 +1,170 @@
+//! PROXY protocol (version 2) support
+//!
+//! Proxies terminating connections in front of bffh prepend a binary header to the stream
+//! carrying the address of the actual client. Headers are only accepted from configured trusted
+//! proxies, since anybody else could use them to spoof their address.
+//!
+//! See <https://www.haproxy.org/download/2.6/doc/proxy-protocol.txt>
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use futures_util::{AsyncRead, AsyncReadExt};
+use miette::Diagnostic;
+use thiserror::Error;
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+const CMD_LOCAL: u8 = 0x0;
+const CMD_PROXY: u8 = 0x1;
+
+const FAMILY_TCP4: u8 = 0x11;
+const FAMILY_TCP6: u8 = 0x21;
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum ProxyError {
+    #[error("failed to read PROXY header")]
+    Io(
+        #[from]
+        #[source]
+        io::Error,
+    ),
+    #[error("trusted proxy did not send a PROXY v2 header")]
+    #[diagnostic(
+        code(bffh::api::proxy::missing),
+        help("Enable PROXY protocol version 2 in the proxy or remove it from `trusted_proxies`")
+    )]
+    Missing,
+    #[error("PROXY header has unsupported version or command {0:#x}")]
+    #[diagnostic(code(bffh::api::proxy::version))]
+    Unsupported(u8),
+    #[error("PROXY header address block is too short")]
+    #[diagnostic(code(bffh::api::proxy::truncated))]
+    Truncated,
+}
+
+/// Check if `prefix`, the first bytes received on a connection, start a PROXY v2 header
+pub fn looks_like_header(prefix: &[u8]) -> bool {
+    !prefix.is_empty() && SIGNATURE.starts_with(&prefix[..prefix.len().min(SIGNATURE.len())])
+}
+
+/// Read a PROXY v2 header from `stream`, returning the source address it contains
+///
+/// `None` is returned for health checks sent by the proxy itself and for connections over
+/// transports other than TCP.
+pub async fn read_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<Option<SocketAddr>, ProxyError> {
+    let mut header = [0u8; 16];
+    stream.read_exact(&mut header).await?;
+    if header[..12] != SIGNATURE {
+        return Err(ProxyError::Missing);
+    }
+
+    let version_command = header[12];
+    let family = header[13];
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+
+    // Always consume the whole header so the stream is positioned at the client data
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(ProxyError::Unsupported(version_command));
+    }
+    match version_command & 0xF {
+        CMD_LOCAL => return Ok(None),
+        CMD_PROXY => {}
+        _ => return Err(ProxyError::Unsupported(version_command)),
+    }
+
+    let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+    match family {
+        FAMILY_TCP4 => {
+            if body.len() < 12 {
+                return Err(ProxyError::Truncated);
+            }
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port(&body[8..10]))))
+        }
+        FAMILY_TCP6 => {
+            if body.len() < 36 {
+                return Err(ProxyError::Truncated);
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let ip = Ipv6Addr::from(octets);
+            Ok(Some(SocketAddr::new(IpAddr::V6(ip), port(&body[32..34]))))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Determine the address of the client at the other end of `stream`
+///
+/// Connections from a trusted proxy must start with a PROXY header naming the client, all other
+/// connections are taken to come directly from `peer`.
+pub async fn client_addr<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    peer: SocketAddr,
+    trusted: &[IpAddr],
+) -> Result<SocketAddr, ProxyError> {
+    if trusted.contains(&peer.ip()) {
+        Ok(read_header(stream).await?.unwrap_or(peer))
+    } else {
+        Ok(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::io::Cursor;
+
+    fn v4_header(src: [u8; 4], port: u16) -> Vec<u8> {
+        let mut header = SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, FAMILY_TCP4, 0, 12]);
+        header.extend_from_slice(&src);
+        header.extend_from_slice(&[10, 0, 0, 1]);
+        header.extend_from_slice(&port.to_be_bytes());
+        header.extend_from_slice(&59661u16.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn trusted_proxy_header_yields_client_addr() {
+        let proxy: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let mut data = v4_header([192, 0, 2, 7], 51234);
+        data.extend_from_slice(b"\x16\x03\x01");
+        let mut stream = Cursor::new(data);
+
+        let addr = async_io::block_on(client_addr(&mut stream, proxy, &[proxy.ip()])).unwrap();
+        assert_eq!(addr, "192.0.2.7:51234".parse().unwrap());
+
+        // The TLS data following the header is left untouched
+        let mut rest = Vec::new();
+        async_io::block_on(stream.read_to_end(&mut rest)).unwrap();
+        assert_eq!(rest, b"\x16\x03\x01");
+    }
+
+    #[test]
+    fn untrusted_peer_keeps_its_addr() {
+        let peer: SocketAddr = "198.51.100.3:40000".parse().unwrap();
+        let data = v4_header([192, 0, 2, 7], 51234);
+        assert!(looks_like_header(&data[..5]));
+        assert!(!looks_like_header(b"\x16\x03\x01"));
+
+        let mut stream = Cursor::new(data);
+        let trusted = ["10.0.0.2".parse().unwrap()];
+        let addr = async_io::block_on(client_addr(&mut stream, peer, &trusted)).unwrap();
+        assert_eq!(addr, peer);
+    }
+
+    #[test]
+    fn trusted_proxy_without_header_is_rejected() {
+        let proxy: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let mut stream = Cursor::new(vec![0x16; 32]);
+        let result = async_io::block_on(client_addr(&mut stream, proxy, &[proxy.ip()]));
+        assert!(matches!(result, Err(ProxyError::Missing)));
+    }
+}
@@ -0,0 +1,163 @@
+//! Resolving listen addresses without giving up on the first DNS hiccup
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use async_io::Timer;
+use async_net::TcpListener;
+use futures_util::stream::{self, BoxStream};
+use futures_util::StreamExt;
+
+use crate::capnp::Listen;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How often resolving a listen address is tried before giving up on it
+pub struct Retry {
+    pub attempts: u32,
+    /// Delay before the second attempt, doubled for every further one
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(8),
+        }
+    }
+}
+
+/// Call `resolve` until it succeeds or `retry.attempts` are used up, returning the last error
+pub async fn with_retry<F, Fut>(
+    listen: &Listen,
+    retry: Retry,
+    mut resolve: F,
+) -> io::Result<Vec<SocketAddr>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<Vec<SocketAddr>>>,
+{
+    let mut delay = retry.initial;
+    let mut attempt = 1;
+    loop {
+        match resolve().await {
+            Ok(addrs) => return Ok(addrs),
+            Err(error) if attempt < retry.attempts => {
+                tracing::warn!(%listen, attempt, %error, retry_in = ?delay,
+                    "failed to resolve listen address");
+                Timer::after(delay).await;
+                delay = (delay * 2).min(retry.max);
+                attempt += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+pub async fn resolve(listen: &Listen, retry: Retry) -> io::Result<Vec<SocketAddr>> {
+    with_retry(listen, retry, || async_net::resolve(listen.to_tuple())).await
+}
+
+/// Listens given by name instead of IP address, whose addresses may change over time
+pub fn is_hostname(listen: &Listen) -> bool {
+    listen.address.parse::<IpAddr>().is_err()
+}
+
+/// Stream of sockets for addresses newly resolved for `listens`, checked every `interval`
+///
+/// Addresses in `known` are already listened on and skipped. Addresses that fail to bind are
+/// tried again on the next check.
+pub fn watch(
+    listens: Vec<Listen>,
+    known: HashSet<SocketAddr>,
+    interval: Duration,
+) -> BoxStream<'static, TcpListener> {
+    stream::unfold(known, move |mut known| {
+        let listens = listens.clone();
+        async move {
+            Timer::after(interval).await;
+            let mut listeners = Vec::new();
+            for listen in listens.iter() {
+                let resolved = match async_net::resolve(listen.to_tuple()).await {
+                    Ok(resolved) => resolved,
+                    Err(error) => {
+                        tracing::warn!(%listen, %error, "failed to re-resolve listen address");
+                        continue;
+                    }
+                };
+                for addr in resolved {
+                    if known.contains(&addr) {
+                        continue;
+                    }
+                    match TcpListener::bind(addr).await {
+                        Ok(listener) => {
+                            tracing::info!("Opened listen socket on newly resolved {}", addr);
+                            known.insert(addr);
+                            listeners.push(listener);
+                        }
+                        Err(error) => {
+                            tracing::error!(
+                                "Failed to open socket on resolved {}: {}",
+                                addr,
+                                error
+                            );
+                        }
+                    }
+                }
+            }
+            Some((stream::iter(listeners), known))
+        }
+    })
+    .flatten()
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn listen() -> Listen {
+        Listen {
+            address: "api.example.org".to_string(),
+            port: None,
+        }
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        let retry = Retry {
+            attempts: 5,
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(4),
+        };
+        let addr: SocketAddr = "192.0.2.1:59661".parse().unwrap();
+        let calls = Cell::new(0);
+        let flaky = || {
+            calls.set(calls.get() + 1);
+            let result = if calls.get() < 3 {
+                Err(io::Error::new(io::ErrorKind::Other, "temporary failure"))
+            } else {
+                Ok(vec![addr])
+            };
+            async move { result }
+        };
+
+        let resolved = async_io::block_on(with_retry(&listen(), retry, flaky)).unwrap();
+        assert_eq!(resolved, [addr]);
+        assert_eq!(calls.get(), 3);
+
+        calls.set(0);
+        let broken = || {
+            calls.set(calls.get() + 1);
+            async { Err(io::Error::new(io::ErrorKind::Other, "no such host")) }
+        };
+        assert!(async_io::block_on(with_retry(&listen(), retry, broken)).is_err());
+        assert_eq!(calls.get(), 5);
+    }
+}
@@ -0,0 +1,737 @@
+//! Calls every method of the API systems with minimal parameters
+//!
+//! Catches methods that panic instead of returning an error, and keeps the list of methods that
+//! are knowingly left unimplemented in sync with the code.
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::rc::Rc;
+
+use api::authenticationsystem_capnp::response;
+use api::machine_capnp::machine;
+use api::machinesystem_capnp::machine_system;
+use api::permissionsystem_capnp::permission_system;
+use api::user_capnp::user;
+use api::usersystem_capnp::user_system;
+use capnp::capability::Promise;
+use capnp::ErrorKind;
+
+use crate::audit::Source;
+use crate::authentication::AuthenticationHandle;
+use crate::authorization::permissions::{PermRule, PermissionBuf};
+use crate::authorization::roles::{Role, Roles};
+use crate::capnp::connection::{self, BootCap};
+use crate::capnp::limits::ConnectionLimits;
+use crate::capnp::machine::{Machine, StateCallback};
+use crate::capnp::machinesystem::Machines;
+use crate::capnp::permissionsystem::Permissions;
+use crate::capnp::user::User;
+use crate::capnp::version::{ApiVersion, API_VERSION};
+use crate::resources::group::GroupRequest;
+use crate::resources::modules::fabaccess::Status;
+use crate::resources::search::ResourcesHandle;
+use crate::resources::state::db::StateDB;
+use crate::resources::state::value;
+use crate::resources::state::value::ValueKind;
+use crate::resources::Resource;
+use crate::session::{DisconnectError, EffectivePermissions, SessionHandle, SessionManager};
+use crate::shutdown::ShutdownSignal;
+use crate::testing;
+use crate::users::invites::{self, InviteDB};
+use crate::users::{db, UserRef};
+use crate::Users;
+
+/// Methods answering with an `unimplemented` error on purpose
+///
+/// Remove a method from this list when implementing it.
+const UNIMPLEMENTED: &[&str] = &[
+    "machine.admin.forceSetUser",
+    "machine.admin.getAdminPropertyList",
+    "machine.admin.removeAdminProperty",
+    "machine.admin.setAdminProperty",
+    "machine.check.check",
+    "machine.check.reject",
+    "machine.info.getReservationList",
+    "machine.inuse.sendRawData",
+    "machine.manage.forceTransfer",
+    "machine.use.reserveto",
+    "user.admin.getUserInfoExtended",
+];
+
+#[derive(Default)]
+struct Coverage {
+    unimplemented: BTreeSet<&'static str>,
+}
+
+impl Coverage {
+    /// Send the request built by `request`, failing the test if the method panics
+    fn call<T, F>(&mut self, method: &'static str, request: impl FnOnce() -> F)
+    where
+        F: Future<Output = capnp::Result<T>>,
+    {
+        let result = catch_unwind(AssertUnwindSafe(|| async_io::block_on(request())))
+            .unwrap_or_else(|_| panic!("`{}` panicked instead of returning", method));
+        match result {
+            Err(error) if error.kind == ErrorKind::Unimplemented => {
+                self.unimplemented.insert(method);
+            }
+            // Errors like missing permissions are fine, the method did return
+            Err(error) => tracing::debug!(method, %error, "method returned an error"),
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Session of a user allowed to do everything, and a machine it has full access to
+fn setup(dir: &tempfile::TempDir) -> (SessionManager, SessionHandle, Resource) {
+    let env = StateDB::open_env(dir.path().join("db")).unwrap();
+    let users = Users::new(env.clone()).unwrap();
+    let mut admin = db::User::new_with_plain_pw("capnp-admin", "secret");
+    admin.userdata.roles.push("admin".to_string());
+    users.put_user("capnp-admin", &admin).unwrap();
+
+    let perm = PermissionBuf::from_string_unchecked("test.perm".to_string());
+    let rules = [
+        "bffh.users.info",
+        "bffh.users.manage",
+        "bffh.users.admin",
+        "bffh.sessions.admin",
+    ]
+    .iter()
+    .map(|p| PermRule::Base(PermissionBuf::from_string_unchecked(p.to_string())))
+    .chain([PermRule::Base(perm.clone())])
+    .collect();
+    let roles = Roles::leak(HashMap::from([(
+        "admin".to_string(),
+        Role::new(Vec::new(), rules),
+    )]));
+    let sessions = SessionManager::new(users, roles, None, false);
+    let session = sessions
+        .try_open(&tracing::Span::none(), "capnp-admin")
+        .unwrap();
+
+    let desc = testing::machine("Coverage", &perm);
+    let resource = testing::resource(env, "coverage", desc);
+    (sessions, session, resource)
+}
+
+#[test]
+fn all_methods_return() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_sessions, session, resource) = setup(&dir);
+    let mut coverage = Coverage::default();
+
+    let machines: machine_system::info::Client = capnp_rpc::new_client(Machines::with_resources(
+        session.clone(),
+        ResourcesHandle::new([resource.clone()]),
+    ));
+    coverage.call("machinesystem.info.getMachineList", || {
+        machines.get_machine_list_request().send().promise
+    });
+    coverage.call("machinesystem.info.getMachine", || {
+        let mut request = machines.get_machine_request();
+        request.get().set_id("coverage");
+        request.send().promise
+    });
+    coverage.call("machinesystem.info.getMachineURN", || {
+        let mut request = machines.get_machine_u_r_n_request();
+        request.get().set_urn("urn:fabaccess:resource:coverage");
+        request.send().promise
+    });
+
+    let m = Machine::new(session.clone(), resource);
+    let info: machine::info::Client = capnp_rpc::new_client(m.clone());
+    coverage.call("machine.info.getPropertyList", || {
+        info.get_property_list_request().send().promise
+    });
+    coverage.call("machine.info.getReservationList", || {
+        info.get_reservation_list_request().send().promise
+    });
+    let use_: machine::use_::Client = capnp_rpc::new_client(m.clone());
+    coverage.call("machine.use.use", || use_.use_request().send().promise);
+    coverage.call("machine.use.reserve", || {
+        use_.reserve_request().send().promise
+    });
+    coverage.call("machine.use.reserveto", || {
+        use_.reserveto_request().send().promise
+    });
+    let inuse: machine::in_use::Client = capnp_rpc::new_client(m.clone());
+    coverage.call("machine.inuse.giveBack", || {
+        inuse.give_back_request().send().promise
+    });
+    coverage.call("machine.inuse.sendRawData", || {
+        inuse.send_raw_data_request().send().promise
+    });
+    let check: machine::check::Client = capnp_rpc::new_client(m.clone());
+    coverage.call("machine.check.check", || {
+        check.check_request().send().promise
+    });
+    coverage.call("machine.check.reject", || {
+        check.reject_request().send().promise
+    });
+    let manage: machine::manage::Client = capnp_rpc::new_client(m.clone());
+    coverage.call("machine.manage.getMachineInfoExtended", || {
+        manage.get_machine_info_extended_request().send().promise
+    });
+    coverage.call("machine.manage.setProperty", || {
+        let mut request = manage.set_property_request();
+        let mut property = request.get().init_property();
+        property.set_key("disabled_reason");
+        property.set_value("coverage");
+        request.send().promise
+    });
+    coverage.call("machine.manage.removeProperty", || {
+        let mut request = manage.remove_property_request();
+        request.get().init_property().set_key("disabled_reason");
+        request.send().promise
+    });
+    coverage.call("machine.manage.forceUse", || {
+        manage.force_use_request().send().promise
+    });
+    coverage.call("machine.manage.forceFree", || {
+        manage.force_free_request().send().promise
+    });
+    coverage.call("machine.manage.forceTransfer", || {
+        manage.force_transfer_request().send().promise
+    });
+    coverage.call("machine.manage.block", || {
+        manage.block_request().send().promise
+    });
+    coverage.call("machine.manage.disabled", || {
+        manage.disabled_request().send().promise
+    });
+    let admin: machine::admin::Client = capnp_rpc::new_client(m);
+    coverage.call("machine.admin.forceSetState", || {
+        admin.force_set_state_request().send().promise
+    });
+    coverage.call("machine.admin.forceSetUser", || {
+        admin.force_set_user_request().send().promise
+    });
+    coverage.call("machine.admin.getAdminPropertyList", || {
+        admin.get_admin_property_list_request().send().promise
+    });
+    coverage.call("machine.admin.setAdminProperty", || {
+        admin.set_admin_property_request().send().promise
+    });
+    coverage.call("machine.admin.removeAdminProperty", || {
+        admin.remove_admin_property_request().send().promise
+    });
+
+    let users = crate::capnp::user_system::Users::new(session.clone());
+    let info: user_system::info::Client = capnp_rpc::new_client(users.clone());
+    coverage.call("usersystem.info.getUserSelf", || {
+        info.get_user_self_request().send().promise
+    });
+    let manage: user_system::manage::Client = capnp_rpc::new_client(users.clone());
+    coverage.call("usersystem.manage.getUserList", || {
+        manage.get_user_list_request().send().promise
+    });
+    coverage.call("usersystem.manage.addUserFallible", || {
+        let mut request = manage.add_user_fallible_request();
+        request.get().set_username("capnp-added");
+        request.get().set_password("secret");
+        request.send().promise
+    });
+    coverage.call("usersystem.manage.removeUser", || {
+        let mut request = manage.remove_user_request();
+        request.get().init_user().set_username("capnp-added");
+        request.send().promise
+    });
+    let search: user_system::search::Client = capnp_rpc::new_client(users);
+    coverage.call("usersystem.search.getUserByName", || {
+        let mut request = search.get_user_by_name_request();
+        request.get().set_username("capnp-admin");
+        request.send().promise
+    });
+
+    let u = User::new(session.clone(), UserRef::new("capnp-admin".to_string()));
+    let info: user::info::Client = capnp_rpc::new_client(u.clone());
+    coverage.call("user.info.listRoles", || {
+        info.list_roles_request().send().promise
+    });
+    let manage: user::manage::Client = capnp_rpc::new_client(u.clone());
+    coverage.call("user.manage.pwd", || {
+        let mut request = manage.pwd_request();
+        request.get().set_old_pwd("secret");
+        request.get().set_new_pwd("secret");
+        request.send().promise
+    });
+    let admin: user::admin::Client = capnp_rpc::new_client(u.clone());
+    coverage.call("user.admin.getUserInfoExtended", || {
+        admin.get_user_info_extended_request().send().promise
+    });
+    coverage.call("user.admin.addRole", || {
+        let mut request = admin.add_role_request();
+        request.get().init_role().set_name("admin");
+        request.send().promise
+    });
+    coverage.call("user.admin.removeRole", || {
+        let mut request = admin.remove_role_request();
+        request.get().init_role().set_name("nonexistent");
+        request.send().promise
+    });
+    coverage.call("user.admin.pwd", || {
+        let mut request = admin.pwd_request();
+        request.get().set_new_pwd("secret");
+        request.send().promise
+    });
+    let card: user::card_d_e_s_fire_e_v2::Client = capnp_rpc::new_client(u);
+    coverage.call("user.cardDESFireEV2.getTokenList", || {
+        card.get_token_list_request().send().promise
+    });
+    coverage.call("user.cardDESFireEV2.bind", || {
+        let mut request = card.bind_request();
+        request.get().set_token(b"https://example.org/card");
+        request.get().set_auth_key(&[0; 16]);
+        request.send().promise
+    });
+    coverage.call("user.cardDESFireEV2.unbind", || {
+        let mut request = card.unbind_request();
+        request.get().set_token(b"https://example.org/card");
+        request.send().promise
+    });
+    coverage.call("user.cardDESFireEV2.genCardToken", || {
+        card.gen_card_token_request().send().promise
+    });
+    coverage.call("user.cardDESFireEV2.getMetaInfo", || {
+        card.get_meta_info_request().send().promise
+    });
+    coverage.call("user.cardDESFireEV2.getSpaceInfo", || {
+        card.get_space_info_request().send().promise
+    });
+
+    let permissions: permission_system::info::Client = capnp_rpc::new_client(
+        Permissions::with_resources(session, ResourcesHandle::new([resource])),
+    );
+    coverage.call("permissionsystem.info.getRoleList", || {
+        permissions.get_role_list_request().send().promise
+    });
+
+    let expected: BTreeSet<&str> = UNIMPLEMENTED.iter().copied().collect();
+    assert_eq!(coverage.unimplemented, expected);
+}
+
+#[test]
+fn disconnected_session_is_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let (sessions, admin, resource) = setup(&dir);
+    let mut kiosk = db::User::new_with_plain_pw("capnp-kiosk", "secret");
+    kiosk.userdata.roles.push("admin".to_string());
+    admin.users.put_user("capnp-kiosk", &kiosk).unwrap();
+    let kiosk = sessions
+        .try_open(&tracing::Span::none(), "capnp-kiosk")
+        .unwrap();
+    let resources = ResourcesHandle::new([resource.clone()]);
+
+    let other = sessions
+        .try_open(&tracing::Span::none(), "capnp-kiosk")
+        .unwrap();
+
+    let use_: machine::use_::Client =
+        capnp_rpc::new_client(Machine::new(kiosk.clone(), resource.clone()));
+    async_io::block_on(use_.use_request().send().promise).unwrap();
+    assert!(!resource.is_free());
+
+    // Only machines put in use through the disconnected session itself are freed
+    admin
+        .disconnect_session(other.id(), true, &resources)
+        .unwrap();
+    assert!(!resource.is_free());
+    admin
+        .disconnect_session(kiosk.id(), true, &resources)
+        .unwrap();
+    assert!(resource.is_free());
+    let listed = admin.connected_sessions(&resources).unwrap();
+    assert!(listed.iter().all(|session| session.uid != "capnp-kiosk"));
+
+    assert!(async_io::block_on(use_.use_request().send().promise).is_err());
+    assert_eq!(
+        kiosk.disconnect_session(admin.id(), false, &resources),
+        Err(DisconnectError::Denied)
+    );
+}
+
+#[test]
+fn effective_permissions_do_not_leak_hidden_machines() {
+    let dir = tempfile::tempdir().unwrap();
+    let (sessions, admin, resource) = setup(&dir);
+    let guest = db::User::new_with_plain_pw("capnp-guest", "secret");
+    admin.users.put_user("capnp-guest", &guest).unwrap();
+    let guest = sessions
+        .try_open(&tracing::Span::none(), "capnp-guest")
+        .unwrap();
+    let resources = ResourcesHandle::new([resource]);
+
+    let permissions = Permissions::with_resources(admin, resources.clone());
+    let all = EffectivePermissions {
+        disclose: true,
+        read: true,
+        write: true,
+        manage: true,
+    };
+    assert_eq!(permissions.effective_permissions("coverage").unwrap(), all);
+    assert!(permissions.effective_permissions("unknown").is_err());
+
+    let permissions = Permissions::with_resources(guest, resources);
+    // Answered exactly like a machine that doesn't exist
+    let hidden = permissions.effective_permissions("coverage").unwrap_err();
+    assert_eq!(hidden.kind, ErrorKind::Failed);
+    assert_eq!(hidden.description, "no machine with id `coverage`");
+}
+
+/// Records the states pushed to it
+#[derive(Clone, Default)]
+struct Pushed(Rc<RefCell<Vec<machine::MachineState>>>);
+
+impl StateCallback for Pushed {
+    fn push(&self, state: machine::MachineState) -> Promise<(), capnp::Error> {
+        self.0.borrow_mut().push(state);
+        Promise::ok(())
+    }
+}
+
+/// Callback of a client that went away
+struct Gone;
+
+impl StateCallback for Gone {
+    fn push(&self, _: machine::MachineState) -> Promise<(), capnp::Error> {
+        Promise::err(capnp::Error::disconnected("client went away".to_string()))
+    }
+}
+
+#[test]
+fn subscription_pushes_coalesced_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_, admin, resource) = setup(&dir);
+    let pushed = Pushed::default();
+    let machine = Machine::new(admin.clone(), resource.clone());
+    let mut subscription = Box::pin(machine.subscribe(pushed.clone()));
+    drop(machine);
+    let mut poll = || async_io::block_on(futures_lite::future::poll_once(&mut subscription));
+
+    assert_eq!(poll(), None);
+    assert_eq!(*pushed.0.borrow(), vec![machine::MachineState::Free]);
+
+    // A burst of changes reaches the client as the latest state only
+    let user = admin.get_user_ref();
+    for status in [
+        Status::InUse(user.clone()),
+        Status::Free,
+        Status::Blocked(user),
+    ] {
+        resource.set_status(status, Source::Admin).unwrap();
+    }
+    assert_eq!(poll(), None);
+    assert_eq!(
+        *pushed.0.borrow(),
+        vec![machine::MachineState::Free, machine::MachineState::Blocked]
+    );
+
+    drop(resource);
+    assert_eq!(poll(), Some(()));
+}
+
+#[test]
+fn subscription_ends_when_client_goes_away() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_, admin, resource) = setup(&dir);
+    let subscription = Machine::new(admin, resource.clone()).subscribe(Gone);
+    async_io::block_on(subscription);
+}
+
+#[test]
+fn catalog_generation_changes_with_machines() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_sessions, session, resource) = setup(&dir);
+    let resources = ResourcesHandle::new([resource.clone()]);
+    let machines = Machines::with_resources(session, resources.clone());
+
+    let catalog = machines.catalog();
+    assert_eq!(catalog.machines.len(), 1);
+    assert_eq!(catalog.machines[0].id, "coverage");
+    assert_eq!(catalog.machines[0].name, "Coverage");
+
+    // Reloading an unchanged config or changing states keeps the token
+    let other = tempfile::tempdir().unwrap();
+    let statedb = StateDB::create_with_env(StateDB::open_env(other.path()).unwrap()).unwrap();
+    let desc = (*resource.get_description()).clone();
+    let mut config = HashMap::from([("coverage".to_string(), desc.clone())]);
+    resources.reload(&config, &statedb);
+    resource
+        .set_status(Status::Disabled, Source::System)
+        .unwrap();
+    assert_eq!(machines.catalog(), catalog);
+
+    config.insert("added".to_string(), desc);
+    resources.reload(&config, &statedb);
+    let changed = machines.catalog();
+    assert_ne!(changed.generation, catalog.generation);
+    let ids: Vec<&str> = changed.machines.iter().map(|m| m.id.as_str()).collect();
+    assert_eq!(ids, ["added", "coverage"]);
+    assert_eq!(resources.generation(), changed.generation);
+}
+
+#[test]
+fn group_requests_assign_free_members_and_queue() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_sessions, session, resource) = setup(&dir);
+    let (id, members) = ("drills".to_string(), vec!["coverage".to_string()]);
+    let resources = ResourcesHandle::new([resource]).with_groups([(&id, &members, true)]);
+    let machines = Machines::with_resources(session, resources);
+
+    let assigned = async_io::block_on(machines.request_group("drills")).unwrap();
+    assert!(matches!(assigned, GroupRequest::Assigned(member) if member.get_id() == "coverage"));
+    let queued = async_io::block_on(machines.request_group("drills")).unwrap();
+    assert!(matches!(queued, GroupRequest::Queued(1)));
+    assert!(async_io::block_on(machines.request_group("saws")).is_err());
+}
+
+#[test]
+fn user_management_counts_against_the_call_limit() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_sessions, admin, _resource) = setup(&dir);
+    let limited = SessionManager::new(admin.users, admin.roles.clone(), Some(1), false);
+    let session = limited
+        .try_open(&tracing::Span::none(), "capnp-admin")
+        .unwrap();
+    let manage: user_system::manage::Client =
+        capnp_rpc::new_client(crate::capnp::user_system::Users::new(session.clone()));
+    let add = || {
+        let mut request = manage.add_user_fallible_request();
+        request.get().set_username("capnp-limited");
+        request.get().set_password("secret");
+        async_io::block_on(request.send().promise).map(drop)
+    };
+
+    let busy = session.start_call().unwrap();
+    let refused = add().unwrap_err();
+    assert_eq!(refused.kind, ErrorKind::Overloaded);
+    assert!(admin.users.get_user("capnp-limited").is_none());
+
+    drop(busy);
+    add().unwrap();
+    assert!(admin.users.get_user("capnp-limited").is_some());
+}
+
+#[test]
+fn admins_suspend_users() {
+    let dir = tempfile::tempdir().unwrap();
+    let (sessions, admin, _resource) = setup(&dir);
+    let guest = db::User::new_with_plain_pw("capnp-guest", "secret");
+    admin.users.put_user("capnp-guest", &guest).unwrap();
+    let guest_session = sessions
+        .try_open(&tracing::Span::none(), "capnp-guest")
+        .unwrap();
+    let guest_ref = UserRef::new("capnp-guest".to_string());
+
+    let by_admin = User::new(admin.clone(), guest_ref.clone());
+    by_admin.set_suspension(Some("unpaid fees")).unwrap();
+    assert_eq!(
+        by_admin.suspension().unwrap().as_deref(),
+        Some("unpaid fees")
+    );
+    let stored = admin.users.get_user("capnp-guest").unwrap();
+    assert!(stored.userdata.is_suspended());
+
+    // Users see their own suspension but can't lift it
+    let by_self = User::new(guest_session, guest_ref);
+    assert_eq!(
+        by_self.suspension().unwrap().as_deref(),
+        Some("unpaid fees")
+    );
+    assert!(by_self.set_suspension(None).is_err());
+
+    by_admin.set_suspension(None).unwrap();
+    assert_eq!(by_admin.suspension().unwrap(), None);
+    let missing = User::new(admin, UserRef::new("capnp-nobody".to_string()));
+    assert!(missing.set_suspension(Some("unknown")).is_err());
+}
+
+#[test]
+fn invite_tokens_register_and_log_in() {
+    let dir = tempfile::tempdir().unwrap();
+    let env = StateDB::open_env(dir.path().join("db")).unwrap();
+    let users = Users::new(env.clone()).unwrap();
+    let invites = unsafe { InviteDB::create(env) }.unwrap();
+    let token = invites.issue(Some("member".to_string()), 60).unwrap();
+    let sessions = SessionManager::new(users, Roles::leak(HashMap::new()), None, false);
+    let authentication = AuthenticationHandle::new(users).with_invites(Some(invites));
+    let limits = ConnectionLimits::new(None, None);
+    let bootstrap: connection::Client = capnp_rpc::new_client(BootCap::new(
+        "127.0.0.1:59661".parse().unwrap(),
+        authentication,
+        sessions,
+        limits.try_connect().unwrap(),
+        ShutdownSignal::new(),
+        tracing::Span::none(),
+    ));
+
+    let mechanisms = async_io::block_on(bootstrap.mechanisms_request().send().promise).unwrap();
+    let mechanisms = mechanisms.get().unwrap().get_mechs().unwrap();
+    assert!(mechanisms.iter().any(|m| m.unwrap() == invites::MECHANISM));
+
+    let register = |data: String| {
+        let mut request = bootstrap.create_session_request();
+        request.get().set_mechanism(invites::MECHANISM);
+        let created = async_io::block_on(request.send().promise).unwrap();
+        let authentication = created.get().unwrap().get_authentication().unwrap();
+        let mut request = authentication.step_request();
+        request.get().set_data(data.as_bytes());
+        let reply = async_io::block_on(request.send().promise).unwrap();
+        let reply = reply.get().unwrap();
+        matches!(reply.which().unwrap(), response::Which::Successful(_))
+    };
+
+    assert!(!register(format!("{}\0newbie", token)));
+    assert!(register(format!("{}\0newbie\0secret", token)));
+    let user = users.get_user("newbie").unwrap();
+    assert_eq!(user.userdata.roles, vec!["member".to_string()]);
+    // Tokens can only be used once
+    assert!(!register(format!("{}\0other\0secret", token)));
+    assert!(users.get_user("other").is_none());
+}
+
+#[test]
+fn incompatible_client_is_refused_at_bootstrap() {
+    let dir = tempfile::tempdir().unwrap();
+    let (sessions, admin, _resource) = setup(&dir);
+    let limits = ConnectionLimits::new(None, None);
+    let mut boot = BootCap::new(
+        "127.0.0.1:59661".parse().unwrap(),
+        AuthenticationHandle::new(admin.users),
+        sessions,
+        limits.try_connect().unwrap(),
+        ShutdownSignal::new(),
+        tracing::Span::none(),
+    );
+    assert!(boot.negotiate(ApiVersion::new(1, 0)).is_err());
+    let bootstrap: connection::Client = capnp_rpc::new_client(boot);
+
+    let version = async_io::block_on(bootstrap.get_a_p_i_version_request().send().promise).unwrap();
+    let version = version.get().unwrap();
+    assert_eq!(
+        ApiVersion::new(version.get_major() as u32, version.get_minor() as u32),
+        API_VERSION
+    );
+
+    let mut request = bootstrap.create_session_request();
+    request.get().set_mechanism("PLAIN");
+    let refused = async_io::block_on(request.send().promise).err().unwrap();
+    assert!(refused
+        .description
+        .contains("client API version 1.0 is not supported"));
+}
+
+#[test]
+fn machine_system_lists_value_types() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_sessions, session, resource) = setup(&dir);
+    let machines = Machines::with_resources(session, ResourcesHandle::new([resource]));
+
+    let types = machines.value_types();
+    let vec3 = types
+        .iter()
+        .find(|t| t.oid == "1.3.6.1.4.1.48398.612.1.13")
+        .unwrap();
+    assert_eq!(vec3.kind, ValueKind::Vec3u8);
+    assert_eq!(types, value::value_types());
+}
+
+#[test]
+fn info_passes_icon_and_metadata_through() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_sessions, session, _resource) = setup(&dir);
+    let perm = PermissionBuf::from_string_unchecked("test.perm".to_string());
+    let mut desc = testing::machine("Lasercutter", &perm);
+    desc.icon = Some("https://example.org/icons/lasercutter.svg".to_string());
+    desc.metadata = HashMap::from([
+        ("colour".to_string(), "#ff8000".to_string()),
+        (
+            "position".to_string(),
+            "Room 2, left of the door".to_string(),
+        ),
+    ]);
+    let other = tempfile::tempdir().unwrap();
+    let env = StateDB::open_env(other.path().join("db")).unwrap();
+    let resource = testing::resource(env, "lasercutter", desc);
+    let info: machine::info::Client = capnp_rpc::new_client(Machine::new(session, resource));
+
+    let reply = async_io::block_on(info.get_property_list_request().send().promise).unwrap();
+    let mut properties: Vec<(String, String)> = reply
+        .get()
+        .unwrap()
+        .get_property_list()
+        .unwrap()
+        .iter()
+        .map(|kv| {
+            let (key, value) = (kv.get_key().unwrap(), kv.get_value().unwrap());
+            (key.to_string(), value.to_string())
+        })
+        .collect();
+    properties.sort();
+    assert_eq!(
+        properties,
+        [
+            ("colour".to_string(), "#ff8000".to_string()),
+            (
+                "icon".to_string(),
+                "https://example.org/icons/lasercutter.svg".to_string()
+            ),
+            (
+                "position".to_string(),
+                "Room 2, left of the door".to_string()
+            ),
+            ("usage_count".to_string(), "0".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn usage_count_is_shown_and_reset_through_properties() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_sessions, session, resource) = setup(&dir);
+    let m = Machine::new(session, resource);
+    let info: machine::info::Client = capnp_rpc::new_client(m.clone());
+    let use_: machine::use_::Client = capnp_rpc::new_client(m.clone());
+    let inuse: machine::in_use::Client = capnp_rpc::new_client(m.clone());
+    let manage: machine::manage::Client = capnp_rpc::new_client(m);
+
+    let usage_count = || {
+        let reply = async_io::block_on(info.get_property_list_request().send().promise).unwrap();
+        for kv in reply.get().unwrap().get_property_list().unwrap().iter() {
+            if kv.get_key().unwrap() == "usage_count" {
+                return Some(kv.get_value().unwrap().to_string());
+            }
+        }
+        None
+    };
+    let set_property = |value: &str| {
+        let mut request = manage.set_property_request();
+        let mut property = request.get().init_property();
+        property.set_key("usage_count");
+        property.set_value(value);
+        async_io::block_on(request.send().promise).map(|_| ())
+    };
+
+    assert_eq!(usage_count().as_deref(), Some("0"));
+    for _ in 0..2 {
+        async_io::block_on(use_.use_request().send().promise).unwrap();
+        async_io::block_on(inuse.give_back_request().send().promise).unwrap();
+    }
+    assert_eq!(usage_count().as_deref(), Some("2"));
+
+    // The counter can only be reset, not set to arbitrary values
+    assert!(set_property("5").is_err());
+    assert_eq!(usage_count().as_deref(), Some("2"));
+    set_property("0").unwrap();
+    assert_eq!(usage_count().as_deref(), Some("0"));
+
+    async_io::block_on(use_.use_request().send().promise).unwrap();
+    async_io::block_on(inuse.give_back_request().send().promise).unwrap();
+    let mut request = manage.remove_property_request();
+    request.get().init_property().set_key("usage_count");
+    async_io::block_on(request.send().promise).unwrap();
+    assert_eq!(usage_count().as_deref(), Some("0"));
+}
@@ -76,6 +76,43 @@ impl User {
             builder.set_card_d_e_s_fire_e_v2(capnp_rpc::new_client(client));
         }
     }
+
+    /// Reason the user is suspended for, if they are
+    ///
+    /// Not part of the API schema yet, clients can't call this over RPC. Visible to the user
+    /// themselves and to holders of `bffh.users.info`.
+    pub fn suspension(&self) -> Result<Option<String>, Error> {
+        let is_me = self.session.get_user_ref().id == self.user.id;
+        if !is_me && !self.session.has_perm(Permission::new("bffh.users.info")) {
+            return Err(Error::failed(
+                "missing permission to view the user".to_string(),
+            ));
+        }
+        let user = self.session.users.get_user(self.user.get_username());
+        Ok(user.and_then(|user| user.userdata.suspension_reason().map(str::to_string)))
+    }
+
+    /// Suspend the user giving `reason`, or lift their suspension if it's `None`
+    ///
+    /// Not part of the API schema yet, clients can't call this over RPC. Needs `bffh.users.admin`
+    /// like the other admin calls.
+    pub fn set_suspension(&self, reason: Option<&str>) -> Result<(), Error> {
+        if !self.session.has_perm(Permission::new("bffh.users.admin")) {
+            return Err(Error::failed(
+                "missing permission to suspend users".to_string(),
+            ));
+        }
+        let _call = self.session.start_call()?;
+        let uid = self.user.get_username();
+        let updated = self.session.users.update_user(uid, |user| {
+            match reason {
+                Some(reason) => user.userdata.suspend(reason),
+                None => user.userdata.unsuspend(),
+            }
+            Ok::<_, Error>(())
+        })?;
+        updated.ok_or_else(|| Error::failed(format!("no such user {}", uid)))
+    }
 }
 
 impl info::Server for User {
@@ -101,6 +138,7 @@ impl manage::Server for User {
         params: manage::PwdParams,
         _results: manage::PwdResults,
     ) -> Promise<(), ::capnp::Error> {
+        let _call = pry!(self.session.start_call());
         let params = pry!(params.get());
         let old_pw = pry!(params.get_old_pwd());
         let new_pw = pry!(params.get_new_pwd());
@@ -131,6 +169,7 @@ impl admin::Server for User {
         param: admin::AddRoleParams,
         _: admin::AddRoleResults,
     ) -> Promise<(), ::capnp::Error> {
+        let _call = pry!(self.session.start_call());
         let rolename = pry!(pry!(pry!(param.get()).get_role()).get_name());
 
         if let Some(_role) = self.session.roles.get(rolename) {
@@ -156,6 +195,7 @@ impl admin::Server for User {
         param: admin::RemoveRoleParams,
         _: admin::RemoveRoleResults,
     ) -> Promise<(), ::capnp::Error> {
+        let _call = pry!(self.session.start_call());
         let rolename = pry!(pry!(pry!(param.get()).get_role()).get_name());
 
         if let Some(_role) = self.session.roles.get(rolename) {
@@ -181,6 +221,7 @@ impl admin::Server for User {
         param: admin::PwdParams,
         _: admin::PwdResults,
     ) -> Promise<(), ::capnp::Error> {
+        let _call = pry!(self.session.start_call());
         let new_pw = pry!(pry!(param.get()).get_new_pwd());
         let uid = self.user.get_username();
         if let Some(mut user) = self.session.users.get_user(uid) {
@@ -231,6 +272,7 @@ impl card_d_e_s_fire_e_v2::Server for User {
     fn bind(&mut self, params: BindParams, _: BindResults) -> Promise<(), Error> {
         let _guard = self.span.enter();
         let _span = tracing::trace_span!(target: TARGET, "bind").entered();
+        let _call = pry!(self.session.start_call());
         let params = pry!(params.get());
         let card_key = pry!(params.get_auth_key());
         let token = pry!(params.get_token());
@@ -307,7 +349,7 @@ impl card_d_e_s_fire_e_v2::Server for User {
     fn unbind(&mut self, params: UnbindParams, _: UnbindResults) -> Promise<(), Error> {
         let _guard = self.span.enter();
         let _span = tracing::trace_span!(target: TARGET, "unbind").entered();
-
+        let _call = pry!(self.session.start_call());
         let params = pry!(params.get());
         let token = pry!(params.get_token());
 
@@ -6,7 +6,7 @@ use tracing::Span;
 use crate::capnp::user::User;
 
 use crate::session::SessionHandle;
-use crate::users::{db, UserRef};
+use crate::users::{db, Error as UsersError, UserRef};
 
 const TARGET: &str = "bffh::api::usersystem";
 
@@ -72,6 +72,7 @@ impl manage::Server for Users {
     ) -> Promise<(), ::capnp::Error> {
         let _guard = self.span.enter();
         let _span = tracing::trace_span!(target: TARGET, "addUserFallible").entered();
+        let _call = pry!(self.session.start_call());
 
         let params = pry!(params.get());
         let username = pry!(params.get_username());
@@ -86,7 +87,11 @@ impl manage::Server for Users {
 
         let builder = result.get();
 
-        if !username.is_empty() && !password.is_empty() {
+        if let Err(error) = self.session.users.check_username(username) {
+            let mut builder = builder.init_failed();
+            builder.set_error(manage::add_user_error::AddUserError::UsernameInvalid);
+            tracing::warn!(%error, "Failed to add user: Username invalid");
+        } else if !password.is_empty() {
             if self.session.users.get_user(username).is_none() {
                 let user = db::User::new_with_plain_pw(username, password);
                 pry!(self.session.users.put_user(username, &user));
@@ -98,15 +103,9 @@ impl manage::Server for Users {
                 tracing::warn!("Failed to add user: Username taken");
             }
         } else {
-            if username.is_empty() {
-                let mut builder = builder.init_failed();
-                builder.set_error(manage::add_user_error::AddUserError::UsernameInvalid);
-                tracing::warn!("Failed to add user: Username empty");
-            } else if password.is_empty() {
-                let mut builder = builder.init_failed();
-                builder.set_error(manage::add_user_error::AddUserError::PasswordInvalid);
-                tracing::warn!("Failed to add user: Password empty");
-            }
+            let mut builder = builder.init_failed();
+            builder.set_error(manage::add_user_error::AddUserError::PasswordInvalid);
+            tracing::warn!("Failed to add user: Password empty");
         }
 
         tracing::trace!("method return");
@@ -120,15 +119,16 @@ impl manage::Server for Users {
     ) -> Promise<(), ::capnp::Error> {
         let _guard = self.span.enter();
         let _span = tracing::trace_span!(target: TARGET, "removeUser",).entered();
+        let _call = pry!(self.session.start_call());
 
         let who: &str = pry!(pry!(pry!(params.get()).get_user()).get_username());
 
         tracing::trace!(params.user = who, "method call");
 
-        if let Err(e) = self.session.users.del_user(who) {
-            tracing::warn!("Failed to delete user: {:?}", e);
-        } else {
-            tracing::info!("Deleted user {}", who);
+        match self.session.users.del_user(who) {
+            Ok(()) => tracing::info!("Deleted user {}", who),
+            Err(UsersError::Maintenance(e)) => return Promise::err(e.into()),
+            Err(e) => tracing::warn!("Failed to delete user: {:?}", e),
         }
 
         tracing::trace!("method return");
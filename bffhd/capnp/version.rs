@@ -0,0 +1,79 @@
+//! Version of the FabAccess API spoken by this server
+
+use std::fmt;
+
+use miette::Diagnostic;
+use thiserror::Error;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ApiVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl ApiVersion {
+    pub const fn new(major: u32, minor: u32) -> Self {
+        Self { major, minor }
+    }
+}
+
+impl fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Version of the API implemented by this server
+pub const API_VERSION: ApiVersion = ApiVersion::new(0, 3);
+
+/// Oldest client version this server still talks to
+pub const MIN_SUPPORTED: ApiVersion = ApiVersion::new(0, 3);
+
+#[derive(Debug, Clone, Error, Diagnostic, PartialEq, Eq)]
+#[error("client API version {client} is not supported, this server supports {min} to {max}")]
+#[diagnostic(
+    code(bffh::api::version_mismatch),
+    help("Update the client or server so their API versions overlap")
+)]
+pub struct VersionMismatch {
+    pub client: ApiVersion,
+    pub min: ApiVersion,
+    pub max: ApiVersion,
+}
+
+/// Decide which API version to use with a client speaking `client`
+///
+/// Minor versions only add to the API, so clients newer than the server are talked to using the
+/// server's version as long as the major version matches.
+pub fn negotiate(client: ApiVersion) -> Result<ApiVersion, VersionMismatch> {
+    if client.major == API_VERSION.major && client >= MIN_SUPPORTED {
+        Ok(client.min(API_VERSION))
+    } else {
+        Err(VersionMismatch {
+            client,
+            min: MIN_SUPPORTED,
+            max: API_VERSION,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incompatible_clients_are_rejected() {
+        assert_eq!(negotiate(API_VERSION), Ok(API_VERSION));
+        let newer = ApiVersion::new(API_VERSION.major, API_VERSION.minor + 1);
+        assert_eq!(negotiate(newer), Ok(API_VERSION));
+
+        let next_major = ApiVersion::new(API_VERSION.major + 1, 0);
+        let error = negotiate(next_major).unwrap_err();
+        assert_eq!(error.client, next_major);
+        assert_eq!(
+            error.to_string(),
+            "client API version 1.0 is not supported, this server supports 0.3 to 0.3"
+        );
+        assert!(negotiate(ApiVersion::new(0, 2)).is_err());
+    }
+}
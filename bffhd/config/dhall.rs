@@ -1,14 +1,22 @@
 use std::collections::HashMap;
 use std::default::Default;
 use std::fmt::Debug;
+use std::net::IpAddr;
 use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
-use crate::authorization::permissions::PrivilegesBuf;
+use crate::authorization::permissions::{PermissionBuf, PrivilegesBuf};
 use crate::authorization::roles::Role;
-use crate::capnp::{Listen, TlsListen};
+use crate::capnp::{ConnectionRate, Keepalive, Listen, TlsListen};
+use crate::config::{RedactedUrl, Secret};
 use crate::logging::LogConfig;
+use crate::process::Umask;
+use crate::resources::state::{StateLimits, UnknownOidPolicy};
+use crate::session::ResumptionPolicy;
+use crate::users::cache::CacheCapacity;
+use crate::users::validation::{PasswordPolicy, UsernamePolicy};
+use crate::utils::truncate::DEFAULT_MAX_PAYLOAD_LOG;
 
 use std::path::Path;
 
@@ -53,33 +61,224 @@ pub struct MachineDescription {
     )]
     pub category: Option<String>,
 
+    /// Icon clients should show for the machine, e.g. a name from their icon set or a URL
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub icon: Option<String>,
+
+    /// Presentation data passed to clients as properties without being interpreted by bffh
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+
+    /// Permission held by supervisors of this machine
+    ///
+    /// If set, the machine can only be started while a user with this permission has an active
+    /// session.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub supervisor: Option<PermissionBuf>,
+
+    /// Marking the machine as to be checked needs a note explaining what to check
+    #[serde(default)]
+    pub require_check_note: bool,
+
+    /// Returning the machine marks it as to be checked instead of freeing it. Only users with the
+    /// manage privilege can free it from there.
+    #[serde(default)]
+    pub check_on_return: bool,
+
+    /// Tell users watching the machine when it becomes free
+    #[serde(default)]
+    pub announce_on_free: bool,
+
+    /// Milliseconds a state has to stay unchanged before it is applied, collapsing quick flaps
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub debounce_ms: Option<u64>,
+
+    /// Key in the user's data recording the training needed to start the machine
+    ///
+    /// See [`UserData::has_training`](crate::users::db::UserData::has_training) for the format.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub required_training: Option<String>,
+
+    /// Message shown instead of the generic one when starting the machine is refused for
+    /// missing permissions, by language tag, e.g. pointing to the safety course to take
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub denied_message: HashMap<String, String>,
+
     /// The permission required
     #[serde(flatten)]
     pub privs: PrivilegesBuf,
 }
 
+impl MachineDescription {
+    /// The configured denied message in `lang`, falling back to English and then any language
+    pub fn denied_message(&self, lang: Option<&str>) -> Option<&str> {
+        lang.and_then(|lang| self.denied_message.get(lang))
+            .or_else(|| self.denied_message.get("en"))
+            .or_else(|| {
+                self.denied_message
+                    .iter()
+                    .min_by_key(|(lang, _)| lang.as_str())
+                    .map(|(_, message)| message)
+            })
+            .map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// A group of interchangeable machines that can be requested as a whole
+pub struct GroupDescription {
+    /// Ids of the machines in this group
+    pub members: Vec<String>,
+
+    /// Queue users requesting the group while all members are in use instead of denying them
+    #[serde(default)]
+    pub queue: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// Machine whose state a sensor reports
+pub struct SensorConnection {
+    pub machine: String,
+    pub sensor: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// An additional destination audit log entries are written to
+pub struct AuditSinkConfig {
+    /// One of `file`, `syslog` or `http`
+    pub kind: String,
+    /// Path of the file, `host:port` of the syslog server or URL of the HTTP collector
+    pub target: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// Shape of the entries written for state changes
+pub enum AuditFormat {
+    /// The new state as the text shown to users, e.g. `inuse Testuser`
+    #[default]
+    Simple,
+    /// Status, user and previous user as separate fields, for log ingestion
+    Structured,
+}
+
+impl AuditFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditFormat::Simple => "simple",
+            AuditFormat::Structured => "structured",
+        }
+    }
+}
+
+impl Serialize for AuditFormat {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for AuditFormat {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        match s.as_str() {
+            "simple" => Ok(AuditFormat::Simple),
+            "structured" => Ok(AuditFormat::Structured),
+            _ => Err(serde::de::Error::unknown_variant(
+                &s,
+                &["simple", "structured"],
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     /// A list of address/port pairs to listen on.
     pub listens: Vec<Listen>,
 
+    /// Seconds between resolving listens given by hostname again, listening on any new address.
+    /// Addresses are only resolved at startup if not set, 0 is refused.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub listen_reresolve_interval: Option<u64>,
+
+    /// Seconds open API connections get to finish on shutdown before they are closed. Defaults
+    /// to 10.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub drain_timeout: Option<u64>,
+
     /// Machine descriptions to load
     pub machines: HashMap<String, MachineDescription>,
 
+    /// Groups of interchangeable machines
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub groups: HashMap<String, GroupDescription>,
+
     /// Actors to load and their configuration options
     pub actors: HashMap<String, ModuleConfig>,
 
     /// Initiators to load and their configuration options
     pub initiators: HashMap<String, ModuleConfig>,
 
-    pub mqtt_url: String,
+    /// Sensors to load and their configuration options
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub sensors: HashMap<String, ModuleConfig>,
+
+    /// Passwords in the URL are hidden in log output, but better use `mqtt_password`
+    pub mqtt_url: RedactedUrl,
+
+    /// Password for the MQTT broker, overriding the one given in `mqtt_url`
+    ///
+    /// Can be read from a file or environment variable using `file:<path>` or `env:<name>`.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_some"
+    )]
+    pub mqtt_password: Option<Secret>,
 
     pub actor_connections: Vec<(String, String)>,
     pub init_connections: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sensor_connections: Vec<SensorConnection>,
 
     pub db_path: PathBuf,
+    /// Create the directory `db_path` is in on startup if it doesn't exist
+    #[serde(default = "default_create_db_dir")]
+    pub create_db_dir: bool,
     pub auditlog_path: PathBuf,
 
+    /// Destinations audit log entries are written to in addition to `auditlog_path`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audit_sinks: Vec<AuditSinkConfig>,
+    #[serde(default)]
+    pub audit_format: AuditFormat,
+
     pub roles: HashMap<String, Role>,
 
     #[serde(flatten)]
@@ -88,6 +287,85 @@ pub struct Config {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub tlskeylog: Option<PathBuf>,
 
+    /// TCP keepalive for API connections. Disabled if not set.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub keepalive: Option<Keepalive>,
+
+    /// Proxies allowed to pass on the address of the client using the PROXY protocol (v2)
+    ///
+    /// Connections from these addresses must start with a PROXY header.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_proxies: Vec<IpAddr>,
+
+    /// Maximum number of API connections that have not authenticated yet. Defaults to 64.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub max_anonymous_connections: Option<usize>,
+
+    /// Seconds a new API connection has to authenticate in before it is closed. Defaults to 30.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub auth_timeout: Option<u64>,
+
+    /// Maximum number of authenticated API connections. Unlimited if not set.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub max_authenticated_connections: Option<usize>,
+
+    /// Rate at which a single IP address may open API connections. Unlimited if not set.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub connection_rate: Option<ConnectionRate>,
+
+    /// Maximum number of calls a single API session may have outstanding. Unlimited if not set.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub max_inflight_calls: Option<usize>,
+
+    /// Run in maintenance mode, refusing any API call that would change machines or users
+    #[serde(default)]
+    pub read_only: bool,
+
+    /// Check stored machine states when loading them. A corrupt state is moved to the
+    /// `quarantine` db and the machine reset instead of crashing on every start.
+    #[serde(default)]
+    pub strict_state: bool,
+
+    /// How to handle state values of types this build doesn't know when loading a dump
+    #[serde(default)]
+    pub unknown_oids: UnknownOidPolicy,
+
+    /// Upper bounds for the state of a single machine. Changes exceeding them are refused.
+    #[serde(default)]
+    pub state_limits: StateLimits,
+
+    /// Number of users kept in memory so permission checks don't read the database every time
+    #[serde(default)]
+    pub user_cache_size: CacheCapacity,
+
+    /// Characters of payloads received from clients and cards logged before cutting them off
+    #[serde(default = "default_max_payload_log")]
+    pub max_payload_log: usize,
+
     #[serde(default, skip)]
     pub verbosity: isize,
 
@@ -97,6 +375,64 @@ pub struct Config {
     pub spacename: String,
 
     pub instanceurl: String,
+
+    /// Rules for names of newly created users
+    #[serde(default)]
+    pub usernames: UsernamePolicy,
+
+    /// Rules for plain text passwords of users loaded from a file
+    #[serde(default)]
+    pub passwords: PasswordPolicy,
+
+    /// Hide the addresses of connected sessions from admins without `bffh.sessions.superadmin`
+    #[serde(default)]
+    pub redact_session_peers: bool,
+
+    /// Lifetime and per-user limit of session resumption tokens
+    #[serde(default)]
+    pub resumption: ResumptionPolicy,
+
+    /// Allow users to register themselves using admin-issued invite tokens
+    ///
+    /// Clients register by creating a session with the `X-FABACCESS-INVITE` mechanism.
+    #[serde(default)]
+    pub self_registration: bool,
+
+    /// Directory to change into at startup. Relative paths in the config are resolved against it.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub working_directory: Option<PathBuf>,
+
+    /// umask to set at startup, as an octal string like `"027"`
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub umask: Option<Umask>,
+
+    /// Start even when running as root
+    #[serde(default)]
+    pub allow_root: bool,
+
+    /// User to switch to after the listening sockets were opened, e.g. to bind port 443 as root
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub user: Option<String>,
+
+    /// Group to switch to together with `user`. Defaults to the primary group of `user`.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub group: Option<String>,
 }
 
 impl Config {
@@ -111,6 +447,14 @@ pub struct ModuleConfig {
     pub params: HashMap<String, String>,
 }
 
+fn default_create_db_dir() -> bool {
+    true
+}
+
+fn default_max_payload_log() -> usize {
+    DEFAULT_MAX_PAYLOAD_LOG
+}
+
 pub(crate) fn deser_option<'de, D, T>(d: D) -> std::result::Result<Option<T>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -119,6 +463,15 @@ where
     Ok(T::deserialize(d).ok())
 }
 
+/// Like [`deser_option`], but reporting invalid values instead of ignoring them
+fn deser_some<'de, D, T>(d: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::Deserialize<'de>,
+{
+    T::deserialize(d).map(Some)
+}
+
 impl Default for Config {
     fn default() -> Self {
         let mut actors: HashMap<String, ModuleConfig> = HashMap::new();
@@ -145,15 +498,24 @@ impl Default for Config {
                 address: "127.0.0.1".to_string(),
                 port: None,
             }],
+            listen_reresolve_interval: None,
+            drain_timeout: None,
             actors,
             initiators,
+            sensors: HashMap::new(),
             machines,
-            mqtt_url: "tcp://localhost:1883".to_string(),
-            actor_connections: vec![("Testmachine".to_string(), "Actor".to_string())],
+            groups: HashMap::new(),
+            mqtt_url: "tcp://localhost:1883".into(),
+            mqtt_password: None,
+            actor_connections: vec![("Actor".to_string(), "Testmachine".to_string())],
             init_connections: vec![("Initiator".to_string(), "Testmachine".to_string())],
+            sensor_connections: Vec::new(),
 
             db_path: PathBuf::from("/run/bffh/database"),
+            create_db_dir: true,
             auditlog_path: PathBuf::from("/var/log/bffh/audit.log"),
+            audit_sinks: Vec::new(),
+            audit_format: AuditFormat::default(),
             roles: HashMap::new(),
 
             tlsconfig: TlsListen {
@@ -163,10 +525,33 @@ impl Default for Config {
             },
 
             tlskeylog: None,
+            keepalive: None,
+            trusted_proxies: Vec::new(),
+            max_anonymous_connections: None,
+            auth_timeout: None,
+            max_authenticated_connections: None,
+            connection_rate: None,
+            max_inflight_calls: None,
+            read_only: false,
+            strict_state: false,
+            unknown_oids: UnknownOidPolicy::default(),
+            state_limits: StateLimits::default(),
+            user_cache_size: CacheCapacity::default(),
+            max_payload_log: DEFAULT_MAX_PAYLOAD_LOG,
             verbosity: 0,
             logging: LogConfig::default(),
             instanceurl: "".into(),
             spacename: "".into(),
+            usernames: UsernamePolicy::default(),
+            passwords: PasswordPolicy::default(),
+            redact_session_peers: false,
+            resumption: ResumptionPolicy::default(),
+            self_registration: false,
+            working_directory: None,
+            umask: None,
+            allow_root: false,
+            user: None,
+            group: None,
         }
     }
 }
@@ -1,11 +1,17 @@
+use std::collections::HashSet;
 use std::path::Path;
 
 use miette::Diagnostic;
 use thiserror::Error;
 
 pub(crate) use dhall::deser_option;
-pub use dhall::{Config, MachineDescription, ModuleConfig};
+pub use dhall::{
+    AuditFormat, AuditSinkConfig, Config, GroupDescription, MachineDescription, ModuleConfig,
+    SensorConnection,
+};
+pub use secret::{RedactedUrl, Secret, SecretError};
 mod dhall;
+mod secret;
 
 #[derive(Debug, Error, Diagnostic)]
 pub enum ConfigError {
@@ -28,6 +34,161 @@ pub enum ConfigError {
         #[source]
         serde_dhall::Error,
     ),
+    #[error("config contains {} invalid entries", .errors.len())]
+    #[diagnostic(
+        code(config::invalid),
+        help("Run bffhd with `--check` to list all problems with the config")
+    )]
+    Invalid {
+        #[related]
+        errors: Vec<ValidationError>,
+    },
+}
+
+#[derive(Debug, Error, Diagnostic, PartialEq, Eq)]
+pub enum ValidationError {
+    #[error("{kind} '{id}' is connected to machine '{machine}' which is not defined")]
+    #[diagnostic(
+        code(config::dangling_machine),
+        help("Define the machine in `machines` or fix the connection")
+    )]
+    UnknownMachine {
+        kind: &'static str,
+        id: String,
+        machine: String,
+    },
+    #[error("a connection references {kind} '{id}' which is not defined")]
+    #[diagnostic(code(config::dangling_module))]
+    UnknownModule { kind: &'static str, id: String },
+    #[error("{kind} '{id}' is connected more than once")]
+    #[diagnostic(
+        code(config::duplicate_connection),
+        help("Each actor and initiator can only be connected to a single machine")
+    )]
+    DuplicateConnection { kind: &'static str, id: String },
+    #[error("machine '{machine}' uses reserved metadata key '{key}'")]
+    #[diagnostic(
+        code(config::reserved_metadata),
+        help("The keys `icon` and `disabled_reason` are set by bffh itself")
+    )]
+    ReservedMetadataKey { machine: String, key: String },
+    #[error("audit sink '{target}' has unknown kind '{kind}'")]
+    #[diagnostic(
+        code(config::audit_sink),
+        help("Audit sinks can be of kind `file`, `syslog` or `http`")
+    )]
+    UnknownAuditSink { kind: String, target: String },
+    #[error("`{setting}` must not be 0")]
+    #[diagnostic(
+        code(config::zero_interval),
+        help("Leave the setting out to turn the feature off")
+    )]
+    ZeroInterval { setting: &'static str },
+    #[error("id '{id}' is used by both a machine and a group")]
+    #[diagnostic(
+        code(config::duplicate_id),
+        help("Machines and groups are requested by id, rename one of them")
+    )]
+    DuplicateId { id: String },
+}
+
+/// Property keys bffh sets itself, which machine metadata can't override
+pub const RESERVED_METADATA_KEYS: &[&str] = &["icon", "disabled_reason"];
+
+/// Check the config for duplicate ids and references to things that are not defined
+///
+/// All problems found are reported at once.
+pub fn validate(config: &Config) -> Result<(), ConfigError> {
+    let mut errors = Vec::new();
+
+    let mut check_connections = |kind: &'static str,
+                                 connections: &Vec<(String, String)>,
+                                 defined: &dyn Fn(&str) -> bool| {
+        let mut seen = HashSet::new();
+        for (id, machine) in connections {
+            if !seen.insert(id) {
+                errors.push(ValidationError::DuplicateConnection {
+                    kind,
+                    id: id.clone(),
+                });
+            }
+            if !defined(id) {
+                errors.push(ValidationError::UnknownModule {
+                    kind,
+                    id: id.clone(),
+                });
+            }
+            if !config.machines.contains_key(machine) {
+                errors.push(ValidationError::UnknownMachine {
+                    kind,
+                    id: id.clone(),
+                    machine: machine.clone(),
+                });
+            }
+        }
+    };
+
+    check_connections("actor", &config.actor_connections, &|id| {
+        config.actors.contains_key(id)
+    });
+    check_connections("initiator", &config.init_connections, &|id| {
+        config.initiators.contains_key(id)
+    });
+    let sensor_connections = config
+        .sensor_connections
+        .iter()
+        .map(|connection| (connection.sensor.clone(), connection.machine.clone()))
+        .collect();
+    check_connections("sensor", &sensor_connections, &|id| {
+        config.sensors.contains_key(id)
+    });
+
+    for (id, machine) in config.machines.iter() {
+        for key in machine.metadata.keys() {
+            if RESERVED_METADATA_KEYS.contains(&key.as_str()) {
+                errors.push(ValidationError::ReservedMetadataKey {
+                    machine: id.clone(),
+                    key: key.clone(),
+                });
+            }
+        }
+    }
+
+    for sink in config.audit_sinks.iter() {
+        if !crate::audit::SINK_KINDS.contains(&sink.kind.as_str()) {
+            errors.push(ValidationError::UnknownAuditSink {
+                kind: sink.kind.clone(),
+                target: sink.target.clone(),
+            });
+        }
+    }
+
+    if config.listen_reresolve_interval == Some(0) {
+        errors.push(ValidationError::ZeroInterval {
+            setting: "listen_reresolve_interval",
+        });
+    }
+
+    for (id, group) in config.groups.iter() {
+        if config.machines.contains_key(id) {
+            errors.push(ValidationError::DuplicateId { id: id.clone() });
+        }
+        for machine in group.members.iter() {
+            if !config.machines.contains_key(machine) {
+                errors.push(ValidationError::UnknownMachine {
+                    kind: "group",
+                    id: id.clone(),
+                    machine: machine.clone(),
+                });
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ConfigError::Invalid { errors })
+    }
 }
 
 pub fn read(file: impl AsRef<Path>) -> Result<Config, ConfigError> {
@@ -48,5 +209,101 @@ pub fn read(file: impl AsRef<Path>) -> Result<Config, ConfigError> {
     //         _ => {}
     //     }
     // }
+    validate(&config)?;
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorization::permissions::PermissionBuf;
+    use crate::testing;
+
+    fn machine(name: &str) -> MachineDescription {
+        let perm = PermissionBuf::from_string_unchecked("test.perm".to_string());
+        testing::machine(name, &perm)
+    }
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config
+            .machines
+            .insert("Testmachine".to_string(), machine("Testmachine"));
+        config
+    }
+
+    fn errors(config: &Config) -> Vec<ValidationError> {
+        match validate(config) {
+            Err(ConfigError::Invalid { errors }) => errors,
+            other => panic!("expected validation errors, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn default_config_with_machine_is_valid() {
+        assert!(validate(&config()).is_ok());
+    }
+
+    #[test]
+    fn dangling_actor_machine_is_reported() {
+        let mut config = config();
+        config.actor_connections = vec![("Actor".to_string(), "Missing".to_string())];
+
+        assert_eq!(
+            errors(&config),
+            vec![ValidationError::UnknownMachine {
+                kind: "actor",
+                id: "Actor".to_string(),
+                machine: "Missing".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn duplicate_connection_is_reported() {
+        let mut config = config();
+        config.machines.insert("Other".to_string(), machine("Other"));
+        config.init_connections = vec![
+            ("Initiator".to_string(), "Testmachine".to_string()),
+            ("Initiator".to_string(), "Other".to_string()),
+        ];
+
+        assert_eq!(
+            errors(&config),
+            vec![ValidationError::DuplicateConnection {
+                kind: "initiator",
+                id: "Initiator".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn duplicate_machine_id_is_reported() {
+        let mut config = config();
+        let group = GroupDescription {
+            members: vec!["Testmachine".to_string()],
+            queue: false,
+        };
+        config.groups.insert("Testmachine".to_string(), group);
+
+        assert_eq!(
+            errors(&config),
+            vec![ValidationError::DuplicateId {
+                id: "Testmachine".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn zero_reresolve_interval_is_reported() {
+        let mut config = config();
+        config.listen_reresolve_interval = Some(0);
+
+        assert_eq!(
+            errors(&config),
+            vec![ValidationError::ZeroInterval {
+                setting: "listen_reresolve_interval",
+            }]
+        );
+    }
+}
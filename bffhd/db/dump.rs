@@ -0,0 +1,194 @@
+//! Backups of everything stored in the databases in a single human-readable file
+
+use std::collections::BTreeMap;
+
+use miette::{IntoDiagnostic, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+use crate::resources::state::db::StateDB;
+use crate::resources::state::dump::StateDump;
+use crate::resources::state::UnknownOidPolicy;
+use crate::users::db::UserData;
+use crate::users::Users;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// Users and machine states as of a single point in time
+///
+/// Encoded as TOML with the tables `users`, keyed by user id, and `states`, keyed by machine id.
+/// Usage counters and quarantined states are not part of a dump.
+pub struct Dump {
+    pub users: BTreeMap<String, UserData>,
+    pub states: BTreeMap<String, StateDump>,
+}
+
+impl Dump {
+    pub fn new(users: &Users, statedb: &StateDB) -> Result<Self, db::Error> {
+        Ok(Self {
+            users: users.into_inner().get_all()?.into_iter().collect(),
+            states: statedb.dump()?,
+        })
+    }
+
+    /// Replace all stored users and states with the ones in this dump
+    ///
+    /// Users and states are checked first and then written in a single transaction, so a failing
+    /// restore changes nothing. Both have to be stored in the same environment. State values of
+    /// unknown type are handled according to `policy`.
+    pub fn restore(
+        &self,
+        users: &Users,
+        statedb: &StateDB,
+        policy: UnknownOidPolicy,
+    ) -> miette::Result<()> {
+        let map = self.users.clone().into_iter().collect();
+        let states = users.load_map_with(map, |txn| {
+            Ok(statedb.restore_txn(txn, &self.states, policy)?)
+        })?;
+        tracing::info!(users = self.users.len(), states, "restored dump");
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, toml::ser::Error> {
+        toml::ser::to_vec(self)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, toml::de::Error> {
+        toml::from_slice(bytes)
+    }
+
+    pub fn write_file(&self, path: &str) -> miette::Result<()> {
+        let encoded = self.encode().into_diagnostic()?;
+        std::fs::write(path, encoded)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to write dump to {}", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::ArchivedValue;
+    use crate::resources::modules::fabaccess::MachineState;
+    use crate::users::UserRef;
+    use rkyv::ser::serializers::AllocSerializer;
+    use rkyv::ser::Serializer;
+    use std::collections::HashMap;
+
+    /// Store a few machine states, returning what a dump of them should contain
+    fn store_states(statedb: &StateDB) -> BTreeMap<String, StateDump> {
+        let alice = UserRef::new("alice".to_string());
+        let states = [
+            ("Lasercutter", MachineState::used(alice.clone(), None)),
+            ("Drill", MachineState::free(Some(alice.clone()))),
+            (
+                "Saw",
+                MachineState::disabled(Some("blade".to_string()), None),
+            ),
+            (
+                "Printer",
+                MachineState::reserved(alice, None).expiring(1700000000),
+            ),
+        ];
+        for (id, state) in states.iter() {
+            let mut serializer = AllocSerializer::<1024>::default();
+            serializer.serialize_value(&state.to_state()).unwrap();
+            let state = ArchivedValue::new(serializer.into_serializer().into_inner());
+            statedb.put(id, &state).unwrap();
+        }
+        states
+            .iter()
+            .map(|(id, state)| (id.to_string(), StateDump::from(state)))
+            .collect()
+    }
+
+    #[test]
+    fn dump_roundtrips_through_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = StateDB::open_env(dir.path().join("db")).unwrap();
+        let statedb = StateDB::create_with_env(env).unwrap();
+        let expected = store_states(&statedb);
+
+        let mut user = UserData::new_with_kv(
+            vec!["member".to_string()],
+            HashMap::from([("cardkey".to_string(), "00112233".to_string())]),
+        );
+        user.passwd = Some("$argon2i$v=19$m=4096,t=3,p=1$c2FsdA$aGFzaA".to_string());
+        let dump = Dump {
+            users: BTreeMap::from([("alice".to_string(), user)]),
+            states: statedb.dump().unwrap(),
+        };
+        assert_eq!(dump.states, expected);
+
+        let path = dir.path().join("dump.toml");
+        dump.write_file(path.to_str().unwrap()).unwrap();
+        let loaded = Dump::decode(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(loaded, dump);
+
+        // Restoring into a fresh database gives back the same users and states
+        let env = StateDB::open_env(dir.path().join("restored")).unwrap();
+        let restored = StateDB::create_with_env(env.clone()).unwrap();
+        let users = Users::new(env).unwrap();
+        loaded
+            .restore(&users, &restored, UnknownOidPolicy::Error)
+            .unwrap();
+        assert_eq!(Dump::new(&users, &restored).unwrap(), dump);
+
+        // A dump with an invalid state leaves the users alone as well
+        let mut broken = Dump {
+            users: BTreeMap::new(),
+            states: loaded.states.clone(),
+        };
+        broken.states.get_mut("Drill").unwrap().status = "exploded".to_string();
+        assert!(broken
+            .restore(&users, &restored, UnknownOidPolicy::Error)
+            .is_err());
+        assert_eq!(Dump::new(&users, &restored).unwrap(), dump);
+    }
+
+    /// A dump made by a bffhd knowing more value types than this one
+    const WITH_UNKNOWN: &str = r#"
+[users]
+
+[states.Lasercutter]
+status = "free"
+
+[states.Lasercutter.values."1.3.6.1.4.1.61783.612.9.1"]
+brightness = 80
+"#;
+
+    /// Load [`WITH_UNKNOWN`] into a fresh database with `policy`, returning what it now dumps
+    fn load_with(policy: UnknownOidPolicy) -> miette::Result<Dump> {
+        let dir = tempfile::tempdir().unwrap();
+        let env = StateDB::open_env(dir.path().join("db")).unwrap();
+        let statedb = StateDB::create_with_env(env.clone()).unwrap();
+        let users = Users::new(env).unwrap();
+
+        let dump = Dump::decode(WITH_UNKNOWN.as_bytes()).unwrap();
+        dump.restore(&users, &statedb, policy)?;
+        Ok(Dump::new(&users, &statedb).unwrap())
+    }
+
+    #[test]
+    fn unknown_values_are_refused_by_default() {
+        assert!(load_with(UnknownOidPolicy::Error).is_err());
+    }
+
+    #[test]
+    fn unknown_values_can_be_skipped() {
+        let loaded = load_with(UnknownOidPolicy::Skip).unwrap();
+        let expected = StateDump::from(&MachineState::free(None));
+        assert_eq!(loaded.states.get("Lasercutter"), Some(&expected));
+    }
+
+    #[test]
+    fn unknown_values_are_dumped_again_with_passthrough() {
+        let loaded = load_with(UnknownOidPolicy::Passthrough).unwrap();
+        let original = Dump::decode(WITH_UNKNOWN.as_bytes()).unwrap();
+        assert_eq!(loaded, original);
+
+        let encoded = loaded.encode().unwrap();
+        assert_eq!(Dump::decode(&encoded).unwrap(), original);
+    }
+}
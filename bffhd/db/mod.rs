@@ -12,6 +12,9 @@ use std::fmt::{Debug, Display};
 mod typed;
 pub use typed::{Adapter, AlignedAdapter, ArchivedValue, DB};
 
+mod dump;
+pub use dump::Dump;
+
 pub type ErrorO = lmdb::Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -40,7 +40,9 @@ impl Dummy {
                 Status::InUse(_) => Status::Free,
                 _ => Status::Free,
             };
-            callbacks.try_update(session, status).await;
+            if let Err(reason) = callbacks.try_update(session, status).await {
+                tracing::warn!(%reason, "dummy initiator update denied");
+            }
 
             next
         })
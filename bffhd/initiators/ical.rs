@@ -0,0 +1,747 @@
+use super::Initiator;
+use super::InitiatorCallbacks;
+use crate::resources::modules::fabaccess::Status;
+use crate::session::SessionHandle;
+use crate::users::UserRef;
+use crate::utils::http;
+use async_io::Timer;
+use chrono::{Datelike, Local, Months, NaiveDate, NaiveDateTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use futures_util::future::BoxFuture;
+use miette::{miette, Diagnostic};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use url::Url;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(300);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Calendars larger than this are refused instead of read into memory
+const MAX_CALENDAR_SIZE: u64 = 8 * 1024 * 1024;
+/// Recurring events are expanded this far ahead of each fetch, in seconds
+const HORIZON: i64 = 31 * 24 * 60 * 60;
+/// Recurrence rules are given up on after this many periods, e.g. days of a daily event
+const MAX_PERIODS: u32 = 100_000;
+
+/// An initiator reserving its machine while a shared calendar has an event booking it
+///
+/// The calendar at `url` is fetched every `interval_secs` seconds (default 300). Events whose
+/// summary or location contain `match`, or all events if it isn't given, reserve the machine for
+/// `uid` from their start until their end. If an event is removed while its reservation is
+/// running the machine is freed again. Cancelled events are ignored. Times with a `TZID` are read
+/// in that IANA timezone, times without one as local time.
+///
+/// Recurring events are expanded a month ahead. Their rules may use `FREQ`, `INTERVAL`, `COUNT`,
+/// `UNTIL`, `WKST` and `BYDAY` for weekly events; events with other rules are skipped. Excluded
+/// dates and moved occurrences are respected.
+///
+/// If fetching or reading the calendar fails the events of the last good one are kept.
+pub struct ICal {
+    task: BoxFuture<'static, ()>,
+}
+
+struct Calendar {
+    url: Url,
+    interval: Duration,
+    pattern: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Time span of an event as Unix timestamps
+struct Booking {
+    start: i64,
+    end: i64,
+}
+
+/// Properties of an event by name, with their parameters and value
+type Properties = HashMap<String, (String, String)>;
+
+/// A date or time of an event as written in the calendar
+#[derive(Debug, Clone, Copy)]
+struct Time {
+    at: NaiveDateTime,
+    zone: Zone,
+    all_day: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Zone {
+    Utc,
+    Local,
+    Named(Tz),
+}
+
+/// The parts of a `RRULE` that are supported
+#[derive(Debug)]
+struct Rule {
+    frequency: Frequency,
+    interval: u32,
+    count: Option<u32>,
+    /// Kept as written, it is read in the timezone of the event
+    until: Option<String>,
+    weekdays: Vec<Weekday>,
+    week_start: Weekday,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+enum CalendarError {
+    #[error("fetching the calendar failed")]
+    Fetch(#[from] http::Error),
+    #[error("response is not an iCalendar")]
+    NotACalendar,
+    #[error("events in the calendar are not properly closed")]
+    Unterminated,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Reserve { until: i64 },
+    Free,
+    Keep,
+}
+
+impl Future for ICal {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.task.as_mut().poll(cx)
+    }
+}
+
+impl Initiator for ICal {
+    fn new(params: &HashMap<String, String>, callbacks: InitiatorCallbacks) -> miette::Result<Self>
+    where
+        Self: Sized,
+    {
+        let url = match params.get("url").map(|url| Url::parse(url)) {
+            Some(Ok(url)) => {
+                http::check_url(&url)?;
+                url
+            }
+            Some(Err(error)) => return Err(miette!("invalid `url` for ICal initiator: {}", error)),
+            None => return Err(miette!("ICal initiator configured without an `url`")),
+        };
+        let interval = match params.get("interval_secs").map(|secs| secs.parse()) {
+            None => DEFAULT_INTERVAL,
+            Some(Ok(0)) => return Err(miette!("`interval_secs` of ICal initiator must not be 0")),
+            Some(Ok(secs)) => Duration::from_secs(secs),
+            Some(Err(error)) => {
+                return Err(miette!(
+                    "invalid `interval_secs` for ICal initiator: {}",
+                    error
+                ))
+            }
+        };
+        let uid = params
+            .get("uid")
+            .ok_or_else(|| miette!("ICal initiator configured without an UID"))?;
+        let session = callbacks
+            .open_session(uid)
+            .ok_or_else(|| miette!("The configured user for the ICal initiator does not exist"))?;
+
+        let calendar = Calendar {
+            url,
+            interval,
+            pattern: params.get("match").map(|pattern| pattern.to_lowercase()),
+        };
+        Ok(Self {
+            task: Box::pin(run(calendar, callbacks, session)),
+        })
+    }
+}
+
+async fn run(calendar: Calendar, mut callbacks: InitiatorCallbacks, session: SessionHandle) {
+    let user = session.get_user_ref();
+    let mut bookings = Vec::new();
+    let mut next_fetch = Instant::now();
+    loop {
+        if Instant::now() >= next_fetch {
+            match calendar.fetch(Utc::now().timestamp()).await {
+                Ok(fetched) => {
+                    let count = fetched.len();
+                    tracing::debug!(url = %calendar.url, bookings = count, "fetched calendar");
+                    bookings = fetched;
+                }
+                Err(error) => {
+                    tracing::warn!(url = %calendar.url, %error,
+                        "reading calendar failed, keeping last bookings");
+                }
+            }
+            next_fetch = Instant::now() + calendar.interval;
+        }
+
+        let now = Utc::now().timestamp();
+        match decide(&bookings, now, &callbacks.status(), &user) {
+            Action::Reserve { until } => {
+                let expiry = Duration::from_secs((until - now) as u64);
+                if let Err(reason) = callbacks.try_reserve(session.clone(), Some(expiry)).await {
+                    tracing::warn!(%reason, "reserving machine for booking denied");
+                }
+            }
+            Action::Free => {
+                if let Err(reason) = callbacks.try_update(session.clone(), Status::Free).await {
+                    tracing::warn!(%reason, "freeing machine of removed booking denied");
+                }
+            }
+            Action::Keep => {}
+        }
+
+        // Wake up for the next event starting or ending even if that's before the next fetch
+        let wait = next_boundary(&bookings, now)
+            .map(|at| Duration::from_secs((at - now).max(1) as u64))
+            .map_or(calendar.interval, |wait| wait.min(calendar.interval));
+        Timer::after(wait.min(next_fetch.saturating_duration_since(Instant::now()))).await;
+    }
+}
+
+impl Calendar {
+    /// The bookings that haven't ended at `now`
+    async fn fetch(&self, now: i64) -> Result<Vec<Booking>, CalendarError> {
+        let body = http::Request::get(&self.url)
+            .header("Accept", "text/calendar")
+            .timeout(REQUEST_TIMEOUT)
+            .max_body(MAX_CALENDAR_SIZE)
+            .send()
+            .await?;
+        let body = String::from_utf8_lossy(&body);
+        parse(&body, self.pattern.as_deref(), now)
+    }
+}
+
+/// Read the events matching `pattern` that haven't ended at `now` from an iCalendar, skipping
+/// those that can't be read
+fn parse(calendar: &str, pattern: Option<&str>, now: i64) -> Result<Vec<Booking>, CalendarError> {
+    // Long lines are folded by starting the continuation with a space or tab
+    let mut lines: Vec<String> = Vec::new();
+    for line in calendar.lines() {
+        match (
+            line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')),
+            lines.last_mut(),
+        ) {
+            (Some(continued), Some(last)) => last.push_str(continued),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    if lines.first().map(|line| line.trim()) != Some("BEGIN:VCALENDAR") {
+        return Err(CalendarError::NotACalendar);
+    }
+
+    let mut events = Vec::new();
+    let mut event: Option<Properties> = None;
+    for line in lines.iter() {
+        let line = line.trim_end();
+        if line == "BEGIN:VEVENT" {
+            event = Some(HashMap::new());
+        } else if line == "END:VEVENT" {
+            events.push(event.take().ok_or(CalendarError::Unterminated)?);
+        } else if let (Some(properties), Some((name, value))) =
+            (event.as_mut(), line.split_once(':'))
+        {
+            let (name, parameters) = name.split_once(';').unwrap_or((name, ""));
+            let name = name.to_ascii_uppercase();
+            match properties.get_mut(&name) {
+                // Excluded dates may be spread over several lines
+                Some((_, dates)) if name == "EXDATE" => {
+                    dates.push(',');
+                    dates.push_str(value);
+                }
+                _ => {
+                    properties.insert(name, (parameters.to_string(), value.to_string()));
+                }
+            }
+        }
+    }
+    if event.is_some() {
+        return Err(CalendarError::Unterminated);
+    }
+
+    // Occurrences of a recurring event that were moved or cancelled are events of their own with
+    // the same UID, naming the occurrence they replace
+    let mut replaced: HashMap<&str, Vec<i64>> = HashMap::new();
+    for properties in events.iter() {
+        if let (Some((_, uid)), Some(occurrence)) = (
+            properties.get("UID"),
+            properties
+                .get("RECURRENCE-ID")
+                .and_then(|(parameters, value)| Time::parse(parameters, value))
+                .and_then(|time| time.timestamp()),
+        ) {
+            replaced.entry(uid).or_default().push(occurrence);
+        }
+    }
+
+    let mut bookings = Vec::new();
+    for properties in events.iter() {
+        let replaced = properties
+            .get("UID")
+            .and_then(|(_, uid)| replaced.get(uid.as_str()))
+            .map_or(&[][..], |replaced| replaced.as_slice());
+        match event_bookings(properties, pattern, replaced, now) {
+            Some(Ok(found)) => bookings.extend(found),
+            Some(Err(uid)) => tracing::debug!(%uid, "skipping unreadable calendar event"),
+            None => {}
+        }
+    }
+    Ok(bookings)
+}
+
+/// The bookings of an event that haven't ended at `now`, `None` if it doesn't concern the machine
+/// and `Err(uid)` if it can't be read
+///
+/// Occurrences of a recurring event starting at a time in `replaced` are left out.
+fn event_bookings(
+    properties: &Properties,
+    pattern: Option<&str>,
+    replaced: &[i64],
+    now: i64,
+) -> Option<Result<Vec<Booking>, String>> {
+    let value = |name: &str| properties.get(name).map(|(_, value)| value.as_str());
+    if value("STATUS") == Some("CANCELLED") {
+        return None;
+    }
+    if let Some(pattern) = pattern {
+        let matches = |name| value(name).map_or(false, |v| v.to_lowercase().contains(pattern));
+        if !matches("SUMMARY") && !matches("LOCATION") {
+            return None;
+        }
+    }
+
+    let unreadable = || Some(Err(value("UID").unwrap_or("<no uid>").to_string()));
+    let time = |name| {
+        let (parameters, value) = properties.get(name)?;
+        Time::parse(parameters, value)
+    };
+    let start = match time("DTSTART") {
+        Some(start) => start,
+        None => return unreadable(),
+    };
+    let end = match time("DTEND") {
+        Some(end) => end,
+        // An all-day event without end lasts that day
+        None if start.all_day => Time {
+            at: start.at + chrono::Duration::days(1),
+            ..start
+        },
+        None => return unreadable(),
+    };
+
+    let mut excluded = Vec::new();
+    let occurrences = match value("RRULE") {
+        None => vec![start.at],
+        Some(rule) => {
+            excluded.extend_from_slice(replaced);
+            if let Some((parameters, dates)) = properties.get("EXDATE") {
+                for date in dates.split(',') {
+                    match Time::parse(parameters, date).and_then(|date| date.timestamp()) {
+                        Some(date) => excluded.push(date),
+                        None => return unreadable(),
+                    }
+                }
+            }
+            match Rule::parse(rule).and_then(|rule| rule.expand(&start, now + HORIZON)) {
+                Some(occurrences) => occurrences,
+                None => return unreadable(),
+            }
+        }
+    };
+
+    let mut bookings = Vec::new();
+    for at in occurrences {
+        // Occurrences keep the wall clock times of the first one across DST changes
+        let shift = at - start.at;
+        let (start, end) = match (start.zone.timestamp(at), end.zone.timestamp(end.at + shift)) {
+            (Some(start), Some(end)) if start < end => (start, end),
+            _ => return unreadable(),
+        };
+        if end > now && !excluded.contains(&start) {
+            bookings.push(Booking { start, end });
+        }
+    }
+    Some(Ok(bookings))
+}
+
+impl Time {
+    /// A `DTSTART`, `DTEND` or similar property with its `parameters`
+    fn parse(parameters: &str, value: &str) -> Option<Self> {
+        let zone = match parameter(parameters, "TZID") {
+            Some(tzid) => Zone::Named(tzid.parse().ok()?),
+            None => Zone::Local,
+        };
+        Self::parse_in(zone, value)
+    }
+
+    /// A date or time, read in `zone` unless it's given in UTC
+    fn parse_in(zone: Zone, value: &str) -> Option<Self> {
+        if !value.contains('T') {
+            let date = NaiveDate::parse_from_str(value, "%Y%m%d").ok()?;
+            return Some(Self {
+                at: date.and_hms_opt(0, 0, 0)?,
+                zone,
+                all_day: true,
+            });
+        }
+        let (value, zone) = match value.strip_suffix('Z') {
+            Some(utc) => (utc, Zone::Utc),
+            None => (value, zone),
+        };
+        let at = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?;
+        Some(Self {
+            at,
+            zone,
+            all_day: false,
+        })
+    }
+
+    fn timestamp(&self) -> Option<i64> {
+        self.zone.timestamp(self.at)
+    }
+}
+
+impl Zone {
+    /// The Unix timestamp of the wall clock time `at` in this zone
+    fn timestamp(self, at: NaiveDateTime) -> Option<i64> {
+        match self {
+            Zone::Utc => Some(Utc.from_utc_datetime(&at).timestamp()),
+            Zone::Local => local_timestamp(&Local, at),
+            Zone::Named(tz) => local_timestamp(&tz, at),
+        }
+    }
+}
+
+/// Times skipped by a DST change are moved past the gap, like RFC 5545 asks
+fn local_timestamp<T: TimeZone>(tz: &T, at: NaiveDateTime) -> Option<i64> {
+    tz.from_local_datetime(&at)
+        .earliest()
+        .or_else(|| {
+            tz.from_local_datetime(&(at + chrono::Duration::hours(1)))
+                .earliest()
+        })
+        .map(|time| time.timestamp())
+}
+
+/// The value of the parameter `name`, without quotes
+fn parameter<'a>(parameters: &'a str, name: &str) -> Option<&'a str> {
+    parameters.split(';').find_map(|parameter| {
+        let (key, value) = parameter.split_once('=')?;
+        key.eq_ignore_ascii_case(name)
+            .then(|| value.trim_matches('"'))
+    })
+}
+
+impl Rule {
+    /// The rule of a `RRULE` value, `None` if it uses parts that aren't supported
+    fn parse(value: &str) -> Option<Self> {
+        let mut frequency = None;
+        let mut rule = Self {
+            frequency: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            weekdays: Vec::new(),
+            week_start: Weekday::Mon,
+        };
+        for part in value.split(';') {
+            let (name, value) = part.split_once('=')?;
+            match name.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    frequency = Some(match value.to_ascii_uppercase().as_str() {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        "YEARLY" => Frequency::Yearly,
+                        _ => return None,
+                    })
+                }
+                "INTERVAL" => rule.interval = value.parse().ok().filter(|n| *n > 0)?,
+                "COUNT" => rule.count = Some(value.parse().ok()?),
+                "UNTIL" => rule.until = Some(value.to_string()),
+                "BYDAY" => rule.weekdays = value.split(',').map(weekday).collect::<Option<_>>()?,
+                "WKST" => rule.week_start = weekday(value)?,
+                _ => return None,
+            }
+        }
+        rule.frequency = frequency?;
+        if !rule.weekdays.is_empty() && rule.frequency != Frequency::Weekly {
+            return None;
+        }
+        Some(rule)
+    }
+
+    /// Wall clock times of the occurrences of an event first starting at `start`
+    ///
+    /// Stops at the end of the rule or the first occurrence starting at or after `horizon`.
+    /// `None` if `UNTIL` can't be read.
+    fn expand(&self, start: &Time, horizon: i64) -> Option<Vec<NaiveDateTime>> {
+        let until = match self.until.as_deref() {
+            Some(until) => Some(Time::parse_in(start.zone, until)?.timestamp()?),
+            None => None,
+        };
+        let first = start.at.date();
+        let days_since_week_start = |day: Weekday| {
+            i64::from((day.num_days_from_monday() + 7 - self.week_start.num_days_from_monday()) % 7)
+        };
+        let week = add_days(first, -days_since_week_start(first.weekday()))?;
+        let mut weekdays = self.weekdays.clone();
+        weekdays.sort_by_key(|day| days_since_week_start(*day));
+
+        let mut occurrences = Vec::new();
+        for period in 0..MAX_PERIODS {
+            let step = i64::from(period) * i64::from(self.interval);
+            let days = match self.frequency {
+                Frequency::Daily => add_days(first, step).map(|day| vec![day]),
+                Frequency::Weekly if weekdays.is_empty() => {
+                    add_days(first, 7 * step).map(|day| vec![day])
+                }
+                Frequency::Weekly => weekdays
+                    .iter()
+                    .map(|day| add_days(week, 7 * step + days_since_week_start(*day)))
+                    .collect::<Option<Vec<_>>>()
+                    .map(|days| days.into_iter().filter(|day| *day >= first).collect()),
+                Frequency::Monthly => add_months(first, step),
+                Frequency::Yearly => add_months(first, 12 * step),
+            };
+            // Out of the range of dates, nothing can come after this
+            let days = match days {
+                Some(days) => days,
+                None => break,
+            };
+            for day in days {
+                let at = day.and_time(start.at.time());
+                let timestamp = match start.zone.timestamp(at) {
+                    Some(timestamp) => timestamp,
+                    None => continue,
+                };
+                if timestamp >= horizon || until.map_or(false, |until| timestamp > until) {
+                    return Some(occurrences);
+                }
+                occurrences.push(at);
+                if self
+                    .count
+                    .map_or(false, |count| occurrences.len() >= count as usize)
+                {
+                    return Some(occurrences);
+                }
+            }
+        }
+        Some(occurrences)
+    }
+}
+
+/// A `BYDAY` or `WKST` weekday, `None` for those of a given week of the month
+fn weekday(value: &str) -> Option<Weekday> {
+    match value.to_ascii_uppercase().as_str() {
+        "MO" => Some(Weekday::Mon),
+        "TU" => Some(Weekday::Tue),
+        "WE" => Some(Weekday::Wed),
+        "TH" => Some(Weekday::Thu),
+        "FR" => Some(Weekday::Fri),
+        "SA" => Some(Weekday::Sat),
+        "SU" => Some(Weekday::Sun),
+        _ => None,
+    }
+}
+
+fn add_days(day: NaiveDate, days: i64) -> Option<NaiveDate> {
+    let days = i64::from(day.num_days_from_ce()).checked_add(days)?;
+    NaiveDate::from_num_days_from_ce_opt(i32::try_from(days).ok()?)
+}
+
+/// The day `months` after `day`, no day if that month is too short for it
+fn add_months(day: NaiveDate, months: i64) -> Option<Vec<NaiveDate>> {
+    let later = day.checked_add_months(Months::new(u32::try_from(months).ok()?))?;
+    Some(if later.day() == day.day() {
+        vec![later]
+    } else {
+        Vec::new()
+    })
+}
+
+/// What to do with a machine in `status` at `now`, reservations being made for `user`
+fn decide(bookings: &[Booking], now: i64, status: &Status, user: &UserRef) -> Action {
+    let current = bookings
+        .iter()
+        .filter(|booking| booking.start <= now && now < booking.end)
+        .map(|booking| booking.end)
+        .max();
+    match (current, status) {
+        (Some(until), Status::Free) => Action::Reserve { until },
+        (None, Status::Reserved(whom)) if whom == user => Action::Free,
+        _ => Action::Keep,
+    }
+}
+
+/// The next time after `now` an event starts or ends
+fn next_boundary(bookings: &[Booking], now: i64) -> Option<i64> {
+    bookings
+        .iter()
+        .flat_map(|booking| [booking.start, booking.end])
+        .filter(|at| *at > now)
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = "BEGIN:VCALENDAR\r
+VERSION:2.0\r
+PRODID:-//Makerspace//Bookings//EN\r
+BEGIN:VEVENT\r
+UID:laser-1\r
+SUMMARY:Lasercutter: \r
+ signs for the fair\r
+DTSTART:20240301T100000Z\r
+DTEND:20240301T120000Z\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:printer-1\r
+SUMMARY:3D printer\r
+DTSTART:20240301T100000Z\r
+DTEND:20240301T180000Z\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:laser-cancelled\r
+SUMMARY:Lasercutter\r
+STATUS:CANCELLED\r
+DTSTART:20240301T140000Z\r
+DTEND:20240301T150000Z\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:laser-broken\r
+SUMMARY:Lasercutter\r
+DTSTART:tomorrow\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+
+    #[test]
+    fn booking_reserves_during_event() {
+        let start = Utc
+            .with_ymd_and_hms(2024, 3, 1, 10, 0, 0)
+            .unwrap()
+            .timestamp();
+        let end = Utc
+            .with_ymd_and_hms(2024, 3, 1, 12, 0, 0)
+            .unwrap()
+            .timestamp();
+        let bookings = parse(FIXTURE, Some("lasercutter"), start - 60).unwrap();
+        assert_eq!(bookings, vec![Booking { start, end }]);
+        // Events that are over aren't kept
+        assert_eq!(parse(FIXTURE, Some("lasercutter"), end).unwrap(), vec![]);
+
+        let user = UserRef::new("calendar".to_string());
+        let other = UserRef::new("alice".to_string());
+        assert_eq!(
+            decide(&bookings, start - 1, &Status::Free, &user),
+            Action::Keep
+        );
+        assert_eq!(
+            decide(&bookings, start + 60, &Status::Free, &user),
+            Action::Reserve { until: end }
+        );
+        // Somebody already using the machine isn't interrupted
+        assert_eq!(
+            decide(&bookings, start + 60, &Status::InUse(other.clone()), &user),
+            Action::Keep
+        );
+        // The event was removed from the calendar while reserved
+        assert_eq!(
+            decide(&[], start + 60, &Status::Reserved(user.clone()), &user),
+            Action::Free
+        );
+        assert_eq!(
+            decide(&[], start + 60, &Status::Reserved(other), &user),
+            Action::Keep
+        );
+        assert_eq!(next_boundary(&bookings, start), Some(end));
+
+        assert!(matches!(
+            parse("<html>not found</html>", None, start),
+            Err(CalendarError::NotACalendar)
+        ));
+        assert!(matches!(
+            parse("BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\n", None, start),
+            Err(CalendarError::Unterminated)
+        ));
+    }
+
+    const RECURRING: &str = "BEGIN:VCALENDAR\r
+VERSION:2.0\r
+BEGIN:VEVENT\r
+UID:open-lab\r
+SUMMARY:Open lab\r
+DTSTART;TZID=Europe/Berlin:20240325T180000\r
+DTEND;TZID=Europe/Berlin:20240325T200000\r
+RRULE:FREQ=WEEKLY;BYDAY=TH,MO;COUNT=5\r
+EXDATE;TZID=Europe/Berlin:20240328T180000\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:open-lab\r
+RECURRENCE-ID;TZID=Europe/Berlin:20240401T180000\r
+SUMMARY:Open lab\r
+DTSTART;TZID=Europe/Berlin:20240401T190000\r
+DTEND;TZID=Europe/Berlin:20240401T210000\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:open-lab-maintenance\r
+SUMMARY:Open lab maintenance\r
+DTSTART:20240131T090000Z\r
+DTEND:20240131T100000Z\r
+RRULE:FREQ=MONTHLY;COUNT=3\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:open-lab-cleanup\r
+SUMMARY:Open lab cleanup\r
+DTSTART:20240301T090000Z\r
+DTEND:20240301T100000Z\r
+RRULE:FREQ=MONTHLY;BYMONTHDAY=-1\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:open-lab-elsewhere\r
+SUMMARY:Open lab\r
+DTSTART;TZID=Nowhere/Special:20240401T090000\r
+DTEND;TZID=Nowhere/Special:20240401T100000\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+
+    #[test]
+    fn recurring_events_are_expanded_in_their_timezone() {
+        let utc = |month, day, hour| {
+            Utc.with_ymd_and_hms(2024, month, day, hour, 0, 0)
+                .unwrap()
+                .timestamp()
+        };
+        let booking = |month, day, hour, hours| Booking {
+            start: utc(month, day, hour),
+            end: utc(month, day, hour + hours),
+        };
+        let bookings = parse(RECURRING, Some("open lab"), utc(3, 25, 0)).unwrap();
+
+        // Unsupported rules and unknown timezones are skipped
+        assert_eq!(
+            bookings,
+            vec![
+                // 18:00 in Berlin is 17:00 UTC before DST starts on March 31st and 16:00 after.
+                // The 28th is excluded and the 1st moved by an hour.
+                booking(3, 25, 17, 2),
+                booking(4, 4, 16, 2),
+                booking(4, 8, 16, 2),
+                booking(4, 1, 17, 2),
+                // February has no 31st, the first occurrence is over and May is too far ahead.
+                booking(3, 31, 9, 1),
+            ]
+        );
+    }
+}
@@ -1,19 +1,29 @@
+use crate::actors::MqttClient;
+use crate::audit::Source;
 use crate::initiators::dummy::Dummy;
+use crate::initiators::ical::ICal;
+use crate::initiators::mqtt::Mqtt;
 use crate::initiators::process::Process;
-use crate::resources::modules::fabaccess::Status;
+use crate::resources::modules::fabaccess::{MachineState, Status};
+use crate::resources::Denied;
 use crate::session::SessionHandle;
+use crate::users::db::User;
 use crate::{
     AuthenticationHandle, Config, Resource, ResourcesHandle, SessionManager,
 };
 use executor::prelude::Executor;
 use futures_util::ready;
+use lightproc::recoverable_handle::RecoverableHandle;
 use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tracing::Span;
 
 mod dummy;
+mod ical;
+mod mqtt;
 mod process;
 
 pub trait Initiator: Future<Output = ()> {
@@ -40,16 +50,52 @@ impl InitiatorCallbacks {
         }
     }
 
-    pub async fn try_update(&mut self, session: SessionHandle, status: Status) {
+    pub async fn try_update(
+        &mut self,
+        session: SessionHandle,
+        status: Status,
+    ) -> Result<(), Denied> {
         self.resource.try_update(session, status).await
     }
 
-    pub fn set_status(&mut self, status: Status) {
-        self.resource.set_status(status)
+    /// Return the machine if the user of `session` is using it, see [`Resource::give_back`]
+    pub async fn give_back(&mut self, session: SessionHandle) -> Result<(), Denied> {
+        self.resource.give_back(session).await
+    }
+
+    /// Reserve the machine for the user of `session`, freeing it again after `expiry` if given
+    pub async fn try_reserve(
+        &mut self,
+        session: SessionHandle,
+        expiry: Option<Duration>,
+    ) -> Result<(), Denied> {
+        self.resource.try_reserve(session, expiry).await
+    }
+
+    pub fn status(&self) -> Status {
+        MachineState::from(self.resource.get_state().as_ref()).state
+    }
+
+    pub fn set_status(&mut self, status: Status) -> Result<(), Denied> {
+        self.resource.set_status(status, Source::Initiator)
     }
 
     pub fn open_session(&self, uid: &str) -> Option<SessionHandle> {
-        self.sessions.try_open(&self.span, uid)
+        self.sessions
+            .try_open(&self.span, uid)
+            .map(|session| session.with_source(Source::Initiator))
+    }
+
+    /// The user whose kv entry `key` is `value`, e.g. the user a card UID is stored for
+    pub fn find_user(&self, key: &str, value: &str) -> Option<User> {
+        self.sessions.find_user(key, value)
+    }
+
+    /// Open a session for a user that was already looked up
+    pub fn open_session_for(&self, user: User) -> SessionHandle {
+        self.sessions
+            .open(&self.span, user)
+            .with_source(Source::Initiator)
     }
 }
 
@@ -72,11 +118,20 @@ impl InitiatorDriver {
     {
         let callbacks = InitiatorCallbacks::new(span.clone(), resource, sessions);
         let initiator = Box::new(I::new(params, callbacks)?);
-        Ok(Self {
+        Ok(Self::with_initiator(span, name, initiator))
+    }
+
+    /// Drive an initiator that needed more than its params to be set up
+    pub fn with_initiator(
+        span: Span,
+        name: String,
+        initiator: Box<dyn Initiator + Unpin + Send>,
+    ) -> Self {
+        Self {
             span,
             name,
             initiator,
-        })
+        }
     }
 }
 
@@ -101,7 +156,8 @@ pub fn load(
     resources: ResourcesHandle,
     sessions: SessionManager,
     _authentication: AuthenticationHandle,
-) -> miette::Result<()> {
+    mqtt: &MqttClient,
+) -> miette::Result<Vec<RecoverableHandle<()>>> {
     let span = tracing::info_span!("loading initiators");
     let _guard = span.enter();
 
@@ -110,7 +166,7 @@ pub fn load(
         .iter()
         .filter_map(|(k, v)| {
             if let Some(resource) = resources.get_by_id(v) {
-                Some((k.clone(), resource.clone()))
+                Some((k.clone(), resource))
             } else {
                 tracing::error!(initiator=%k, machine=%v,
                     "Machine configured for initiator not found!");
@@ -119,11 +175,14 @@ pub fn load(
         })
         .collect();
 
+    let mut tasks = Vec::new();
     for (name, cfg) in config.initiators.iter() {
         if let Some(resource) = initiator_map.remove(name) {
-            if let Some(driver) = load_single(name, &cfg.module, &cfg.params, resource, &sessions) {
+            if let Some(driver) =
+                load_single(name, &cfg.module, &cfg.params, resource, &sessions, mqtt)
+            {
                 tracing::debug!(module_name=%cfg.module, %name, "starting initiator task");
-                executor.spawn(driver);
+                tasks.push(executor.spawn_named(&format!("initiator:{}", name), driver));
             } else {
                 tracing::error!(module_name=%cfg.module, %name, "Initiator module could not be configured");
             }
@@ -132,7 +191,7 @@ pub fn load(
         }
     }
 
-    Ok(())
+    Ok(tasks)
 }
 
 fn load_single(
@@ -141,6 +200,7 @@ fn load_single(
     params: &HashMap<String, String>,
     resource: Resource,
     sessions: &SessionManager,
+    mqtt: &MqttClient,
 ) -> Option<InitiatorDriver> {
     let span = tracing::info_span!(
         "initiator",
@@ -156,6 +216,13 @@ fn load_single(
             resource,
             sessions.clone(),
         )),
+        "ICal" => Some(InitiatorDriver::new::<ICal>(
+            span,
+            name.clone(),
+            params,
+            resource,
+            sessions.clone(),
+        )),
         "Process" => Some(InitiatorDriver::new::<Process>(
             span,
             name.clone(),
@@ -163,6 +230,16 @@ fn load_single(
             resource,
             sessions.clone(),
         )),
+        "Mqtt" => {
+            let callbacks = InitiatorCallbacks::new(span.clone(), resource, sessions.clone());
+            Some(
+                Mqtt::new(params, callbacks)
+                    .and_then(|initiator| initiator.subscribe(mqtt))
+                    .map(|initiator| {
+                        InitiatorDriver::with_initiator(span, name.clone(), Box::new(initiator))
+                    }),
+            )
+        }
         _ => None,
     };
 
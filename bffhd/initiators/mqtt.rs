@@ -0,0 +1,359 @@
+use super::Initiator;
+use super::InitiatorCallbacks;
+use crate::actors::MqttClient;
+use crate::resources::modules::fabaccess::Status;
+use crate::resources::Denied;
+use crate::users::UserRef;
+use crate::utils::ratelimit::LogLimiter;
+use crate::utils::truncate::Truncated;
+use async_channel::{Receiver, Sender, TrySendError};
+use futures_util::future::BoxFuture;
+use miette::miette;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::str::Utf8Error;
+use std::task::{Context, Poll};
+use thiserror::Error;
+
+/// Messages received but not handled yet before further ones are dropped
+const QUEUE_SIZE: usize = 32;
+/// Key in the kv data of users holding the UID of their card if not configured otherwise
+const DEFAULT_USER_KEY: &str = "carduid";
+
+#[derive(Debug, Error)]
+enum MessageError {
+    #[error("payload is not valid UTF-8")]
+    Utf8(#[from] Utf8Error),
+    #[error("payload is not `inuse <card>` or `free <card>`")]
+    Malformed,
+    #[error("status `{0}` can't be set through MQTT, only `inuse` and `free`")]
+    Action(String),
+    #[error("no user has the card presented")]
+    UnknownCard,
+    #[error("user `{0}` is suspended")]
+    Suspended(String),
+    #[error("user `{0}` is not allowed to use this initiator")]
+    NotAllowed(String),
+    #[error("`{0}` does not apply while the machine is `{1}`")]
+    Unchanged(&'static str, &'static str),
+    #[error(transparent)]
+    Denied(#[from] Denied),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// What the user presenting a card asks for
+enum Action {
+    /// Start using the machine
+    Use,
+    /// Return the machine they are using
+    Return,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Action::Use => "inuse",
+            Action::Return => "free",
+        }
+    }
+}
+
+/// The action asked for and the card UID it was asked with
+fn parse(payload: &[u8]) -> Result<(Action, &str), MessageError> {
+    let payload = std::str::from_utf8(payload)?.trim();
+    let (action, card) = payload
+        .split_once(char::is_whitespace)
+        .ok_or(MessageError::Malformed)?;
+    let card = card.trim();
+    if card.is_empty() || card.contains(char::is_whitespace) {
+        return Err(MessageError::Malformed);
+    }
+    let action = match action {
+        "inuse" => Action::Use,
+        "free" => Action::Return,
+        other => return Err(MessageError::Action(other.to_string())),
+    };
+    Ok((action, card))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Which users an initiator acts for
+struct Settings {
+    /// Key in the kv data of users holding their card UID
+    user_key: String,
+    /// Only these users may use the initiator, everybody with a card if `None`
+    allowed_users: Option<HashSet<String>>,
+}
+
+impl Settings {
+    fn from_params(params: &HashMap<String, String>) -> Self {
+        let user_key = params
+            .get("user_key")
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_USER_KEY.to_string());
+        let allowed_users = params.get("allowed_users").map(|users| {
+            users
+                .split(',')
+                .map(str::trim)
+                .filter(|uid| !uid.is_empty())
+                .map(String::from)
+                .collect()
+        });
+        Self {
+            user_key,
+            allowed_users,
+        }
+    }
+}
+
+async fn handle(
+    callbacks: &mut InitiatorCallbacks,
+    settings: &Settings,
+    payload: &[u8],
+) -> Result<(), MessageError> {
+    let (action, card) = parse(payload)?;
+    let user = callbacks
+        .find_user(&settings.user_key, card)
+        .ok_or(MessageError::UnknownCard)?;
+    if user.userdata.is_suspended() {
+        return Err(MessageError::Suspended(user.id));
+    }
+    if let Some(ref allowed) = settings.allowed_users {
+        if !allowed.contains(&user.id) {
+            return Err(MessageError::NotAllowed(user.id));
+        }
+    }
+
+    // Only ever start a free machine or return one used by the card holder, so a card of an
+    // admin can't take over or free machines of others
+    let holder = UserRef::new(user.id.clone());
+    match (action, callbacks.status()) {
+        (Action::Use, Status::Free) => {}
+        (Action::Use, Status::Reserved(reserved)) if reserved == holder => {}
+        (Action::Return, Status::InUse(using)) if using == holder => {}
+        (action, current) => {
+            return Err(MessageError::Unchanged(action.as_str(), current.as_str()))
+        }
+    }
+
+    let session = callbacks.open_session_for(user);
+    tracing::debug!(action = action.as_str(), "MQTT initiator changing state");
+    match action {
+        Action::Use => callbacks.try_update(session, Status::InUse(holder)).await?,
+        // Like giving it back through the API, so machines to check after use aren't just freed
+        Action::Return => callbacks.give_back(session).await?,
+    }
+    Ok(())
+}
+
+async fn run(mut callbacks: InitiatorCallbacks, settings: Settings, messages: Receiver<Vec<u8>>) {
+    let mut limiter = LogLimiter::default();
+    while let Ok(payload) = messages.recv().await {
+        if let Err(error) = handle(&mut callbacks, &settings, &payload).await {
+            let key = error.to_string();
+            crate::log_limited!(limiter, &key, warn, %error, payload = %Truncated(
+                String::from_utf8_lossy(&payload)), "MQTT initiator ignored message");
+        }
+    }
+}
+
+/// An initiator starting and returning its machine when a card is presented to a reader
+/// publishing to `topic`
+///
+/// Payloads are `inuse <card>` to start the machine and `free <card>` to return it, with the
+/// UID of the card presented. The user is the one whose kv data holds that UID under
+/// `user_key` (default `carduid`). If `allowed_users` is set to a comma separated list of user
+/// ids, only they can use the initiator.
+///
+/// A card can only start a free machine, or one reserved for its user, and only return a machine
+/// its user is using. The change is then checked against the user's permissions like any other.
+/// Malformed messages and denied changes are logged and otherwise ignored.
+pub struct Mqtt {
+    topic: String,
+    messages: Sender<Vec<u8>>,
+    future: BoxFuture<'static, ()>,
+}
+
+impl Mqtt {
+    /// Start receiving the messages published to the configured topic
+    pub fn subscribe(self, mqtt: &MqttClient) -> miette::Result<Self> {
+        let messages = self.messages.clone();
+        let topic = self.topic.clone();
+        mqtt.subscribe(
+            self.topic.clone(),
+            Box::new(move |payload| match messages.try_send(payload.to_vec()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    tracing::warn!(%topic, "MQTT initiator is busy, dropping message")
+                }
+                // The initiator stopped, nothing to deliver to anymore
+                Err(TrySendError::Closed(_)) => {}
+            }),
+        )
+        .map_err(|error| miette!("failed to subscribe to `{}`: {}", self.topic, error))?;
+        Ok(self)
+    }
+}
+
+impl Initiator for Mqtt {
+    fn new(params: &HashMap<String, String>, callbacks: InitiatorCallbacks) -> miette::Result<Self>
+    where
+        Self: Sized,
+    {
+        let topic = params
+            .get("topic")
+            .ok_or_else(|| miette!("Mqtt initiator needs a `topic`"))?
+            .clone();
+        let settings = Settings::from_params(params);
+        let (messages, received) = async_channel::bounded(QUEUE_SIZE);
+        Ok(Self {
+            topic,
+            messages,
+            future: Box::pin(run(callbacks, settings, received)),
+        })
+    }
+}
+
+impl Future for Mqtt {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.future.as_mut().poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::Source;
+    use crate::authorization::permissions::{PermRule, PermissionBuf};
+    use crate::authorization::roles::{Role, Roles};
+    use crate::resources::modules::fabaccess::MachineState;
+    use crate::resources::state::db::StateDB;
+    use crate::resources::Resource;
+    use crate::session::SessionManager;
+    use crate::users::db::User;
+    use crate::{testing, Users};
+
+    #[test]
+    fn payload_names_action_and_card() {
+        assert_eq!(
+            parse(b"inuse 04A1B2C3\n").unwrap(),
+            (Action::Use, "04A1B2C3")
+        );
+        assert_eq!(
+            parse(b"free 04a1b2c3").unwrap(),
+            (Action::Return, "04a1b2c3")
+        );
+
+        assert!(matches!(parse(b"free"), Err(MessageError::Malformed)));
+        assert!(matches!(parse(b"inuse a b"), Err(MessageError::Malformed)));
+        assert!(matches!(
+            parse(b"disabled 04a1b2c3"),
+            Err(MessageError::Action(_))
+        ));
+        assert!(matches!(parse(&[0xff, 0xfe]), Err(MessageError::Utf8(_))));
+    }
+
+    fn setup(dir: &tempfile::TempDir) -> (Resource, InitiatorCallbacks) {
+        let env = StateDB::open_env(dir.path().join("db")).unwrap();
+        let users = Users::new(env.clone()).unwrap();
+        for (uid, card, role) in [
+            ("mqtt-alice", "04a1b2c3", "member"),
+            ("mqtt-bob", "04b0b0b0", "member"),
+            ("mqtt-eve", "04e0e0e0", "member"),
+            ("mqtt-guest", "04c0c0c0", "guest"),
+        ] {
+            let mut user = User::new_with_plain_pw(uid, "secret");
+            user.userdata.roles.push(role.to_string());
+            user.userdata
+                .kv
+                .insert(DEFAULT_USER_KEY.to_string(), card.to_string());
+            if uid == "mqtt-eve" {
+                user.userdata.suspend("testing");
+            }
+            users.put_user(uid, &user).unwrap();
+        }
+
+        let perm = PermissionBuf::from_string_unchecked("test.perm".to_string());
+        let roles = Roles::leak(HashMap::from([
+            (
+                "member".to_string(),
+                Role::new(Vec::new(), vec![PermRule::Base(perm.clone())]),
+            ),
+            ("guest".to_string(), Role::new(Vec::new(), Vec::new())),
+        ]));
+        let resource = testing::resource(env, "mqtt", testing::machine("Mqtt", &perm));
+        let sessions = SessionManager::new(users, roles, None, false);
+        let callbacks = InitiatorCallbacks::new(tracing::Span::none(), resource.clone(), sessions);
+        (resource, callbacks)
+    }
+
+    #[test]
+    fn cards_use_and_return_the_machine() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, mut callbacks) = setup(&dir);
+        let settings = Settings::from_params(&HashMap::new());
+        let mut send = |payload: &str| {
+            async_io::block_on(handle(&mut callbacks, &settings, payload.as_bytes()))
+        };
+        let status = || MachineState::from(resource.get_state().as_ref()).state;
+        let alice = Status::InUse(UserRef::new("mqtt-alice".to_string()));
+
+        assert!(matches!(send("inuse 0000"), Err(MessageError::UnknownCard)));
+        assert!(matches!(
+            send("inuse 04e0e0e0"),
+            Err(MessageError::Suspended(_))
+        ));
+        // Permissions are checked as for any other change
+        assert!(matches!(
+            send("inuse 04c0c0c0"),
+            Err(MessageError::Denied(Denied::MissingPermission))
+        ));
+        assert_eq!(status(), Status::Free);
+
+        send("inuse 04A1B2C3").unwrap();
+        assert_eq!(status(), alice);
+
+        // Nobody else can take over or return the machine
+        assert!(matches!(
+            send("inuse 04b0b0b0"),
+            Err(MessageError::Unchanged("inuse", "inuse"))
+        ));
+        assert!(matches!(
+            send("free 04b0b0b0"),
+            Err(MessageError::Unchanged("free", "inuse"))
+        ));
+        assert_eq!(status(), alice);
+
+        send("free 04a1b2c3").unwrap();
+        assert_eq!(status(), Status::Free);
+
+        // Audited as changes by the initiator, not by the users themselves
+        let recent = resource.recent_changes();
+        assert_eq!(recent.len(), 2);
+        assert!(recent
+            .iter()
+            .all(|change| change.source == Source::Initiator));
+    }
+
+    #[test]
+    fn only_allowed_users_are_accepted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, mut callbacks) = setup(&dir);
+        let settings = Settings::from_params(&HashMap::from([(
+            "allowed_users".to_string(),
+            "mqtt-alice, mqtt-guest".to_string(),
+        )]));
+
+        let result = async_io::block_on(handle(&mut callbacks, &settings, b"inuse 04b0b0b0"));
+        assert!(matches!(result, Err(MessageError::NotAllowed(uid)) if uid == "mqtt-bob"));
+        let result = async_io::block_on(handle(&mut callbacks, &settings, b"inuse 04a1b2c3"));
+        assert!(result.is_ok());
+        assert_eq!(
+            MachineState::from(resource.get_state().as_ref()).state,
+            Status::InUse(UserRef::new("mqtt-alice".to_string()))
+        );
+    }
+}
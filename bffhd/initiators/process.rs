@@ -2,6 +2,7 @@ use super::Initiator;
 use super::InitiatorCallbacks;
 use crate::resources::modules::fabaccess::Status;
 use crate::utils::linebuffer::LineBuffer;
+use crate::utils::ratelimit::LogLimiter;
 use async_process::{Child, ChildStderr, ChildStdout, Command, Stdio};
 use futures_lite::AsyncRead;
 use miette::{miette, IntoDiagnostic};
@@ -58,6 +59,7 @@ struct ProcessState {
     pub stderr: ChildStderr,
     pub stderr_closed: bool,
     pub child: Child,
+    limiter: LogLimiter,
 }
 
 impl ProcessState {
@@ -67,6 +69,7 @@ impl ProcessState {
             stderr,
             stderr_closed: false,
             child,
+            limiter: LogLimiter::default(),
         }
     }
 
@@ -102,10 +105,19 @@ impl ProcessState {
                     Ok(state) => {
                         tracing::trace!(?state, "got new state for process initiator");
                         let InputMessage::SetState(status) = state;
-                        callbacks.set_status(status);
+                        if let Err(error) = callbacks.set_status(status) {
+                            tracing::warn!(%error, "process initiator state refused");
+                        }
                     }
                     Err(error) => {
-                        tracing::warn!(%error, "process initiator did not send a valid line")
+                        let key = error.to_string();
+                        crate::log_limited!(
+                            self.limiter,
+                            &key,
+                            warn,
+                            %error,
+                            "process initiator did not send a valid line"
+                        )
                     }
                 }
             }
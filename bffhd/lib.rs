@@ -35,16 +35,27 @@ pub mod capnp;
 
 pub mod utils;
 
+pub mod verify;
+
+pub mod stress;
+
 // Store build information in the `env` module.
 shadow_rs::shadow!(env);
 
 mod audit;
 mod keylog;
 mod logging;
+mod process;
 mod session;
+mod shutdown;
+#[cfg(test)]
+mod testing;
 mod tls;
 
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures_util::{FutureExt, StreamExt};
 use once_cell::sync::OnceCell;
@@ -59,11 +70,12 @@ use crate::resources::search::ResourcesHandle;
 use crate::resources::state::db::StateDB;
 use crate::resources::Resource;
 use crate::session::SessionManager;
+use crate::shutdown::{Phase, ShutdownHandler, ShutdownSignal};
 use crate::tls::TlsConfig;
 use crate::users::db::UserDB;
+use crate::users::invites::InviteDB;
 use crate::users::Users;
 use executor::pool::Executor;
-use lightproc::recoverable_handle::RecoverableHandle;
 use signal_hook::consts::signal::*;
 use tracing::Span;
 
@@ -72,8 +84,14 @@ pub struct Difluoroborane {
     executor: Executor<'static>,
     pub statedb: StateDB,
     pub users: Users,
+    pub invites: Option<InviteDB>,
     pub roles: Roles,
     pub resources: ResourcesHandle,
+    /// Who to switch to once the API sockets are bound
+    identity: Option<process::Identity>,
+    /// Config file machines are reloaded from on `SIGHUP`
+    config_path: Option<PathBuf>,
+    metrics: console::MetricsHandle,
     span: Span,
 }
 
@@ -107,6 +125,12 @@ pub enum BFFHError {
         #[source]
         resources::state::db::StateDBError,
     ),
+    #[error("state database directory is unusable")]
+    StateDirError(
+        #[from]
+        #[source]
+        resources::state::db::StateDirError,
+    ),
     #[error("audit log failed")]
     AuditLogError(
         #[from]
@@ -127,6 +151,12 @@ pub enum BFFHError {
         #[source]
         tls::Error,
     ),
+    #[error("failed to set up process environment")]
+    ProcessSetup(
+        #[from]
+        #[source]
+        process::Error,
+    ),
     #[error("API handler failed")]
     ApiError(
         #[from]
@@ -135,11 +165,32 @@ pub enum BFFHError {
     ),
 }
 
+/// Make `resources` and `config` available to the API handlers
+///
+/// Only the first instance created in a process gets registered, e.g. when a test harness starts
+/// several servers. Later ones keep their own handles but the API keeps using the first ones.
+fn register_globals(resources: &ResourcesHandle, config: &Config) {
+    if RESOURCES.set(resources.clone()).is_err() {
+        tracing::warn!("resources already registered by another instance, API keeps using those");
+    }
+    if CONFIG.set(config.clone()).is_err() {
+        tracing::warn!("config already registered by another instance, API keeps using that one");
+    }
+}
+
 impl Difluoroborane {
     pub fn setup() {}
 
     pub fn new(config: Config) -> Result<Self, BFFHError> {
+        // Checked right away so a typo in the user name doesn't only show up once we're running
+        let identity = process::Identity::lookup(config.user.as_deref(), config.group.as_deref())?;
+        // Starting as root is fine if we drop privileges before talking to anybody
+        process::check_root(config.allow_root || identity.is_some())?;
+        // Has to happen before anything opens or creates files
+        process::apply(config.working_directory.as_deref(), config.umask)?;
+
         let mut server = logging::init(&config.logging);
+        utils::truncate::set_max_payload_log(config.max_payload_log);
         let span = tracing::info_span!(
             target: "bffh",
             "bffh"
@@ -147,12 +198,36 @@ impl Difluoroborane {
         let span2 = span.clone();
         let _guard = span2.enter();
         tracing::info!(version = env::VERSION, "Starting BFFH");
+        if let Some(ref dir) = config.working_directory {
+            tracing::info!(dir = %dir.display(), "changed working directory");
+        }
+        if process::is_root() && identity.is_none() {
+            tracing::warn!("running as root, consider using an unprivileged user instead");
+        }
 
         let executor = Executor::new();
 
         if let Some(aggregator) = server.aggregator.take() {
             executor.spawn(aggregator.run());
         }
+        // SIGUSR1 doubles and SIGUSR2 halves the console event buffer, to be able to react to
+        // dropped events without a restart
+        let event_buffer = server.event_buffer();
+        let metrics = server.metrics();
+        let mut buffer_signals = signal_hook_async_std::Signals::new(&[SIGUSR1, SIGUSR2])
+            .map_err(BFFHError::SignalsError)?;
+        executor.spawn(async move {
+            while let Some(signal) = buffer_signals.next().await {
+                let capacity = event_buffer.capacity();
+                let capacity = if signal == SIGUSR1 {
+                    capacity.saturating_mul(2)
+                } else {
+                    (capacity / 2).max(1)
+                };
+                event_buffer.resize(capacity);
+            }
+        });
+
         tracing::info!("Server is being spawned");
         let handle = executor.spawn(server.serve());
         executor.spawn(handle.map(|result| match result {
@@ -167,11 +242,25 @@ impl Difluoroborane {
             }
         }));
 
+        resources::state::db::ensure_db_dir(&config.db_path, config.create_db_dir)?;
         let env = StateDB::open_env(&config.db_path)?;
 
-        let statedb = StateDB::create_with_env(env.clone())?;
-
-        let users = Users::new(env.clone())?;
+        let statedb = StateDB::create_with_env(env.clone())?
+            .strict(config.strict_state)
+            .limits(config.state_limits);
+
+        let users = Users::new(env.clone())?
+            .with_username_policy(config.usernames.clone())
+            .with_password_policy(config.passwords.clone())
+            .with_cache(config.user_cache_size);
+        let invites = if config.self_registration {
+            Some(
+                unsafe { InviteDB::create(env.clone())? }
+                    .with_username_policy(config.usernames.clone()),
+            )
+        } else {
+            None
+        };
         let roles = Roles::new(config.roles.clone());
 
         let _audit_log = AuditLog::new(&config)?;
@@ -182,81 +271,258 @@ impl Difluoroborane {
                 statedb.clone(),
                 desc.clone(),
             )))
-        }));
-        RESOURCES.set(resources.clone()).unwrap();
-        CONFIG.set(config.clone()).unwrap();
+        }))
+        .with_groups(
+            config
+                .groups
+                .iter()
+                .map(|(id, group)| (id, &group.members, group.queue)),
+        );
+        register_globals(&resources, &config);
 
         Ok(Self {
             config,
             executor,
             statedb,
             users,
+            invites,
             roles,
             resources,
+            identity,
+            config_path: None,
+            metrics,
             span,
         })
     }
 
+    /// Counts of live and dropped tasks and task poll durations of the executor
+    ///
+    /// Only reads a few atomics, so it can be polled from any thread, e.g. by a metrics exporter.
+    pub fn console_metrics(&self) -> console::MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Number of times the device of each actor reported a state different from the one applied
+    ///
+    /// Only actors with a `desync` policy compare reported states, see [`actors::desync_counts`].
+    pub fn actor_desyncs(&self) -> BTreeMap<String, u64> {
+        actors::desync_counts()
+    }
+
+    /// Reload machines from the config file at `path` when receiving `SIGHUP`
+    ///
+    /// `path` should be absolute as the working directory may have been changed.
+    pub fn with_config_path(mut self, path: PathBuf) -> Self {
+        self.config_path = Some(path);
+        self
+    }
+
+    /// Re-read the config file and apply changes to the machines without a restart
+    ///
+    /// Only `machines` are reloaded, all other settings including actor and initiator
+    /// connections still need a restart. Returns the machines that were added so their
+    /// background tasks can be started.
+    pub fn reload_config(&mut self) -> Result<Vec<Resource>, config::ConfigError> {
+        let path = match &self.config_path {
+            Some(path) => path,
+            None => {
+                tracing::warn!("not started from a config file, nothing to reload");
+                return Ok(Vec::new());
+            }
+        };
+        let config = config::read(path)?;
+        let (reloaded, added) = self.resources.reload(&config.machines, &self.statedb);
+        tracing::info!(
+            path = %path.display(),
+            added = reloaded.added,
+            updated = reloaded.updated,
+            removed = reloaded.removed,
+            "reloaded machines"
+        );
+        self.config.machines = config.machines;
+        Ok(added)
+    }
+
     pub fn run(&mut self) -> Result<(), BFFHError> {
-        let _guard = self.span.enter();
-        let mut signals = signal_hook_async_std::Signals::new(&[SIGINT, SIGQUIT, SIGTERM])
+        let span = self.span.clone();
+        let _guard = span.enter();
+        let mut signals = signal_hook_async_std::Signals::new(&[SIGINT, SIGQUIT, SIGTERM, SIGHUP])
             .map_err(BFFHError::SignalsError)?;
 
-        let sessionmanager = SessionManager::new(self.users.clone(), self.roles.clone());
-        let authentication = AuthenticationHandle::new(self.users.clone());
+        let sessionmanager = SessionManager::new(
+            self.users.clone(),
+            self.roles.clone(),
+            self.config.max_inflight_calls,
+            self.config.read_only,
+        )
+        .with_peer_redaction(self.config.redact_session_peers)
+        .with_resumption(self.config.resumption.clone());
+        if self.config.read_only {
+            tracing::warn!("running in maintenance mode, all changes will be refused");
+        }
+        let authentication =
+            AuthenticationHandle::new(self.users.clone()).with_invites(self.invites.clone());
+
+        // Connects to the MQTT broker, which initiators subscribe to topics on as well
+        let actor_shutdown = ShutdownSignal::new();
+        let actors = actors::load(
+            self.executor.clone(),
+            &self.config,
+            self.resources.clone(),
+            &actor_shutdown,
+        )?;
 
-        initiators::load(
+        let initiators = initiators::load(
             self.executor.clone(),
             &self.config,
             self.resources.clone(),
             sessionmanager.clone(),
             authentication.clone(),
-        ).expect("initializing initiators failed");
+            &actors.client,
+        )
+        .expect("initializing initiators failed");
         // TODO 0.5: error handling. Add variant to BFFHError
 
-        actors::load(self.executor.clone(), &self.config, self.resources.clone())?;
+        let sensors = sensors::load(
+            self.executor.clone(),
+            &self.config,
+            self.resources.clone(),
+        )
+        .expect("initializing sensors failed");
+
+        let reservations = self
+            .resources
+            .list_all()
+            .into_iter()
+            .map(|resource| self.executor.spawn(resource.expire_reservations()))
+            .collect();
+
+        let resumption = self.executor.spawn(sessionmanager.reap_resumption_tokens());
+        let expiry = self.executor.spawn(sessionmanager.reap_expired_sessions());
+
+        let debounce_shutdown = ShutdownSignal::new();
+        let reload_shutdown = debounce_shutdown.clone();
+        let debounce = self
+            .resources
+            .list_all()
+            .into_iter()
+            .map(|resource| {
+                self.executor
+                    .spawn(resource.settle_changes(debounce_shutdown.clone()))
+            })
+            .collect();
 
         let tlsconfig = TlsConfig::new(self.config.tlskeylog.as_ref(), !self.config.is_quiet())?;
-        let acceptor = tlsconfig.make_tls_acceptor(&self.config.tlsconfig)?;
+        let acceptor = tlsconfig.make_reloadable_acceptor(&self.config.tlsconfig)?;
 
         let apiserver = self.executor.run(APIServer::bind(
             self.executor.clone(),
-            &self.config.listens,
-            acceptor,
+            &self.config,
+            acceptor.clone(),
             sessionmanager,
             authentication,
         ))?;
 
-        let (mut tx, rx) = async_oneshot::oneshot();
+        if let Some(identity) = &self.identity {
+            identity.switch()?;
+            tracing::info!(
+                user = %identity.name,
+                uid = %identity.uid,
+                gid = %identity.gid,
+                "dropped privileges"
+            );
+        }
 
-        self.executor.spawn(apiserver.handle_until(rx));
+        let (mut tx, rx) = async_oneshot::oneshot();
 
-        let f = async {
-            let mut sig;
-            while {
-                sig = signals.next().await;
-                sig.is_none()
-            } {}
-            tracing::info!(signal = %sig.unwrap(), "Received signal");
-            _ = tx.send(()); // ignore result, as an Err means that the executor we want to stop has already stopped
-        };
+        let api = self.executor.spawn(apiserver.handle_until(rx));
+        let drain_timeout = self
+            .config
+            .drain_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(capnp::DEFAULT_DRAIN_TIMEOUT);
+
+        // The API goes first so calls still running can finish while everything they need is up.
+        // Initiators, sensors and expiring reservations follow so nothing changes state anymore,
+        // then debounced changes are settled and actors get to apply what's still pending before
+        // the MQTT client carrying their messages is closed.
+        let statedb = self.statedb.clone();
+        let shutdown = ShutdownHandler::new()
+            .then(
+                Phase::graceful("api", vec![api], move || {
+                    // ignore result, as an Err means that the API server has already stopped
+                    _ = tx.send(());
+                })
+                .with_timeout(drain_timeout + shutdown::DEFAULT_TIMEOUT),
+            )
+            .then(Phase::cancel("initiators", initiators))
+            .then(Phase::cancel("sensors", sensors))
+            .then(Phase::cancel("reservations", reservations))
+            .then(Phase::cancel("resumption", vec![resumption]))
+            .then(Phase::cancel("session expiry", vec![expiry]))
+            .then(Phase::graceful("debounce", debounce, move || {
+                debounce_shutdown.trigger()
+            }))
+            .then(Phase::graceful("actors", actors.actors, move || {
+                actor_shutdown.trigger()
+            }))
+            .then(Phase::cancel("mqtt", vec![actors.mqtt]))
+            .then(Phase::graceful("statedb", Vec::new(), move || {
+                if let Err(error) = statedb.sync() {
+                    tracing::error!(%error, "failed to flush state database");
+                }
+            }));
+
+        // SIGHUP reloads the machines and the TLS certificate, any other signal stops bffh
+        let executor = self.executor.clone();
+        loop {
+            match executor.run(signals.next()) {
+                Some(SIGHUP) => {
+                    match self.reload_config() {
+                        Ok(added) => {
+                            for resource in added {
+                                executor.spawn(resource.clone().expire_reservations());
+                                executor.spawn(resource.settle_changes(reload_shutdown.clone()));
+                            }
+                        }
+                        Err(error) => tracing::error!(
+                            %error,
+                            "failed to reload config, keeping the current one"
+                        ),
+                    }
+                    // New connections get the new certificate, open ones keep theirs
+                    match acceptor.reload() {
+                        Ok(()) => tracing::info!("reloaded TLS certificate"),
+                        Err(error) => tracing::error!(
+                            %error,
+                            "failed to reload TLS certificate, keeping the current one"
+                        ),
+                    }
+                }
+                Some(signal) => {
+                    tracing::info!(%signal, "Received signal");
+                    break;
+                }
+                None => {}
+            }
+        }
 
-        self.executor.run(f);
+        self.executor.run(shutdown.shutdown());
         Ok(())
     }
 }
 
-struct ShutdownHandler {
-    tasks: Vec<RecoverableHandle<()>>,
-}
-impl ShutdownHandler {
-    pub fn new(tasks: Vec<RecoverableHandle<()>>) -> Self {
-        Self { tasks }
-    }
-
-    pub fn shutdown(&mut self) {
-        for handle in self.tasks.drain(..) {
-            handle.cancel()
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registering_twice_does_not_panic() {
+        let first = ResourcesHandle::new(std::iter::empty());
+        register_globals(&first, &Config::default());
+        let second = ResourcesHandle::new(std::iter::empty());
+        register_globals(&second, &Config::default());
+        assert!(RESOURCES.get().is_some());
+        assert!(CONFIG.get().is_some());
     }
 }
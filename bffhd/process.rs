@@ -0,0 +1,317 @@
+//! Process-wide settings applied at startup before any file is opened
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use miette::Diagnostic;
+use nix::sys::stat::Mode;
+use nix::unistd::{AccessFlags, Gid, Group, Uid, User};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// File mode creation mask
+///
+/// Written as an octal string in the config, e.g. `"027"`, since dhall has no octal literals.
+pub struct Umask(u32);
+
+impl Umask {
+    pub fn new(mask: u32) -> Option<Self> {
+        if mask <= 0o777 {
+            Some(Self(mask))
+        } else {
+            None
+        }
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+}
+
+impl fmt::Display for Umask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:03o}", self.0)
+    }
+}
+
+impl Serialize for Umask {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Umask {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        u32::from_str_radix(&s, 8)
+            .ok()
+            .and_then(Umask::new)
+            .ok_or_else(|| {
+                serde::de::Error::custom(format!("'{}' is not a valid octal umask", s))
+            })
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+    #[error("working directory '{}' does not exist or is not a directory", .0.display())]
+    #[diagnostic(code(bffh::process::workdir::missing))]
+    NotADirectory(PathBuf),
+    #[error("working directory '{}' is not writable", .path.display())]
+    #[diagnostic(
+        code(bffh::process::workdir::permissions),
+        help("Make sure the directory is writable by the user running bffh")
+    )]
+    NotWritable {
+        path: PathBuf,
+        #[source]
+        source: nix::Error,
+    },
+    #[error("failed to change into working directory '{}'", .path.display())]
+    #[diagnostic(code(bffh::process::workdir::chdir))]
+    Chdir {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("refusing to run as root")]
+    #[diagnostic(
+        code(bffh::process::root),
+        help("Run bffhd as an unprivileged user, or set `allow_root = True` in the config if you really have to")
+    )]
+    RunningAsRoot,
+    #[error("user '{0}' to switch to does not exist")]
+    #[diagnostic(code(bffh::process::user::unknown))]
+    UnknownUser(String),
+    #[error("group '{0}' to switch to does not exist")]
+    #[diagnostic(code(bffh::process::group::unknown))]
+    UnknownGroup(String),
+    #[error("a group to switch to was configured without a user")]
+    #[diagnostic(
+        code(bffh::process::group::without_user),
+        help("Set `user` as well, the group on its own is not used")
+    )]
+    GroupWithoutUser,
+    #[error("failed to look up user or group")]
+    #[diagnostic(code(bffh::process::lookup))]
+    Lookup(#[source] nix::Error),
+    #[error("failed to switch to user '{user}'")]
+    #[diagnostic(
+        code(bffh::process::switch),
+        help("Dropping privileges requires bffhd to be started as root")
+    )]
+    Switch {
+        user: String,
+        #[source]
+        source: nix::Error,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Unprivileged user and group to switch to once all privileged setup is done
+pub struct Identity {
+    pub name: String,
+    pub uid: Uid,
+    pub gid: Gid,
+}
+
+impl Identity {
+    /// Look up `user` and `group`, falling back to the primary group of `user`
+    pub fn lookup(user: Option<&str>, group: Option<&str>) -> Result<Option<Self>, Error> {
+        let name = match (user, group) {
+            (Some(user), _) => user,
+            (None, Some(_)) => return Err(Error::GroupWithoutUser),
+            (None, None) => return Ok(None),
+        };
+        let user = User::from_name(name)
+            .map_err(Error::Lookup)?
+            .ok_or_else(|| Error::UnknownUser(name.to_string()))?;
+        let gid = match group {
+            Some(group) => {
+                Group::from_name(group)
+                    .map_err(Error::Lookup)?
+                    .ok_or_else(|| Error::UnknownGroup(group.to_string()))?
+                    .gid
+            }
+            None => user.gid,
+        };
+        Ok(Some(Self {
+            name: user.name,
+            uid: user.uid,
+            gid,
+        }))
+    }
+
+    /// Switch the whole process to this user and group
+    ///
+    /// Supplementary groups are dropped first and the gid is changed before the uid, since
+    /// changing the gid is no longer permitted once the uid isn't root anymore.
+    pub fn switch(&self) -> Result<(), Error> {
+        let switch = || -> nix::Result<()> {
+            nix::unistd::setgroups(&[self.gid])?;
+            nix::unistd::setgid(self.gid)?;
+            nix::unistd::setuid(self.uid)
+        };
+        switch().map_err(|source| Error::Switch {
+            user: self.name.clone(),
+            source,
+        })
+    }
+}
+
+/// Whether the process runs with root privileges. Always false on non-Unix platforms.
+pub fn is_root() -> bool {
+    #[cfg(unix)]
+    return nix::unistd::geteuid().is_root();
+    #[cfg(not(unix))]
+    return false;
+}
+
+/// Refuse to start as root unless `allow_root` is set
+pub fn check_root(allow_root: bool) -> Result<(), Error> {
+    check_privileges(is_root(), allow_root)
+}
+
+fn check_privileges(root: bool, allow_root: bool) -> Result<(), Error> {
+    if root && !allow_root {
+        Err(Error::RunningAsRoot)
+    } else {
+        Ok(())
+    }
+}
+
+/// Set the umask and working directory of the process
+///
+/// The working directory is checked to exist and be writable before changing into it.
+pub fn apply(workdir: Option<&Path>, umask: Option<Umask>) -> Result<(), Error> {
+    if let Some(path) = workdir {
+        if !path.is_dir() {
+            return Err(Error::NotADirectory(path.to_path_buf()));
+        }
+        nix::unistd::access(path, AccessFlags::W_OK | AccessFlags::X_OK).map_err(|source| {
+            Error::NotWritable {
+                path: path.to_path_buf(),
+                source,
+            }
+        })?;
+        std::env::set_current_dir(path).map_err(|source| Error::Chdir {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    }
+
+    if let Some(umask) = umask {
+        set_umask(umask);
+    }
+
+    Ok(())
+}
+
+/// Set the umask, returning the previous one
+fn set_umask(umask: Umask) -> Umask {
+    let old = nix::sys::stat::umask(Mode::from_bits_truncate(umask.0 as nix::libc::mode_t));
+    Umask(old.bits() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn umask_parses_octal() {
+        let umask: Umask = serde_json::from_str("\"027\"").unwrap();
+        assert_eq!(umask.bits(), 0o027);
+        assert_eq!(serde_json::to_string(&umask).unwrap(), "\"027\"");
+        assert!(serde_json::from_str::<Umask>("\"089\"").is_err());
+        assert!(serde_json::from_str::<Umask>("\"1777\"").is_err());
+    }
+
+    /// Directory the child process of [`created_files_honor_umask`] creates its file in
+    const UMASK_CHILD_DIR: &str = "BFFH_TEST_UMASK_DIR";
+
+    #[test]
+    fn created_files_honor_umask() {
+        if let Some(dir) = std::env::var_os(UMASK_CHILD_DIR) {
+            apply(None, Some(Umask(0o077))).unwrap();
+            std::fs::File::create(Path::new(&dir).join("audit.log")).unwrap();
+            return;
+        }
+
+        // The umask is shared by the whole process, so it's changed in a child running only this
+        // test instead of under the feet of tests creating files concurrently
+        let dir = tempfile::tempdir().unwrap();
+        let (_, module) = module_path!().split_once("::").unwrap();
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", &format!("{}::created_files_honor_umask", module)])
+            .env(UMASK_CHILD_DIR, dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let file = std::fs::metadata(dir.path().join("audit.log")).unwrap();
+        assert_eq!(file.permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn root_is_refused_unless_allowed() {
+        assert!(matches!(
+            check_privileges(true, false),
+            Err(Error::RunningAsRoot)
+        ));
+        assert!(check_privileges(true, true).is_ok());
+        assert!(check_privileges(false, false).is_ok());
+    }
+
+    #[test]
+    fn unknown_user_is_rejected() {
+        assert!(matches!(
+            Identity::lookup(Some("bffh-no-such-user"), None),
+            Err(Error::UnknownUser(_))
+        ));
+        assert!(matches!(
+            Identity::lookup(None, Some("nogroup")),
+            Err(Error::GroupWithoutUser)
+        ));
+        assert_eq!(Identity::lookup(None, None).unwrap(), None);
+    }
+
+    #[test]
+    fn switching_user_changes_effective_uid() {
+        use nix::sys::wait::{waitpid, WaitStatus};
+        use nix::unistd::ForkResult;
+
+        // Only root may switch users
+        if !is_root() {
+            return;
+        }
+        let identity = Identity::lookup(Some("nobody"), None).unwrap().unwrap();
+
+        // Switching affects the whole process, so it happens in a child to not break other tests
+        match unsafe { nix::unistd::fork() }.unwrap() {
+            ForkResult::Child => {
+                let switched = identity.switch().is_ok()
+                    && nix::unistd::geteuid() == identity.uid
+                    && nix::unistd::getegid() == identity.gid
+                    && nix::unistd::getgroups().map_or(false, |groups| {
+                        groups.iter().all(|gid| *gid == identity.gid)
+                    });
+                unsafe { nix::libc::_exit(if switched { 0 } else { 1 }) }
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn missing_workdir_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        assert!(matches!(
+            apply(Some(&missing), None),
+            Err(Error::NotADirectory(_))
+        ));
+    }
+}
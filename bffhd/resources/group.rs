@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::resources::modules::fabaccess::Status;
+use crate::resources::{Denied, Resource};
+use crate::session::SessionHandle;
+use crate::users::UserRef;
+
+/// Result of requesting a group of resources
+#[derive(Debug)]
+pub enum GroupRequest {
+    /// A free member was found and is now in use by the requesting user
+    Assigned(Resource),
+    /// No member could be assigned, the user is waiting at the given position (starting at 1)
+    Queued(usize),
+}
+
+#[derive(Debug, Default)]
+/// First come, first served list of users waiting for a machine or any member of a group
+pub struct WaitQueue {
+    waiting: VecDeque<UserRef>,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decide if `user` may take one of `available` free members right now
+    ///
+    /// The first `available` users in the queue may, so a user that doesn't come back for their
+    /// turn only holds up one member. Users that can't are added to the end of the queue if they
+    /// aren't waiting already, and their position is returned. Admitted users leave the queue.
+    pub fn admit(&mut self, user: &UserRef, available: usize) -> Result<(), usize> {
+        let admitted = match self.position(user) {
+            Some(position) => position <= available,
+            None => self.waiting.len() < available,
+        };
+        if admitted {
+            self.leave(user);
+            return Ok(());
+        }
+
+        Err(self.join(user))
+    }
+
+    /// Add `user` to the end of the queue unless they are waiting already, returning their position
+    pub fn join(&mut self, user: &UserRef) -> usize {
+        self.position(user).unwrap_or_else(|| {
+            self.waiting.push_back(user.clone());
+            self.waiting.len()
+        })
+    }
+
+    /// Take `user` out of the queue, e.g. because they got what they waited for elsewhere
+    pub fn leave(&mut self, user: &UserRef) {
+        self.waiting.retain(|waiting| waiting != user);
+    }
+
+    /// Position of `user` in the queue, starting at 1
+    pub fn position(&self, user: &UserRef) -> Option<usize> {
+        self.waiting
+            .iter()
+            .position(|waiting| waiting == user)
+            .map(|idx| idx + 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+}
+
+#[derive(Clone, Debug)]
+/// A pool of interchangeable resources, e.g. "any of three identical drills"
+///
+/// Requesting the group puts the requesting user onto the first free member they are allowed to
+/// use. Whether users are queued or denied if no member is free depends on the configuration.
+pub struct ResourceGroup {
+    id: String,
+    members: Vec<Resource>,
+    queue: Option<Arc<Mutex<WaitQueue>>>,
+}
+
+impl ResourceGroup {
+    pub fn new(id: String, members: Vec<Resource>, queue: bool) -> Self {
+        let queue = if queue {
+            Some(Arc::new(Mutex::new(WaitQueue::new())))
+        } else {
+            None
+        };
+        Self { id, members, queue }
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn members(&self) -> &[Resource] {
+        &self.members
+    }
+
+    /// A group is busy if none of its members are free
+    pub fn is_busy(&self) -> bool {
+        !self.members.iter().any(Resource::is_free)
+    }
+
+    /// Put the user of `session` on a free member they may use
+    ///
+    /// The queue stays locked while a member is chosen and started, so concurrent requests can't
+    /// overtake queued users. A member taken by somebody else in the meantime is skipped.
+    pub async fn request(&self, session: SessionHandle) -> Result<GroupRequest, Denied> {
+        let user = session.get_user_ref();
+        let usable: Vec<&Resource> = self
+            .members
+            .iter()
+            .filter(|member| session.has_write(member))
+            .collect();
+        if usable.is_empty() {
+            return Err(Denied::MissingPermission);
+        }
+
+        let mut queue = self.queue.as_ref().map(|queue| queue.lock().unwrap());
+        let free: Vec<&Resource> = usable.into_iter().filter(|m| m.is_free()).collect();
+        if let Some(ref mut queue) = queue {
+            if let Err(position) = queue.admit(&user, free.len()) {
+                tracing::debug!(group = %self.id, %user.id, position, "queued for group");
+                return Ok(GroupRequest::Queued(position));
+            }
+        }
+
+        let mut denied = Denied::Busy;
+        for member in free {
+            match member.try_transition(session.clone(), Status::InUse(user.clone()), None, None) {
+                Ok(()) => return Ok(GroupRequest::Assigned(member.clone())),
+                Err(Denied::Busy | Denied::Queued(_)) => continue,
+                Err(Denied::Maintenance(maintenance)) => return Err(maintenance.into()),
+                Err(reason) => denied = reason,
+            }
+        }
+        match queue {
+            // Every member was taken before the user got to it, they keep waiting at the front
+            Some(ref mut queue) if matches!(denied, Denied::Busy) => {
+                queue.waiting.push_front(user);
+                Ok(GroupRequest::Queued(1))
+            }
+            _ => Err(denied),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_member_is_assigned_and_busy_group_queues() {
+        let a = UserRef::new("a".to_string());
+        let b = UserRef::new("b".to_string());
+        let mut queue = WaitQueue::new();
+
+        // One member free: the first request gets it
+        assert_eq!(queue.admit(&a, 1), Ok(()));
+        // None free anymore: the second request is queued
+        assert_eq!(queue.admit(&b, 0), Err(1));
+        assert_eq!(queue.admit(&b, 0), Err(1));
+        assert_eq!(queue.position(&b), Some(1));
+    }
+
+    #[test]
+    fn queued_users_are_served_in_order() {
+        let a = UserRef::new("a".to_string());
+        let b = UserRef::new("b".to_string());
+        let c = UserRef::new("c".to_string());
+        let mut queue = WaitQueue::new();
+
+        assert_eq!(queue.admit(&a, 0), Err(1));
+        assert_eq!(queue.admit(&b, 0), Err(2));
+
+        // A member freed up, but `b` has to wait for `a`
+        assert_eq!(queue.admit(&b, 1), Err(2));
+        assert_eq!(queue.admit(&c, 1), Err(3));
+        assert_eq!(queue.admit(&a, 1), Ok(()));
+        assert_eq!(queue.admit(&b, 1), Ok(()));
+        assert_eq!(queue.position(&c), Some(1));
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn absent_users_only_hold_up_one_member() {
+        let a = UserRef::new("a".to_string());
+        let b = UserRef::new("b".to_string());
+        let mut queue = WaitQueue::new();
+
+        assert_eq!(queue.admit(&a, 0), Err(1));
+        assert_eq!(queue.admit(&b, 0), Err(2));
+
+        // `a` doesn't come back, with a second member free `b` still gets one
+        assert_eq!(queue.admit(&b, 1), Err(2));
+        assert_eq!(queue.admit(&b, 2), Ok(()));
+        assert_eq!(queue.position(&a), Some(1));
+    }
+}
@@ -1,16 +1,23 @@
-use futures_signals::signal::{Mutable, Signal};
+use async_io::Timer;
+use futures_lite::FutureExt;
+use futures_signals::signal::{Mutable, Signal, SignalExt};
+use futures_util::StreamExt;
 use rkyv::Infallible;
+use std::collections::VecDeque;
 use std::ops::Deref;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::Duration;
 
-use crate::audit::AUDIT;
+use crate::audit::{Source, AUDIT};
 use crate::authorization::permissions::PrivilegesBuf;
 use crate::config::MachineDescription;
 use crate::db::ArchivedValue;
+use crate::resources::group::WaitQueue;
 use crate::resources::modules::fabaccess::{ArchivedStatus, MachineState, Status};
 use crate::resources::state::db::StateDB;
-use crate::resources::state::State;
-use crate::session::SessionHandle;
+use crate::resources::state::{State, StateLimitError};
+use crate::session::{Inbox, Maintenance, Notification, SessionHandle};
+use crate::shutdown::ShutdownSignal;
 use crate::users::UserRef;
 use rkyv::option::ArchivedOption;
 use rkyv::ser::serializers::AllocSerializer;
@@ -18,23 +25,180 @@ use rkyv::ser::Serializer;
 use rkyv::{Archived, Deserialize};
 
 pub mod db;
+pub mod group;
 pub mod search;
 pub mod state;
 
 pub mod modules;
 
-pub struct PermissionDenied;
+/// Reason an update of a resource state was denied
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Denied {
+    #[error("missing permission to use this machine")]
+    MissingPermission,
+    /// Missing permission to start the machine, explained by its configured `denied_message`
+    #[error("{0}")]
+    MissingPermissionMessage(String),
+    #[error("machine is currently in use by somebody else")]
+    Busy,
+    /// The machine is free but kept for the users queued for it first
+    #[error("machine is kept for the users queued before you, you are at position {0}")]
+    Queued(usize),
+    #[error("machine can not be changed to the requested state")]
+    IllegalTransition,
+    #[error(transparent)]
+    Maintenance(#[from] Maintenance),
+    #[error("machine can only be used while a supervisor is present")]
+    NoSupervisor,
+    #[error("a note explaining what needs to be checked is required")]
+    NoteRequired,
+    #[error("missing or expired training `{0}` required to use this machine")]
+    MissingTraining(String),
+    #[error(transparent)]
+    Limits(#[from] StateLimitError),
+    #[error("machine has to be marked as to be checked when returning it")]
+    CheckRequired,
+}
+
+/// Decide if `user` may move a resource from the state `old` into `new`
+///
+/// Machines with `check_on_return` can only be returned by marking them as to be checked, unless
+/// by a manager.
+fn check_transition(
+    old: &ArchivedStatus,
+    new: &Status,
+    user: &UserRef,
+    has_manage: bool,
+    has_write: bool,
+    check_on_return: bool,
+) -> Result<(), Denied> {
+    // Default allow for managers
+    if has_manage {
+        return Ok(());
+    }
+
+    // Default permissions everybody has
+    match (old, new) {
+        (ArchivedStatus::InUse(who), Status::Free) if who == user && check_on_return => {
+            return Err(Denied::CheckRequired)
+        }
+
+        // Returning things we've been using is okay. This includes both if
+        // they're being freed or marked as to be checked.
+        (ArchivedStatus::InUse(who), Status::Free | Status::ToCheck(_)) if who == user => {
+            return Ok(())
+        }
+
+        // Un-reserving things we reserved is okay
+        (ArchivedStatus::Reserved(whom), Status::Free) if whom == user => return Ok(()),
+
+        // Signing off a check is what the manage privilege is for
+        (ArchivedStatus::ToCheck(_), Status::Free) => return Err(Denied::MissingPermission),
+
+        _ => {}
+    }
+
+    if !has_write {
+        return Err(Denied::MissingPermission);
+    }
+
+    // Decision tree for writers
+    match (old, new) {
+        // Going from available to used by the person requesting is okay.
+        // Check that the person requesting does not request for somebody else.
+        // *That* is manage privilege.
+        (ArchivedStatus::Free, Status::InUse(who)) if who == user => Ok(()),
+
+        // Reserving things for ourself is okay.
+        (ArchivedStatus::Free, Status::Reserved(whom)) if user == whom => Ok(()),
+
+        // Using things that we've reserved is okay. But the person requesting
+        // that has to be the person that reserved the machine. Otherwise
+        // somebody could make a machine reserved by a different user as used by
+        // that different user but use it themself.
+        (ArchivedStatus::Reserved(whom), Status::InUse(who)) if whom == user && who == user => {
+            Ok(())
+        }
+
+        (ArchivedStatus::InUse(other) | ArchivedStatus::Reserved(other), _) if other != user => {
+            Err(Denied::Busy)
+        }
+
+        // Default is deny.
+        _ => Err(Denied::IllegalTransition),
+    }
+}
+
+/// The state after changing from `old` to `state`, ending at `until` if it is a reservation
+fn transitioned(
+    old: &MachineState,
+    state: Status,
+    reason: Option<String>,
+    until: Option<i64>,
+) -> MachineState {
+    let reserving = matches!(state, Status::Reserved(_));
+    let new = old.transition(state, reason);
+    match (reserving, until) {
+        (true, Some(until)) => new.expiring(until),
+        _ => new,
+    }
+}
+
+/// Number of state changes kept in memory per machine
+const RECENT_CHANGES: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A state change of a machine, kept in memory to show recent activity
+pub struct Change {
+    /// Unix timestamp in seconds, like in the audit log
+    pub timestamp: i64,
+    /// The new state, including the user it refers to
+    pub state: MachineState,
+    pub source: Source,
+}
+
+#[derive(Debug, Clone)]
+/// State of a machine together with its description, including what it takes to use it, all as of
+/// the same instant
+pub struct Snapshot {
+    pub state: ArchivedValue<State>,
+    pub description: Arc<MachineDescription>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Who has a machine and who is waiting for it, as far as the asking session may know
+pub struct QueueStatus {
+    /// User currently holding the machine, only told to sessions that may read its state
+    pub holder: Option<UserRef>,
+    /// Position of the asking user in the queue, starting at 1, if they are queued
+    pub position: Option<usize>,
+    /// Number of users waiting for the machine
+    pub length: usize,
+}
 
 #[derive(Debug)]
 pub(crate) struct Inner {
     id: String,
     db: StateDB,
     signal: Mutable<ArchivedValue<State>>,
-    desc: MachineDescription,
+    /// Replaced in place when the config is reloaded, see [`Inner::set_description`]
+    desc: RwLock<Arc<MachineDescription>>,
+    /// The last [`RECENT_CHANGES`] changes, oldest first
+    recent: Mutex<VecDeque<Change>>,
+    /// Sessions to notify when the machine becomes free
+    watchers: Mutex<Vec<Weak<Inbox>>>,
+    /// Users waiting for the machine, first come first served. Only kept in memory.
+    queue: Mutex<WaitQueue>,
+    /// Time changes have to stay unchanged for before they are applied
+    debounce: Option<Duration>,
+    /// Latest change not applied yet because the debounce window is still running
+    pending: Mutable<Option<(ArchivedValue<State>, Source)>>,
+    /// Held while deciding on and making a change, so no other change slips in between
+    updating: Mutex<()>,
 }
 impl Inner {
     pub fn new(id: String, db: StateDB, desc: MachineDescription) -> Self {
-        let state = if let Some(previous) = db.get(id.as_bytes()).unwrap() {
+        let state = if let Some(previous) = db.load(id.as_bytes()).unwrap() {
             tracing::info!(%id, ?previous, "Found previous state");
             previous
         } else {
@@ -52,20 +216,50 @@ impl Inner {
             val
         };
         let signal = Mutable::new(state);
+        let debounce = desc.debounce_ms.map(Duration::from_millis);
 
         Self {
             id,
             db,
             signal,
-            desc,
+            desc: RwLock::new(Arc::new(desc)),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_CHANGES)),
+            watchers: Mutex::new(Vec::new()),
+            queue: Mutex::new(WaitQueue::new()),
+            debounce,
+            pending: Mutable::new(None),
+            updating: Mutex::new(()),
         }
     }
 
+    fn desc(&self) -> Arc<MachineDescription> {
+        self.desc.read().unwrap().clone()
+    }
+
+    /// Replace the description, e.g. after the config was reloaded
+    ///
+    /// Everything holding this machine sees the new description right away. The debounce window
+    /// is kept because the task settling changes is already running with it.
+    fn set_description(&self, desc: MachineDescription) {
+        // Taken so no snapshot is read while the description changes
+        let _state = self.signal.lock_mut();
+        *self.desc.write().unwrap() = Arc::new(desc);
+    }
+
     pub fn signal(&self) -> impl Signal<Item = ArchivedValue<State>> {
         Box::pin(self.signal.signal_cloned())
     }
 
+    /// The state further changes build on, including a change still being debounced
     fn get_state(&self) -> ArchivedValue<State> {
+        if let Some((state, _)) = &*self.pending.lock_ref() {
+            return state.clone();
+        }
+        self.get_applied()
+    }
+
+    /// The state last applied, i.e. stored and sent to the actors
+    fn get_applied(&self) -> ArchivedValue<State> {
         self.db
             .get(self.id.as_bytes())
             .expect("lmdb error")
@@ -76,11 +270,42 @@ impl Inner {
         self.signal.lock_ref()
     }
 
-    fn set_state(&self, state: ArchivedValue<State>) {
-        let span = tracing::debug_span!("set_state", id = %self.id, ?state);
+    fn set_state(&self, state: ArchivedValue<State>, source: Source) -> Result<(), Denied> {
+        if let Err(error) = self.db.check_limits(&state) {
+            tracing::error!(id = %self.id, ?source, %error, "refusing state change");
+            return Err(error.into());
+        }
+        if self.debounce.is_some() {
+            tracing::trace!(id = %self.id, ?state, ?source, "debouncing state change");
+            self.pending.set(Some((state, source)));
+        } else {
+            self.apply(state, source);
+        }
+        Ok(())
+    }
+
+    /// Apply the change still being debounced, unless it ends up where the machine already is
+    fn settle(&self) {
+        if let Some((state, source)) = self.pending.replace(None) {
+            let applied = self.get_applied();
+            if MachineState::from(state.as_ref()) == MachineState::from(applied.as_ref()) {
+                tracing::debug!(id = %self.id, "state flapped back, nothing to apply");
+            } else {
+                self.apply(state, source);
+            }
+        }
+    }
+
+    fn apply(&self, state: ArchivedValue<State>, source: Source) {
+        let span = tracing::debug_span!("set_state", id = %self.id, ?state, ?source);
         let _guard = span.enter();
         tracing::debug!("Updating state");
 
+        let old = self.get_applied();
+        let was_free = matches!(old.as_ref().inner.state, ArchivedStatus::Free);
+        let is_free = matches!(state.as_ref().inner.state, ArchivedStatus::Free);
+        let starting = was_free && matches!(state.as_ref().inner.state, ArchivedStatus::InUse(_));
+
         tracing::trace!("Updating DB");
         self.db.put(&self.id.as_bytes(), &state).unwrap();
         tracing::trace!("Updated DB, sending update signal");
@@ -88,13 +313,113 @@ impl Inner {
         let res = AUDIT
             .get()
             .unwrap()
-            .log(self.id.as_str(), &format!("{}", state));
+            .log(self.id.as_str(), &state.as_ref().inner, source);
         if let Err(e) = res {
             tracing::error!("Writing to the audit log failed for {} {}: {e}", self.id.as_str(), state);
         }
 
+        self.remember(MachineState::from(state.as_ref()), source);
         self.signal.set(state);
         tracing::trace!("Sent update signal");
+
+        if let ArchivedStatus::InUse(user) | ArchivedStatus::Reserved(user) =
+            &self.get_state_ref().as_ref().inner.state
+        {
+            // Whoever got the machine doesn't have to wait for it anymore
+            let user = Deserialize::<UserRef, _>::deserialize(user, &mut Infallible).unwrap();
+            self.leave_queue(&user);
+        }
+        if starting {
+            self.count_use();
+        }
+        if !was_free && is_free {
+            self.announce_free();
+        }
+    }
+
+    fn remember(&self, state: MachineState, source: Source) {
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_CHANGES {
+            recent.pop_front();
+        }
+        recent.push_back(Change {
+            timestamp: chrono::Utc::now().timestamp(),
+            state,
+            source,
+        });
+    }
+
+    fn recent_changes(&self) -> Vec<Change> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn watch(&self, inbox: Weak<Inbox>) {
+        let mut watchers = self.watchers.lock().unwrap();
+        if !watchers.iter().any(|watcher| watcher.ptr_eq(&inbox)) {
+            watchers.push(inbox);
+        }
+    }
+
+    fn unwatch(&self, inbox: &Weak<Inbox>) {
+        self.watchers
+            .lock()
+            .unwrap()
+            .retain(|watcher| !watcher.ptr_eq(inbox));
+    }
+
+    /// Append `user` to the queue unless they are waiting already, returning their position
+    fn enqueue(&self, user: &UserRef) -> usize {
+        self.queue.lock().unwrap().join(user)
+    }
+
+    fn leave_queue(&self, user: &UserRef) {
+        self.queue.lock().unwrap().leave(user);
+    }
+
+    /// Position of `user` in the queue starting at 1 and the length of the queue
+    fn queue_position(&self, user: &UserRef) -> (Option<usize>, usize) {
+        let queue = self.queue.lock().unwrap();
+        (queue.position(user), queue.len())
+    }
+
+    /// Tell all watching sessions that the machine is free now, if configured to do so
+    fn announce_free(&self) {
+        if !self.desc().announce_on_free {
+            return;
+        }
+        let mut watchers = self.watchers.lock().unwrap();
+        // Sessions that were closed in the meantime are forgotten
+        watchers.retain(|watcher| match watcher.upgrade() {
+            Some(inbox) => {
+                inbox.push(Notification::MachineFree {
+                    id: self.id.clone(),
+                });
+                true
+            }
+            None => false,
+        });
+        tracing::debug!(id = %self.id, watchers = watchers.len(), "announced machine is free");
+    }
+
+    fn get_usage(&self) -> u64 {
+        self.db.get_usage(self.id.as_bytes()).expect("lmdb error")
+    }
+
+    fn reset_usage(&self) {
+        self.db.reset_usage(self.id.as_bytes()).expect("lmdb error")
+    }
+
+    fn count_use(&self) {
+        match self.db.count_use(self.id.as_bytes()) {
+            Ok(count) => tracing::trace!(id = %self.id, count, "counted use"),
+            Err(error) => tracing::error!(id = %self.id, %error, "failed to count use"),
+        }
+    }
+
+    /// Send the current state to everybody watching again without changing it
+    fn reapply(&self) {
+        tracing::debug!(id = %self.id, "re-applying current state");
+        self.signal.set(self.get_applied());
     }
 }
 
@@ -103,11 +428,28 @@ pub struct Resource {
     inner: Arc<Inner>,
 }
 
+#[derive(Clone, Debug)]
+/// Handle to a resource that doesn't keep it alive, e.g. for long running subscriptions
+pub struct WeakResource {
+    inner: Weak<Inner>,
+}
+
+impl WeakResource {
+    pub fn upgrade(&self) -> Option<Resource> {
+        self.inner.upgrade().map(Resource::new)
+    }
+}
+
 impl Resource {
     pub(crate) fn new(inner: Arc<Inner>) -> Self {
         Self { inner }
     }
 
+    /// Replace the description of this machine, see [`Inner::set_description`]
+    pub(crate) fn set_description(&self, desc: MachineDescription) {
+        self.inner.set_description(desc)
+    }
+
     pub fn get_state(&self) -> ArchivedValue<State> {
         self.inner.get_state()
     }
@@ -116,24 +458,44 @@ impl Resource {
         self.inner.get_state_ref()
     }
 
+    pub fn downgrade(&self) -> WeakResource {
+        WeakResource {
+            inner: Arc::downgrade(&self.inner),
+        }
+    }
+
     pub fn get_id(&self) -> &str {
         &self.inner.id
     }
 
-    pub fn get_name(&self) -> &str {
-        self.inner.desc.name.as_str()
+    pub fn get_name(&self) -> String {
+        self.inner.desc().name.clone()
     }
 
     pub fn get_signal(&self) -> impl Signal<Item = ArchivedValue<State>> {
         self.inner.signal()
     }
 
-    pub fn get_required_privs(&self) -> &PrivilegesBuf {
-        &self.inner.desc.privs
+    pub fn get_required_privs(&self) -> PrivilegesBuf {
+        self.inner.desc().privs.clone()
     }
 
-    pub fn get_description(&self) -> &MachineDescription {
-        &self.inner.desc
+    pub fn get_description(&self) -> Arc<MachineDescription> {
+        self.inner.desc()
+    }
+
+    /// State and description of the machine, read together
+    ///
+    /// Both are read under the state lock, which changes to either take as well. Unlike calling
+    /// [`Resource::get_state`] and the other getters one after the other this can't mix a state
+    /// from before a concurrent update with data from after it.
+    pub fn snapshot(&self) -> Snapshot {
+        let state = self.inner.get_state_ref();
+        let description = self.inner.desc();
+        Snapshot {
+            state: state.clone(),
+            description,
+        }
     }
 
     pub fn get_current_user(&self) -> Option<UserRef> {
@@ -151,6 +513,12 @@ impl Resource {
         }
     }
 
+    pub fn is_free(&self) -> bool {
+        let state = self.get_state_ref();
+        let state: &Archived<State> = state.as_ref();
+        matches!(state.inner.state, ArchivedStatus::Free)
+    }
+
     pub fn get_previous_user(&self) -> Option<UserRef> {
         let state = self.get_state_ref();
         let state: &Archived<State> = state.as_ref();
@@ -162,93 +530,446 @@ impl Resource {
         }
     }
 
-    fn set_state(&self, state: MachineState) {
+    /// Reason given for the current state, e.g. why the machine was disabled
+    pub fn get_reason(&self) -> Option<String> {
+        let state = self.get_state_ref();
+        let state: &Archived<State> = state.as_ref();
+        if let ArchivedOption::Some(reason) = &state.inner.reason {
+            Some(reason.to_string())
+        } else {
+            None
+        }
+    }
+
+    fn set_state(&self, state: MachineState, source: Source) -> Result<(), Denied> {
         let mut serializer = AllocSerializer::<1024>::default();
         serializer.serialize_value(&state).expect("serializing a MachineState shoud be infallible");
         let archived = ArchivedValue::new(serializer.into_serializer().into_inner());
-        self.inner.set_state(archived)
+        self.inner.set_state(archived, source)
     }
 
-    pub fn set_status(&self, state: Status) {
-        let old = self.inner.get_state();
-        let oldref: &Archived<State> = old.as_ref();
-        let previous: &Archived<Option<UserRef>> = &oldref.inner.previous;
-        let previous =
-            Deserialize::<Option<UserRef>, _>::deserialize(previous, &mut rkyv::Infallible)
-                .expect("Infallible deserializer failed");
-        let new = MachineState { state, previous };
-        self.set_state(new);
+    /// Change to `state`, updating the previous user as described in [`MachineState::transition`]
+    ///
+    /// `source` is recorded in the audit log to tell manual from automatic changes.
+    pub fn set_status(&self, state: Status, source: Source) -> Result<(), Denied> {
+        self.transition(state, None, None, source)
     }
 
-    pub async fn try_update(&self, session: SessionHandle, new: Status) {
-        let old = self.get_state();
-        let old: &Archived<State> = old.as_ref();
+    /// Change to `state`, which ends at the Unix timestamp `until` if it is a reservation
+    fn transition(
+        &self,
+        state: Status,
+        reason: Option<String>,
+        until: Option<i64>,
+        source: Source,
+    ) -> Result<(), Denied> {
+        self.transition_from(|_| true, state, reason, until, source)
+            .map(drop)
+    }
+
+    /// Like [`Resource::transition`], but only if the current state is still `expected`
+    ///
+    /// Returns if the state was changed. No other change can happen between checking and changing
+    /// the state.
+    pub(crate) fn transition_from(
+        &self,
+        expected: impl FnOnce(&MachineState) -> bool,
+        state: Status,
+        reason: Option<String>,
+        until: Option<i64>,
+        source: Source,
+    ) -> Result<bool, Denied> {
+        let _updating = self.inner.updating.lock().unwrap();
+        let old = MachineState::from(self.inner.get_state().as_ref());
+        if !expected(&old) {
+            return Ok(false);
+        }
+        self.set_state(transitioned(&old, state, reason, until), source)?;
+        Ok(true)
+    }
+
+    pub async fn try_update(&self, session: SessionHandle, new: Status) -> Result<(), Denied> {
+        self.try_update_with_reason(session, new, None).await
+    }
+
+    /// Reserve the machine for the user of `session`, freeing it again after `expiry` if given
+    pub async fn try_reserve(
+        &self,
+        session: SessionHandle,
+        expiry: Option<Duration>,
+    ) -> Result<(), Denied> {
+        let new = Status::Reserved(session.get_user_ref());
+        let until = expiry.map(|expiry| chrono::Utc::now().timestamp() + expiry.as_secs() as i64);
+        self.try_transition(session, new, None, until)
+    }
+
+    /// Like [`Resource::try_update`], storing `reason` with the new state
+    ///
+    /// For machines requiring it, marking them as to be checked needs a reason.
+    pub async fn try_update_with_reason(
+        &self,
+        session: SessionHandle,
+        new: Status,
+        reason: Option<String>,
+    ) -> Result<(), Denied> {
+        self.try_transition(session, new, reason, None)
+    }
+
+    /// The decision tree shared by all updates requested through a session
+    fn try_transition(
+        &self,
+        session: SessionHandle,
+        new: Status,
+        reason: Option<String>,
+        until: Option<i64>,
+    ) -> Result<(), Denied> {
         let user = session.get_user_ref();
+        let result = self.change(&session, session.source(), |old| {
+            check_transition(
+                &old.inner.state,
+                &new,
+                &user,
+                session.has_manage(self),
+                session.has_write(self),
+                self.inner.desc().check_on_return,
+            )
+            .map_err(|denied| self.explain_denied(denied, &new, &session))?;
+            self.check_supervisor(&session, &new)?;
+            self.check_training(&session, &new)?;
+            self.check_note(&new, reason.as_deref())?;
+            self.check_queue(&session, &old.inner.state, &new)?;
+            Ok(Some(transitioned(
+                &MachineState::from(old),
+                new,
+                reason,
+                until,
+            )))
+        });
+        if let Err(reason) = &result {
+            tracing::debug!(id = self.get_id(), %user.id, %reason, "denied update");
+        }
+        result.map(drop)
+    }
 
-        if session.has_manage(self) // Default allow for managers
-
-            || (session.has_write(self) // Decision tree for writers
-                && match (&old.inner.state, &new) {
-                // Going from available to used by the person requesting is okay.
-                (ArchivedStatus::Free, Status::InUse(who))
-                // Check that the person requesting does not request for somebody else.
-                // *That* is manage privilege.
-                if who == &user => true,
-
-                // Reserving things for ourself is okay.
-                (ArchivedStatus::Free, Status::Reserved(whom))
-                if &user == whom => true,
-
-                // Returning things we've been using is okay. This includes both if
-                // they're being freed or marked as to be checked.
-                (ArchivedStatus::InUse(who), Status::Free | Status::ToCheck(_))
-                if who == &user => true,
-
-                // Un-reserving things we reserved is okay
-                (ArchivedStatus::Reserved(whom), Status::Free)
-                if whom == &user => true,
-                // Using things that we've reserved is okay. But the person requesting
-                // that has to be the person that reserved the machine. Otherwise
-                // somebody could make a machine reserved by a different user as used by
-                // that different user but use it themself.
-                (ArchivedStatus::Reserved(whom), Status::InUse(who))
-                if whom == &user && who == whom => true,
-
-                // Default is deny.
-                _ => false
-            })
+    /// Change the state on behalf of `session` to what `decide` makes of the current one
+    ///
+    /// Every change requested through a session goes through here, it's where maintenance mode is
+    /// enforced and the machines the session uses are tracked. `decide` returns `None` to leave
+    /// the state alone. Returns if the state was changed.
+    fn change(
+        &self,
+        session: &SessionHandle,
+        source: Source,
+        decide: impl FnOnce(&Archived<State>) -> Result<Option<MachineState>, Denied>,
+    ) -> Result<bool, Denied> {
+        session.check_writable()?;
+        let _updating = self.inner.updating.lock().unwrap();
+        let old = self.get_state();
+        let new = match decide(old.as_ref())? {
+            Some(new) => new,
+            None => return Ok(false),
+        };
+        let using = new.state == Status::InUse(session.get_user_ref());
+        self.set_state(new, source)?;
+        session.set_using(self.get_id(), using);
+        Ok(true)
+    }
 
-            // Default permissions everybody has
-            || match (&old.inner.state, &new) {
-                // Returning things we've been using is okay. This includes both if
-                // they're being freed or marked as to be checked.
-                (ArchivedStatus::InUse(who), Status::Free | Status::ToCheck(_)) if who == &user => true,
+    /// Replace a missing permission to start the machine with its configured message, if any
+    ///
+    /// The message is given in the language the user prefers, if configured in it.
+    fn explain_denied(&self, denied: Denied, new: &Status, session: &SessionHandle) -> Denied {
+        match (denied, new) {
+            (Denied::MissingPermission, Status::InUse(_)) => {
+                let language = session.language();
+                match self.inner.desc().denied_message(language.as_deref()) {
+                    Some(message) => Denied::MissingPermissionMessage(message.to_string()),
+                    None => Denied::MissingPermission,
+                }
+            }
+            (denied, _) => denied,
+        }
+    }
 
-                // Un-reserving things we reserved is okay
-                (ArchivedStatus::Reserved(whom), Status::Free) if whom == &user => true,
+    /// Starting machines with a configured supervisor permission needs a supervisor to be around
+    fn check_supervisor(&self, session: &SessionHandle, new: &Status) -> Result<(), Denied> {
+        match (&self.inner.desc().supervisor, new) {
+            (Some(supervisor), Status::InUse(_)) if !session.is_present(supervisor) => {
+                Err(Denied::NoSupervisor)
+            }
+            _ => Ok(()),
+        }
+    }
 
-                // Default is deny.
-                _ => false,
+    /// Starting machines with a required training needs a valid record of it, whatever the permissions
+    fn check_training(&self, session: &SessionHandle, new: &Status) -> Result<(), Denied> {
+        match (&self.inner.desc().required_training, new) {
+            (Some(training), Status::InUse(_)) if !session.has_training(training) => {
+                Err(Denied::MissingTraining(training.clone()))
             }
-        {
-            self.set_status(new);
+            _ => Ok(()),
         }
     }
 
-    pub async fn give_back(&self, session: SessionHandle) {
-        let state = self.get_state();
-        let s: &Archived<State> = state.as_ref();
-        let i: &Archived<MachineState> = &s.inner;
-        if let ArchivedStatus::InUse(user) = &i.state {
-            let current = session.get_user_ref();
-            if user == &current {
-                self.set_state(MachineState::free(Some(current)));
+    /// Only the first user in the queue may take a free machine, managers may hand it to anybody
+    ///
+    /// Users that aren't first are queued if they aren't already.
+    fn check_queue(
+        &self,
+        session: &SessionHandle,
+        old: &ArchivedStatus,
+        new: &Status,
+    ) -> Result<(), Denied> {
+        match (old, new) {
+            (ArchivedStatus::Free, Status::InUse(user) | Status::Reserved(user))
+                if !session.has_manage(self) =>
+            {
+                let mut queue = self.inner.queue.lock().unwrap();
+                queue.admit(user, 1).map_err(Denied::Queued)
             }
+            _ => Ok(()),
         }
     }
 
-    pub async fn force_set(&self, new: Status) {
-        self.set_status(new);
+    fn check_note(&self, new: &Status, reason: Option<&str>) -> Result<(), Denied> {
+        let missing = reason.map_or(true, |reason| reason.trim().is_empty());
+        if self.inner.desc().require_check_note && matches!(new, Status::ToCheck(_)) && missing {
+            Err(Denied::NoteRequired)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Return the machine if the user of `session` is using it
+    ///
+    /// Machines with `check_on_return` are marked as to be checked by their last user instead of
+    /// being freed.
+    pub async fn give_back(&self, session: SessionHandle) -> Result<(), Denied> {
+        let current = session.get_user_ref();
+        let using = Status::InUse(current.clone());
+        let returned = if self.inner.desc().check_on_return {
+            Status::ToCheck(current)
+        } else {
+            Status::Free
+        };
+        self.change(&session, session.source(), |old| {
+            let old = MachineState::from(old);
+            Ok((old.state == using).then(|| transitioned(&old, returned, None, None)))
+        })
+        .map(drop)
+    }
+
+    /// Set the state on behalf of a manager, without the checks users are subject to
+    pub async fn force_set(&self, session: &SessionHandle, new: Status) -> Result<(), Denied> {
+        self.change(session, Source::Admin, |old| {
+            Ok(Some(transitioned(
+                &MachineState::from(old),
+                new,
+                None,
+                None,
+            )))
+        })
+        .map(drop)
+    }
+
+    /// Disable the machine, optionally giving a reason that is shown to users
+    pub async fn disable(
+        &self,
+        session: &SessionHandle,
+        reason: Option<String>,
+    ) -> Result<(), Denied> {
+        self.change(session, Source::Admin, |old| {
+            let old = MachineState::from(old);
+            Ok(Some(transitioned(&old, Status::Disabled, reason, None)))
+        })
+        .map(drop)
+    }
+
+    /// End of the current reservation as Unix timestamp, if the machine is reserved with expiry
+    pub fn reserved_until(&self) -> Option<i64> {
+        let state = self.get_state_ref();
+        let state: &Archived<State> = state.as_ref();
+        match (&state.inner.state, &state.inner.reserved_until) {
+            (ArchivedStatus::Reserved(_), ArchivedOption::Some(until)) => Some(*until),
+            _ => None,
+        }
+    }
+
+    /// Free the machine if its reservation has run out, returning if it was freed
+    ///
+    /// A reservation replaced or taken up in the meantime is left alone.
+    fn expire_reservation(&self) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        let expired = |state: &MachineState| match (&state.state, state.reserved_until) {
+            (Status::Reserved(_), Some(until)) => until <= now,
+            _ => false,
+        };
+        match self.transition_from(expired, Status::Free, None, None, Source::System) {
+            Ok(freed) => {
+                if freed {
+                    tracing::info!(id = self.get_id(), "reservation expired, freed machine");
+                }
+                freed
+            }
+            Err(error) => {
+                tracing::error!(id = self.get_id(), %error, "freeing expired reservation failed");
+                false
+            }
+        }
+    }
+
+    /// Free the machine whenever a reservation runs out
+    ///
+    /// Meant to run for as long as bffh does. The pending reservation loaded from the database is
+    /// picked up right away, and any change of state before the deadline cancels the timer.
+    pub async fn expire_reservations(self) {
+        let mut states = self.get_signal().to_stream();
+        while states.next().await.is_some() {
+            while let Some(until) = self.reserved_until() {
+                let wait = until - chrono::Utc::now().timestamp();
+                let wait = Duration::from_secs(wait.max(0) as u64);
+                let changed = async { Some(states.next().await) }
+                    .or(async {
+                        Timer::after(wait).await;
+                        None
+                    })
+                    .await;
+                match changed {
+                    // Check again, the reservation may have been replaced by a different one
+                    Some(Some(_)) => {}
+                    Some(None) => return,
+                    // Otherwise wait for whatever replaced the reservation to be applied
+                    None if self.expire_reservation() => {}
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Apply state changes once they stopped changing for the configured debounce window
+    ///
+    /// Every further change restarts the window and only the last one is applied. Returns right
+    /// away for machines without `debounce_ms`, otherwise runs until `shutdown` is triggered and
+    /// then applies a still pending change immediately so it isn't lost.
+    pub async fn settle_changes(self, shutdown: ShutdownSignal) {
+        let window = match self.inner.debounce {
+            Some(window) => window,
+            None => return,
+        };
+        let mut changes = self.inner.pending.signal_ref(Option::is_some).to_stream();
+        let settling = async {
+            while let Some(pending) = changes.next().await {
+                if !pending {
+                    continue;
+                }
+                while async { changes.next().await.is_some() }
+                    .or(async {
+                        Timer::after(window).await;
+                        false
+                    })
+                    .await
+                {}
+                self.inner.settle();
+            }
+        };
+        settling.or(shutdown.wait()).await;
+        self.inner.settle();
+    }
+
+    /// Get notified when the machine becomes free, without queueing for it
+    ///
+    /// Only machines configured with `announce_on_free` send notifications. Watching lasts until
+    /// [`Resource::unwatch`] is called or the session is closed.
+    pub fn watch(&self, session: &SessionHandle) -> Result<(), Denied> {
+        if !session.has_read(self) {
+            return Err(Denied::MissingPermission);
+        }
+        self.inner.watch(session.inbox());
+        Ok(())
+    }
+
+    pub fn unwatch(&self, session: &SessionHandle) {
+        self.inner.unwatch(&session.inbox());
+    }
+
+    /// Queue up for the machine, returning the position of the user starting at 1
+    ///
+    /// Queueing again keeps the earlier position. While users are queued a free machine can only
+    /// be used or reserved by the first of them. The user leaves the queue once they start using
+    /// or reserve the machine, or by calling [`Resource::leave_queue`].
+    pub fn enqueue(&self, session: &SessionHandle) -> Result<usize, Denied> {
+        session.check_writable()?;
+        if !session.has_write(self) {
+            return Err(Denied::MissingPermission);
+        }
+        if self.is_owned_by(session.get_user_ref()) {
+            return Err(Denied::IllegalTransition);
+        }
+        Ok(self.inner.enqueue(&session.get_user_ref()))
+    }
+
+    pub fn leave_queue(&self, session: &SessionHandle) {
+        self.inner.leave_queue(&session.get_user_ref());
+    }
+
+    /// Current holder, position of the user of `session` and length of the queue, in one go
+    ///
+    /// The holder is only included for sessions allowed to read the machine state, as when
+    /// listing machines.
+    pub fn queue_status(&self, session: &SessionHandle) -> Result<QueueStatus, Denied> {
+        if !(self.visible(session) || session.has_read(self)) {
+            return Err(Denied::MissingPermission);
+        }
+        let holder = if session.has_read(self) {
+            self.get_current_user()
+        } else {
+            None
+        };
+        let (position, length) = self.inner.queue_position(&session.get_user_ref());
+        Ok(QueueStatus {
+            holder,
+            position,
+            length,
+        })
+    }
+
+    /// The last few state changes since bffh started, oldest first
+    ///
+    /// Only kept in memory, the audit log has the full history.
+    pub fn recent_changes(&self) -> Vec<Change> {
+        self.inner.recent_changes()
+    }
+
+    /// Number of times the machine was started since the counter was last reset
+    pub fn get_usage(&self) -> u64 {
+        self.inner.get_usage()
+    }
+
+    /// Reset the usage counter, e.g. after the machine was serviced
+    pub fn reset_usage(&self, session: &SessionHandle) -> Result<(), Denied> {
+        session.check_writable()?;
+        if !session.has_manage(self) {
+            return Err(Denied::MissingPermission);
+        }
+        tracing::info!(
+            id = self.get_id(),
+            user = %session.get_user_ref().id,
+            "resetting usage counter"
+        );
+        self.inner.reset_usage();
+        Ok(())
+    }
+
+    /// Make the actors of this machine apply its current state again
+    ///
+    /// Useful after a device was fixed or replaced and has to be brought back in line with the
+    /// state bffh has. Since nothing changes no audit log entry is written.
+    pub fn reapply(&self, session: &SessionHandle) -> Result<(), Denied> {
+        if !session.has_manage(self) {
+            return Err(Denied::MissingPermission);
+        }
+        self.inner.reapply();
+        Ok(())
     }
 
     pub fn visible(&self, session: &SessionHandle) -> bool {
@@ -266,3 +987,861 @@ impl Resource {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn archive(status: Status) -> ArchivedValue<State> {
+        let mut serializer = AllocSerializer::<1024>::default();
+        serializer
+            .serialize_value(
+                &MachineState {
+                    state: status,
+                    previous: None,
+                    reason: None,
+                    reserved_until: None,
+                }
+                .to_state(),
+            )
+            .unwrap();
+        ArchivedValue::new(serializer.into_serializer().into_inner())
+    }
+
+    fn check(old: Status, new: Status, has_manage: bool, has_write: bool) -> Result<(), Denied> {
+        let user = UserRef::new("user".to_string());
+        let old = archive(old);
+        let old: &Archived<State> = old.as_ref();
+        check_transition(&old.inner.state, &new, &user, has_manage, has_write, false)
+    }
+
+    #[test]
+    fn denial_distinguishes_permission_from_busy() {
+        let user = UserRef::new("user".to_string());
+        let other = UserRef::new("other".to_string());
+
+        assert_eq!(
+            check(Status::Free, Status::InUse(user.clone()), false, false),
+            Err(Denied::MissingPermission)
+        );
+        assert_eq!(
+            check(Status::InUse(other), Status::InUse(user.clone()), false, true),
+            Err(Denied::Busy)
+        );
+        assert_eq!(
+            check(Status::Disabled, Status::InUse(user.clone()), false, true),
+            Err(Denied::IllegalTransition)
+        );
+        assert_eq!(check(Status::Free, Status::InUse(user), false, true), Ok(()));
+    }
+
+    #[test]
+    fn checked_machines_are_not_freed_on_return() {
+        let user = UserRef::new("user".to_string());
+        let used = archive(Status::InUse(user.clone()));
+        let used: &Archived<State> = used.as_ref();
+        let check = |new: Status, has_manage| {
+            check_transition(&used.inner.state, &new, &user, has_manage, true, true)
+        };
+
+        assert_eq!(check(Status::Free, false), Err(Denied::CheckRequired));
+        assert_eq!(check(Status::ToCheck(user.clone()), false), Ok(()));
+        assert_eq!(check(Status::Free, true), Ok(()));
+    }
+
+    /// A machine and session manager with the users `user` (role `member`) and `supervisor`
+    fn setup(
+        dir: &tempfile::TempDir,
+        id: &str,
+        supervisor: Option<&str>,
+        read_only: bool,
+    ) -> (Resource, crate::session::SessionManager) {
+        use crate::authorization::permissions::PermissionBuf;
+
+        setup_with(dir, id, read_only, |desc| {
+            desc.supervisor =
+                supervisor.map(|perm| PermissionBuf::from_string_unchecked(perm.to_string()))
+        })
+    }
+
+    /// Like [`setup`], letting `configure` change the machine description
+    fn setup_with(
+        dir: &tempfile::TempDir,
+        id: &str,
+        read_only: bool,
+        configure: impl FnOnce(&mut MachineDescription),
+    ) -> (Resource, crate::session::SessionManager) {
+        use crate::authorization::permissions::{PermRule, PermissionBuf};
+        use crate::authorization::roles::{Role, Roles};
+        use crate::session::SessionManager;
+        use crate::users::db::User;
+        use crate::Users;
+        use std::collections::HashMap;
+
+        let env = StateDB::open_env(dir.path().join("db")).unwrap();
+        let users = Users::new(env.clone()).unwrap();
+        for (name, role) in [("user", "member"), ("supervisor", "supervisor")] {
+            let mut user = User::new_with_plain_pw(name, "secret");
+            user.userdata.roles.push(role.to_string());
+            users.put_user(name, &user).unwrap();
+        }
+
+        let perm = PermissionBuf::from_string_unchecked("test.perm".to_string());
+        let supervise = PermissionBuf::from_string_unchecked("test.supervise".to_string());
+        let roles = Roles::leak(HashMap::from([
+            (
+                "member".to_string(),
+                Role::new(Vec::new(), vec![PermRule::Base(perm.clone())]),
+            ),
+            (
+                "supervisor".to_string(),
+                Role::new(Vec::new(), vec![PermRule::Base(supervise)]),
+            ),
+        ]));
+        let mut desc = testing::machine("Testmachine", &perm);
+        configure(&mut desc);
+        let resource = testing::resource(env, id, desc);
+
+        (resource, SessionManager::new(users, roles, None, read_only))
+    }
+
+    #[test]
+    fn maintenance_refuses_changes_but_not_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, sessions) = setup(&dir, "maintenance", None, true);
+        let session = sessions.try_open(&tracing::Span::none(), "user").unwrap();
+
+        let result = async_io::block_on(
+            resource.try_update(session.clone(), Status::InUse(session.get_user_ref())),
+        );
+        assert_eq!(result, Err(Denied::Maintenance(Maintenance)));
+        let forced = async_io::block_on(resource.force_set(&session, Status::Disabled));
+        assert_eq!(forced, Err(Denied::Maintenance(Maintenance)));
+        let disabled = async_io::block_on(resource.disable(&session, None));
+        assert_eq!(disabled, Err(Denied::Maintenance(Maintenance)));
+        assert_eq!(
+            session.set_preference("color", Some("green")),
+            Err(crate::session::PreferenceError::Maintenance(Maintenance))
+        );
+        assert_eq!(
+            session.users.put_user("user", &session.get_user()),
+            Err(crate::users::Error::Maintenance(Maintenance))
+        );
+
+        assert!(session.has_read(&resource));
+        assert_eq!(
+            resource.get_state().as_ref().inner.state,
+            ArchivedStatus::Free
+        );
+    }
+
+    #[test]
+    fn start_needs_supervisor_present() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, sessions) = setup(&dir, "supervised", Some("test.supervise"), false);
+        let span = tracing::Span::none();
+        let session = sessions.try_open(&span, "user").unwrap();
+        let start = || {
+            async_io::block_on(
+                resource.try_update(session.clone(), Status::InUse(session.get_user_ref())),
+            )
+        };
+
+        assert_eq!(start(), Err(Denied::NoSupervisor));
+
+        let supervisor = sessions.try_open(&span, "supervisor").unwrap();
+        assert_eq!(start(), Ok(()));
+        drop(supervisor);
+
+        // Returning the machine does not need the supervisor
+        assert_eq!(
+            async_io::block_on(resource.try_update(session.clone(), Status::Free)),
+            Ok(())
+        );
+        assert_eq!(start(), Err(Denied::NoSupervisor));
+    }
+
+    #[test]
+    fn audit_records_source_of_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, sessions) = setup(&dir, "audited", None, false);
+        let session = sessions.try_open(&tracing::Span::none(), "user").unwrap();
+
+        async_io::block_on(async {
+            resource
+                .try_update(session.clone(), Status::InUse(session.get_user_ref()))
+                .await
+                .unwrap();
+            resource.set_status(Status::Free, Source::Admin).unwrap();
+        });
+
+        let log = std::fs::read_to_string(testing::audit_log()).unwrap();
+        let sources: Vec<_> = log
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|line| line["machine"] == "audited")
+            .map(|line| line["source"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(sources, ["user", "admin"]);
+    }
+
+    #[test]
+    fn reapply_sends_current_state_to_actors() {
+        use crate::actors::{Actor, ActorDriver};
+        use crate::shutdown::ShutdownSignal;
+        use futures_lite::future::poll_once;
+        use futures_util::future::BoxFuture;
+        use std::sync::Mutex;
+
+        struct Recorder(Arc<Mutex<Vec<ArchivedValue<State>>>>);
+        impl Actor for Recorder {
+            fn name(&self) -> &str {
+                "Recorder"
+            }
+
+            fn apply(&mut self, state: ArchivedValue<State>) -> BoxFuture<'static, ()> {
+                self.0.lock().unwrap().push(state);
+                Box::pin(async {})
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, sessions) = setup(&dir, "reapplied", None, false);
+        let session = sessions.try_open(&tracing::Span::none(), "user").unwrap();
+
+        let applied = Arc::new(Mutex::new(Vec::new()));
+        let mut driver = ActorDriver::new(
+            resource.get_signal(),
+            Box::new(Recorder(applied.clone())),
+            &ShutdownSignal::new(),
+        );
+        async_io::block_on(poll_once(&mut driver));
+        assert_eq!(applied.lock().unwrap().len(), 1);
+
+        resource.reapply(&session).unwrap();
+        async_io::block_on(poll_once(&mut driver));
+
+        let applied = applied.lock().unwrap();
+        assert_eq!(applied.len(), 2);
+        assert_eq!(applied[1].as_ref().inner.state, ArchivedStatus::Free);
+
+        let log = std::fs::read_to_string(testing::audit_log()).unwrap();
+        assert!(!log.contains("reapplied"));
+    }
+
+    #[test]
+    fn recent_changes_keep_the_latest() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, _) = setup(&dir, "recent", None, false);
+
+        let users: Vec<_> = (0..RECENT_CHANGES + 4)
+            .map(|i| UserRef::new(format!("user{}", i)))
+            .collect();
+        for user in users.iter() {
+            resource
+                .set_status(Status::Blocked(user.clone()), Source::Admin)
+                .unwrap();
+        }
+
+        let recent = resource.recent_changes();
+        assert_eq!(recent.len(), RECENT_CHANGES);
+        let blocked: Vec<_> = recent
+            .iter()
+            .map(|change| change.state.state.clone())
+            .collect();
+        let expected: Vec<_> = users[4..].iter().cloned().map(Status::Blocked).collect();
+        assert_eq!(blocked, expected);
+        assert!(recent.iter().all(|change| change.source == Source::Admin));
+    }
+
+    #[test]
+    fn starting_counts_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, sessions) = setup(&dir, "counted", None, false);
+        let session = sessions.try_open(&tracing::Span::none(), "user").unwrap();
+        let user = session.get_user_ref();
+
+        async_io::block_on(async {
+            for _ in 0..2 {
+                let start = resource.try_update(session.clone(), Status::InUse(user.clone()));
+                start.await.unwrap();
+                resource.give_back(session.clone()).await.unwrap();
+            }
+            // Only the change from free counts, not e.g. a reservation being released
+            resource
+                .set_status(Status::Blocked(user.clone()), Source::Admin)
+                .unwrap();
+            resource
+                .set_status(Status::InUse(user.clone()), Source::Admin)
+                .unwrap();
+        });
+        assert_eq!(resource.get_usage(), 2);
+
+        resource.reset_usage(&session).unwrap();
+        assert_eq!(resource.get_usage(), 0);
+    }
+
+    #[test]
+    fn check_needs_note_if_required() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, sessions) =
+            setup_with(&dir, "noted", false, |desc| desc.require_check_note = true);
+        let session = sessions.try_open(&tracing::Span::none(), "user").unwrap();
+        let user = session.get_user_ref();
+        let check = |note: Option<&str>| {
+            async_io::block_on(resource.try_update_with_reason(
+                session.clone(),
+                Status::ToCheck(user.clone()),
+                note.map(str::to_string),
+            ))
+        };
+
+        async_io::block_on(resource.try_update(session.clone(), Status::InUse(user.clone())))
+            .unwrap();
+        assert_eq!(check(None), Err(Denied::NoteRequired));
+        assert_eq!(check(Some("  ")), Err(Denied::NoteRequired));
+        assert_eq!(check(Some("saw dust everywhere")), Ok(()));
+        assert_eq!(
+            resource.get_reason().as_deref(),
+            Some("saw dust everywhere")
+        );
+    }
+
+    #[test]
+    fn returned_machine_waits_for_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, sessions) = setup_with(&dir, "checked", false, |desc| {
+            desc.check_on_return = true;
+            desc.privs.manage = PermissionBuf::from_string_unchecked("test.supervise".to_string());
+        });
+        let span = tracing::Span::none();
+        let session = sessions.try_open(&span, "user").unwrap();
+        let warden = sessions.try_open(&span, "supervisor").unwrap();
+        let user = session.get_user_ref();
+
+        async_io::block_on(async {
+            let start = resource.try_update(session.clone(), Status::InUse(user.clone()));
+            start.await.unwrap();
+            resource.give_back(session.clone()).await.unwrap();
+        });
+        assert_eq!(resource.get_previous_user(), Some(user.clone()));
+        assert!(matches!(
+            &resource.get_state().as_ref().inner.state,
+            ArchivedStatus::ToCheck(by) if by == &user
+        ));
+
+        let free = |session: &SessionHandle| {
+            async_io::block_on(resource.try_update(session.clone(), Status::Free))
+        };
+        assert_eq!(free(&session), Err(Denied::MissingPermission));
+
+        // Freeing it right away instead of giving it back doesn't skip the check either
+        assert_eq!(free(&warden), Ok(()));
+        let start = resource.try_update(session.clone(), Status::InUse(user.clone()));
+        async_io::block_on(start).unwrap();
+        assert_eq!(free(&session), Err(Denied::CheckRequired));
+        async_io::block_on(resource.give_back(session.clone())).unwrap();
+
+        assert_eq!(free(&warden), Ok(()));
+        assert_eq!(
+            resource.get_state().as_ref().inner.state,
+            ArchivedStatus::Free
+        );
+        assert_eq!(resource.get_previous_user(), Some(user));
+    }
+
+    #[test]
+    fn queue_status_tells_each_user_their_position() {
+        use crate::users::db::User;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, sessions) = setup(&dir, "queued", None, false);
+        let span = tracing::Span::none();
+        let outsider = sessions.try_open(&span, "supervisor").unwrap();
+        for name in ["queue_a", "queue_b", "queue_c"] {
+            let mut user = User::new_with_plain_pw(name, "secret");
+            user.userdata.roles.push("member".to_string());
+            outsider.users.put_user(name, &user).unwrap();
+        }
+        let a = sessions.try_open(&span, "queue_a").unwrap();
+        let b = sessions.try_open(&span, "queue_b").unwrap();
+        let c = sessions.try_open(&span, "queue_c").unwrap();
+
+        async_io::block_on(resource.try_reserve(a.clone(), None)).unwrap();
+        assert_eq!(resource.enqueue(&a), Err(Denied::IllegalTransition));
+        assert_eq!(resource.enqueue(&b), Ok(1));
+        assert_eq!(resource.enqueue(&c), Ok(2));
+        assert_eq!(resource.enqueue(&b), Ok(1));
+
+        let status = |position| QueueStatus {
+            holder: Some(a.get_user_ref()),
+            position,
+            length: 2,
+        };
+        assert_eq!(resource.queue_status(&a), Ok(status(None)));
+        assert_eq!(resource.queue_status(&b), Ok(status(Some(1))));
+        assert_eq!(resource.queue_status(&c), Ok(status(Some(2))));
+        assert_eq!(
+            resource.queue_status(&outsider),
+            Err(Denied::MissingPermission)
+        );
+
+        // The free machine is kept for B, once B gets it the rest of the queue moves up
+        async_io::block_on(async {
+            resource.try_update(a.clone(), Status::Free).await.unwrap();
+            assert_eq!(
+                resource.try_reserve(c.clone(), None).await,
+                Err(Denied::Queued(2))
+            );
+            resource.try_reserve(b.clone(), None).await.unwrap();
+        });
+        assert_eq!(
+            resource.queue_status(&c),
+            Ok(QueueStatus {
+                holder: Some(b.get_user_ref()),
+                position: Some(1),
+                length: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn returning_needs_no_permission() {
+        let user = UserRef::new("user".to_string());
+        assert_eq!(
+            check(Status::InUse(user), Status::Free, false, false),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn watchers_are_told_about_free_machine() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, sessions) = setup_with(&dir, "announced", false, |desc| {
+            desc.announce_on_free = true
+        });
+        let span = tracing::Span::none();
+        let watcher = sessions.try_open(&span, "user").unwrap();
+        let other = sessions.try_open(&span, "supervisor").unwrap();
+        let user = watcher.get_user_ref();
+
+        resource.watch(&watcher).unwrap();
+        assert_eq!(resource.watch(&other), Err(Denied::MissingPermission));
+
+        async_io::block_on(async {
+            resource
+                .set_status(Status::InUse(user), Source::Admin)
+                .unwrap();
+            assert!(watcher.take_notifications().is_empty());
+            resource.give_back(watcher.clone()).await.unwrap();
+        });
+
+        assert_eq!(
+            watcher.take_notifications(),
+            [Notification::MachineFree {
+                id: "announced".to_string()
+            }]
+        );
+        assert!(other.take_notifications().is_empty());
+    }
+
+    #[test]
+    fn flapping_states_are_debounced() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, _) =
+            setup_with(&dir, "debounced", false, |desc| desc.debounce_ms = Some(50));
+        let user = UserRef::new("user".to_string());
+        let audited = || {
+            let log = std::fs::read_to_string(testing::audit_log()).unwrap();
+            log.lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                .filter(|line| line["machine"] == "debounced")
+                .map(|line| line["state"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let shutdown = ShutdownSignal::new();
+        let settled = async_io::block_on(
+            async {
+                let mut applied = resource.get_signal().to_stream();
+                applied.next().await;
+                for _ in 0..5 {
+                    resource
+                        .set_status(Status::InUse(user.clone()), Source::Admin)
+                        .unwrap();
+                    resource.set_status(Status::Free, Source::Admin).unwrap();
+                }
+                resource
+                    .set_status(Status::InUse(user.clone()), Source::Admin)
+                    .unwrap();
+                assert!(resource.is_free());
+                assert!(audited().is_empty());
+
+                applied.next().await;
+                // Flapping back to the applied state changes nothing
+                resource.set_status(Status::Free, Source::Admin).unwrap();
+                resource
+                    .set_status(Status::InUse(user.clone()), Source::Admin)
+                    .unwrap();
+                Timer::after(Duration::from_millis(200)).await;
+                true
+            }
+            .or(async {
+                resource.clone().settle_changes(shutdown.clone()).await;
+                false
+            })
+            .or(async {
+                Timer::after(Duration::from_secs(5)).await;
+                false
+            }),
+        );
+        assert!(settled);
+
+        assert_eq!(resource.get_current_user(), Some(user));
+        assert_eq!(audited(), ["inuse user"]);
+    }
+
+    #[test]
+    fn snapshots_are_consistent_under_concurrent_updates() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, _) = setup(&dir, "snapshot", None, false);
+        let user = UserRef::new("user".to_string());
+        let base = (*resource.get_description()).clone();
+
+        // Each round first reloads the description as `Testmachine {i}`, then disables the
+        // machine giving `maintenance {i}` as reason
+        let writer = {
+            let resource = resource.clone();
+            std::thread::spawn(move || {
+                for i in 0..200 {
+                    let mut desc = base.clone();
+                    desc.name = format!("Testmachine {i}");
+                    resource.set_description(desc);
+                    resource
+                        .transition(
+                            Status::Disabled,
+                            Some(format!("maintenance {i}")),
+                            None,
+                            Source::Admin,
+                        )
+                        .unwrap();
+                    resource
+                        .set_status(Status::InUse(user.clone()), Source::Admin)
+                        .unwrap();
+                }
+            })
+        };
+
+        let index = |text: &str, prefix: &str| -> Option<u32> {
+            text.strip_prefix(prefix).map(|i| i.parse().unwrap())
+        };
+        let mut last_round = None;
+        while !writer.is_finished() {
+            let snapshot = resource.snapshot();
+            let state = MachineState::from(snapshot.state.as_ref());
+            let round = index(&snapshot.description.name, "Testmachine ");
+            // Descriptions only ever move forward
+            assert!(round >= last_round, "{:?} after {:?}", round, last_round);
+            last_round = round;
+
+            match state.state {
+                Status::Free => assert_eq!(state.reason, None),
+                Status::InUse(_) => {
+                    assert_eq!(state.reason, None);
+                    assert!(round.is_some());
+                }
+                Status::Disabled => {
+                    // The state of round i comes with the description of round i, or of the
+                    // next round if it was reloaded since. Never with an older one.
+                    let reason = state.reason.as_deref().unwrap();
+                    let disabled = index(reason, "maintenance ").unwrap();
+                    let round = round.unwrap();
+                    assert!(
+                        round == disabled || round == disabled + 1,
+                        "state of round {} with description of round {}",
+                        disabled,
+                        round
+                    );
+                }
+                other => panic!("unexpected state {other:?}"),
+            }
+            assert_eq!(snapshot.description.privs, resource.get_required_privs());
+        }
+        writer.join().unwrap();
+
+        let last = resource.snapshot();
+        assert_eq!(last.description.name, "Testmachine 199");
+        assert!(matches!(
+            MachineState::from(last.state.as_ref()).state,
+            Status::InUse(_)
+        ));
+    }
+
+    /// Wait until the machine is free, giving up after a few seconds
+    fn wait_for_free(resource: &Resource) -> bool {
+        let freed = async {
+            while !resource.is_free() {
+                Timer::after(Duration::from_millis(10)).await;
+            }
+            true
+        };
+        let expiry = async {
+            resource.clone().expire_reservations().await;
+            false
+        };
+        let timeout = async {
+            Timer::after(Duration::from_secs(5)).await;
+            false
+        };
+        async_io::block_on(freed.or(expiry).or(timeout))
+    }
+
+    #[test]
+    fn reservations_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, sessions) = setup(&dir, "expiring", None, false);
+        let session = sessions.try_open(&tracing::Span::none(), "user").unwrap();
+
+        async_io::block_on(resource.try_reserve(session.clone(), Some(Duration::ZERO))).unwrap();
+        assert!(resource.reserved_until().is_some());
+        assert!(wait_for_free(&resource));
+
+        let log = std::fs::read_to_string(testing::audit_log()).unwrap();
+        let states: Vec<_> = log
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|line| line["machine"] == "expiring")
+            .map(|line| line["state"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(states.len(), 2);
+        assert!(states[0].starts_with("reserved user until "));
+        assert_eq!(states[1], "free");
+    }
+
+    #[test]
+    fn using_a_reservation_cancels_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, sessions) = setup(&dir, "taken", None, false);
+        let session = sessions.try_open(&tracing::Span::none(), "user").unwrap();
+
+        async_io::block_on(async {
+            let expiry = Some(Duration::from_secs(1));
+            resource.try_reserve(session.clone(), expiry).await.unwrap();
+            let start = Status::InUse(session.get_user_ref());
+            resource.try_update(session.clone(), start).await.unwrap();
+        });
+        assert_eq!(resource.reserved_until(), None);
+
+        let expiry = async {
+            resource.clone().expire_reservations().await;
+        };
+        let past_deadline = async {
+            Timer::after(Duration::from_millis(1500)).await;
+        };
+        async_io::block_on(expiry.or(past_deadline));
+        assert!(matches!(
+            resource.get_state().as_ref().inner.state,
+            ArchivedStatus::InUse(_)
+        ));
+    }
+
+    #[test]
+    fn transitions_only_happen_from_the_expected_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, _sessions) = setup(&dir, "expected", None, false);
+        let user = UserRef::new("user".to_string());
+
+        resource
+            .set_status(Status::Reserved(user.clone()), Source::Admin)
+            .unwrap();
+        let reserved = |state: &MachineState| matches!(state.state, Status::Reserved(_));
+        let changed =
+            |status| resource.transition_from(reserved, status, None, None, Source::System);
+        assert_eq!(changed(Status::InUse(user.clone())), Ok(true));
+        // The reservation was taken up in the meantime
+        assert_eq!(changed(Status::Free), Ok(false));
+        assert!(matches!(
+            resource.get_state().as_ref().inner.state,
+            ArchivedStatus::InUse(_)
+        ));
+    }
+
+    #[test]
+    fn oversized_states_are_refused() {
+        use crate::resources::state::StateLimits;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, _sessions) = setup(&dir, "oversized", None, false);
+
+        let reason = "x".repeat(StateLimits::default().max_size);
+        let refused = resource.transition(Status::Disabled, Some(reason), None, Source::Admin);
+        assert!(matches!(
+            refused,
+            Err(Denied::Limits(StateLimitError::TooLarge { .. }))
+        ));
+        assert!(resource.is_free());
+    }
+
+    #[test]
+    fn stored_reservations_expire_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, _sessions) = setup(&dir, "restarted", None, false);
+
+        let user = UserRef::new("user".to_string());
+        let lapsed = chrono::Utc::now().timestamp() - 60;
+        resource
+            .set_state(
+                MachineState::reserved(user, None).expiring(lapsed),
+                Source::User,
+            )
+            .unwrap();
+
+        let inner = &resource.inner;
+        let desc = (*inner.desc()).clone();
+        let reloaded = Inner::new(inner.id.clone(), inner.db.clone(), desc);
+        let reloaded = Resource::new(Arc::new(reloaded));
+        assert_eq!(reloaded.reserved_until(), Some(lapsed));
+        assert!(wait_for_free(&reloaded));
+    }
+
+    #[test]
+    fn reload_keeps_state_of_changed_and_removed_machines() {
+        use crate::resources::search::{Reloaded, ResourcesHandle};
+        use std::collections::HashMap;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (resource, _) = setup(&dir, "reloaded", None, false);
+        resource
+            .set_status(Status::Disabled, Source::System)
+            .unwrap();
+        let statedb = resource.inner.db.clone();
+        let handle = ResourcesHandle::new([resource.clone()]);
+
+        let mut desc = (*resource.get_description()).clone();
+        desc.name = "Renamed".to_string();
+        let mut machines = HashMap::from([
+            ("reloaded".to_string(), desc.clone()),
+            ("added".to_string(), desc.clone()),
+        ]);
+        let (reloaded, added) = handle.reload(&machines, &statedb);
+        assert_eq!(
+            reloaded,
+            Reloaded {
+                added: 1,
+                updated: 1,
+                removed: 0
+            }
+        );
+        assert_eq!(
+            added[0].get_state().as_ref().inner.state,
+            ArchivedStatus::Free
+        );
+
+        // Whoever holds the machine, e.g. an actor, sees the new description
+        let updated = handle.get_by_id("reloaded").unwrap();
+        assert!(Arc::ptr_eq(&updated.inner, &resource.inner));
+        assert_eq!(resource.get_name(), "Renamed");
+
+        machines.remove("reloaded");
+        let (reloaded, _) = handle.reload(&machines, &statedb);
+        assert_eq!(reloaded.removed, 1);
+        assert!(handle.get_by_id("reloaded").is_none());
+
+        // Adding it back serves the same machine again, not a second one with the same state
+        desc.name = "Restored".to_string();
+        machines.insert("reloaded".to_string(), desc);
+        let (reloaded, added) = handle.reload(&machines, &statedb);
+        assert_eq!(reloaded.added, 1);
+        assert!(added.is_empty());
+        let restored = handle.get_by_id("reloaded").unwrap();
+        assert!(Arc::ptr_eq(&restored.inner, &resource.inner));
+        assert_eq!(resource.get_name(), "Restored");
+        assert_eq!(
+            restored.get_state().as_ref().inner.state,
+            ArchivedStatus::Disabled
+        );
+    }
+
+    #[test]
+    fn denied_start_shows_configured_message() {
+        use std::collections::HashMap;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (plain, sessions) = setup(&dir, "plain", None, false);
+        let other_dir = tempfile::tempdir().unwrap();
+        let (laser, _) = setup_with(&other_dir, "laser", false, |desc| {
+            desc.denied_message = HashMap::from([
+                ("de".to_string(), "Erst den Laserkurs machen".to_string()),
+                ("en".to_string(), "Take the laser course first".to_string()),
+            ])
+        });
+        // Lacks the permission to use either machine
+        let session = sessions
+            .try_open(&tracing::Span::none(), "supervisor")
+            .unwrap();
+        let start = |resource: &Resource| {
+            async_io::block_on(
+                resource.try_update(session.clone(), Status::InUse(session.get_user_ref())),
+            )
+        };
+
+        assert_eq!(start(&plain), Err(Denied::MissingPermission));
+        assert_eq!(
+            start(&laser),
+            Err(Denied::MissingPermissionMessage(
+                "Take the laser course first".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn denied_message_follows_preferred_language() {
+        use crate::session::LANGUAGE_PREFERENCE;
+        use crate::users::db::User;
+        use std::collections::{BTreeMap, HashMap};
+
+        let dir = tempfile::tempdir().unwrap();
+        let (laser, sessions) = setup_with(&dir, "laser-de", false, |desc| {
+            desc.denied_message = HashMap::from([
+                ("de".to_string(), "Erst den Laserkurs machen".to_string()),
+                ("en".to_string(), "Take the laser course first".to_string()),
+            ])
+        });
+        let span = tracing::Span::none();
+        let supervisor = sessions.try_open(&span, "supervisor").unwrap();
+        let guest = User::new_with_plain_pw("sprachgast", "secret");
+        supervisor.users.put_user("sprachgast", &guest).unwrap();
+        let session = sessions.try_open(&span, "sprachgast").unwrap();
+        let start = || {
+            async_io::block_on(
+                laser.try_update(session.clone(), Status::InUse(session.get_user_ref())),
+            )
+        };
+
+        assert_eq!(
+            start(),
+            Err(Denied::MissingPermissionMessage(
+                "Take the laser course first".to_string()
+            ))
+        );
+        session
+            .set_preference(LANGUAGE_PREFERENCE, Some("de"))
+            .unwrap();
+        assert_eq!(
+            start(),
+            Err(Denied::MissingPermissionMessage(
+                "Erst den Laserkurs machen".to_string()
+            ))
+        );
+
+        // Stored with the user, so other sessions of them see it too
+        let other = sessions.try_open(&span, "sprachgast").unwrap();
+        assert_eq!(
+            other.preferences(),
+            BTreeMap::from([(LANGUAGE_PREFERENCE.to_string(), "de".to_string())])
+        );
+        other.set_preference(LANGUAGE_PREFERENCE, None).unwrap();
+        assert_eq!(session.language(), None);
+    }
+}
@@ -1,14 +1,18 @@
 use crate::config::deser_option;
 use crate::utils::oid::ObjectIdentifier;
+use chrono::{LocalResult, TimeZone, Utc};
+use miette::Diagnostic;
 use once_cell::sync::Lazy;
+use rkyv::option::ArchivedOption;
 use rkyv::{Archive, Archived, Deserialize, Infallible};
 use std::fmt;
 use std::str::FromStr;
+use thiserror::Error;
 
 //use crate::oidvalue;
 use crate::resources::state::State;
 
-use crate::users::UserRef;
+use crate::users::{ArchivedUserRef, UserRef};
 
 /// Status of a Machine
 #[derive(
@@ -22,7 +26,7 @@ use crate::users::UserRef;
     serde::Serialize,
     serde::Deserialize,
 )]
-#[archive_attr(derive(Debug, PartialEq))]
+#[archive_attr(derive(Debug, PartialEq, bytecheck::CheckBytes))]
 pub enum Status {
     /// Not currently used by anybody
     Free,
@@ -38,6 +42,113 @@ pub enum Status {
     Reserved(UserRef),
 }
 
+/// Every name [`Status::as_str`] returns
+pub const STATUS_NAMES: [&str; 6] = [
+    "free", "inuse", "tocheck", "blocked", "disabled", "reserved",
+];
+
+impl Status {
+    /// Name of the status without the user, stable across releases so it can be used in APIs,
+    /// metrics labels and to filter logs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Free => "free",
+            Status::InUse(_) => "inuse",
+            Status::ToCheck(_) => "tocheck",
+            Status::Blocked(_) => "blocked",
+            Status::Disabled => "disabled",
+            Status::Reserved(_) => "reserved",
+        }
+    }
+
+    /// User the status refers to, e.g. who is using or has reserved the machine
+    pub fn user(&self) -> Option<&UserRef> {
+        match self {
+            Status::InUse(user)
+            | Status::ToCheck(user)
+            | Status::Blocked(user)
+            | Status::Reserved(user) => Some(user),
+            Status::Free | Status::Disabled => None,
+        }
+    }
+}
+
+impl ArchivedStatus {
+    /// See [`Status::as_str`]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ArchivedStatus::Free => "free",
+            ArchivedStatus::InUse(_) => "inuse",
+            ArchivedStatus::ToCheck(_) => "tocheck",
+            ArchivedStatus::Blocked(_) => "blocked",
+            ArchivedStatus::Disabled => "disabled",
+            ArchivedStatus::Reserved(_) => "reserved",
+        }
+    }
+
+    pub fn user(&self) -> Option<&ArchivedUserRef> {
+        match self {
+            ArchivedStatus::InUse(user)
+            | ArchivedStatus::ToCheck(user)
+            | ArchivedStatus::Blocked(user)
+            | ArchivedStatus::Reserved(user) => Some(user),
+            ArchivedStatus::Free | ArchivedStatus::Disabled => None,
+        }
+    }
+}
+
+/// The name followed by the user if there is one, e.g. `inuse alice`
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())?;
+        if let Some(user) = self.user() {
+            write!(f, " {}", user.get_username())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error, Diagnostic, PartialEq, Eq)]
+pub enum ParseStatusError {
+    #[error("unknown machine status `{0}`")]
+    #[diagnostic(
+        code(bffh::state::status),
+        help("Known are free, inuse, tocheck, blocked, disabled and reserved")
+    )]
+    Unknown(String),
+    #[error("machine status `{0}` needs a user")]
+    #[diagnostic(code(bffh::state::status::missing_user))]
+    MissingUser(String),
+    #[error("machine status `{0}` doesn't take a user")]
+    #[diagnostic(code(bffh::state::status::unexpected_user))]
+    UnexpectedUser(String),
+}
+
+/// Reads the form written by `Display`
+impl FromStr for Status {
+    type Err = ParseStatusError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, user) = match s.split_once(' ') {
+            Some((name, user)) => (name, Some(UserRef::new(user.to_string()))),
+            None => (s, None),
+        };
+        match (name, user) {
+            ("free", None) => Ok(Status::Free),
+            ("disabled", None) => Ok(Status::Disabled),
+            ("inuse", Some(user)) => Ok(Status::InUse(user)),
+            ("tocheck", Some(user)) => Ok(Status::ToCheck(user)),
+            ("blocked", Some(user)) => Ok(Status::Blocked(user)),
+            ("reserved", Some(user)) => Ok(Status::Reserved(user)),
+            ("free" | "disabled", Some(_)) => Err(ParseStatusError::UnexpectedUser(s.to_string())),
+            ("inuse" | "tocheck" | "blocked" | "reserved", None) => {
+                Err(ParseStatusError::MissingUser(s.to_string()))
+            }
+            _ => Err(ParseStatusError::Unknown(name.to_string())),
+        }
+    }
+}
+
 #[derive(
     Clone,
     PartialEq,
@@ -49,7 +160,7 @@ pub enum Status {
     serde::Serialize,
     serde::Deserialize,
 )]
-#[archive_attr(derive(Debug, PartialEq))]
+#[archive_attr(derive(Debug, PartialEq, bytecheck::CheckBytes))]
 /// The status of the machine
 pub struct MachineState {
     pub state: Status,
@@ -59,6 +170,20 @@ pub struct MachineState {
         deserialize_with = "deser_option"
     )]
     pub previous: Option<UserRef>,
+    /// Free-form explanation shown to users, e.g. why a machine is disabled
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub reason: Option<String>,
+    /// End of a reservation as Unix timestamp in seconds, after which the machine is freed again
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser_option"
+    )]
+    pub reserved_until: Option<i64>,
 }
 
 impl fmt::Display for ArchivedMachineState {
@@ -68,17 +193,35 @@ impl fmt::Display for ArchivedMachineState {
             ArchivedStatus::InUse(user) => write!(f, "inuse {}", user),
             ArchivedStatus::ToCheck(user) => write!(f, "tocheck {}", user),
             ArchivedStatus::Blocked(user) => write!(f, "blocked {}", user),
-            ArchivedStatus::Disabled => f.write_str("disabled"),
-            ArchivedStatus::Reserved(user) => write!(f, "reserved {}", user),
+            ArchivedStatus::Disabled => match &self.reason {
+                ArchivedOption::Some(reason) => write!(f, "disabled: {}", reason),
+                ArchivedOption::None => f.write_str("disabled"),
+            },
+            ArchivedStatus::Reserved(user) => match &self.reserved_until {
+                ArchivedOption::Some(until) => {
+                    write!(f, "reserved {} until {}", user, format_timestamp(*until))
+                }
+                ArchivedOption::None => write!(f, "reserved {}", user),
+            },
         }
     }
 }
 
+/// RFC 3339 form of a Unix timestamp, falling back to the plain number if it is out of range
+fn format_timestamp(timestamp: i64) -> String {
+    match Utc.timestamp_opt(timestamp, 0) {
+        LocalResult::Single(time) => time.to_rfc3339(),
+        _ => timestamp.to_string(),
+    }
+}
+
 impl MachineState {
     pub fn new() -> Self {
         Self {
             state: Status::Free,
             previous: None,
+            reason: None,
+            reserved_until: None,
         }
     }
 
@@ -96,6 +239,8 @@ impl MachineState {
         Self {
             state: Status::Free,
             previous,
+            reason: None,
+            reserved_until: None,
         }
     }
 
@@ -103,6 +248,8 @@ impl MachineState {
         Self {
             state: Status::InUse(user),
             previous,
+            reason: None,
+            reserved_until: None,
         }
     }
 
@@ -110,13 +257,17 @@ impl MachineState {
         Self {
             state: Status::Blocked(user),
             previous,
+            reason: None,
+            reserved_until: None,
         }
     }
 
-    pub fn disabled(previous: Option<UserRef>) -> Self {
+    pub fn disabled(reason: Option<String>, previous: Option<UserRef>) -> Self {
         Self {
             state: Status::Disabled,
             previous,
+            reason,
+            reserved_until: None,
         }
     }
 
@@ -124,6 +275,8 @@ impl MachineState {
         Self {
             state: Status::Reserved(user),
             previous,
+            reason: None,
+            reserved_until: None,
         }
     }
 
@@ -131,8 +284,36 @@ impl MachineState {
         Self {
             state: Status::ToCheck(user.clone()),
             previous: Some(user),
+            reason: None,
+            reserved_until: None,
         }
     }
+
+    /// The state reached by changing from this one to `state`
+    ///
+    /// `previous` always names the last user that had the machine in use. It is set when the
+    /// machine leaves `InUse`, no matter who caused that, so a manager force-freeing a machine
+    /// records the user that was actually using it. All other changes, e.g. reserving or blocking
+    /// a machine, keep `previous` as it is. A reservation expiry is never carried over, see
+    /// [`MachineState::expiring`].
+    pub fn transition(&self, state: Status, reason: Option<String>) -> Self {
+        let previous = match &self.state {
+            Status::InUse(user) if state != Status::InUse(user.clone()) => Some(user.clone()),
+            _ => self.previous.clone(),
+        };
+        Self {
+            state,
+            previous,
+            reason,
+            reserved_until: None,
+        }
+    }
+
+    /// Let a reservation end at `until`, a Unix timestamp in seconds
+    pub fn expiring(mut self, until: i64) -> Self {
+        self.reserved_until = Some(until);
+        self
+    }
 }
 
 pub static OID_TYPE: Lazy<ObjectIdentifier> =
@@ -140,3 +321,93 @@ pub static OID_TYPE: Lazy<ObjectIdentifier> =
 pub static OID_VALUE: Lazy<ObjectIdentifier> =
     Lazy::new(|| ObjectIdentifier::from_str("1.3.6.1.4.1.48398.612.2.4").unwrap());
 //oidvalue!(OID_TYPE, MachineState, ArchivedMachineState);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rkyv::ser::serializers::AllocSerializer;
+    use rkyv::ser::Serializer;
+
+    #[test]
+    fn status_roundtrips_through_string() {
+        let alice = UserRef::new("alice".to_string());
+        let all = [
+            Status::Free,
+            Status::InUse(alice.clone()),
+            Status::ToCheck(alice.clone()),
+            Status::Blocked(alice.clone()),
+            Status::Disabled,
+            Status::Reserved(alice),
+        ];
+        for (status, name) in all.iter().zip(STATUS_NAMES) {
+            assert_eq!(status.as_str(), name);
+            assert_eq!(&status.to_string().parse::<Status>().unwrap(), status);
+        }
+
+        assert_eq!(
+            "inuse".parse::<Status>(),
+            Err(ParseStatusError::MissingUser("inuse".to_string()))
+        );
+        assert_eq!(
+            "in_use alice".parse::<Status>(),
+            Err(ParseStatusError::Unknown("in_use".to_string()))
+        );
+    }
+
+    #[test]
+    fn state_without_reason_deserializes() {
+        let state: MachineState = serde_json::from_str(r#"{"state":"Disabled"}"#).unwrap();
+        assert_eq!(state, MachineState::disabled(None, None));
+    }
+
+    #[test]
+    fn freeing_records_last_user() {
+        let alice = UserRef::new("alice".to_string());
+        let bob = UserRef::new("bob".to_string());
+
+        let state = MachineState::free(None)
+            .transition(Status::InUse(alice.clone()), None)
+            .transition(Status::Free, None);
+        assert_eq!(state.previous, Some(alice.clone()));
+
+        // Reserving and blocking leave `previous` alone, as does starting to use the machine
+        let state = state.transition(Status::Reserved(bob.clone()), None);
+        assert_eq!(state.previous, Some(alice.clone()));
+        let state = state.transition(Status::Blocked(bob.clone()), None);
+        assert_eq!(state.previous, Some(alice.clone()));
+        let state = state
+            .transition(Status::Free, None)
+            .transition(Status::InUse(bob.clone()), None);
+        assert_eq!(state.previous, Some(alice));
+
+        // Handing in for checking counts as leaving `InUse`
+        let state = state.transition(Status::ToCheck(bob.clone()), None);
+        assert_eq!(state.previous, Some(bob));
+    }
+
+    #[test]
+    fn force_free_records_user_not_manager() {
+        let user = UserRef::new("user".to_string());
+        // Managers force-free without any reference to themselves ending up in the state
+        let state = MachineState::used(user.clone(), None).transition(Status::Free, None);
+        assert_eq!(state, MachineState::free(Some(user.clone())));
+
+        let state = MachineState::used(user.clone(), None)
+            .transition(Status::Disabled, Some("broken".into()));
+        assert_eq!(state.previous, Some(user));
+        assert_eq!(state.reason.as_deref(), Some("broken"));
+    }
+
+    #[test]
+    fn disable_reason_roundtrips() {
+        let state = MachineState::disabled(Some("awaiting part".to_string()), None);
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(serde_json::from_str::<MachineState>(&json).unwrap(), state);
+
+        let mut serializer = AllocSerializer::<1024>::default();
+        serializer.serialize_value(&state).unwrap();
+        let bytes = serializer.into_serializer().into_inner();
+        let archived = unsafe { rkyv::archived_root::<MachineState>(&bytes) };
+        assert_eq!(format!("{}", archived), "disabled: awaiting part");
+    }
+}
@@ -1,10 +1,18 @@
-use crate::resources::Resource;
+use crate::config::MachineDescription;
+use crate::resources::group::ResourceGroup;
+use crate::resources::state::db::StateDB;
+use crate::resources::{Inner as ResourceInner, Resource};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 #[derive(Debug)]
 struct Inner {
     id: HashMap<String, Resource>,
+    /// Machines removed by a reload, kept so adding them back serves the same instance again
+    removed: HashMap<String, Resource>,
+    groups: HashMap<String, ResourceGroup>,
+    /// Changed whenever the set of machines or their descriptions change
+    generation: u64,
 }
 
 impl Inner {
@@ -16,31 +24,155 @@ impl Inner {
             assert!(old.is_none());
         }
 
-        Self { id }
+        Self {
+            id,
+            removed: HashMap::new(),
+            groups: HashMap::new(),
+            // Random so clients don't mistake the machines of a restarted bffh for the old ones
+            generation: rand::random(),
+        }
     }
 }
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// What changed when machines were reloaded
+pub struct Reloaded {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+}
+
 #[derive(Clone, Debug)]
+/// The machines being served, shared by everything looking them up
+///
+/// All clones see machines added or removed by [`ResourcesHandle::reload`].
 pub struct ResourcesHandle {
-    inner: Arc<Inner>,
+    inner: Arc<RwLock<Inner>>,
 }
 
 impl ResourcesHandle {
     pub fn new(resources: impl IntoIterator<Item = Resource>) -> Self {
         Self {
-            inner: Arc::new(Inner::new(resources)),
+            inner: Arc::new(RwLock::new(Inner::new(resources))),
         }
     }
 
-    pub fn list_all(&self) -> impl IntoIterator<Item = &Resource> {
-        self.inner.id.values()
+    /// Build groups from their member ids. Unknown members are skipped.
+    pub fn with_groups<'a>(
+        self,
+        groups: impl IntoIterator<Item = (&'a String, &'a Vec<String>, bool)>,
+    ) -> Self {
+        let groups = groups
+            .into_iter()
+            .map(|(id, members, queue)| {
+                let members = members
+                    .iter()
+                    .filter_map(|member| {
+                        let resource = self.get_by_id(member);
+                        if resource.is_none() {
+                            tracing::error!(group=%id, %member, "Machine configured for group not found!");
+                        }
+                        resource
+                    })
+                    .collect();
+                (id.clone(), ResourceGroup::new(id.clone(), members, queue))
+            })
+            .collect();
+        self.inner.write().unwrap().groups = groups;
+        self
+    }
+
+    pub fn get_group(&self, id: &str) -> Option<ResourceGroup> {
+        self.inner.read().unwrap().groups.get(id).cloned()
     }
 
-    pub fn get_by_id(&self, id: &str) -> Option<&Resource> {
-        self.inner.id.get(id)
+    pub fn list_all(&self) -> Vec<Resource> {
+        self.inner.read().unwrap().id.values().cloned().collect()
+    }
+
+    pub fn get_by_id(&self, id: &str) -> Option<Resource> {
+        self.inner.read().unwrap().id.get(id).cloned()
+    }
+
+    /// Token changing whenever machines are added, removed or their description changes
+    ///
+    /// Clients can cache the machine descriptions and only fetch them again once this changed.
+    pub fn generation(&self) -> u64 {
+        self.inner.read().unwrap().generation
+    }
+
+    /// All machines together with the [`generation`](Self::generation) they belong to
+    pub fn list_all_with_generation(&self) -> (u64, Vec<Resource>) {
+        let inner = self.inner.read().unwrap();
+        (inner.generation, inner.id.values().cloned().collect())
+    }
+
+    /// Bring the served machines in line with `machines`, returning the ones newly created
+    ///
+    /// New machines load their state from `statedb` or start out free. Machines with a changed
+    /// description get it replaced in place, so everything bound to them keeps working. Removed
+    /// machines are no longer served but kept around, so adding them back serves the same machine
+    /// again. Those aren't returned as their tasks are still running. Groups are left as they are.
+    pub fn reload(
+        &self,
+        machines: &HashMap<String, MachineDescription>,
+        statedb: &StateDB,
+    ) -> (Reloaded, Vec<Resource>) {
+        let mut inner = self.inner.write().unwrap();
+        let mut reloaded = Reloaded::default();
+
+        let gone: Vec<String> = inner
+            .id
+            .keys()
+            .filter(|id| !machines.contains_key(*id))
+            .cloned()
+            .collect();
+        for id in gone {
+            tracing::info!(%id, "machine was removed, no longer serving it");
+            if let Some(resource) = inner.id.remove(&id) {
+                inner.removed.insert(id, resource);
+            }
+            reloaded.removed += 1;
+        }
+
+        let mut added = Vec::new();
+        for (id, desc) in machines {
+            match inner.id.get(id) {
+                Some(resource) if *resource.get_description() == *desc => {}
+                Some(resource) => {
+                    tracing::info!(%id, "machine description changed, updating it");
+                    resource.set_description(desc.clone());
+                    reloaded.updated += 1;
+                }
+                None => {
+                    tracing::info!(%id, "machine was added");
+                    let resource = match inner.removed.remove(id) {
+                        Some(resource) => {
+                            resource.set_description(desc.clone());
+                            resource
+                        }
+                        None => {
+                            let resource = Resource::new(Arc::new(ResourceInner::new(
+                                id.clone(),
+                                statedb.clone(),
+                                desc.clone(),
+                            )));
+                            added.push(resource.clone());
+                            resource
+                        }
+                    };
+                    inner.id.insert(id.clone(), resource);
+                    reloaded.added += 1;
+                }
+            }
+        }
+        if reloaded != Reloaded::default() {
+            inner.generation = inner.generation.wrapping_add(1);
+        }
+        (reloaded, added)
     }
 
-    pub fn get_by_urn(&self, urn: &str) -> Option<&Resource> {
+    pub fn get_by_urn(&self, urn: &str) -> Option<Resource> {
         if let Some(id) = {
             let mut parts = urn.split_terminator(':');
             let part_urn = parts.next().map(|u| u == "urn").unwrap_or(false);
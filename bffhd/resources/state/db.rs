@@ -2,17 +2,102 @@ use thiserror::Error;
 
 use crate::db;
 use crate::db::{AlignedAdapter, ArchivedValue, RawDB, DB};
-use lmdb::{DatabaseFlags, Environment, EnvironmentFlags, Transaction, WriteFlags};
+use lmdb::{DatabaseFlags, Environment, EnvironmentFlags, RwTransaction, Transaction, WriteFlags};
 use miette::Diagnostic;
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::ser::Serializer;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::fmt::Debug;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
 use std::{path::Path, sync::Arc};
 
-use crate::resources::state::State;
+use crate::resources::modules::fabaccess::MachineState;
+use crate::resources::state::dump::{RestoreError, StateDump};
+use crate::resources::state::migrate;
+use crate::resources::state::{State, StateLimitError, StateLimits, UnknownOidPolicy};
 
 #[derive(Debug, Clone)]
 pub struct StateDB {
     env: Arc<Environment>,
     db: DB<AlignedAdapter<State>>,
+    /// How often each machine was used. Kept out of [`State`] so the archived layout of existing
+    /// entries stays valid.
+    usage: RawDB,
+    /// Raw bytes of states that failed validation, for later inspection
+    quarantine: RawDB,
+    /// Layout version of the stored states, see [`migrate`]
+    meta: RawDB,
+    /// Values of unknown type restored with [`UnknownOidPolicy::Passthrough`], as JSON object
+    /// keyed by OID, so the next dump writes them out again
+    values: RawDB,
+    /// Validate states in [`StateDB::load`]
+    strict: bool,
+    limits: StateLimits,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum StateDirError {
+    #[error("state db directory {} doesn't exist", .0.display())]
+    #[diagnostic(
+        code(bffh::db::state::dir::missing),
+        help("create it or set `create_db_dir = True` to have it created on startup")
+    )]
+    Missing(PathBuf),
+    #[error("{} is not a directory, the state db can't be created inside it", .0.display())]
+    #[diagnostic(
+        code(bffh::db::state::dir::not_a_directory),
+        help("`db_path` names the database file, its parent has to be a directory")
+    )]
+    NotADirectory(PathBuf),
+    #[error("creating state db directory {} failed", .path.display())]
+    #[diagnostic(
+        code(bffh::db::state::dir::create),
+        help("does the user running bffhd have write access to the parent directory?")
+    )]
+    Create {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// Make sure the directory the state db at `db_path` is kept in exists
+///
+/// If `create` is set a missing directory is created including its parents, accessible only by
+/// the user running bffhd.
+pub fn ensure_db_dir(db_path: &Path, create: bool) -> Result<(), StateDirError> {
+    let dir = match db_path.parent() {
+        // A relative path without directory is put into the working directory
+        Some(dir) if dir.as_os_str().is_empty() => return Ok(()),
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+    match dir.metadata() {
+        Ok(meta) if meta.is_dir() => Ok(()),
+        Ok(_) => Err(StateDirError::NotADirectory(dir.to_path_buf())),
+        Err(error) if error.kind() == io::ErrorKind::NotFound && create => {
+            tracing::info!(dir = %dir.display(), "creating state db directory");
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)
+                .map_err(|source| StateDirError::Create {
+                    path: dir.to_path_buf(),
+                    source,
+                })
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            Err(StateDirError::Missing(dir.to_path_buf()))
+        }
+        // A file in the way further up is reported as NotADirectory by the OS
+        Err(source) => Err(StateDirError::Create {
+            path: dir.to_path_buf(),
+            source,
+        }),
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Error, Diagnostic)]
@@ -29,8 +114,26 @@ pub enum StateDBError {
     #[error("creating the state db failed")]
     #[diagnostic(code(bffh::db::state::create))]
     Create(#[source] db::Error),
+    #[error("upgrading the stored machine states failed")]
+    #[diagnostic(code(bffh::db::state::upgrade))]
+    Upgrade(#[source] db::Error),
+    #[error("machine states were stored by a newer bffhd (layout version {0})")]
+    #[diagnostic(
+        code(bffh::db::state::version),
+        help("upgrade bffhd or restore a dump made with this version")
+    )]
+    Newer(u32),
+    #[error("machine states are stored in an older layout (version {0})")]
+    #[diagnostic(
+        code(bffh::db::state::outdated),
+        help("start bffhd once to upgrade them")
+    )]
+    Outdated(u32),
 }
 
+/// Key in the meta db holding the layout version of the stored states
+const VERSION_KEY: &str = "version";
+
 impl StateDB {
     pub fn open_env<P: AsRef<Path>>(path: P) -> Result<Arc<Environment>, StateDBError> {
         Environment::new()
@@ -46,15 +149,64 @@ impl StateDB {
             .map_err(|e| StateDBError::OpenEnv(e.into()))
     }
 
-    fn new(env: Arc<Environment>, db: RawDB) -> Self {
+    fn new(
+        env: Arc<Environment>,
+        db: RawDB,
+        usage: RawDB,
+        quarantine: RawDB,
+        meta: RawDB,
+        values: RawDB,
+    ) -> Self {
         let db = DB::new(db);
-        Self { env, db }
+        Self {
+            env,
+            db,
+            usage,
+            quarantine,
+            meta,
+            values,
+            strict: false,
+            limits: StateLimits::default(),
+        }
+    }
+
+    /// Validate states when loading them, quarantining corrupt ones
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Refuse changes to states exceeding `limits`, see [`StateDB::check_limits`]
+    pub fn limits(mut self, limits: StateLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Check a new state against the configured limits before it is stored
+    pub fn check_limits(&self, state: &ArchivedValue<State>) -> Result<(), StateLimitError> {
+        self.limits.check_size(state.as_slice().len())
     }
 
     pub fn open_with_env(env: Arc<Environment>) -> Result<Self, StateDBError> {
         let db = RawDB::open(&env, Some("state"))
             .map_err(|e| StateDBError::Open(e.into()))?;
-        Ok(Self::new(env, db))
+        let usage = RawDB::open(&env, Some("usage"))
+            .map_err(|e| StateDBError::Open(e.into()))?;
+        let quarantine = RawDB::open(&env, Some("quarantine"))
+            .map_err(|e| StateDBError::Open(e.into()))?;
+        let meta = RawDB::open(&env, Some("meta")).map_err(|e| StateDBError::Open(e.into()))?;
+        let values = RawDB::open(&env, Some("values")).map_err(|e| StateDBError::Open(e.into()))?;
+        let this = Self::new(env, db, usage, quarantine, meta, values);
+        // Opening doesn't write, states of older layouts are only upgraded by `create`
+        match this
+            .stored_version()
+            .map_err(StateDBError::Open)?
+            .unwrap_or(0)
+        {
+            migrate::CURRENT => Ok(this),
+            version if version > migrate::CURRENT => Err(StateDBError::Newer(version)),
+            version => Err(StateDBError::Outdated(version)),
+        }
     }
 
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StateDBError> {
@@ -66,8 +218,93 @@ impl StateDB {
         let flags = DatabaseFlags::empty();
         let db = RawDB::create(&env, Some("state"), flags)
             .map_err(|e| StateDBError::Create(e.into()))?;
+        let usage = RawDB::create(&env, Some("usage"), flags)
+            .map_err(|e| StateDBError::Create(e.into()))?;
+        let quarantine = RawDB::create(&env, Some("quarantine"), flags)
+            .map_err(|e| StateDBError::Create(e.into()))?;
+        let meta =
+            RawDB::create(&env, Some("meta"), flags).map_err(|e| StateDBError::Create(e.into()))?;
+        let values = RawDB::create(&env, Some("values"), flags)
+            .map_err(|e| StateDBError::Create(e.into()))?;
+
+        let this = Self::new(env, db, usage, quarantine, meta, values);
+        this.upgrade()?;
+        Ok(this)
+    }
+
+    fn stored_version(&self) -> Result<Option<u32>, db::Error> {
+        let txn = self.env.begin_ro_txn()?;
+        let version = self.meta.get(&txn, &VERSION_KEY)?.map(decode_version);
+        Ok(version)
+    }
+
+    /// Convert all states stored with an older layout to the current one
+    ///
+    /// Databases without a version were written before versions were recorded, i.e. by layout
+    /// 0. States that can't be read as their version are moved to quarantine. Everything happens
+    /// in a single transaction, so an interrupted upgrade leaves the old states untouched.
+    fn upgrade(&self) -> Result<(), StateDBError> {
+        let mut txn = self
+            .env
+            .begin_rw_txn()
+            .map_err(|e| StateDBError::Upgrade(e.into()))?;
+        let stored = self
+            .meta
+            .get(&txn, &VERSION_KEY)
+            .map_err(|e| StateDBError::Upgrade(e.into()))?
+            .map(decode_version);
+        let states: Vec<(Vec<u8>, Vec<u8>)> = self
+            .db
+            .get_all(&txn)
+            .map_err(StateDBError::Upgrade)?
+            .into_iter()
+            .map(|(key, state)| (key.to_vec(), state.as_slice().to_vec()))
+            .collect();
+        let version = match stored {
+            Some(version) => version,
+            // A new database, there's nothing to upgrade
+            None if states.is_empty() => migrate::CURRENT,
+            None => 0,
+        };
+        if version > migrate::CURRENT {
+            return Err(StateDBError::Newer(version));
+        }
 
-        Ok(Self::new(env, db))
+        if version < migrate::CURRENT {
+            tracing::info!(
+                from = version,
+                to = migrate::CURRENT,
+                states = states.len(),
+                "upgrading stored machine states"
+            );
+            for (key, bytes) in states {
+                let id = String::from_utf8_lossy(&key).into_owned();
+                match migrate::upgrade(version, &bytes) {
+                    Some(state) => self
+                        .db
+                        .put(&mut txn, &key, &archive(&state), WriteFlags::empty())
+                        .map_err(StateDBError::Upgrade)?,
+                    None => {
+                        tracing::error!(%id, version, "stored state can't be upgraded, moving it to quarantine");
+                        self.quarantine
+                            .put(&mut txn, &key, &bytes, WriteFlags::empty())
+                            .map_err(|e| StateDBError::Upgrade(e.into()))?;
+                        self.db.del(&mut txn, &key).map_err(StateDBError::Upgrade)?;
+                    }
+                }
+            }
+        }
+        if stored != Some(migrate::CURRENT) {
+            self.meta
+                .put(
+                    &mut txn,
+                    &VERSION_KEY,
+                    &migrate::CURRENT.to_le_bytes(),
+                    WriteFlags::empty(),
+                )
+                .map_err(|e| StateDBError::Upgrade(e.into()))?;
+        }
+        txn.commit().map_err(|e| StateDBError::Upgrade(e.into()))
     }
 
     pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, StateDBError> {
@@ -75,6 +312,11 @@ impl StateDB {
         Self::create_with_env(env)
     }
 
+    /// Flush all changes to disk
+    pub fn sync(&self) -> Result<(), db::Error> {
+        self.env.sync(true).map_err(db::Error::from)
+    }
+
     pub fn begin_ro_txn(&self) -> Result<impl Transaction + '_, db::Error> {
         self.env.begin_ro_txn().map_err(db::Error::from)
     }
@@ -91,12 +333,155 @@ impl StateDB {
         self.db.get_all(txn)
     }
 
+    /// Get the stored state of `key`
+    ///
+    /// In strict mode a state that fails validation is moved to the quarantine db and `None`
+    /// returned, as if there never was a state stored.
+    pub fn load(&self, key: impl AsRef<[u8]>) -> Result<Option<ArchivedValue<State>>, db::Error> {
+        let state = self.get(&key)?;
+        match state {
+            Some(state) if self.strict && !is_valid(&state) => {
+                tracing::error!(
+                    key = %String::from_utf8_lossy(key.as_ref()),
+                    "stored state is corrupt, moving it to quarantine and resetting the machine"
+                );
+                let mut txn = self.env.begin_rw_txn()?;
+                self.quarantine
+                    .put(&mut txn, &key, &state.as_slice(), WriteFlags::empty())?;
+                self.db.del(&mut txn, &key)?;
+                txn.commit()?;
+                Ok(None)
+            }
+            state => Ok(state),
+        }
+    }
+
+    /// Raw bytes of the state of `key` moved to quarantine by [`StateDB::load`]
+    pub fn get_quarantined(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, db::Error> {
+        let txn = self.env.begin_ro_txn()?;
+        let bytes = self.quarantine.get(&txn, &key)?.map(<[u8]>::to_vec);
+        Ok(bytes)
+    }
+
+    /// All stored states in their human-readable form, ordered by machine id
+    pub fn dump(&self) -> Result<BTreeMap<String, StateDump>, db::Error> {
+        let txn = self.env.begin_ro_txn()?;
+        let mut states = BTreeMap::new();
+        for (key, state) in self.get_all(&txn)? {
+            let id = String::from_utf8_lossy(key).into_owned();
+            let mut dump = StateDump::from(&MachineState::from(state.as_ref()));
+            if let Some(values) = self.values.get(&txn, &key)? {
+                match serde_json::from_slice(values) {
+                    Ok(values) => dump.values = values,
+                    Err(error) => {
+                        tracing::error!(%id, %error, "stored values of unknown type are unreadable")
+                    }
+                }
+            }
+            states.insert(id, dump);
+        }
+        Ok(states)
+    }
+
+    /// Replace all stored states with `states` in a single transaction
+    ///
+    /// All states are checked first, so nothing is changed if any of them is invalid. Values of
+    /// unknown type are handled according to `policy`. Usage counters are kept.
+    pub fn restore(
+        &self,
+        states: &BTreeMap<String, StateDump>,
+        policy: UnknownOidPolicy,
+    ) -> Result<usize, RestoreError> {
+        let mut txn = self.env.begin_rw_txn().map_err(db::Error::from)?;
+        let restored = self.restore_txn(&mut txn, states, policy)?;
+        txn.commit().map_err(db::Error::from)?;
+        Ok(restored)
+    }
+
+    /// Like [`StateDB::restore`], writing into `txn` of the environment of this database
+    pub(crate) fn restore_txn(
+        &self,
+        txn: &mut RwTransaction,
+        states: &BTreeMap<String, StateDump>,
+        policy: UnknownOidPolicy,
+    ) -> Result<usize, RestoreError> {
+        let states = states
+            .iter()
+            .map(|(id, dump)| {
+                let invalid = |source| RestoreError::Invalid {
+                    id: id.clone(),
+                    source,
+                };
+                let values = dump.unknown_values(policy).map_err(invalid)?;
+                let state = MachineState::try_from(dump.clone()).map_err(invalid)?;
+                Ok((id, archive(&state), values))
+            })
+            .collect::<Result<Vec<_>, RestoreError>>()?;
+
+        self.db.clear(txn)?;
+        self.values.clear(txn).map_err(db::Error::from)?;
+        for (id, state, values) in states.iter() {
+            self.db.put(txn, id, state, WriteFlags::empty())?;
+            if !values.is_empty() {
+                let values = serde_json::to_vec(values).expect("TOML values are always valid JSON");
+                self.values
+                    .put(txn, id, &values, WriteFlags::empty())
+                    .map_err(db::Error::from)?;
+            }
+        }
+        Ok(states.len())
+    }
+
     pub fn put(&self, key: &impl AsRef<[u8]>, val: &ArchivedValue<State>) -> Result<(), db::Error> {
         let mut txn = self.env.begin_rw_txn()?;
         let flags = WriteFlags::empty();
         self.db.put(&mut txn, key, val, flags)?;
         Ok(txn.commit()?)
     }
+
+    /// Number of times `key` was used since its counter was last reset
+    pub fn get_usage(&self, key: impl AsRef<[u8]>) -> Result<u64, db::Error> {
+        let txn = self.env.begin_ro_txn()?;
+        let count = self.usage.get(&txn, &key)?.map_or(0, decode_count);
+        Ok(count)
+    }
+
+    /// Increment the usage counter of `key`, returning the new count
+    pub fn count_use(&self, key: impl AsRef<[u8]>) -> Result<u64, db::Error> {
+        let mut txn = self.env.begin_rw_txn()?;
+        let count = self.usage.get(&txn, &key)?.map_or(0, decode_count) + 1;
+        self.usage
+            .put(&mut txn, &key, &count.to_le_bytes(), WriteFlags::empty())?;
+        txn.commit()?;
+        Ok(count)
+    }
+
+    pub fn reset_usage(&self, key: impl AsRef<[u8]>) -> Result<(), db::Error> {
+        let mut txn = self.env.begin_rw_txn()?;
+        self.usage
+            .put(&mut txn, &key, &0u64.to_le_bytes(), WriteFlags::empty())?;
+        Ok(txn.commit()?)
+    }
+}
+
+fn is_valid(state: &ArchivedValue<State>) -> bool {
+    rkyv::check_archived_root::<State>(state.as_slice()).is_ok()
+}
+
+fn decode_count(buf: &[u8]) -> u64 {
+    buf.try_into().map(u64::from_le_bytes).unwrap_or(0)
+}
+
+fn decode_version(buf: &[u8]) -> u32 {
+    buf.try_into().map(u32::from_le_bytes).unwrap_or(0)
+}
+
+fn archive(state: &MachineState) -> ArchivedValue<State> {
+    let mut serializer = AllocSerializer::<1024>::default();
+    serializer
+        .serialize_value(&state.to_state())
+        .expect("serializing a MachineState should be infallible");
+    ArchivedValue::new(serializer.into_serializer().into_inner())
 }
 
 #[cfg(test)]
@@ -104,4 +489,82 @@ mod tests {
     use super::*;
 
     use std::ops::Deref;
+
+    #[test]
+    fn missing_db_dir_is_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("state").join("bffh.db");
+
+        assert!(matches!(
+            ensure_db_dir(&db_path, false),
+            Err(StateDirError::Missing(_))
+        ));
+        ensure_db_dir(&db_path, true).unwrap();
+        assert!(dir.path().join("state").is_dir());
+        StateDB::open_env(&db_path).unwrap();
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        let error = ensure_db_dir(&file.join("bffh.db"), true).unwrap_err();
+        assert!(matches!(error, StateDirError::NotADirectory(path) if path == file));
+    }
+
+    #[test]
+    fn corrupt_state_is_quarantined() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = StateDB::open_env(dir.path().join("db")).unwrap();
+        let db = StateDB::create_with_env(env).unwrap();
+        let garbage = [0xff; 64];
+        db.put(&"Testmachine", &ArchivedValue::build(&garbage))
+            .unwrap();
+
+        // Not checked unless running strict
+        let loaded = db.load("Testmachine").unwrap().unwrap();
+        assert_eq!(loaded.as_slice(), &garbage[..]);
+
+        let db = db.strict(true);
+        assert!(db.load("Testmachine").unwrap().is_none());
+        assert!(db.get("Testmachine").unwrap().is_none());
+        assert_eq!(
+            db.get_quarantined("Testmachine").unwrap().as_deref(),
+            Some(&garbage[..])
+        );
+    }
+
+    #[test]
+    fn states_of_older_layouts_are_upgraded() {
+        use crate::resources::modules::fabaccess::Status;
+        use crate::users::UserRef;
+
+        let dir = tempfile::tempdir().unwrap();
+        let env = StateDB::open_env(dir.path().join("db")).unwrap();
+        // As written before layout versions were recorded
+        let user = UserRef::new("alice".to_string());
+        let raw = RawDB::create(&env, Some("state"), DatabaseFlags::empty()).unwrap();
+        let mut txn = env.begin_rw_txn().unwrap();
+        let old = migrate::v0(Status::InUse(user.clone()), None);
+        raw.put(&mut txn, &"Lathe", &old, WriteFlags::empty())
+            .unwrap();
+        raw.put(&mut txn, &"Broken", &[0xff; 8], WriteFlags::empty())
+            .unwrap();
+        txn.commit().unwrap();
+        assert!(matches!(
+            StateDB::open_with_env(env.clone()),
+            Err(StateDBError::Open(_))
+        ));
+
+        let db = StateDB::create_with_env(env.clone()).unwrap();
+        let state = db.get("Lathe").unwrap().unwrap();
+        assert_eq!(
+            MachineState::from(state.as_ref()),
+            MachineState::used(user, None)
+        );
+        assert!(db.get("Broken").unwrap().is_none());
+        assert_eq!(
+            db.get_quarantined("Broken").unwrap().as_deref(),
+            Some(&[0xff; 8][..])
+        );
+        assert_eq!(db.stored_version().unwrap(), Some(migrate::CURRENT));
+        StateDB::open_with_env(env).unwrap();
+    }
 }
@@ -0,0 +1,216 @@
+//! Human-readable form of machine states for dumps and backups
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+use miette::Diagnostic;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::db;
+use crate::resources::modules::fabaccess::{MachineState, Status};
+use crate::resources::state::UnknownOidPolicy;
+use crate::users::UserRef;
+use crate::utils::oid::ObjectIdentifier;
+
+#[derive(Debug, Error, Diagnostic, PartialEq, Eq)]
+pub enum DumpError {
+    #[error("unknown machine status `{0}`")]
+    #[diagnostic(
+        code(bffh::state::dump::status),
+        help("Known are free, inuse, tocheck, blocked, disabled and reserved")
+    )]
+    UnknownStatus(String),
+    #[error("machine status `{0}` needs a user")]
+    #[diagnostic(code(bffh::state::dump::missing_user))]
+    MissingUser(String),
+    #[error("machine status `{0}` doesn't take a user")]
+    #[diagnostic(code(bffh::state::dump::unexpected_user))]
+    UnexpectedUser(String),
+    #[error("`{0}` is not an OID")]
+    #[diagnostic(code(bffh::state::dump::invalid_oid))]
+    InvalidOid(String),
+    #[error("value of unknown type {0}")]
+    #[diagnostic(
+        code(bffh::state::dump::unknown_oid),
+        help("Set `unknown_oids` to \"skip\" or \"passthrough\" to load the dump anyway")
+    )]
+    UnknownOid(String),
+}
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum RestoreError {
+    #[error("dumped state of machine '{id}' is invalid")]
+    #[diagnostic(code(bffh::state::dump::invalid), help("Nothing was restored"))]
+    Invalid {
+        id: String,
+        #[source]
+        source: DumpError,
+    },
+    #[error("writing restored states failed")]
+    #[diagnostic(code(bffh::state::dump::write))]
+    Write(#[from] db::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// A [`MachineState`] with each part under its own key
+///
+/// Unlike the stored form this can be read and edited by hand, e.g.
+///
+/// ```toml
+/// status = "inuse"
+/// user = "alice"
+/// previous = "bob"
+/// ```
+///
+/// Reservations that run out carry `reserved_until` as Unix timestamp in seconds. Values of other
+/// types, e.g. from a newer bffhd, go into the `values` table keyed by their OID.
+pub struct StateDump {
+    /// One of `free`, `inuse`, `tocheck`, `blocked`, `disabled` and `reserved`
+    pub status: String,
+    /// User the status refers to, e.g. who is using or has reserved the machine
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Last user that had the machine in use
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reserved_until: Option<i64>,
+    /// Values of types other than the machine state, handled according to [`UnknownOidPolicy`]
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        serialize_with = "toml::ser::tables_last"
+    )]
+    pub values: BTreeMap<String, toml::Value>,
+}
+
+impl StateDump {
+    /// The [`values`](Self::values) to keep when restoring this dump with `policy`
+    pub fn unknown_values(
+        &self,
+        policy: UnknownOidPolicy,
+    ) -> Result<BTreeMap<String, toml::Value>, DumpError> {
+        for oid in self.values.keys() {
+            ObjectIdentifier::from_str(oid).map_err(|_| DumpError::InvalidOid(oid.clone()))?;
+        }
+        match policy {
+            UnknownOidPolicy::Error => match self.values.keys().next() {
+                Some(oid) => Err(DumpError::UnknownOid(oid.clone())),
+                None => Ok(BTreeMap::new()),
+            },
+            UnknownOidPolicy::Skip => {
+                for oid in self.values.keys() {
+                    tracing::warn!(%oid, "skipping state value of unknown type");
+                }
+                Ok(BTreeMap::new())
+            }
+            UnknownOidPolicy::Passthrough => Ok(self.values.clone()),
+        }
+    }
+}
+
+impl From<&MachineState> for StateDump {
+    fn from(state: &MachineState) -> Self {
+        Self {
+            status: state.state.as_str().to_string(),
+            user: state
+                .state
+                .user()
+                .map(|user| user.get_username().to_string()),
+            previous: state
+                .previous
+                .as_ref()
+                .map(|user| user.get_username().to_string()),
+            reason: state.reason.clone(),
+            reserved_until: state.reserved_until,
+            values: BTreeMap::new(),
+        }
+    }
+}
+
+impl TryFrom<StateDump> for MachineState {
+    type Error = DumpError;
+
+    fn try_from(dump: StateDump) -> Result<Self, Self::Error> {
+        let StateDump {
+            status,
+            user,
+            previous,
+            reason,
+            reserved_until,
+            values: _,
+        } = dump;
+        let user = user.map(UserRef::new);
+        let state = match (status.as_str(), user) {
+            ("free", None) => Status::Free,
+            ("disabled", None) => Status::Disabled,
+            ("inuse", Some(user)) => Status::InUse(user),
+            ("tocheck", Some(user)) => Status::ToCheck(user),
+            ("blocked", Some(user)) => Status::Blocked(user),
+            ("reserved", Some(user)) => Status::Reserved(user),
+            ("free" | "disabled", Some(_)) => return Err(DumpError::UnexpectedUser(status)),
+            ("inuse" | "tocheck" | "blocked" | "reserved", None) => {
+                return Err(DumpError::MissingUser(status))
+            }
+            _ => return Err(DumpError::UnknownStatus(status)),
+        };
+        Ok(Self {
+            state,
+            previous: previous.map(UserRef::new),
+            reason,
+            reserved_until,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_use_machine_is_dumped_readably() {
+        let state = MachineState::used(
+            UserRef::new("alice".to_string()),
+            Some(UserRef::new("bob".to_string())),
+        );
+
+        let dump = StateDump::from(&state);
+        let encoded = toml::to_string(&dump).unwrap();
+        assert_eq!(
+            encoded,
+            "status = \"inuse\"\nuser = \"alice\"\nprevious = \"bob\"\n"
+        );
+
+        let decoded: StateDump = toml::from_str(&encoded).unwrap();
+        assert_eq!(MachineState::try_from(decoded), Ok(state));
+    }
+
+    #[test]
+    fn inconsistent_dumps_are_rejected() {
+        let dump = |status: &str, user: Option<&str>| StateDump {
+            status: status.to_string(),
+            user: user.map(str::to_string),
+            previous: None,
+            reason: None,
+            reserved_until: None,
+            values: BTreeMap::new(),
+        };
+        assert_eq!(
+            MachineState::try_from(dump("inuse", None)),
+            Err(DumpError::MissingUser("inuse".to_string()))
+        );
+        assert_eq!(
+            MachineState::try_from(dump("free", Some("alice"))),
+            Err(DumpError::UnexpectedUser("free".to_string()))
+        );
+        assert_eq!(
+            MachineState::try_from(dump("broken", None)),
+            Err(DumpError::UnknownStatus("broken".to_string()))
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! Upgrades of stored machine states to the layout of this build
+//!
+//! States are stored in their archived rkyv form and read without validation, so a state written
+//! with a different layout of [`MachineState`] can't be read at all. Every change to that layout
+//! needs a new version here and a conversion from the previous one.
+
+use rkyv::{Archive, Deserialize, Infallible};
+
+use crate::db::ArchivedValue;
+use crate::resources::modules::fabaccess::{MachineState, Status};
+use crate::users::UserRef;
+
+/// Layout version of the states written by this build
+///
+/// 0. The status and the previous user
+/// 1. Adds the reason and the expiry of reservations
+pub const CURRENT: u32 = 1;
+
+#[derive(Archive, Deserialize, rkyv::Serialize)]
+#[archive_attr(derive(bytecheck::CheckBytes))]
+/// `State` as stored by version 0
+struct StateV0 {
+    inner: MachineStateV0,
+}
+
+#[derive(Archive, Deserialize, rkyv::Serialize)]
+#[archive_attr(derive(bytecheck::CheckBytes))]
+struct MachineStateV0 {
+    state: Status,
+    previous: Option<UserRef>,
+}
+
+/// Read a state stored by layout `version`
+///
+/// Returns `None` if `bytes` aren't a valid state of that version.
+pub fn upgrade(version: u32, bytes: &[u8]) -> Option<MachineState> {
+    // LMDB makes no promises about alignment, the archived form needs it
+    let aligned = ArchivedValue::<StateV0>::build(bytes);
+    match version {
+        0 => {
+            let archived = rkyv::check_archived_root::<StateV0>(aligned.as_slice()).ok()?;
+            let StateV0 { inner } = archived.deserialize(&mut Infallible).ok()?;
+            Some(MachineState {
+                state: inner.state,
+                previous: inner.previous,
+                reason: None,
+                reserved_until: None,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// A state as version 0 stored it
+#[cfg(test)]
+pub fn v0(state: Status, previous: Option<UserRef>) -> Vec<u8> {
+    use rkyv::ser::serializers::AllocSerializer;
+    use rkyv::ser::Serializer;
+
+    let mut serializer = AllocSerializer::<1024>::default();
+    serializer
+        .serialize_value(&StateV0 {
+            inner: MachineStateV0 { state, previous },
+        })
+        .unwrap();
+    serializer.into_serializer().into_inner().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_0_states_are_upgraded() {
+        let user = UserRef::new("alice".to_string());
+        let bytes = v0(Status::InUse(user.clone()), Some(user.clone()));
+
+        assert_eq!(
+            upgrade(0, &bytes),
+            Some(MachineState::used(user.clone(), Some(user)))
+        );
+        assert_eq!(upgrade(0, b"garbage"), None);
+        assert_eq!(upgrade(CURRENT + 1, &bytes), None);
+    }
+}
@@ -4,7 +4,7 @@ use std::fmt;
 use std::ops::Deref;
 
 use rkyv::{out_field, Archive, Deserialize, Serialize};
-use serde::de::{Error, MapAccess, Unexpected};
+use serde::de::{DeserializeSeed, Error, IgnoredAny, MapAccess, Unexpected};
 use serde::ser::SerializeMap;
 use serde::Deserializer;
 
@@ -14,10 +14,12 @@ use crate::MachineState;
 use crate::utils::oid::ObjectIdentifier;
 
 pub mod db;
+pub mod dump;
+mod migrate;
 pub mod value;
 
 #[derive(Archive, Serialize, Deserialize, Clone, PartialEq, Eq)]
-#[archive_attr(derive(Debug))]
+#[archive_attr(derive(Debug, bytecheck::CheckBytes))]
 pub struct State {
     pub inner: MachineState,
 }
@@ -54,28 +56,169 @@ impl<'de> serde::Deserialize<'de> for State {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_map(StateVisitor)
+        StateSeed::new(UnknownOidPolicy::Error)
+            .deserialize(deserializer)
+            .map(|state| state.state)
     }
 }
 
-struct StateVisitor;
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// What to do with state values whose OID no implementation is registered for
+pub enum UnknownOidPolicy {
+    /// Refuse to read the whole state
+    #[default]
+    Error,
+    /// Drop the value, keeping the rest of the state
+    Skip,
+    /// Keep the value uninterpreted so it's written out again unchanged, e.g. for a newer build
+    Passthrough,
+}
+
+impl UnknownOidPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnknownOidPolicy::Error => "error",
+            UnknownOidPolicy::Skip => "skip",
+            UnknownOidPolicy::Passthrough => "passthrough",
+        }
+    }
+}
+
+impl serde::Serialize for UnknownOidPolicy {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for UnknownOidPolicy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+        match s.as_str() {
+            "error" => Ok(UnknownOidPolicy::Error),
+            "skip" => Ok(UnknownOidPolicy::Skip),
+            "passthrough" => Ok(UnknownOidPolicy::Passthrough),
+            _ => Err(D::Error::unknown_variant(
+                &s,
+                &["error", "skip", "passthrough"],
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+/// Upper bounds for a single machine state, so a misbehaving actor or initiator can't bloat the db
+pub struct StateLimits {
+    /// Size of the stored form of a state in bytes
+    pub max_size: usize,
+}
+
+impl Default for StateLimits {
+    fn default() -> Self {
+        Self { max_size: 4096 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error, miette::Diagnostic)]
+pub enum StateLimitError {
+    #[error("state takes {size} bytes but at most {max} are allowed")]
+    #[diagnostic(
+        code(bffh::state::limits::size),
+        help("Raise `state_limits.max_size` in the config if this is intended")
+    )]
+    TooLarge { size: usize, max: usize },
+}
+
+impl StateLimits {
+    pub fn check_size(&self, size: usize) -> Result<(), StateLimitError> {
+        if size > self.max_size {
+            return Err(StateLimitError::TooLarge {
+                size,
+                max: self.max_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A [`State`] together with the values kept by [`UnknownOidPolicy::Passthrough`]
+pub struct StateWithUnknown {
+    pub state: State,
+    pub unknown: Vec<(ObjectIdentifier, serde_json::Value)>,
+}
+
+impl serde::Serialize for StateWithUnknown {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut ser = serializer.serialize_map(Some(1 + self.unknown.len()))?;
+        ser.serialize_entry(OID_VALUE.deref(), &self.state.inner)?;
+        for (oid, value) in self.unknown.iter() {
+            ser.serialize_entry(oid, value)?;
+        }
+        ser.end()
+    }
+}
+
+/// Reads a [`StateWithUnknown`], handling values of unknown type according to the policy
+pub struct StateSeed {
+    pub policy: UnknownOidPolicy,
+}
+
+impl StateSeed {
+    pub fn new(policy: UnknownOidPolicy) -> Self {
+        Self { policy }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for StateSeed {
+    type Value = StateWithUnknown;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_map(StateVisitor(self))
+    }
+}
+
+struct StateVisitor(StateSeed);
 impl<'de> serde::de::Visitor<'de> for StateVisitor {
-    type Value = State;
+    type Value = StateWithUnknown;
 
     fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
         write!(formatter, "a map from OIDs to value objects")
     }
 
     fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
-        let oid: ObjectIdentifier = map.next_key()?.ok_or(A::Error::missing_field("oid"))?;
-        if oid != *OID_VALUE.deref() {
-            return Err(A::Error::invalid_value(
-                Unexpected::Other("Unknown OID"),
-                &"OID of fabaccess state",
-            ));
+        let mut inner = None;
+        let mut unknown = Vec::new();
+        while let Some(oid) = map.next_key::<ObjectIdentifier>()? {
+            if oid == *OID_VALUE.deref() {
+                inner = Some(map.next_value::<MachineState>()?);
+                continue;
+            }
+            match self.0.policy {
+                UnknownOidPolicy::Error => {
+                    return Err(A::Error::invalid_value(
+                        Unexpected::Other("Unknown OID"),
+                        &"OID of fabaccess state",
+                    ))
+                }
+                UnknownOidPolicy::Skip => {
+                    tracing::warn!(%oid, "skipping state value of unknown type");
+                    map.next_value::<IgnoredAny>()?;
+                }
+                UnknownOidPolicy::Passthrough => unknown.push((oid, map.next_value()?)),
+            }
         }
-        let val: MachineState = map.next_value()?;
-        Ok(State { inner: val })
+        let inner = inner.ok_or_else(|| A::Error::missing_field("oid"))?;
+        Ok(StateWithUnknown {
+            state: State { inner },
+            unknown,
+        })
     }
 }
 
@@ -83,4 +226,104 @@ impl<'de> serde::de::Visitor<'de> for StateVisitor {
 pub mod tests {
     use super::value::*;
     use super::*;
+    use crate::resources::modules::fabaccess::Status;
+    use crate::users::UserRef;
+    use crate::utils::oid::tests::seeded_rng;
+    use rand::Rng;
+
+    fn gen_user(rng: &mut impl Rng) -> UserRef {
+        UserRef::new(format!("user{}", rng.gen::<u8>()))
+    }
+
+    pub(crate) fn gen_random(rng: &mut impl Rng) -> MachineState {
+        let state = match rng.gen_range(0..6) {
+            0 => Status::Free,
+            1 => Status::InUse(gen_user(rng)),
+            2 => Status::ToCheck(gen_user(rng)),
+            3 => Status::Blocked(gen_user(rng)),
+            4 => Status::Disabled,
+            _ => Status::Reserved(gen_user(rng)),
+        };
+        let previous = rng.gen::<bool>().then(|| gen_user(rng));
+        let reason = rng
+            .gen::<bool>()
+            .then(|| format!("reason {}", rng.gen::<u32>()));
+        let reserved_until =
+            matches!(state, Status::Reserved(_)).then(|| rng.gen_range(0..i64::from(u32::MAX)));
+        MachineState {
+            state,
+            previous,
+            reason,
+            reserved_until,
+        }
+    }
+
+    const WITH_UNKNOWN: &str = r#"{
+        "1.3.6.1.4.1.48398.612.2.4": { "state": "Free" },
+        "1.3.6.1.4.1.61783.612.9.1": { "brightness": 80 }
+    }"#;
+
+    fn read(policy: UnknownOidPolicy) -> Result<StateWithUnknown, serde_json::Error> {
+        let mut deserializer = serde_json::Deserializer::from_str(WITH_UNKNOWN);
+        StateSeed::new(policy).deserialize(&mut deserializer)
+    }
+
+    #[test]
+    fn unknown_oid_is_an_error_by_default() {
+        assert!(read(UnknownOidPolicy::Error).is_err());
+        assert!(serde_json::from_str::<State>(WITH_UNKNOWN).is_err());
+    }
+
+    #[test]
+    fn unknown_oid_can_be_skipped() {
+        let read = read(UnknownOidPolicy::Skip).unwrap();
+        assert_eq!(read.state, MachineState::free(None).to_state());
+        assert!(read.unknown.is_empty());
+    }
+
+    #[test]
+    fn unknown_oid_can_be_passed_through() {
+        let read = read(UnknownOidPolicy::Passthrough).unwrap();
+        assert_eq!(read.state, MachineState::free(None).to_state());
+
+        let written = serde_json::to_value(&read).unwrap();
+        let original: serde_json::Value = serde_json::from_str(WITH_UNKNOWN).unwrap();
+        assert_eq!(written, original);
+    }
+
+    #[test]
+    fn states_beyond_limits_are_rejected() {
+        let limits = StateLimits { max_size: 64 };
+        let mut state = MachineState::free(None);
+        let small = rkyv::to_bytes::<_, 1024>(&state.to_state()).unwrap();
+        assert_eq!(limits.check_size(small.len()), Ok(()));
+
+        state.reason = Some("x".repeat(100));
+        let large = rkyv::to_bytes::<_, 1024>(&state.to_state()).unwrap();
+        assert_eq!(
+            limits.check_size(large.len()),
+            Err(StateLimitError::TooLarge {
+                size: large.len(),
+                max: 64
+            })
+        );
+    }
+
+    #[test]
+    fn random_states_roundtrip() {
+        let (seed, mut rng) = seeded_rng();
+        for _ in 0..100 {
+            let state = gen_random(&mut rng).to_state();
+
+            let bytes = rkyv::to_bytes::<_, 1024>(&state).unwrap();
+            let archived = rkyv::check_archived_root::<State>(&bytes[..])
+                .unwrap_or_else(|error| panic!("{}, BFFH_TEST_SEED={}", error, seed));
+            let restored: State = archived.deserialize(&mut rkyv::Infallible).unwrap();
+            assert_eq!(restored, state, "BFFH_TEST_SEED={}", seed);
+
+            let json = serde_json::to_string(&state).unwrap();
+            let restored: State = serde_json::from_str(&json).unwrap();
+            assert_eq!(restored, state, "BFFH_TEST_SEED={}", seed);
+        }
+    }
 }
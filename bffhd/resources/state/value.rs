@@ -19,8 +19,12 @@ use std::collections::HashMap;
 use std::ops::Deref;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
+use rkyv::string::ArchivedString;
 use rkyv::vec::ArchivedVec;
 
+use once_cell::sync::Lazy;
+use std::str::FromStr;
+
 #[repr(transparent)]
 struct MetaBox<T: ?Sized>(Box<T>);
 impl<T: ?Sized> From<Box<T>> for MetaBox<T> {
@@ -144,6 +148,23 @@ pub trait SerializeDynOid {
 pub trait TypeOid {
     fn type_oid() -> &'static ObjectIdentifier;
     fn type_name() -> &'static str;
+    fn value_kind() -> ValueKind;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// Shape of a state value, allowing clients to decode values of types they don't know the OID of
+pub enum ValueKind {
+    Bool,
+    /// Unsigned integer of any width
+    UInt,
+    /// Signed integer of any width
+    Int,
+    /// Three `u8`s named `a`, `b` and `c`, e.g. a RGB colour
+    Vec3u8,
+    String,
+    /// A value without a generic representation, only decodable by clients knowing its OID
+    Opaque,
 }
 
 impl<T> SerializeDynOid for T
@@ -253,6 +274,7 @@ impl ImplId<'static> {
 struct ImplData<'a> {
     pub vtable: usize,
     pub name: &'a str,
+    pub kind: ValueKind,
     pub info: ImplDebugInfo,
 }
 
@@ -286,6 +308,7 @@ impl ImplEntry<'_> {
             data: ImplData {
                 vtable: <T as RegisteredImpl>::vtable(),
                 name: <T as TypeOid>::type_name(),
+                kind: <T as TypeOid>::value_kind(),
                 info: <T as RegisteredImpl>::debug_info(),
             },
         }
@@ -323,6 +346,21 @@ impl ImplRegistry {
     fn get(&self, type_oid: ImplId) -> Option<ImplData> {
         self.oid_to_data.get(&type_oid).map(|d| *d)
     }
+
+    fn value_types(&self) -> Vec<ValueType> {
+        let mut entries: Vec<_> = self.oid_to_data.iter().collect();
+        entries.sort_by_key(|(id, _)| id.type_oid);
+        entries
+            .into_iter()
+            .map(|(id, data)| ValueType {
+                oid: ObjectIdentifier::try_from(id.type_oid)
+                    .map(|oid| oid.to_string())
+                    .unwrap_or_else(|_| hex::encode(id.type_oid)),
+                name: data.name,
+                kind: data.kind,
+            })
+            .collect()
+    }
 }
 lazy_static::lazy_static! {
     // FIXME: Dynamic modules *will* break this.
@@ -335,6 +373,19 @@ lazy_static::lazy_static! {
     };
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+/// Catalog entry describing a registered state value type
+pub struct ValueType {
+    pub oid: String,
+    pub name: &'static str,
+    pub kind: ValueKind,
+}
+
+/// All state value types registered in this build, ordered by OID
+pub fn value_types() -> Vec<ValueType> {
+    IMPL_REGISTRY.value_types()
+}
+
 pub unsafe trait RegisteredImpl {
     fn vtable() -> usize;
     fn debug_info() -> ImplDebugInfo;
@@ -357,6 +408,9 @@ pub mod macros {
     #[macro_export]
     macro_rules! statevalue_typeoid {
         ( $x:ident, $y:ty, $z:ty ) => {
+            $crate::statevalue_typeoid! { $x, $y, $z, Opaque }
+        };
+        ( $x:ident, $y:ty, $z:ty, $k:ident ) => {
             impl $crate::resources::state::value::TypeOid for $z {
                 fn type_oid() -> &'static $crate::utils::oid::ObjectIdentifier {
                     &$x
@@ -365,6 +419,10 @@ pub mod macros {
                 fn type_name() -> &'static str {
                     stringify!($y)
                 }
+
+                fn value_kind() -> $crate::resources::state::value::ValueKind {
+                    $crate::resources::state::value::ValueKind::$k
+                }
             }
         };
     }
@@ -389,10 +447,13 @@ pub mod macros {
     #[macro_export]
     macro_rules! statevalue_register {
         ( $x:ident, $y:ty ) => {
-            $crate::oidvalue! {$x, $y, $y}
+            $crate::statevalue_register! {$x, $y, $y}
         };
         ( $x:ident, $y:ty, $z:ty ) => {
-            $crate::statevalue_typeoid! { $x, $y, $z }
+            $crate::statevalue_register! {$x, $y, $z, Opaque}
+        };
+        ( $x:ident, $y:ty, $z:ty, $k:ident ) => {
+            $crate::statevalue_typeoid! { $x, $y, $z, $k }
             $crate::statevalue_registeredimpl! { $z }
 
             ::inventory::submit! {$crate::resources::state::value::ImplEntry::new::<$z>()}
@@ -400,6 +461,102 @@ pub mod macros {
     }
 }
 
+/// OIDs of the built-in value types
+pub static OID_BOOL: Lazy<ObjectIdentifier> =
+    Lazy::new(|| ObjectIdentifier::from_str("1.3.6.1.4.1.48398.612.1.1").unwrap());
+pub static OID_U8: Lazy<ObjectIdentifier> =
+    Lazy::new(|| ObjectIdentifier::from_str("1.3.6.1.4.1.48398.612.1.2").unwrap());
+pub static OID_U16: Lazy<ObjectIdentifier> =
+    Lazy::new(|| ObjectIdentifier::from_str("1.3.6.1.4.1.48398.612.1.3").unwrap());
+pub static OID_U32: Lazy<ObjectIdentifier> =
+    Lazy::new(|| ObjectIdentifier::from_str("1.3.6.1.4.1.48398.612.1.4").unwrap());
+pub static OID_U64: Lazy<ObjectIdentifier> =
+    Lazy::new(|| ObjectIdentifier::from_str("1.3.6.1.4.1.48398.612.1.5").unwrap());
+pub static OID_I8: Lazy<ObjectIdentifier> =
+    Lazy::new(|| ObjectIdentifier::from_str("1.3.6.1.4.1.48398.612.1.7").unwrap());
+pub static OID_I16: Lazy<ObjectIdentifier> =
+    Lazy::new(|| ObjectIdentifier::from_str("1.3.6.1.4.1.48398.612.1.8").unwrap());
+pub static OID_I32: Lazy<ObjectIdentifier> =
+    Lazy::new(|| ObjectIdentifier::from_str("1.3.6.1.4.1.48398.612.1.9").unwrap());
+pub static OID_I64: Lazy<ObjectIdentifier> =
+    Lazy::new(|| ObjectIdentifier::from_str("1.3.6.1.4.1.48398.612.1.10").unwrap());
+pub static OID_STRING: Lazy<ObjectIdentifier> =
+    Lazy::new(|| ObjectIdentifier::from_str("1.3.6.1.4.1.48398.612.1.12").unwrap());
+pub static OID_VEC3U8: Lazy<ObjectIdentifier> =
+    Lazy::new(|| ObjectIdentifier::from_str("1.3.6.1.4.1.48398.612.1.13").unwrap());
+
+impl ArchivedStateValue for bool {}
+impl ArchivedStateValue for u8 {}
+impl ArchivedStateValue for u16 {}
+impl ArchivedStateValue for u32 {}
+impl ArchivedStateValue for u64 {}
+impl ArchivedStateValue for i8 {}
+impl ArchivedStateValue for i16 {}
+impl ArchivedStateValue for i32 {}
+impl ArchivedStateValue for i64 {}
+impl ArchivedStateValue for ArchivedString {}
+impl ArchivedStateValue for ArchivedVec3u8 {}
+
+statevalue_register!(OID_BOOL, bool, bool, Bool);
+statevalue_register!(OID_U8, u8, u8, UInt);
+statevalue_register!(OID_U16, u16, u16, UInt);
+statevalue_register!(OID_U32, u32, u32, UInt);
+statevalue_register!(OID_U64, u64, u64, UInt);
+statevalue_register!(OID_I8, i8, i8, Int);
+statevalue_register!(OID_I16, i16, i16, Int);
+statevalue_register!(OID_I32, i32, i32, Int);
+statevalue_register!(OID_I64, i64, i64, Int);
+statevalue_register!(OID_STRING, String, ArchivedString, String);
+statevalue_register!(OID_VEC3U8, Vec3u8, ArchivedVec3u8, Vec3u8);
+
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    rkyv::Archive,
+    rkyv::Serialize,
+    rkyv::Deserialize,
+)]
+#[archive_attr(derive(Debug, PartialEq))]
+pub struct Vec3u8 {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalog_contains_builtin_types() {
+        let types = value_types();
+        let find = |oid: &str| {
+            types
+                .iter()
+                .find(|t| t.oid == oid)
+                .unwrap_or_else(|| panic!("{} is not in the catalog", oid))
+        };
+
+        assert_eq!(find("1.3.6.1.4.1.48398.612.1.1").kind, ValueKind::Bool);
+        assert_eq!(find("1.3.6.1.4.1.48398.612.1.4").kind, ValueKind::UInt);
+        assert_eq!(find("1.3.6.1.4.1.48398.612.1.10").kind, ValueKind::Int);
+        assert_eq!(find("1.3.6.1.4.1.48398.612.1.12").kind, ValueKind::String);
+
+        let vec3 = find("1.3.6.1.4.1.48398.612.1.13");
+        assert_eq!(vec3.kind, ValueKind::Vec3u8);
+        assert_eq!(vec3.name, "Vec3u8");
+
+        let json = serde_json::to_value(vec3).unwrap();
+        assert_eq!(json["kind"], "vec3u8");
+    }
+}
+
 /*
 /// Adding a custom type to BFFH state management:
 ///
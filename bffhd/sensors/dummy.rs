@@ -0,0 +1,106 @@
+use miette::{miette, IntoDiagnostic};
+
+use super::{Sensor, SensorCallbacks};
+use crate::resources::modules::fabaccess::Status;
+use crate::users::UserRef;
+use async_io::Timer;
+use futures_util::future::BoxFuture;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// A sensor pretending to be a door contact that opens and closes every `interval_ms`
+/// milliseconds (default 2000), marking the machine as used by `uid` while open
+///
+/// Only flips between free and in use, machines in any other state are left alone.
+pub struct Dummy {
+    run: BoxFuture<'static, ()>,
+}
+
+impl Dummy {
+    async fn run(callbacks: SensorCallbacks, user: UserRef, interval: Duration) {
+        loop {
+            Timer::after(interval).await;
+            let next = match callbacks.get_status() {
+                Status::Free => Status::InUse(user.clone()),
+                Status::InUse(_) => Status::Free,
+                _ => continue,
+            };
+            tracing::trace!(?next, "Dummy sensor flipping state");
+            if let Err(error) = callbacks.set_status(next) {
+                tracing::warn!(%error, "Dummy sensor state refused");
+            }
+        }
+    }
+}
+
+impl Future for Dummy {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.run.as_mut().poll(cx)
+    }
+}
+
+impl Sensor for Dummy {
+    fn new(params: &HashMap<String, String>, callbacks: SensorCallbacks) -> miette::Result<Self>
+    where
+        Self: Sized,
+    {
+        let uid = params
+            .get("uid")
+            .ok_or_else(|| miette!("Dummy sensor configured without an UID"))?;
+        let interval = match params.get("interval_ms") {
+            Some(ms) => Duration::from_millis(ms.parse().into_diagnostic()?),
+            None => Duration::from_secs(2),
+        };
+
+        Ok(Self {
+            run: Box::pin(Self::run(callbacks, UserRef::new(uid.clone()), interval)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::Source;
+    use crate::authorization::permissions::PermissionBuf;
+    use crate::resources::state::db::StateDB;
+    use crate::sensors::load_single;
+    use crate::testing;
+    use futures_lite::FutureExt;
+
+    #[test]
+    fn dummy_sensor_updates_machine() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = StateDB::open_env(dir.path().join("db")).unwrap();
+        let perm = PermissionBuf::from_string_unchecked("test.perm".to_string());
+        let resource = testing::resource(env, "door", testing::machine("Door", &perm));
+
+        let params = HashMap::from([
+            ("uid".to_string(), "door-user".to_string()),
+            ("interval_ms".to_string(), "10".to_string()),
+        ]);
+        let driver = load_single(
+            &"Door".to_string(),
+            &"Dummy".to_string(),
+            &params,
+            resource.clone(),
+        )
+        .unwrap();
+        async_io::block_on(driver.or(async {
+            Timer::after(Duration::from_millis(200)).await;
+        }));
+
+        let recent = resource.recent_changes();
+        let used = Status::InUse(UserRef::new("door-user".to_string()));
+        assert!(recent.iter().any(|change| change.state.state == used));
+        assert!(recent
+            .iter()
+            .any(|change| change.state.state == Status::Free));
+        assert!(recent.iter().all(|change| change.source == Source::Sensor));
+    }
+}
@@ -1 +1,153 @@
+use crate::audit::Source;
+use crate::resources::modules::fabaccess::{MachineState, Status};
+use crate::resources::Denied;
+use crate::sensors::dummy::Dummy;
+use crate::{Config, Resource, ResourcesHandle};
+use executor::prelude::Executor;
+use futures_util::ready;
+use lightproc::recoverable_handle::RecoverableHandle;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tracing::Span;
 
+mod dummy;
+
+/// A driver observing something in the real world, e.g. a door contact, and reporting it as the
+/// state of the machine it is connected to
+///
+/// Unlike initiators, sensors don't act on behalf of a user; their changes are applied without
+/// permission checks and recorded as coming from a sensor.
+pub trait Sensor: Future<Output = ()> {
+    fn new(params: &HashMap<String, String>, callbacks: SensorCallbacks) -> miette::Result<Self>
+    where
+        Self: Sized;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        <Self as Future>::poll(self, cx)
+    }
+}
+
+#[derive(Clone)]
+pub struct SensorCallbacks {
+    resource: Resource,
+}
+impl SensorCallbacks {
+    pub fn new(resource: Resource) -> Self {
+        Self { resource }
+    }
+
+    /// Current status of the connected machine
+    pub fn get_status(&self) -> Status {
+        MachineState::from(self.resource.get_state().as_ref()).state
+    }
+
+    pub fn set_status(&self, status: Status) -> Result<(), Denied> {
+        self.resource.set_status(status, Source::Sensor)
+    }
+}
+
+pub struct SensorDriver {
+    span: Span,
+    name: String,
+    sensor: Box<dyn Sensor + Unpin + Send>,
+}
+
+impl SensorDriver {
+    pub fn new<S>(
+        span: Span,
+        name: String,
+        params: &HashMap<String, String>,
+        resource: Resource,
+    ) -> miette::Result<Self>
+    where
+        S: 'static + Sensor + Unpin + Send,
+    {
+        let callbacks = SensorCallbacks::new(resource);
+        let sensor = Box::new(S::new(params, callbacks)?);
+        Ok(Self { span, name, sensor })
+    }
+}
+
+impl Future for SensorDriver {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let span = self.span.clone();
+        let _guard = span.enter();
+        tracing::trace!(sensor=%self.name, "polling sensor");
+
+        ready!(Pin::new(&mut self.sensor).poll(cx));
+
+        tracing::warn!(sensor=%self.name, "sensor module ran to completion!");
+
+        Poll::Ready(())
+    }
+}
+
+pub fn load(
+    executor: Executor,
+    config: &Config,
+    resources: ResourcesHandle,
+) -> miette::Result<Vec<RecoverableHandle<()>>> {
+    let span = tracing::info_span!("loading sensors");
+    let _guard = span.enter();
+
+    let mut sensor_map: HashMap<String, Resource> = config
+        .sensor_connections
+        .iter()
+        .filter_map(|connection| {
+            if let Some(resource) = resources.get_by_id(&connection.machine) {
+                Some((connection.sensor.clone(), resource))
+            } else {
+                tracing::error!(sensor=%connection.sensor, machine=%connection.machine,
+                    "Machine configured for sensor not found!");
+                None
+            }
+        })
+        .collect();
+
+    let mut tasks = Vec::new();
+    for (name, cfg) in config.sensors.iter() {
+        if let Some(resource) = sensor_map.remove(name) {
+            if let Some(driver) = load_single(name, &cfg.module, &cfg.params, resource) {
+                tracing::debug!(module_name=%cfg.module, %name, "starting sensor task");
+                tasks.push(executor.spawn_named(&format!("sensor:{}", name), driver));
+            } else {
+                tracing::error!(module_name=%cfg.module, %name, "Sensor module could not be configured");
+            }
+        } else {
+            tracing::warn!(sensor=%name, "Sensor has no machine configured. Skipping!");
+        }
+    }
+
+    Ok(tasks)
+}
+
+fn load_single(
+    name: &String,
+    module_name: &String,
+    params: &HashMap<String, String>,
+    resource: Resource,
+) -> Option<SensorDriver> {
+    let span = tracing::info_span!(
+        "sensor",
+        name = %name,
+        module = %module_name,
+    );
+    tracing::info!(%name, %module_name, ?params, "Loading sensor");
+    let o = match module_name.as_ref() {
+        "Dummy" => Some(SensorDriver::new::<Dummy>(
+            span,
+            name.clone(),
+            params,
+            resource,
+        )),
+        _ => None,
+    };
+
+    o.transpose().unwrap_or_else(|error| {
+        tracing::error!(%error, "failed to configure sensor");
+        None
+    })
+}
@@ -1,20 +1,126 @@
-use crate::authorization::permissions::Permission;
+use crate::audit::{Source, AUDIT};
+use crate::authorization::permissions::{PermRule, Permission};
 use crate::authorization::roles::Roles;
+use crate::resources::modules::fabaccess::Status;
+use crate::resources::search::ResourcesHandle;
 use crate::resources::Resource;
+use crate::shutdown::ShutdownSignal;
 use crate::users::db::User;
 use crate::users::{db, UserRef};
 use crate::Users;
+use async_io::Timer;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 use tracing::Span;
 
+mod resume;
+pub use resume::{InvalidToken, ResumptionPolicy, ResumptionTokens};
+
 #[derive(Clone)]
 pub struct SessionManager {
     users: Users,
     roles: Roles,
-    // cache: SessionCache // todo
+    max_inflight_calls: Option<usize>,
+    read_only: bool,
+    redact_peers: bool,
+    active: ActiveSessions,
+    resumption: ResumptionTokens,
 }
 impl SessionManager {
-    pub fn new(users: Users, roles: Roles) -> Self {
-        Self { users, roles }
+    pub fn new(
+        users: Users,
+        roles: Roles,
+        max_inflight_calls: Option<usize>,
+        read_only: bool,
+    ) -> Self {
+        Self {
+            // Changes to users made by sessions are refused in maintenance mode
+            users: users.with_read_only(read_only),
+            roles,
+            max_inflight_calls,
+            read_only,
+            redact_peers: false,
+            active: ActiveSessions::default(),
+            resumption: ResumptionTokens::new(ResumptionPolicy::default()),
+        }
+    }
+
+    /// Hide the addresses of connected sessions from admins without `bffh.sessions.superadmin`
+    pub fn with_peer_redaction(mut self, redact: bool) -> Self {
+        self.redact_peers = redact;
+        self
+    }
+
+    pub fn with_resumption(mut self, policy: ResumptionPolicy) -> Self {
+        self.resumption = ResumptionTokens::new(policy);
+        self
+    }
+
+    /// Send `notification` to every open session
+    pub fn notify_all(&self, notification: Notification) {
+        for (_, active) in self.active.list() {
+            if let Some(inbox) = active.inbox.upgrade() {
+                inbox.push(notification.clone());
+            }
+        }
+    }
+
+    /// Close the connections of all sessions that have no calls outstanding
+    ///
+    /// Used on shutdown so calls that are already running can finish. Returns the number of
+    /// sessions that are still busy.
+    pub fn close_idle(&self) -> usize {
+        let mut busy = 0;
+        for (_, active) in self.active.list() {
+            match &active.close {
+                Some(close) if active.calls.is_idle() => close.trigger(),
+                Some(_) => busy += 1,
+                None => {}
+            }
+        }
+        busy
+    }
+
+    /// Close the connections of all sessions that outlived their lifetime
+    ///
+    /// Returns the number of sessions closed.
+    pub fn close_expired(&self) -> usize {
+        let now = Instant::now();
+        let expired: Vec<(u64, Active)> = self
+            .active
+            .list()
+            .into_iter()
+            .filter(|(_, active)| active.expires.map_or(false, |expires| expires <= now))
+            .collect();
+        for (id, active) in expired.iter() {
+            tracing::info!(uid = %active.uid, id, "closing expired session");
+            self.active.revoke(*id);
+        }
+        expired.len()
+    }
+
+    /// Task closing expired sessions every second, to be spawned once
+    pub fn reap_expired_sessions(&self) -> impl std::future::Future<Output = ()> {
+        let this = self.clone();
+        async move {
+            loop {
+                Timer::after(Duration::from_secs(1)).await;
+                this.close_expired();
+            }
+        }
+    }
+
+    /// Task purging expired resumption tokens, to be spawned once
+    pub fn reap_resumption_tokens(&self) -> impl std::future::Future<Output = ()> {
+        self.resumption.clone().reap()
+    }
+
+    /// The user whose kv entry `key` is `value`, see [`Users::find_by`]
+    pub fn find_user(&self, key: &str, value: &str) -> Option<User> {
+        self.users.find_by(key, value)
     }
 
     pub fn try_open(&self, parent: &Span, uid: impl AsRef<str>) -> Option<SessionHandle> {
@@ -25,6 +131,29 @@ impl SessionManager {
 
     // TODO: make infallible
     pub fn open(&self, parent: &Span, user: User) -> SessionHandle {
+        self.open_inner(parent, user, None, None)
+    }
+
+    /// Open a session for a client connected from `peer`
+    ///
+    /// `close` is triggered when an admin disconnects the session, to close the connection.
+    pub fn open_with_peer(
+        &self,
+        parent: &Span,
+        user: User,
+        peer: IpAddr,
+        close: ShutdownSignal,
+    ) -> SessionHandle {
+        self.open_inner(parent, user, Some(peer), Some(close))
+    }
+
+    fn open_inner(
+        &self,
+        parent: &Span,
+        user: User,
+        peer: Option<IpAddr>,
+        close: Option<ShutdownSignal>,
+    ) -> SessionHandle {
         let uid = user.id.as_str();
         let span = tracing::info_span!(
             target: "bffh::api",
@@ -33,15 +162,291 @@ impl SessionManager {
             uid,
         );
         tracing::trace!(parent: &span, uid, ?user, "opening session");
+        let ttl = self.roles.session_ttl(&user.userdata);
+        if let Some(ttl) = ttl {
+            tracing::debug!(parent: &span, uid, ?ttl, "session has a limited lifetime");
+        }
+        let expires = ttl.map(|ttl| Instant::now() + ttl);
+        let inbox: Arc<Inbox> = Arc::default();
+        let calls = CallLimiter::new(self.max_inflight_calls);
+        let guard = self.active.enter(
+            uid,
+            peer,
+            close,
+            expires,
+            calls.clone(),
+            Arc::downgrade(&inbox),
+        );
         SessionHandle {
             span,
             users: self.users.clone(),
             roles: self.roles.clone(),
             user: UserRef::new(user.id),
+            calls,
+            read_only: self.read_only,
+            redact_peers: self.redact_peers,
+            source: Source::User,
+            expires,
+            active: self.active.clone(),
+            inbox,
+            guard: Arc::new(guard),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Active {
+    uid: String,
+    peer: Option<IpAddr>,
+    since: i64,
+    revoked: Arc<AtomicBool>,
+    close: Option<ShutdownSignal>,
+    /// End of the lifetime given by the roles of the user, if any
+    expires: Option<Instant>,
+    calls: CallLimiter,
+    inbox: Weak<Inbox>,
+    /// Machines the session put in use and didn't release since
+    using: Arc<Mutex<BTreeSet<String>>>,
+}
+
+#[derive(Clone, Debug, Default)]
+/// Sessions that are currently open
+struct ActiveSessions {
+    sessions: Arc<Mutex<HashMap<u64, Active>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl ActiveSessions {
+    fn enter(
+        &self,
+        uid: &str,
+        peer: Option<IpAddr>,
+        close: Option<ShutdownSignal>,
+        expires: Option<Instant>,
+        calls: CallLimiter,
+        inbox: Weak<Inbox>,
+    ) -> ActiveGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let revoked = Arc::new(AtomicBool::new(false));
+        let using = Arc::default();
+        let active = Active {
+            uid: uid.to_string(),
+            peer,
+            since: chrono::Utc::now().timestamp(),
+            revoked: revoked.clone(),
+            close,
+            expires,
+            calls,
+            inbox,
+            using: Arc::clone(&using),
+        };
+        self.sessions.lock().unwrap().insert(id, active);
+        ActiveGuard {
+            active: self.clone(),
+            id,
+            revoked,
+            using,
+        }
+    }
+
+    fn users(&self) -> Vec<String> {
+        let sessions = self.sessions.lock().unwrap();
+        let users: BTreeSet<&String> = sessions.values().map(|active| &active.uid).collect();
+        users.into_iter().cloned().collect()
+    }
+
+    fn list(&self) -> Vec<(u64, Active)> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .iter()
+            .map(|(id, active)| (*id, active.clone()))
+            .collect()
+    }
+
+    /// Invalidate the session `id` and close its connection
+    fn revoke(&self, id: u64) -> Option<Active> {
+        let active = self.sessions.lock().unwrap().remove(&id)?;
+        active.revoked.store(true, Ordering::Release);
+        if let Some(close) = &active.close {
+            close.trigger();
+        }
+        Some(active)
+    }
+}
+
+/// Marks a session as active until dropped
+struct ActiveGuard {
+    active: ActiveSessions,
+    id: u64,
+    /// Set once an admin disconnected the session
+    revoked: Arc<AtomicBool>,
+    using: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.active.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A session as shown to admins
+pub struct ConnectedSession {
+    /// Id to disconnect the session with
+    pub id: u64,
+    pub uid: String,
+    /// Address the client connected from, unless unknown or redacted
+    pub peer: Option<IpAddr>,
+    /// Unix timestamp of when the session was opened
+    pub connected_since: i64,
+    /// Machines held by the user of the session, e.g. in use or reserved
+    pub machines: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// What a session may do with one machine
+pub struct EffectivePermissions {
+    pub disclose: bool,
+    pub read: bool,
+    pub write: bool,
+    pub manage: bool,
+}
+
+#[derive(Clone, Debug)]
+/// Limit on the number of calls a session may have outstanding at the same time
+pub struct CallLimiter {
+    inflight: Arc<AtomicUsize>,
+    max: Option<usize>,
+}
+
+impl CallLimiter {
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            inflight: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Try to start a call, failing if the limit is already reached
+    ///
+    /// The call counts as outstanding until the returned permit is dropped.
+    pub fn try_acquire(&self) -> Option<CallPermit> {
+        let max = self.max.unwrap_or(usize::MAX);
+        self.inflight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                if n < max {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()?;
+        Some(CallPermit {
+            inflight: self.inflight.clone(),
+        })
+    }
+
+    /// Whether no call is outstanding right now
+    pub fn is_idle(&self) -> bool {
+        self.inflight.load(Ordering::Acquire) == 0
+    }
+}
+
+#[derive(Debug)]
+pub struct CallPermit {
+    inflight: Arc<AtomicUsize>,
+}
+
+impl Drop for CallPermit {
+    fn drop(&mut self) {
+        self.inflight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("server is in maintenance mode, changes are currently not possible")]
+/// A state-changing call was refused because the server runs read-only
+pub struct Maintenance;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("session has expired, please log in again")]
+/// A call was refused because the session outlived the lifetime allowed by the user's roles
+pub struct SessionExpired;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("too many calls in flight")]
+/// A state-changing call was refused because the session has too many others outstanding
+pub struct Overloaded;
+
+/// Preference holding the language tag messages for the user are localized to, e.g. `de`
+pub const LANGUAGE_PREFERENCE: &str = "language";
+
+/// Number of preferences a user can store
+const MAX_PREFERENCES: usize = 32;
+/// Maximum length of the name and of the value of a preference, in bytes
+const MAX_PREFERENCE_LEN: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+/// Storing a preference of the user was refused or failed
+pub enum PreferenceError {
+    #[error(transparent)]
+    Maintenance(#[from] Maintenance),
+    #[error(transparent)]
+    Expired(#[from] SessionExpired),
+    #[error("preference name or value is too long")]
+    TooLong,
+    #[error("too many preferences stored already")]
+    TooMany,
+    #[error("the user doesn't exist anymore")]
+    UnknownUser,
+    #[error("storing the preference failed")]
+    Storage(#[from] crate::db::Error),
+}
+
+impl From<crate::users::Error> for PreferenceError {
+    fn from(error: crate::users::Error) -> Self {
+        match error {
+            crate::users::Error::Db(error) => Self::Storage(error),
+            crate::users::Error::Maintenance(error) => Self::Maintenance(error),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+/// An admin's request to disconnect a session was refused
+pub enum DisconnectError {
+    #[error("not allowed to disconnect sessions")]
+    Denied,
+    #[error("no open session with this id")]
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Something a session is told about without having asked for it
+pub enum Notification {
+    /// A machine the session is watching became free
+    MachineFree { id: String },
+    /// The server is shutting down, the connection will be closed soon
+    GoingAway,
+}
+
+/// Number of notifications kept for a session before the oldest ones are dropped
+const MAX_NOTIFICATIONS: usize = 32;
+
+#[derive(Debug, Default)]
+/// Notifications waiting to be picked up by a session
+pub struct Inbox(Mutex<VecDeque<Notification>>);
+
+impl Inbox {
+    pub fn push(&self, notification: Notification) {
+        let mut pending = self.0.lock().unwrap();
+        if pending.len() == MAX_NOTIFICATIONS {
+            pending.pop_front();
+        }
+        pending.push_back(notification);
+    }
+}
+
 #[derive(Clone)]
 pub struct SessionHandle {
     pub span: Span,
@@ -50,13 +455,157 @@ pub struct SessionHandle {
     pub roles: Roles,
 
     user: UserRef,
+
+    pub calls: CallLimiter,
+
+    read_only: bool,
+    redact_peers: bool,
+    /// What the audit log records as cause of changes made through this session
+    source: Source,
+    /// End of the lifetime given by the roles of the user, if any
+    expires: Option<Instant>,
+    active: ActiveSessions,
+    inbox: Arc<Inbox>,
+    guard: Arc<ActiveGuard>,
 }
 
 impl SessionHandle {
+    /// Check if this session may change any state
+    ///
+    /// Machines are changed through `Resource::change`, which checks this, and users through
+    /// [`Self::users`], which is read-only in maintenance mode. Reading state is not affected.
+    pub fn check_writable(&self) -> Result<(), Maintenance> {
+        if self.read_only {
+            Err(Maintenance)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn expires_at(&self) -> Option<Instant> {
+        self.expires
+    }
+
+    /// Record changes made through this session as caused by `source`, e.g. an initiator
+    pub fn with_source(mut self, source: Source) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub fn source(&self) -> Source {
+        self.source
+    }
+
+    /// Count a state-changing call against the in-flight limit until the permit is dropped
+    pub fn start_call(&self) -> Result<CallPermit, Overloaded> {
+        self.calls.try_acquire().ok_or(Overloaded)
+    }
+
+    /// Id of this session among the open ones
+    pub fn id(&self) -> u64 {
+        self.guard.id
+    }
+
+    /// Remember whether this session is using the machine `id` after changing its state
+    ///
+    /// Only machines put in use through this session are freed when it's disconnected, other
+    /// sessions of the same user keep theirs.
+    pub(crate) fn set_using(&self, id: &str, using: bool) {
+        let mut machines = self.guard.using.lock().unwrap();
+        if using {
+            machines.insert(id.to_string());
+        } else {
+            machines.remove(id);
+        }
+    }
+
+    /// Whether the session outlived its lifetime or was disconnected by an admin
+    pub fn is_expired(&self) -> bool {
+        self.guard.revoked.load(Ordering::Acquire)
+            || self
+                .expires
+                .map_or(false, |expires| expires <= Instant::now())
+    }
+
+    /// Check if this session is still within its lifetime
+    ///
+    /// Expired sessions also lose all permissions, so a client holding on to capabilities of an
+    /// expired session can't do anything with them anymore.
+    pub fn check_active(&self) -> Result<(), SessionExpired> {
+        if self.is_expired() {
+            Err(SessionExpired)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The user of this session, unless the session expired
+    fn current_user(&self) -> Option<db::User> {
+        if self.is_expired() {
+            None
+        } else {
+            self.users.get_user(self.user.get_username())
+        }
+    }
+
+    /// Notifications that arrived since the last call, oldest first
+    pub fn take_notifications(&self) -> Vec<Notification> {
+        self.inbox.0.lock().unwrap().drain(..).collect()
+    }
+
+    /// Handle to send notifications to this session without keeping it alive
+    pub fn inbox(&self) -> Weak<Inbox> {
+        Arc::downgrade(&self.inbox)
+    }
+
     pub fn get_user_ref(&self) -> UserRef {
         self.user.clone()
     }
 
+    /// Preferences the user stored, e.g. their language, so they follow them across devices
+    pub fn preferences(&self) -> BTreeMap<String, String> {
+        self.current_user().map_or_else(BTreeMap::new, |user| {
+            user.userdata
+                .preferences()
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        })
+    }
+
+    /// Store the preference `name` of the user, or remove it if `value` is `None`
+    pub fn set_preference(&self, name: &str, value: Option<&str>) -> Result<(), PreferenceError> {
+        self.check_active()?;
+        if name.is_empty()
+            || name.len() > MAX_PREFERENCE_LEN
+            || value.map_or(false, |value| value.len() > MAX_PREFERENCE_LEN)
+        {
+            return Err(PreferenceError::TooLong);
+        }
+        let uid = self.user.get_username();
+        let updated = self.users.update_user(uid, |user| {
+            let preferences = user.userdata.preferences();
+            if value.is_some()
+                && !preferences.contains_key(name)
+                && preferences.len() >= MAX_PREFERENCES
+            {
+                return Err(PreferenceError::TooMany);
+            }
+            user.userdata
+                .set_preference(name, value.map(str::to_string));
+            Ok(())
+        })?;
+        updated.ok_or(PreferenceError::UnknownUser)
+    }
+
+    /// Language the user prefers messages in, if they set one
+    pub fn language(&self) -> Option<String> {
+        let user = self.current_user()?;
+        user.userdata
+            .preference(LANGUAGE_PREFERENCE)
+            .map(str::to_string)
+    }
+
     pub fn get_user(&self) -> db::User {
         self.users
             .get_user(self.user.get_username())
@@ -64,7 +613,7 @@ impl SessionHandle {
     }
 
     pub fn has_disclose(&self, resource: &Resource) -> bool {
-        if let Some(user) = self.users.get_user(self.user.get_username()) {
+        if let Some(user) = self.current_user() {
             self.roles
                 .is_permitted(&user.userdata, &resource.get_required_privs().disclose)
         } else {
@@ -72,7 +621,7 @@ impl SessionHandle {
         }
     }
     pub fn has_read(&self, resource: &Resource) -> bool {
-        if let Some(user) = self.users.get_user(self.user.get_username()) {
+        if let Some(user) = self.current_user() {
             self.roles
                 .is_permitted(&user.userdata, &resource.get_required_privs().read)
         } else {
@@ -80,7 +629,7 @@ impl SessionHandle {
         }
     }
     pub fn has_write(&self, resource: &Resource) -> bool {
-        if let Some(user) = self.users.get_user(self.user.get_username()) {
+        if let Some(user) = self.current_user() {
             self.roles
                 .is_permitted(&user.userdata, &resource.get_required_privs().write)
         } else {
@@ -88,18 +637,323 @@ impl SessionHandle {
         }
     }
     pub fn has_manage(&self, resource: &Resource) -> bool {
-        if let Some(user) = self.users.get_user(self.user.get_username()) {
+        if let Some(user) = self.current_user() {
             self.roles
                 .is_permitted(&user.userdata, &resource.get_required_privs().manage)
         } else {
             false
         }
     }
+    /// All of `has_disclose`, `has_read`, `has_write` and `has_manage` at once
+    pub fn effective_permissions(&self, resource: &Resource) -> EffectivePermissions {
+        let user = match self.current_user() {
+            Some(user) => user,
+            None => return EffectivePermissions::default(),
+        };
+        let privs = resource.get_required_privs();
+        let permitted = |perm| self.roles.is_permitted(&user.userdata, perm);
+        EffectivePermissions {
+            disclose: permitted(&privs.disclose),
+            read: permitted(&privs.read),
+            write: permitted(&privs.write),
+            manage: permitted(&privs.manage),
+        }
+    }
+
+    /// Check if the user of this session has the valid training record `key`
+    pub fn has_training(&self, key: &str) -> bool {
+        self.current_user().map_or(false, |user| {
+            user.userdata
+                .has_training(key, chrono::Utc::now().timestamp())
+        })
+    }
+
+    /// Check if any user with an open session, including this one, holds `perm`
+    pub fn is_present(&self, perm: &Permission) -> bool {
+        self.active.users().iter().any(|uid| {
+            self.users
+                .get_user(uid)
+                .map(|user| self.roles.is_permitted(&user.userdata, perm))
+                .unwrap_or(false)
+        })
+    }
+
     pub fn has_perm(&self, perm: impl AsRef<Permission>) -> bool {
-        if let Some(user) = self.users.get_user(self.user.get_username()) {
+        if let Some(user) = self.current_user() {
             self.roles.is_permitted(&user.userdata, perm)
         } else {
             false
         }
     }
+
+    /// All roles with the permissions they grant including inherited ones, for admin clients
+    ///
+    /// Only available to sessions holding `bffh.roles.admin`.
+    pub fn role_catalog(&self) -> Option<BTreeMap<String, Vec<PermRule>>> {
+        if self.has_perm(Permission::new("bffh.roles.admin")) {
+            Some(self.roles.catalog())
+        } else {
+            None
+        }
+    }
+
+    /// All open sessions, oldest first, for admin clients
+    ///
+    /// Only available to sessions holding `bffh.sessions.admin`. If peer redaction is enabled,
+    /// addresses are only included for sessions also holding `bffh.sessions.superadmin`.
+    pub fn connected_sessions(&self, resources: &ResourcesHandle) -> Option<Vec<ConnectedSession>> {
+        if !self.has_perm(Permission::new("bffh.sessions.admin")) {
+            return None;
+        }
+        let show_peers =
+            !self.redact_peers || self.has_perm(Permission::new("bffh.sessions.superadmin"));
+
+        let mut sessions: Vec<ConnectedSession> = self
+            .active
+            .list()
+            .into_iter()
+            .map(|(id, active)| {
+                let user = UserRef::new(active.uid.clone());
+                let machines = resources
+                    .list_all()
+                    .into_iter()
+                    .filter(|resource| resource.is_owned_by(user.clone()))
+                    .map(|resource| resource.get_id().to_string())
+                    .collect();
+                ConnectedSession {
+                    id,
+                    uid: active.uid,
+                    peer: active.peer.filter(|_| show_peers),
+                    connected_since: active.since,
+                    machines,
+                }
+            })
+            .collect();
+        sessions.sort_by(|a, b| (a.connected_since, &a.uid).cmp(&(b.connected_since, &b.uid)));
+        Some(sessions)
+    }
+
+    /// Disconnect the open session `id`, optionally freeing the machines it put in use
+    ///
+    /// The session loses all permissions right away and its connection is closed. Only
+    /// available to sessions holding `bffh.sessions.admin`.
+    pub fn disconnect_session(
+        &self,
+        id: u64,
+        free_machines: bool,
+        resources: &ResourcesHandle,
+    ) -> Result<(), DisconnectError> {
+        if !self.has_perm(Permission::new("bffh.sessions.admin")) {
+            return Err(DisconnectError::Denied);
+        }
+        let target = self.active.revoke(id).ok_or(DisconnectError::Unknown)?;
+        let admin = self.user.get_username();
+        tracing::info!(parent: &self.span, admin, uid = %target.uid, id, "disconnecting session");
+        if let Some(audit) = AUDIT.get() {
+            if let Err(error) = audit.log_admin("disconnect", &target.uid, admin) {
+                tracing::error!(%error, "writing disconnect to the audit log failed");
+            }
+        }
+
+        if free_machines {
+            let in_use = Status::InUse(UserRef::new(target.uid));
+            let using = std::mem::take(&mut *target.using.lock().unwrap());
+            for resource in using.iter().filter_map(|id| resources.get_by_id(id)) {
+                // Someone else may have taken the machine over in the meantime
+                let freed = resource.transition_from(
+                    |old| old.state == in_use,
+                    Status::Free,
+                    None,
+                    None,
+                    Source::Admin,
+                );
+                if let Err(error) = freed {
+                    tracing::error!(id = resource.get_id(), %error, "freeing machine failed");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call_limit_is_enforced() {
+        let limiter = CallLimiter::new(Some(2));
+        let first = limiter.try_acquire().unwrap();
+        let _second = limiter.try_acquire().unwrap();
+        assert!(limiter.try_acquire().is_none());
+
+        drop(first);
+        assert!(limiter.try_acquire().is_some());
+    }
+
+    #[test]
+    fn guest_sessions_expire_first() {
+        use crate::authorization::roles::Role;
+        use crate::users::db::User;
+
+        let dir = tempfile::tempdir().unwrap();
+        let env = crate::resources::state::db::StateDB::open_env(dir.path().join("db")).unwrap();
+        let users = Users::new(env).unwrap();
+        let roles = Roles::leak(HashMap::from([
+            (
+                "guest".to_string(),
+                Role::new(Vec::new(), Vec::new()).with_session_ttl(Duration::from_secs(1)),
+            ),
+            (
+                "member".to_string(),
+                Role::new(Vec::new(), Vec::new()).with_session_ttl(Duration::from_secs(3600)),
+            ),
+        ]));
+        let sessions = SessionManager::new(users, roles, None, false);
+        let user = |name: &str, role: &str| {
+            let mut user = User::new_with_plain_pw(name, "secret");
+            user.userdata.roles.push(role.to_string());
+            user
+        };
+
+        let span = Span::none();
+        let ip = "127.0.0.1".parse().unwrap();
+        let (guest_close, member_close) = (ShutdownSignal::new(), ShutdownSignal::new());
+        let guest = sessions.open_with_peer(&span, user("guest", "guest"), ip, guest_close.clone());
+        let member =
+            sessions.open_with_peer(&span, user("member", "member"), ip, member_close.clone());
+        assert!(guest.expires_at().unwrap() < member.expires_at().unwrap());
+        assert_eq!(sessions.close_expired(), 0);
+
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(guest.check_active(), Err(SessionExpired));
+        assert_eq!(member.check_active(), Ok(()));
+
+        // Expired sessions get their connection closed instead of lingering
+        assert_eq!(sessions.close_expired(), 1);
+        assert!(guest_close.is_triggered());
+        assert!(!member_close.is_triggered());
+        assert_eq!(sessions.close_expired(), 0);
+    }
+
+    #[test]
+    fn connected_sessions_are_listed() {
+        use crate::audit::Source;
+        use crate::authorization::permissions::{PermRule, PermissionBuf};
+        use crate::authorization::roles::Role;
+        use crate::resources::modules::fabaccess::Status;
+        use crate::resources::state::db::StateDB;
+        use crate::testing;
+
+        let dir = tempfile::tempdir().unwrap();
+        let env = StateDB::open_env(dir.path().join("db")).unwrap();
+        let users = Users::new(env.clone()).unwrap();
+        let mut admin = User::new_with_plain_pw("admin", "secret");
+        admin.userdata.roles.push("admin".to_string());
+        users.put_user("admin", &admin).unwrap();
+        let member = User::new_with_plain_pw("member", "secret");
+        users.put_user("member", &member).unwrap();
+        let rule = PermRule::Base(PermissionBuf::from_string_unchecked(
+            "bffh.sessions.admin".to_string(),
+        ));
+        let roles = Roles::leak(HashMap::from([(
+            "admin".to_string(),
+            Role::new(Vec::new(), vec![rule]),
+        )]));
+        let sessions = SessionManager::new(users, roles, None, false);
+
+        let perm = PermissionBuf::from_string_unchecked("test.perm".to_string());
+        let lathe = testing::resource(env, "lathe", testing::machine("Lathe", &perm));
+        lathe
+            .set_status(
+                Status::InUse(UserRef::new("member".to_string())),
+                Source::User,
+            )
+            .unwrap();
+        let resources = ResourcesHandle::new([lathe]);
+
+        let span = Span::none();
+        let admin_ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let member_ip: IpAddr = "192.0.2.2".parse().unwrap();
+        let admin = sessions.open_with_peer(&span, admin, admin_ip, ShutdownSignal::new());
+        let member = sessions.open_with_peer(&span, member, member_ip, ShutdownSignal::new());
+
+        assert_eq!(member.connected_sessions(&resources), None);
+        let mut listed = admin.connected_sessions(&resources).unwrap();
+        listed.sort_by(|a, b| a.uid.cmp(&b.uid));
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].uid, "admin");
+        assert_eq!(listed[0].peer, Some(admin_ip));
+        assert!(listed[0].machines.is_empty());
+        assert_eq!(listed[1].uid, "member");
+        assert_eq!(listed[1].peer, Some(member_ip));
+        assert_eq!(listed[1].machines, vec!["lathe".to_string()]);
+        assert!(listed[1].connected_since <= chrono::Utc::now().timestamp());
+
+        let redacted = sessions.with_peer_redaction(true);
+        let admin = redacted.try_open(&span, "admin").unwrap();
+        let listed = admin.connected_sessions(&resources).unwrap();
+        assert_eq!(listed.len(), 3);
+        assert!(listed.iter().all(|session| session.peer.is_none()));
+        drop(member);
+        assert_eq!(admin.connected_sessions(&resources).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn preferences_of_deleted_users_are_refused() {
+        use crate::resources::state::db::StateDB;
+        use crate::users::db::User;
+
+        let dir = tempfile::tempdir().unwrap();
+        let env = StateDB::open_env(dir.path().join("db")).unwrap();
+        let users = Users::new(env).unwrap();
+        let user = User::new_with_plain_pw("deleted", "secret");
+        users.put_user("deleted", &user).unwrap();
+        let sessions = SessionManager::new(users, Roles::leak(HashMap::new()), None, false);
+        let session = sessions.try_open(&Span::none(), "deleted").unwrap();
+
+        assert_eq!(session.set_preference("color", Some("green")), Ok(()));
+        let stored = users.get_user("deleted").unwrap();
+        assert_eq!(stored.userdata.preference("color"), Some("green"));
+
+        users.del_user("deleted").unwrap();
+        assert_eq!(
+            session.set_preference("color", None),
+            Err(PreferenceError::UnknownUser)
+        );
+        assert!(users.get_user("deleted").is_none());
+    }
+
+    #[test]
+    fn no_limit_configured() {
+        let limiter = CallLimiter::new(None);
+        let permits: Vec<_> = (0..1000).map(|_| limiter.try_acquire().unwrap()).collect();
+        assert_eq!(permits.len(), 1000);
+    }
+
+    #[test]
+    fn only_idle_sessions_are_closed_on_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = crate::resources::state::db::StateDB::open_env(dir.path().join("db")).unwrap();
+        let users = Users::new(env).unwrap();
+        let sessions = SessionManager::new(users, Roles::leak(HashMap::new()), None, false);
+
+        let span = Span::none();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let close = ShutdownSignal::new();
+        let session = sessions.open_with_peer(
+            &span,
+            User::new_with_plain_pw("draining", "secret"),
+            ip,
+            close.clone(),
+        );
+
+        let call = session.calls.try_acquire().unwrap();
+        assert_eq!(sessions.close_idle(), 1);
+        assert!(!close.is_triggered());
+
+        drop(call);
+        assert_eq!(sessions.close_idle(), 0);
+        assert!(close.is_triggered());
+    }
 }
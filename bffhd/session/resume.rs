@@ -0,0 +1,212 @@
+//! Tokens allowing a client to resume its session after reconnecting without authenticating again
+//!
+//! A token is issued for an open session and can be redeemed exactly once. Tokens that aren't
+//! redeemed within their lifetime are purged by a periodic sweep, and every user only gets a
+//! limited number of outstanding tokens so a misbehaving client can't make them pile up.
+//!
+//! The API has no call to resume a session yet, so nothing issues tokens so far. Whatever does
+//! will have to refuse suspended users and drop the tokens of a user whose password changed.
+
+use async_io::Timer;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+fn default_ttl_secs() -> u64 {
+    300
+}
+
+fn default_max_per_user() -> usize {
+    4
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// How long resumption tokens stay valid and how many a user may have outstanding
+pub struct ResumptionPolicy {
+    /// Seconds after which an unredeemed token expires
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+    /// Outstanding tokens per user. Issuing another one drops the oldest, 0 disables resumption.
+    #[serde(default = "default_max_per_user")]
+    pub max_per_user: usize,
+}
+
+impl Default for ResumptionPolicy {
+    fn default() -> Self {
+        Self {
+            ttl_secs: default_ttl_secs(),
+            max_per_user: default_max_per_user(),
+        }
+    }
+}
+
+impl ResumptionPolicy {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+/// A resumption token was refused
+pub enum InvalidToken {
+    #[error("resumption token is unknown or has already been used")]
+    Unknown,
+    #[error("resumption token has expired")]
+    Expired,
+}
+
+#[derive(Debug)]
+struct Pending {
+    uid: String,
+    issued: Instant,
+}
+
+#[derive(Debug, Clone)]
+/// Resumption tokens that were issued but not redeemed yet
+pub struct ResumptionTokens {
+    policy: ResumptionPolicy,
+    pending: Arc<Mutex<HashMap<String, Pending>>>,
+}
+
+impl ResumptionTokens {
+    pub fn new(policy: ResumptionPolicy) -> Self {
+        Self {
+            policy,
+            pending: Arc::default(),
+        }
+    }
+
+    /// Issue a token for `uid`, or `None` if resumption is disabled
+    pub fn issue(&self, uid: &str) -> Option<String> {
+        self.issue_at(uid, Instant::now())
+    }
+
+    fn issue_at(&self, uid: &str, now: Instant) -> Option<String> {
+        if self.policy.max_per_user == 0 {
+            return None;
+        }
+        let mut pending = self.pending.lock().unwrap();
+
+        let mut outstanding: Vec<(String, Instant)> = pending
+            .iter()
+            .filter(|(_, p)| p.uid == uid)
+            .map(|(token, p)| (token.clone(), p.issued))
+            .collect();
+        outstanding.sort_by_key(|(_, issued)| *issued);
+        let excess = (outstanding.len() + 1).saturating_sub(self.policy.max_per_user);
+        for (token, _) in outstanding.into_iter().take(excess) {
+            tracing::debug!(uid, "dropping oldest resumption token of user");
+            pending.remove(&token);
+        }
+
+        let token = hex::encode(rand::random::<[u8; 32]>());
+        pending.insert(
+            token.clone(),
+            Pending {
+                uid: uid.to_string(),
+                issued: now,
+            },
+        );
+        Some(token)
+    }
+
+    /// Redeem a token, returning the user it was issued for
+    ///
+    /// The token is consumed whether it was still valid or not.
+    pub fn redeem(&self, token: &str) -> Result<String, InvalidToken> {
+        self.redeem_at(token, Instant::now())
+    }
+
+    fn redeem_at(&self, token: &str, now: Instant) -> Result<String, InvalidToken> {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(token)
+            .ok_or(InvalidToken::Unknown)?;
+        if self.is_expired(&pending, now) {
+            Err(InvalidToken::Expired)
+        } else {
+            Ok(pending.uid)
+        }
+    }
+
+    fn is_expired(&self, pending: &Pending, now: Instant) -> bool {
+        now.saturating_duration_since(pending.issued) >= self.policy.ttl()
+    }
+
+    /// Purge all expired tokens, returning how many were removed
+    pub fn sweep(&self) -> usize {
+        self.sweep_at(Instant::now())
+    }
+
+    fn sweep_at(&self, now: Instant) -> usize {
+        let mut pending = self.pending.lock().unwrap();
+        let before = pending.len();
+        pending.retain(|_, p| !self.is_expired(p, now));
+        before - pending.len()
+    }
+
+    /// Sweep expired tokens once per token lifetime, forever
+    pub async fn reap(self) {
+        let interval = self.policy.ttl().max(Duration::from_secs(1));
+        loop {
+            Timer::after(interval).await;
+            let purged = self.sweep();
+            if purged > 0 {
+                tracing::debug!(purged, "purged expired resumption tokens");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_token_is_rejected_and_purged() {
+        let tokens = ResumptionTokens::new(ResumptionPolicy {
+            ttl_secs: 60,
+            max_per_user: 4,
+        });
+        let now = Instant::now();
+        let later = now + Duration::from_secs(60);
+
+        let expired = tokens.issue_at("testuser", now).unwrap();
+        assert_eq!(
+            tokens.redeem_at(&expired, later),
+            Err(InvalidToken::Expired)
+        );
+        assert_eq!(tokens.redeem_at(&expired, now), Err(InvalidToken::Unknown));
+
+        let valid = tokens.issue_at("testuser", now).unwrap();
+        tokens.issue_at("testuser", now).unwrap();
+        assert_eq!(tokens.sweep_at(now), 0);
+        assert_eq!(tokens.sweep_at(later), 2);
+        assert_eq!(tokens.redeem_at(&valid, now), Err(InvalidToken::Unknown));
+    }
+
+    #[test]
+    fn oldest_token_is_dropped_over_the_cap() {
+        let tokens = ResumptionTokens::new(ResumptionPolicy {
+            ttl_secs: 60,
+            max_per_user: 2,
+        });
+        let now = Instant::now();
+        let oldest = tokens.issue_at("testuser", now).unwrap();
+        let older = tokens
+            .issue_at("testuser", now + Duration::from_secs(1))
+            .unwrap();
+        let newest = tokens
+            .issue_at("testuser", now + Duration::from_secs(2))
+            .unwrap();
+        let other = tokens.issue_at("otheruser", now).unwrap();
+
+        assert_eq!(tokens.redeem_at(&oldest, now), Err(InvalidToken::Unknown));
+        assert_eq!(tokens.redeem_at(&older, now), Ok("testuser".to_string()));
+        assert_eq!(tokens.redeem_at(&newest, now), Ok("testuser".to_string()));
+        assert_eq!(tokens.redeem_at(&other, now), Ok("otheruser".to_string()));
+    }
+}
@@ -0,0 +1,210 @@
+//! Ordered teardown of the running subsystems
+//!
+//! Subsystems are stopped one after the other so that e.g. no initiator can change the state of
+//! a machine while the actors are already gone, which could leave devices in a state not
+//! matching the one stored in the database.
+
+use std::time::Duration;
+
+use async_io::Timer;
+use futures_lite::FutureExt;
+use futures_signals::signal::{Mutable, MutableSignal, SignalExt};
+use futures_util::future::join_all;
+use lightproc::recoverable_handle::RecoverableHandle;
+
+/// Time a phase is given to stop before its remaining tasks are cancelled
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Default)]
+/// Flag telling long-running tasks to finish their current work and stop
+pub struct ShutdownSignal(Mutable<bool>);
+
+impl ShutdownSignal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.0.set(true)
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.get()
+    }
+
+    pub fn signal(&self) -> MutableSignal<bool> {
+        self.0.signal()
+    }
+
+    /// Resolves once the shutdown was triggered
+    pub async fn wait(&self) {
+        self.0.signal().wait_for(true).await;
+    }
+}
+
+/// A single step of the shutdown, stopping a group of tasks
+pub struct Phase {
+    name: &'static str,
+    stop: Option<Box<dyn FnOnce() + Send>>,
+    tasks: Vec<RecoverableHandle<()>>,
+    timeout: Duration,
+}
+
+impl Phase {
+    /// Phase cancelling `tasks` right away
+    pub fn cancel(name: &'static str, tasks: Vec<RecoverableHandle<()>>) -> Self {
+        Self {
+            name,
+            stop: None,
+            tasks,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Phase calling `stop` and then waiting for `tasks` to finish on their own
+    ///
+    /// Tasks still running after the timeout are cancelled.
+    pub fn graceful(
+        name: &'static str,
+        tasks: Vec<RecoverableHandle<()>>,
+        stop: impl FnOnce() + Send + 'static,
+    ) -> Self {
+        Self {
+            name,
+            stop: Some(Box::new(stop)),
+            tasks,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn run(mut self) {
+        let name = self.name;
+        tracing::debug!(phase = name, tasks = self.tasks.len(), "stopping");
+        match self.stop.take() {
+            Some(stop) => stop(),
+            None => self.tasks.iter().for_each(RecoverableHandle::cancel),
+        }
+
+        let timeout = self.timeout;
+        let tasks = &mut self.tasks;
+        let finished = async {
+            join_all(tasks.iter_mut()).await;
+            true
+        }
+        .or(async {
+            Timer::after(timeout).await;
+            false
+        })
+        .await;
+
+        if finished {
+            tracing::debug!(phase = name, "stopped");
+        } else {
+            tracing::warn!(
+                phase = name,
+                ?timeout,
+                "tasks did not stop in time, cancelling them"
+            );
+            self.tasks.iter().for_each(RecoverableHandle::cancel);
+        }
+    }
+}
+
+#[derive(Default)]
+/// Stops subsystems in the order their phases were added
+pub struct ShutdownHandler {
+    phases: Vec<Phase>,
+}
+
+impl ShutdownHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn then(mut self, phase: Phase) -> Self {
+        self.phases.push(phase);
+        self
+    }
+
+    /// Run all phases, each one only starting after the previous one finished or timed out
+    pub async fn shutdown(self) {
+        for phase in self.phases {
+            phase.run().await;
+        }
+        tracing::info!("shutdown complete");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use executor::pool::Executor;
+    use std::future::pending;
+    use std::sync::{Arc, Mutex};
+
+    type Log = Arc<Mutex<Vec<&'static str>>>;
+
+    /// Subsystem that stops once `signal` is triggered, recording it in `log`
+    fn subsystem(
+        executor: &Executor<'static>,
+        name: &'static str,
+        log: &Log,
+    ) -> (RecoverableHandle<()>, ShutdownSignal) {
+        let signal = ShutdownSignal::new();
+        let stop = signal.clone();
+        let log = log.clone();
+        let task = executor.spawn(async move {
+            stop.wait().await;
+            log.lock().unwrap().push(name);
+        });
+        (task, signal)
+    }
+
+    fn phase(executor: &Executor<'static>, name: &'static str, log: &Log) -> Phase {
+        let (task, signal) = subsystem(executor, name, log);
+        Phase::graceful(name, vec![task], move || signal.trigger())
+    }
+
+    #[test]
+    fn phases_stop_in_order() {
+        let executor = Executor::new();
+        let log = Log::default();
+
+        let db_log = log.clone();
+        let handler = ShutdownHandler::new()
+            .then(phase(&executor, "initiators", &log))
+            .then(phase(&executor, "actors", &log))
+            .then(phase(&executor, "api", &log))
+            .then(Phase::graceful("statedb", Vec::new(), move || {
+                db_log.lock().unwrap().push("statedb")
+            }));
+
+        executor.run(handler.shutdown());
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["initiators", "actors", "api", "statedb"]
+        );
+    }
+
+    #[test]
+    fn stuck_phase_times_out() {
+        let executor = Executor::new();
+        let log = Log::default();
+
+        let stuck = executor.spawn(pending::<()>());
+        let handler = ShutdownHandler::new()
+            .then(
+                Phase::graceful("stuck", vec![stuck], || {})
+                    .with_timeout(Duration::from_millis(50)),
+            )
+            .then(phase(&executor, "api", &log));
+
+        executor.run(handler.shutdown());
+        assert_eq!(*log.lock().unwrap(), ["api"]);
+    }
+}
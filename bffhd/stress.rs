@@ -0,0 +1,164 @@
+//! Synthetic load to measure the executor without running the server
+//!
+//! Every task busy-loops for `poll` per poll and yields `polls` times before it finishes, so the
+//! numbers mostly depend on how well the executor spreads tasks across its worker threads.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use executor::pool::Executor;
+use futures_lite::future;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stress {
+    /// Number of tasks spawned at once
+    pub tasks: usize,
+    /// How long every poll of a task keeps its worker busy
+    pub poll: Duration,
+    /// How often every task is polled before it finishes
+    pub polls: usize,
+}
+
+impl Default for Stress {
+    fn default() -> Self {
+        Self {
+            tasks: 10_000,
+            poll: Duration::from_micros(50),
+            polls: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Results of a [`Stress`] run
+pub struct Report {
+    /// Tasks that ran to completion, i.e. didn't panic
+    pub completed: usize,
+    pub elapsed: Duration,
+    /// Time from spawning a task until it first got polled
+    pub first_poll: Percentiles,
+    /// Time from spawning a task until it finished
+    pub latency: Percentiles,
+}
+
+impl Report {
+    /// Completed tasks per second
+    pub fn throughput(&self) -> f64 {
+        self.completed as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} tasks in {:?} ({:.0} tasks/s)",
+            self.completed,
+            self.elapsed,
+            self.throughput()
+        )?;
+        writeln!(f, "first poll: {}", self.first_poll)?;
+        write!(f, "latency:    {}", self.latency)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Percentiles {
+    fn of(mut samples: Vec<Duration>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort_unstable();
+        let at = |percent: usize| samples[(samples.len() - 1) * percent / 100];
+        Self {
+            p50: at(50),
+            p90: at(90),
+            p99: at(99),
+            max: at(100),
+        }
+    }
+}
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.p50, self.p90, self.p99, self.max
+        )
+    }
+}
+
+impl Stress {
+    /// Spawn all tasks onto `executor` and block until they finished
+    pub fn run(&self, executor: &Executor<'static>) -> Report {
+        let Self { tasks, poll, polls } = *self;
+        let start = Instant::now();
+        let handles: Vec<_> = (0..tasks)
+            .map(|_| {
+                let spawned = Instant::now();
+                executor.spawn(async move {
+                    let first_poll = spawned.elapsed();
+                    for i in 0..polls {
+                        let busy = Instant::now();
+                        while busy.elapsed() < poll {
+                            std::hint::spin_loop();
+                        }
+                        if i + 1 < polls {
+                            future::yield_now().await;
+                        }
+                    }
+                    (first_poll, spawned.elapsed())
+                })
+            })
+            .collect();
+
+        let results = executor.run(async {
+            let mut results = Vec::with_capacity(handles.len());
+            for handle in handles {
+                results.extend(handle.await);
+            }
+            results
+        });
+        let elapsed = start.elapsed();
+
+        let completed = results.len();
+        let (first_poll, latency) = results.into_iter().unzip();
+        Report {
+            completed,
+            elapsed,
+            first_poll: Percentiles::of(first_poll),
+            latency: Percentiles::of(latency),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_run_reports_all_tasks() {
+        let stress = Stress {
+            tasks: 200,
+            poll: Duration::from_micros(10),
+            polls: 3,
+        };
+        let report = stress.run(&Executor::new());
+
+        assert_eq!(report.completed, 200);
+        assert!(report.throughput() > 0.0);
+        // Every task busy-loops through all its polls before finishing
+        assert!(report.latency.p50 >= Duration::from_micros(30));
+        assert!(report.first_poll.p50 <= report.latency.p50);
+        assert!(report.latency.p50 <= report.latency.p99);
+        assert!(report.latency.max <= report.elapsed);
+    }
+}
@@ -0,0 +1,59 @@
+//! Fixtures shared by the tests of several modules
+//!
+//! Some state of bffh lives in process-wide globals, e.g. the audit log. Tests must set those up
+//! through here so they don't depend on which test happened to run first.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use lmdb::Environment;
+use once_cell::sync::OnceCell;
+
+use crate::audit::{AuditLog, AUDIT};
+use crate::authorization::permissions::{PermissionBuf, PrivilegesBuf};
+use crate::config::MachineDescription;
+use crate::resources::state::db::StateDB;
+use crate::resources::{Inner, Resource};
+
+/// Path of the audit log shared by all tests, opened on first use
+pub fn audit_log() -> &'static Path {
+    static PATH: OnceCell<tempfile::TempPath> = OnceCell::new();
+    let path = PATH.get_or_init(|| tempfile::NamedTempFile::new().unwrap().into_temp_path());
+    AUDIT.get_or_init(|| AuditLog::open(path).unwrap());
+    path
+}
+
+/// Description of a machine named `name` that needs `perm` for everything
+pub fn machine(name: &str, perm: &PermissionBuf) -> MachineDescription {
+    MachineDescription {
+        name: name.to_string(),
+        description: None,
+        wiki: None,
+        category: None,
+        icon: None,
+        metadata: HashMap::new(),
+        supervisor: None,
+        require_check_note: false,
+        check_on_return: false,
+        announce_on_free: false,
+        debounce_ms: None,
+        required_training: None,
+        denied_message: HashMap::new(),
+        privs: PrivilegesBuf {
+            disclose: perm.clone(),
+            read: perm.clone(),
+            write: perm.clone(),
+            manage: perm.clone(),
+        },
+    }
+}
+
+/// The machine `id` described by `desc`, with its state stored in `env`
+///
+/// Also sets up the audit log, which every change of the machine is written to.
+pub fn resource(env: Arc<Environment>, id: &str, desc: MachineDescription) -> Resource {
+    audit_log();
+    let db = StateDB::create_with_env(env).unwrap();
+    Resource::new(Arc::new(Inner::new(id.to_string(), db, desc)))
+}
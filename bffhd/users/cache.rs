@@ -0,0 +1,245 @@
+//! In-memory cache of recently used users
+//!
+//! Every permission check of a session looks up its user. Without the cache each of them is an
+//! LMDB read and a full deserialization of the user.
+//!
+//! Changes made through this process invalidate the cached user right away. Changes made by
+//! another process, e.g. `bffhd --add-user`, can't be noticed, so cached users are only trusted for
+//! a short while.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::users::db::User;
+
+/// Number of users kept if not configured otherwise
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// Time a cached user is used for before it is read from the database again
+const DEFAULT_TTL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+/// Number of users kept in memory, 0 disables the cache
+pub struct CacheCapacity(pub usize);
+
+impl Default for CacheCapacity {
+    fn default() -> Self {
+        Self(DEFAULT_CAPACITY)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    /// Cached users with the tick they were last used at and when they were read
+    users: HashMap<String, (User, u64, Instant)>,
+    /// Uid of every cached user by the tick it was last used at, least recently used first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    /// Bumped on every invalidation, so a lookup racing with a write doesn't cache stale data
+    generation: u64,
+}
+
+impl Entries {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove(&mut self, uid: &str) {
+        if let Some((_, tick, _)) = self.users.remove(uid) {
+            self.order.remove(&tick);
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Bounded cache of deserialized users, evicting the least recently used one when full
+pub struct UserCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+    /// Number of lookups that had to go to the database
+    misses: AtomicU64,
+}
+
+impl UserCache {
+    /// A cache holding up to `capacity` users. A capacity of 0 disables caching.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ttl: DEFAULT_TTL,
+            entries: Mutex::new(Entries::default()),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Read users from the database again once they were cached for `ttl`
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The cached user `uid`, calling `load` to read it from the database if it isn't cached
+    ///
+    /// Users that don't exist are not cached, so one created behind the back of the cache is
+    /// found on the next lookup.
+    pub fn get_or_load(&self, uid: &str, load: impl FnOnce() -> Option<User>) -> Option<User> {
+        let generation = {
+            let mut entries = self.entries.lock().unwrap();
+            let tick = entries.next_tick();
+            let entries = &mut *entries;
+            match entries.users.get_mut(uid) {
+                Some((user, used, read)) if read.elapsed() < self.ttl => {
+                    entries.order.remove(&*used);
+                    entries.order.insert(tick, uid.to_string());
+                    *used = tick;
+                    return Some(user.clone());
+                }
+                Some(_) => entries.remove(uid),
+                None => {}
+            }
+            entries.generation
+        };
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let read = Instant::now();
+        let user = load()?;
+        if self.capacity > 0 {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.generation == generation {
+                entries.remove(uid);
+                if entries.users.len() >= self.capacity {
+                    if let Some((_, oldest)) = entries.order.pop_first() {
+                        entries.users.remove(&oldest);
+                    }
+                }
+                let tick = entries.next_tick();
+                entries.order.insert(tick, uid.to_string());
+                entries
+                    .users
+                    .insert(uid.to_string(), (user.clone(), tick, read));
+            }
+        }
+        Some(user)
+    }
+
+    /// Forget `uid`, e.g. because it was changed or deleted
+    pub fn invalidate(&self, uid: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.generation += 1;
+        entries.remove(uid);
+    }
+
+    /// Forget all users, e.g. after they were replaced in bulk
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.generation += 1;
+        entries.users.clear();
+        entries.order.clear();
+    }
+
+    /// Number of lookups that weren't answered from the cache since it was created
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn user(uid: &str, role: &str) -> User {
+        let mut user = User::new_with_plain_pw(uid, "secret");
+        user.userdata.roles.push(role.to_string());
+        user
+    }
+
+    #[test]
+    fn repeated_lookups_read_the_database_once() {
+        let cache = UserCache::new(2);
+        let stored = user("alice", "member");
+        let reads = Cell::new(0);
+        let load = |uid: &str| {
+            reads.set(reads.get() + 1);
+            (uid == "alice").then(|| stored.clone())
+        };
+
+        for _ in 0..1000 {
+            assert_eq!(
+                cache.get_or_load("alice", || load("alice")),
+                Some(stored.clone())
+            );
+        }
+        assert_eq!(reads.get(), 1);
+        assert_eq!(cache.misses(), 1);
+
+        // Unknown users are looked up every time
+        assert_eq!(cache.get_or_load("nobody", || load("nobody")), None);
+        assert_eq!(cache.get_or_load("nobody", || load("nobody")), None);
+        assert_eq!(reads.get(), 3);
+
+        cache.invalidate("alice");
+        cache.get_or_load("alice", || load("alice"));
+        assert_eq!(reads.get(), 4);
+    }
+
+    #[test]
+    fn least_recently_used_user_is_evicted() {
+        let cache = UserCache::new(2);
+        let reads = Cell::new(0);
+        let get = |uid: &str| {
+            cache.get_or_load(uid, || {
+                reads.set(reads.get() + 1);
+                Some(user(uid, "member"))
+            })
+        };
+
+        get("alice");
+        get("bob");
+        get("alice");
+        // Evicts bob, alice was used more recently
+        get("carol");
+        assert_eq!(reads.get(), 3);
+        get("alice");
+        assert_eq!(reads.get(), 3);
+        get("bob");
+        assert_eq!(reads.get(), 4);
+    }
+
+    #[test]
+    fn users_are_read_again_after_the_ttl() {
+        let cache = UserCache::new(2).with_ttl(Duration::from_millis(50));
+        let reads = Cell::new(0);
+        let get = || {
+            cache.get_or_load("alice", || {
+                reads.set(reads.get() + 1);
+                Some(user("alice", "member"))
+            })
+        };
+
+        get();
+        get();
+        assert_eq!(reads.get(), 1);
+        std::thread::sleep(Duration::from_millis(60));
+        get();
+        get();
+        assert_eq!(reads.get(), 2);
+    }
+
+    #[test]
+    fn disabled_cache_always_reads() {
+        let cache = UserCache::new(0);
+        for _ in 0..3 {
+            cache.get_or_load("alice", || Some(user("alice", "member")));
+        }
+        assert_eq!(cache.misses(), 3);
+    }
+}
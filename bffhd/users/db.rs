@@ -1,11 +1,13 @@
 use lmdb::{DatabaseFlags, Environment, RwTransaction, Transaction, WriteFlags};
 use rkyv::Infallible;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 
 use std::sync::Arc;
 
 use crate::db;
 use crate::db::{AlignedAdapter, ArchivedValue, RawDB, DB};
+use crate::users::scram::{ScramCredentials, SCRAM_KEY};
 use rkyv::ser::serializers::AllocSerializer;
 use rkyv::ser::Serializer;
 use rkyv::Deserialize;
@@ -23,6 +25,7 @@ pub use crate::db::Error;
     serde::Serialize,
     serde::Deserialize,
 )]
+#[archive_attr(derive(bytecheck::CheckBytes))]
 pub struct User {
     pub id: String,
     pub userdata: UserData,
@@ -46,14 +49,16 @@ impl User {
     pub fn new_with_plain_pw(username: &str, password: impl AsRef<[u8]>) -> Self {
         let hash = hash_pw(password.as_ref())
             .expect(&format!("Failed to hash password for {}: ", username));
-        tracing::debug!("Hashed pw for {} to {}", username, hash);
+        tracing::debug!("Hashed pw for {}", username);
 
+        let mut userdata = UserData {
+            passwd: Some(hash),
+            ..Default::default()
+        };
+        userdata.set_scram_credentials(&ScramCredentials::derive(password.as_ref()));
         User {
             id: username.to_string(),
-            userdata: UserData {
-                passwd: Some(hash),
-                ..Default::default()
-            },
+            userdata,
         }
     }
 
@@ -62,6 +67,8 @@ impl User {
             "failed to update hashed password for {}",
             &self.id
         )));
+        self.userdata
+            .set_scram_credentials(&ScramCredentials::derive(password.as_ref()));
     }
 }
 
@@ -69,7 +76,6 @@ impl User {
     Clone,
     PartialEq,
     Eq,
-    Debug,
     Default,
     rkyv::Archive,
     rkyv::Serialize,
@@ -77,6 +83,7 @@ impl User {
     serde::Serialize,
     serde::Deserialize,
 )]
+#[archive_attr(derive(bytecheck::CheckBytes))]
 /// Data on an user to base decisions on
 ///
 /// This of course includes authorization data, i.e. that users set roles
@@ -90,10 +97,70 @@ pub struct UserData {
     pub passwd: Option<String>,
 
     /// Additional data storage
-    #[serde(flatten, skip_serializing_if = "HashMap::is_empty")]
+    #[serde(
+        flatten,
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "serialize_sorted"
+    )]
     pub kv: HashMap<String, String>,
 }
 
+/// Serialize a map ordered by key, keeping dumps stable between runs
+pub(crate) fn serialize_sorted<S, K, V>(
+    map: &HashMap<K, V>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+    K: Ord + serde::Serialize,
+    V: serde::Serialize,
+{
+    serde::Serialize::serialize(&map.iter().collect::<BTreeMap<_, _>>(), serializer)
+}
+
+/// Keys in [`UserData::kv`] holding secrets
+const SECRET_KEYS: &[&str] = &["cardkey", SCRAM_KEY];
+
+/// Password hash and secret values are replaced with `***` so users can be logged safely
+impl fmt::Debug for UserData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Redacted;
+        impl fmt::Debug for Redacted {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("***")
+            }
+        }
+
+        let kv: BTreeMap<&str, &dyn fmt::Debug> = self
+            .kv
+            .iter()
+            .map(|(key, value)| {
+                let value: &dyn fmt::Debug = if SECRET_KEYS.contains(&key.as_str()) {
+                    &Redacted
+                } else {
+                    value
+                };
+                (key.as_str(), value)
+            })
+            .collect();
+
+        f.debug_struct("UserData")
+            .field("roles", &self.roles)
+            .field("passwd", &self.passwd.as_ref().map(|_| Redacted))
+            .field("kv", &kv)
+            .finish()
+    }
+}
+
+/// Key in [`UserData::kv`] marking an account as suspended. The value is the reason given.
+///
+/// Suspension is kept in the key-value store so existing user records stay readable.
+pub const SUSPENDED_KEY: &str = "suspended";
+
+/// Prefix of the keys in [`UserData::kv`] holding preferences set by the user, e.g.
+/// `pref.language`. Kept there for the same reason as [`SUSPENDED_KEY`].
+pub const PREFERENCE_PREFIX: &str = "pref.";
+
 impl UserData {
     pub fn new(roles: Vec<String>) -> Self {
         Self {
@@ -109,6 +176,81 @@ impl UserData {
             passwd: None,
         }
     }
+
+    /// Suspended users keep their roles and password but can not authenticate
+    pub fn is_suspended(&self) -> bool {
+        self.kv.contains_key(SUSPENDED_KEY)
+    }
+
+    pub fn suspension_reason(&self) -> Option<&str> {
+        self.kv.get(SUSPENDED_KEY).map(String::as_str)
+    }
+
+    pub fn suspend(&mut self, reason: impl Into<String>) {
+        self.kv.insert(SUSPENDED_KEY.to_string(), reason.into());
+    }
+
+    pub fn unsuspend(&mut self) {
+        self.kv.remove(SUSPENDED_KEY);
+    }
+
+    /// Preferences set by the user, by name without [`PREFERENCE_PREFIX`]
+    pub fn preferences(&self) -> BTreeMap<&str, &str> {
+        self.kv
+            .iter()
+            .filter_map(|(key, value)| {
+                let name = key.strip_prefix(PREFERENCE_PREFIX)?;
+                Some((name, value.as_str()))
+            })
+            .collect()
+    }
+
+    pub fn preference(&self, name: &str) -> Option<&str> {
+        self.kv
+            .get(&format!("{}{}", PREFERENCE_PREFIX, name))
+            .map(String::as_str)
+    }
+
+    /// Set the preference `name` to `value`, removing it if `value` is `None`
+    pub fn set_preference(&mut self, name: &str, value: Option<String>) {
+        let key = format!("{}{}", PREFERENCE_PREFIX, name);
+        match value {
+            Some(value) => self.kv.insert(key, value),
+            None => self.kv.remove(&key),
+        };
+    }
+
+    /// Credentials for SCRAM-SHA-256, if they were derived from the password yet
+    pub fn scram_credentials(&self) -> Option<ScramCredentials> {
+        let encoded = self.kv.get(SCRAM_KEY)?;
+        let credentials = ScramCredentials::parse(encoded);
+        if credentials.is_none() {
+            tracing::warn!("ignoring invalid SCRAM credentials");
+        }
+        credentials
+    }
+
+    pub fn set_scram_credentials(&mut self, credentials: &ScramCredentials) {
+        self.kv.insert(SCRAM_KEY.to_string(), credentials.encode());
+    }
+
+    /// Check for a training record `key` that is still valid at `now`, a Unix timestamp
+    ///
+    /// An empty value never expires, otherwise the value is the RFC 3339 timestamp the training
+    /// expires at. Values that can't be parsed count as expired.
+    pub fn has_training(&self, key: &str, now: i64) -> bool {
+        match self.kv.get(key).map(|value| value.trim()) {
+            None => false,
+            Some("") => true,
+            Some(expires) => match chrono::DateTime::parse_from_rfc3339(expires) {
+                Ok(expires) => expires.timestamp() > now,
+                Err(error) => {
+                    tracing::warn!(%key, %error, "training record has an invalid expiry");
+                    false
+                }
+            },
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -145,6 +287,14 @@ impl UserDB {
         self.db.get(&txn, &uid.as_bytes())
     }
 
+    pub fn get_txn<T: Transaction>(
+        &self,
+        txn: &T,
+        uid: &str,
+    ) -> Result<Option<ArchivedValue<User>>, db::Error> {
+        self.db.get(txn, &uid.as_bytes())
+    }
+
     pub fn put(&self, uid: &str, user: &User) -> Result<(), db::Error> {
         let mut serializer = AllocSerializer::<1024>::default();
         serializer.serialize_value(user).expect("rkyv error");
@@ -181,6 +331,10 @@ impl UserDB {
         Ok(())
     }
 
+    pub fn delete_txn(&self, txn: &mut RwTransaction, uid: &str) -> Result<(), db::Error> {
+        self.db.del(txn, &uid)
+    }
+
     pub fn clear_txn(&self, txn: &mut RwTransaction) -> Result<(), db::Error> {
         // TODO: why was the result ignored here?
         self.db.clear(txn)
@@ -0,0 +1,266 @@
+//! Invite tokens allowing prospective members to register themselves
+//!
+//! An admin issues a token, optionally bound to a role. Whoever presents the token before it
+//! expires may create exactly one user with it; the token is consumed in the same transaction
+//! the user is stored in.
+
+use lmdb::{DatabaseFlags, Environment, Transaction, WriteFlags};
+use miette::Diagnostic;
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::ser::Serializer;
+use rkyv::{Deserialize, Infallible};
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::db;
+use crate::db::{AlignedAdapter, ArchivedValue, RawDB, DB};
+use crate::users::db::{User, UserDB};
+use crate::users::validation::{InvalidUsername, UsernamePolicy, WeakPassword};
+
+/// Validity of newly issued invites if not specified otherwise: one week
+pub const DEFAULT_VALIDITY: i64 = 7 * 24 * 60 * 60;
+
+/// Authentication mechanism registering a user with an invite token and logging them in
+///
+/// Not a SASL mechanism: its single step takes the token, username and password separated by NUL
+/// bytes.
+pub const MECHANISM: &str = "X-FABACCESS-INVITE";
+
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    rkyv::Archive,
+    rkyv::Serialize,
+    rkyv::Deserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct Invite {
+    /// Role given to the user registering with this invite
+    pub role: Option<String>,
+    /// Unix timestamp after which the invite can no longer be used
+    pub expires: i64,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum RegistrationError {
+    #[error("invite token is unknown or has already been used")]
+    #[diagnostic(code(bffh::users::invite::invalid))]
+    InvalidToken,
+    #[error("invite token has expired")]
+    #[diagnostic(code(bffh::users::invite::expired))]
+    Expired,
+    #[error("username {0} is already taken")]
+    #[diagnostic(code(bffh::users::invite::exists))]
+    AlreadyExists(String),
+    #[error("password must not be empty")]
+    #[diagnostic(code(bffh::users::invite::empty))]
+    Empty,
+    #[error("registration data must be the token, username and password separated by NUL")]
+    #[diagnostic(code(bffh::users::invite::malformed))]
+    Malformed,
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    InvalidUsername(#[from] InvalidUsername),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    WeakPassword(#[from] WeakPassword),
+    #[error(transparent)]
+    Maintenance(#[from] crate::session::Maintenance),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    DB(#[from] db::Error),
+}
+
+impl From<lmdb::Error> for RegistrationError {
+    fn from(e: lmdb::Error) -> Self {
+        Self::DB(e.into())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InviteDB {
+    env: Arc<Environment>,
+    db: DB<AlignedAdapter<Invite>>,
+    policy: UsernamePolicy,
+}
+
+impl InviteDB {
+    pub unsafe fn create(env: Arc<Environment>) -> Result<Self, db::Error> {
+        let db = RawDB::create(&env, Some("invite"), DatabaseFlags::empty())?;
+        Ok(Self {
+            env,
+            db: DB::new(db),
+            policy: UsernamePolicy::default(),
+        })
+    }
+
+    /// Rules usernames chosen during registration have to follow
+    pub fn with_username_policy(mut self, policy: UsernamePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Issue a new invite valid for `validity` seconds, returning the token
+    pub fn issue(&self, role: Option<String>, validity: i64) -> Result<String, db::Error> {
+        self.issue_at(role, validity, chrono::Utc::now().timestamp())
+    }
+
+    fn issue_at(&self, role: Option<String>, validity: i64, now: i64) -> Result<String, db::Error> {
+        let token = hex::encode(rand::random::<[u8; 16]>());
+        let invite = Invite {
+            role,
+            expires: now + validity,
+        };
+
+        let mut serializer = AllocSerializer::<1024>::default();
+        serializer.serialize_value(&invite).expect("rkyv error");
+        let value = ArchivedValue::new(serializer.into_serializer().into_inner());
+
+        let mut txn = self.env.begin_rw_txn()?;
+        self.db
+            .put(&mut txn, &token.as_bytes(), &value, WriteFlags::empty())?;
+        txn.commit()?;
+
+        tracing::info!(expires = invite.expires, role = ?invite.role, "issued invite");
+        Ok(token)
+    }
+
+    /// Register a new user using the given invite token
+    ///
+    /// The token is consumed on success. Expired tokens are removed when they are presented.
+    pub fn register(
+        &self,
+        userdb: &UserDB,
+        token: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<User, RegistrationError> {
+        self.register_at(
+            userdb,
+            token,
+            username,
+            password,
+            chrono::Utc::now().timestamp(),
+        )
+    }
+
+    fn register_at(
+        &self,
+        userdb: &UserDB,
+        token: &str,
+        username: &str,
+        password: &str,
+        now: i64,
+    ) -> Result<User, RegistrationError> {
+        self.policy.check(username)?;
+        if password.is_empty() {
+            return Err(RegistrationError::Empty);
+        }
+
+        let mut txn = self.env.begin_rw_txn()?;
+        let invite = self
+            .db
+            .get(&txn, &token.as_bytes())?
+            .ok_or(RegistrationError::InvalidToken)?;
+        let invite: Invite =
+            Deserialize::<Invite, _>::deserialize(invite.as_ref(), &mut Infallible).unwrap();
+
+        if invite.expires < now {
+            self.db.del(&mut txn, &token.as_bytes())?;
+            txn.commit()?;
+            tracing::debug!(username, "rejected expired invite");
+            return Err(RegistrationError::Expired);
+        }
+
+        if userdb.get_txn(&txn, username)?.is_some() {
+            return Err(RegistrationError::AlreadyExists(username.to_string()));
+        }
+
+        let mut user = User::new_with_plain_pw(username, password);
+        user.userdata.roles.extend(invite.role);
+
+        userdb.put_txn(&mut txn, username, &user)?;
+        self.db.del(&mut txn, &token.as_bytes())?;
+        txn.commit()?;
+
+        tracing::info!(username, roles = ?user.userdata.roles, "registered user via invite");
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::state::db::StateDB;
+
+    fn open() -> (tempfile::TempDir, InviteDB, UserDB) {
+        let dir = tempfile::tempdir().unwrap();
+        let env = StateDB::open_env(dir.path().join("db")).unwrap();
+        let invites = unsafe { InviteDB::create(env.clone()) }.unwrap();
+        let users = unsafe { UserDB::create(env) }.unwrap();
+        (dir, invites, users)
+    }
+
+    #[test]
+    fn register_with_valid_token() {
+        let (_dir, invites, users) = open();
+        let token = invites
+            .issue_at(Some("member".to_string()), 60, 1000)
+            .unwrap();
+
+        let user = invites
+            .register_at(&users, &token, "newbie", "secret", 1030)
+            .unwrap();
+        assert_eq!(user.userdata.roles, vec!["member".to_string()]);
+        assert!(user.check_password(b"secret").unwrap());
+        assert!(users.get("newbie").unwrap().is_some());
+    }
+
+    #[test]
+    fn token_is_single_use() {
+        let (_dir, invites, users) = open();
+        let token = invites.issue_at(None, 60, 1000).unwrap();
+
+        invites
+            .register_at(&users, &token, "first", "secret", 1000)
+            .unwrap();
+        assert!(matches!(
+            invites.register_at(&users, &token, "second", "secret", 1000),
+            Err(RegistrationError::InvalidToken)
+        ));
+        assert!(users.get("second").unwrap().is_none());
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let (_dir, invites, users) = open();
+        let token = invites.issue_at(None, 60, 1000).unwrap();
+
+        assert!(matches!(
+            invites.register_at(&users, &token, "late", "secret", 1061),
+            Err(RegistrationError::Expired)
+        ));
+        assert!(users.get("late").unwrap().is_none());
+    }
+
+    #[test]
+    fn duplicate_username_keeps_token() {
+        let (_dir, invites, users) = open();
+        users
+            .put("taken", &User::new_with_plain_pw("taken", "pw"))
+            .unwrap();
+        let token = invites.issue_at(None, 60, 1000).unwrap();
+
+        assert!(matches!(
+            invites.register_at(&users, &token, "taken", "secret", 1000),
+            Err(RegistrationError::AlreadyExists(_))
+        ));
+        // The invite was not consumed and can still be used with a different name
+        invites
+            .register_at(&users, &token, "untaken", "secret", 1000)
+            .unwrap();
+    }
+}
@@ -1,21 +1,28 @@
 use std::fs;
 
-use lmdb::{Environment, Transaction};
-use once_cell::sync::OnceCell;
+use lmdb::{Environment, RwTransaction, Transaction};
 use rkyv::{Archive, Deserialize, Infallible, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Display, Formatter};
 use std::io::Write;
 
-use miette::{Diagnostic, IntoDiagnostic, SourceSpan};
+use miette::{Diagnostic, IntoDiagnostic, SourceSpan, WrapErr};
 use std::path::Path;
 use std::sync::Arc;
 
 use thiserror::Error;
 
+pub mod cache;
 pub mod db;
+pub mod invites;
+pub mod scram;
+pub mod validation;
 
+use crate::users::cache::{CacheCapacity, UserCache};
 use crate::users::db::UserData;
+use crate::users::invites::{InviteDB, RegistrationError};
+use crate::users::scram::ScramCredentials;
+use crate::users::validation::{InvalidUsername, PasswordPolicy, UsernamePolicy, WeakPassword};
 use crate::UserDB;
 
 #[derive(
@@ -29,7 +36,7 @@ use crate::UserDB;
     serde::Serialize,
     serde::Deserialize,
 )]
-#[archive_attr(derive(Debug, PartialEq))]
+#[archive_attr(derive(Debug, PartialEq, bytecheck::CheckBytes))]
 pub struct UserRef {
     pub id: String,
 }
@@ -61,51 +68,169 @@ impl UserRef {
     }
 }
 
-static USERDB: OnceCell<UserDB> = OnceCell::new();
-
 #[derive(Copy, Clone, Debug)]
-#[repr(transparent)]
 pub struct Users {
     userdb: &'static UserDB,
+    policy: &'static UsernamePolicy,
+    passwords: &'static PasswordPolicy,
+    cache: &'static UserCache,
+    read_only: bool,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Error, Diagnostic)]
-#[error(transparent)]
-#[repr(transparent)]
-pub struct Error(#[from] pub db::Error);
+pub enum Error {
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Db(#[from] db::Error),
+    /// A change was refused because the handle is read-only
+    #[error(transparent)]
+    Maintenance(#[from] crate::session::Maintenance),
+}
 
 impl Users {
     pub fn new(env: Arc<Environment>) -> Result<Self, Error> {
         let span = tracing::debug_span!("users", ?env, "Creating Users handle");
         let _guard = span.enter();
 
-        let userdb = USERDB.get_or_try_init(|| {
-            tracing::debug!("Global resource not yet initialized, initializing…");
-            unsafe { UserDB::create(env) }
-        })?;
+        let userdb = unsafe { UserDB::create(env)? };
+
+        Ok(Self {
+            userdb: Box::leak(Box::new(userdb)),
+            policy: Box::leak(Box::default()),
+            passwords: Box::leak(Box::default()),
+            cache: Box::leak(Box::new(UserCache::new(CacheCapacity::default().0))),
+            read_only: false,
+        })
+    }
+
+    /// Check the names of new users against `policy`
+    pub fn with_username_policy(mut self, policy: UsernamePolicy) -> Self {
+        self.policy = Box::leak(Box::new(policy));
+        self
+    }
+
+    /// Check new passwords against `passwords`
+    pub fn with_password_policy(mut self, passwords: PasswordPolicy) -> Self {
+        self.passwords = Box::leak(Box::new(passwords));
+        self
+    }
+
+    /// Keep up to `capacity` recently used users in memory
+    pub fn with_cache(mut self, capacity: CacheCapacity) -> Self {
+        self.cache = Box::leak(Box::new(UserCache::new(capacity.0)));
+        self
+    }
+
+    /// Refuse changes to users made through this handle, e.g. for sessions in maintenance mode
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            Err(Error::Maintenance(crate::session::Maintenance))
+        } else {
+            Ok(())
+        }
+    }
 
-        Ok(Self { userdb })
+    /// Check if `uid` may be used as the name of a new user
+    pub fn check_username(&self, uid: &str) -> Result<(), InvalidUsername> {
+        self.policy.check(uid)
     }
 
     pub(crate) fn into_inner(self) -> &'static UserDB {
         self.userdb
     }
 
+    /// Look up `uid`, answered from the cache of recently used users if possible
     pub fn get_user(&self, uid: &str) -> Option<db::User> {
-        tracing::trace!(uid, "Looking up user");
-        self.userdb.get(uid).unwrap().map(|user| {
-            Deserialize::<db::User, _>::deserialize(user.as_ref(), &mut Infallible).unwrap()
+        self.cache.get_or_load(uid, || {
+            tracing::trace!(uid, "Looking up user");
+            self.userdb.get(uid).unwrap().map(|user| {
+                Deserialize::<db::User, _>::deserialize(user.as_ref(), &mut Infallible).unwrap()
+            })
         })
     }
 
-    pub fn put_user(&self, uid: &str, user: &db::User) -> Result<(), crate::db::Error> {
+    /// Find the user whose kv entry `key` is `value`, compared ignoring ASCII case
+    ///
+    /// Meant for identifiers like card UIDs, which are written in either case. Reads every user,
+    /// so it's not something to call for each API request.
+    pub fn find_by(&self, key: &str, value: &str) -> Option<db::User> {
+        let users = self.userdb.get_all().ok()?;
+        let (uid, _) = users.iter().find(|(_, userdata)| {
+            userdata
+                .kv
+                .get(key)
+                .map_or(false, |stored| stored.eq_ignore_ascii_case(value))
+        })?;
+        self.get_user(uid)
+    }
+
+    pub fn put_user(&self, uid: &str, user: &db::User) -> Result<(), Error> {
+        self.check_writable()?;
         tracing::trace!(uid, ?user, "Updating user");
-        self.userdb.put(uid, user)
+        let result = self.userdb.put(uid, user);
+        self.cache.invalidate(uid);
+        Ok(result?)
     }
 
-    pub fn del_user(&self, uid: &str) -> Result<(), crate::db::Error> {
+    /// Change user `uid` with `f`, reading and writing it in the same transaction
+    ///
+    /// Returns `None` without calling `f` if there is no such user. Nothing is written if `f`
+    /// fails.
+    pub fn update_user<T, E: From<Error>>(
+        &self,
+        uid: &str,
+        f: impl FnOnce(&mut db::User) -> Result<T, E>,
+    ) -> Result<Option<T>, E> {
+        self.check_writable()?;
+        // Safe, the transaction is only used with the user db it came from
+        let mut txn = unsafe { self.userdb.get_rw_txn() }.map_err(Error::from)?;
+        let mut user = match self.userdb.get_txn(&txn, uid).map_err(Error::from)? {
+            Some(user) => {
+                Deserialize::<db::User, _>::deserialize(user.as_ref(), &mut Infallible).unwrap()
+            }
+            None => return Ok(None),
+        };
+        let result = f(&mut user)?;
+        tracing::trace!(uid, ?user, "Updating user");
+        self.userdb
+            .put_txn(&mut txn, uid, &user)
+            .map_err(Error::from)?;
+        let committed = txn.commit().map_err(crate::db::Error::from);
+        self.cache.invalidate(uid);
+        committed.map_err(Error::from)?;
+        Ok(Some(result))
+    }
+
+    /// Create user `uid` with an invite token, see [`InviteDB::register`]
+    ///
+    /// `password` has to follow the password policy like those set by admins.
+    pub fn register(
+        &self,
+        invites: &InviteDB,
+        token: &str,
+        uid: &str,
+        password: &str,
+    ) -> Result<db::User, RegistrationError> {
+        if self.read_only {
+            return Err(crate::session::Maintenance.into());
+        }
+        self.passwords.check(password)?;
+        let result = invites.register(self.userdb, token, uid, password);
+        self.cache.invalidate(uid);
+        result
+    }
+
+    pub fn del_user(&self, uid: &str) -> Result<(), Error> {
+        self.check_writable()?;
         tracing::trace!(uid, "Deleting user");
-        self.userdb.delete(uid)
+        let result = self.userdb.delete(uid);
+        self.cache.invalidate(uid);
+        Ok(result?)
     }
 
     pub fn load_file(&self, path_str: &str) -> miette::Result<()> {
@@ -140,25 +265,62 @@ impl Users {
         }
         let f = std::fs::read(path).into_diagnostic()?;
         let map: HashMap<String, UserData> = toml::from_slice(&f).into_diagnostic()?;
+        self.load_map(map)
+            .wrap_err_with(|| format!("failed to load users from {}", path_str))
+    }
+
+    /// Make the stored users match `map`, deleting all users not in it
+    ///
+    /// Plain text passwords are hashed. All changes are written in a single transaction.
+    pub fn load_map(&self, map: HashMap<String, UserData>) -> miette::Result<()> {
+        self.load_map_with(map, |_| Ok(()))
+    }
+
+    /// Like [`Users::load_map`], also calling `also` with the transaction the users are written in
+    ///
+    /// Nothing is written if `also` fails, which lets users be loaded together with other data
+    /// stored in the same environment.
+    pub(crate) fn load_map_with<T>(
+        &self,
+        map: HashMap<String, UserData>,
+        also: impl FnOnce(&mut RwTransaction) -> miette::Result<T>,
+    ) -> miette::Result<T> {
+        // Check all names before clearing the DB so an invalid file doesn't leave it half-loaded
+        for uid in map.keys() {
+            self.check_username(uid)
+                .map_err(|error| miette::miette!("invalid username {:?}: {}", uid, error))?;
+        }
+        check_passwords(self.passwords, &map)?;
+
+        let changes = changes(&self.userdb.get_all()?, map);
+        tracing::info!(
+            changed = changes.put.len(),
+            removed = changes.delete.len(),
+            unchanged = changes.unchanged,
+            "loading users"
+        );
 
         let mut txn = unsafe { self.userdb.get_rw_txn()? };
 
-        self.userdb.clear_txn(&mut txn)?;
+        for uid in changes.delete {
+            self.userdb.delete_txn(&mut txn, &uid)?;
+        }
 
-        for (uid, mut userdata) in map {
-            userdata.passwd = userdata.passwd.map(|pw| {
-                if !pw.starts_with("$argon2") {
+        for (uid, mut userdata) in changes.put {
+            if let Some(pw) = userdata.passwd.take() {
+                userdata.passwd = Some(if !pw.starts_with("$argon2") {
                     let config = argon2::Config::default();
                     let salt: [u8; 16] = rand::random();
                     let hash = argon2::hash_encoded(pw.as_bytes(), &salt, &config)
                         .expect(&format!("Failed to hash password for {}: ", uid));
-                    tracing::debug!("Hashed pw for {} to {}", uid, hash);
+                    tracing::debug!("Hashed pw for {}", uid);
+                    userdata.set_scram_credentials(&ScramCredentials::derive(pw.as_bytes()));
 
                     hash
                 } else {
                     pw
-                }
-            });
+                });
+            }
             let user = db::User {
                 id: uid.clone(),
                 userdata,
@@ -169,8 +331,34 @@ impl Users {
             }
         }
 
-        txn.commit().map_err(crate::db::Error::from)?;
-        Ok(())
+        let also = also(&mut txn)?;
+        let committed = txn.commit().map_err(crate::db::Error::from);
+        self.cache.clear();
+        committed?;
+        Ok(also)
+    }
+
+    /// Create user `uid` with the changes in `edit`, leaving every other user untouched
+    ///
+    /// An existing user is only modified with `force`, in which case the changes are merged
+    /// into it.
+    pub fn add_user(&self, uid: &str, edit: &UserEdit, force: bool) -> miette::Result<db::User> {
+        let existing = self.get_user(uid);
+        if existing.is_none() {
+            self.check_username(uid)
+                .map_err(|error| miette::miette!("invalid username {:?}: {}", uid, error))?;
+        }
+        if let Some(ref pw) = edit.password {
+            self.passwords.check(pw).map_err(|reason| RejectedUser {
+                uid: uid.to_string(),
+                reason,
+            })?;
+        }
+
+        let user = edit.apply(uid, existing, force)?;
+        tracing::trace!(uid, ?user, "Storing user object");
+        self.put_user(uid, &user)?;
+        Ok(user)
     }
 
     pub fn dump_file(&self, path_str: &str, force: bool) -> miette::Result<usize> {
@@ -204,9 +392,332 @@ impl Users {
         let mut file = fs::File::create(path).into_diagnostic()?;
 
         let users = self.userdb.get_all()?;
-        let encoded = toml::ser::to_vec(&users).into_diagnostic()?;
+        let number = users.len();
+        let encoded = encode_dump(users).into_diagnostic()?;
         file.write_all(&encoded[..]).into_diagnostic()?;
 
-        Ok(0)
+        Ok(number)
+    }
+}
+
+/// Writes needed to make the stored users match a users file
+#[derive(Debug, Default)]
+struct Changes {
+    /// New or changed users, passwords may still be in plain text
+    put: Vec<(String, UserData)>,
+    delete: Vec<String>,
+    unchanged: usize,
+}
+
+/// Compare the users of a file with the stored ones
+///
+/// Users that didn't change are skipped so reloading the same file repeatedly does not rewrite
+/// the database. Passwords are compared as hashes: checking a plain text password against the
+/// stored hash costs as much as hashing it, so users with one in the file are always written.
+fn changes(stored: &HashMap<String, UserData>, incoming: HashMap<String, UserData>) -> Changes {
+    let delete = stored
+        .keys()
+        .filter(|uid| !incoming.contains_key(*uid))
+        .cloned()
+        .collect();
+    let mut changes = Changes {
+        delete,
+        ..Default::default()
+    };
+
+    for (uid, userdata) in incoming {
+        let plain = userdata
+            .passwd
+            .as_ref()
+            .map_or(false, |pw| !pw.starts_with("$argon2"));
+        if !plain && stored.get(&uid) == Some(&userdata) {
+            changes.unchanged += 1;
+            continue;
+        }
+        changes.put.push((uid, userdata));
+    }
+    changes
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// Changes to a single user as made with `--add-user`
+pub struct UserEdit {
+    /// New plain text password, hashed before it is stored
+    pub password: Option<String>,
+    pub add_roles: Vec<String>,
+    pub remove_roles: Vec<String>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum UserEditError {
+    #[error("user {0:?} already exists")]
+    #[diagnostic(
+        code(bffh::users::exists),
+        help("Add `--force` to merge the changes into the existing user")
+    )]
+    Exists(String),
+    #[error("role {0:?} is both added and removed")]
+    #[diagnostic(
+        code(bffh::users::role_conflict),
+        help("Pass the role to either `--add-role` or `--remove-role`, not both")
+    )]
+    RoleConflict(String),
+}
+
+impl UserEdit {
+    /// Apply the changes to `existing`, or to a new user without roles if there is none yet
+    pub fn apply(
+        &self,
+        uid: &str,
+        existing: Option<db::User>,
+        force: bool,
+    ) -> Result<db::User, UserEditError> {
+        if let Some(role) = self
+            .add_roles
+            .iter()
+            .find(|role| self.remove_roles.contains(role))
+        {
+            return Err(UserEditError::RoleConflict(role.clone()));
+        }
+
+        let mut user = match existing {
+            Some(_) if !force => return Err(UserEditError::Exists(uid.to_string())),
+            Some(user) => user,
+            None => db::User {
+                id: uid.to_string(),
+                userdata: UserData::new(Vec::new()),
+            },
+        };
+
+        let roles = &mut user.userdata.roles;
+        roles.retain(|role| !self.remove_roles.contains(role));
+        for role in self.add_roles.iter() {
+            if !roles.contains(role) {
+                roles.push(role.clone());
+            }
+        }
+        if let Some(ref pw) = self.password {
+            user.set_pw(pw);
+        }
+
+        Ok(user)
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("password of user {uid:?} is too weak: {reason}")]
+#[diagnostic(code(bffh::users::password::weak))]
+pub struct RejectedUser {
+    uid: String,
+    reason: WeakPassword,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("{} users have passwords not allowed by the password policy", .rejected.len())]
+#[diagnostic(
+    code(bffh::users::password::policy),
+    help("Choose stronger passwords or adjust `passwords` in the config. No users were loaded.")
+)]
+pub struct WeakPasswords {
+    #[related]
+    rejected: Vec<RejectedUser>,
+}
+
+/// Check all plain text passwords against `policy`, reporting every user violating it
+///
+/// Hashed passwords are skipped since their plain text isn't known anymore.
+fn check_passwords(
+    policy: &PasswordPolicy,
+    map: &HashMap<String, UserData>,
+) -> Result<(), WeakPasswords> {
+    let mut rejected: Vec<RejectedUser> = map
+        .iter()
+        .filter_map(|(uid, userdata)| {
+            let pw = userdata.passwd.as_ref()?;
+            if pw.starts_with("$argon2") {
+                return None;
+            }
+            let reason = policy.check(pw).err()?;
+            Some(RejectedUser {
+                uid: uid.clone(),
+                reason,
+            })
+        })
+        .collect();
+    if rejected.is_empty() {
+        return Ok(());
+    }
+    rejected.sort_by(|a, b| a.uid.cmp(&b.uid));
+    Err(WeakPasswords { rejected })
+}
+
+/// Encode users as TOML, ordered by user id so dumps of the same data are byte-identical
+fn encode_dump(users: HashMap<String, UserData>) -> Result<Vec<u8>, toml::ser::Error> {
+    let users: BTreeMap<String, UserData> = users.into_iter().collect();
+    toml::ser::to_vec(&users)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dumps_are_deterministic() {
+        let users = |order: &[&str]| {
+            order
+                .iter()
+                .map(|id| {
+                    let kv = order
+                        .iter()
+                        .map(|key| (format!("key-{}", key), id.to_string()))
+                        .collect();
+                    let data = UserData::new_with_kv(vec!["member".to_string()], kv);
+                    (id.to_string(), data)
+                })
+                .collect::<HashMap<_, _>>()
+        };
+
+        let first = encode_dump(users(&["alice", "bob", "carol", "dave"])).unwrap();
+        let second = encode_dump(users(&["dave", "carol", "bob", "alice"])).unwrap();
+        assert_eq!(first, second);
+
+        let encoded = String::from_utf8(first).unwrap();
+        let alice = encoded.find("[alice]").unwrap();
+        let dave = encoded.find("[dave]").unwrap();
+        assert!(alice < dave);
+        assert!(encoded.find("key-alice").unwrap() < encoded.find("key-bob").unwrap());
+    }
+
+    #[test]
+    fn reloading_unchanged_users_writes_nothing() {
+        let file = r#"
+            [alice]
+            roles = ["member"]
+            passwd = "secret"
+
+            [bob]
+            roles = ["admin"]
+            passwd = "$argon2i$v=19$m=4096,t=3,p=1$c2FsdHNhbHQ$bm90IGEgcmVhbCBoYXNo"
+        "#;
+        let load = || toml::from_str::<HashMap<String, UserData>>(file).unwrap();
+
+        let first = changes(&HashMap::new(), load());
+        assert_eq!(first.put.len(), 2);
+
+        // Store the users the way `load_file` would, hashing plain text passwords
+        let stored: HashMap<_, _> = first
+            .put
+            .into_iter()
+            .map(|(uid, mut userdata)| {
+                if uid == "alice" {
+                    let user = db::User::new_with_plain_pw(&uid, "secret");
+                    userdata.passwd = user.userdata.passwd;
+                }
+                (uid, userdata)
+            })
+            .collect();
+
+        // Only the plain text password is hashed again
+        let second = changes(&stored, load());
+        let put: Vec<&str> = second.put.iter().map(|(uid, _)| uid.as_str()).collect();
+        assert_eq!(put, ["alice"]);
+        assert!(second.delete.is_empty());
+        assert_eq!(second.unchanged, 1);
+
+        let mut edited = load();
+        edited.remove("bob");
+        edited.get_mut("alice").unwrap().passwd = Some("changed".to_string());
+        let third = changes(&stored, edited);
+        assert_eq!(third.put.len(), 1);
+        assert_eq!(third.delete, ["bob"]);
+    }
+
+    #[test]
+    fn weak_passwords_are_rejected_per_user() {
+        let file = r#"
+            [alice]
+            roles = []
+            passwd = "secret"
+
+            [bob]
+            roles = []
+            passwd = "$argon2i$v=19$m=4096,t=3,p=1$c2FsdHNhbHQ$bm90IGEgcmVhbCBoYXNo"
+
+            [carol]
+            roles = []
+            passwd = "correct horse battery staple"
+
+            [dave]
+            roles = []
+            passwd = "hunter2"
+        "#;
+        let map: HashMap<String, UserData> = toml::from_str(file).unwrap();
+        let policy = PasswordPolicy {
+            min_length: 12,
+            ..Default::default()
+        };
+
+        let error = check_passwords(&policy, &map).unwrap_err();
+        let rejected: Vec<_> = error.rejected.iter().map(|r| r.uid.as_str()).collect();
+        assert_eq!(rejected, ["alice", "dave"]);
+        assert!(check_passwords(&PasswordPolicy::default(), &map).is_ok());
+    }
+
+    #[test]
+    fn user_edits_merge_only_with_force() {
+        let edit = UserEdit {
+            password: Some("secret".to_string()),
+            add_roles: vec!["member".to_string(), "admin".to_string()],
+            remove_roles: Vec::new(),
+        };
+        let alice = edit.apply("alice", None, false).unwrap();
+        assert_eq!(alice.userdata.roles, ["member", "admin"]);
+        assert!(alice.check_password(b"secret").unwrap());
+        assert!(alice.userdata.scram_credentials().is_some());
+
+        let demote = UserEdit {
+            add_roles: vec!["member".to_string(), "guest".to_string()],
+            remove_roles: vec!["admin".to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            demote.apply("alice", Some(alice.clone()), false),
+            Err(UserEditError::Exists(_))
+        ));
+        let merged = demote.apply("alice", Some(alice), true).unwrap();
+        assert_eq!(merged.userdata.roles, ["member", "guest"]);
+        // Untouched without `password`
+        assert!(merged.check_password(b"secret").unwrap());
+
+        let conflicting = UserEdit {
+            add_roles: vec!["admin".to_string()],
+            remove_roles: vec!["admin".to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            conflicting.apply("bob", None, false),
+            Err(UserEditError::RoleConflict(_))
+        ));
+    }
+
+    #[test]
+    fn logged_users_hide_secrets() {
+        let mut user = db::User::new_with_plain_pw("alice", "secret");
+        user.userdata.kv.insert(
+            "cardkey".to_string(),
+            "00112233445566778899aabbccddeeff".to_string(),
+        );
+        user.userdata.kv.insert(
+            "cardtoken".to_string(),
+            "https://example.org/card".to_string(),
+        );
+        let hash = user.userdata.passwd.clone().unwrap();
+
+        let logged = format!("{:?}", user);
+        assert!(!logged.contains(&hash));
+        assert!(!logged.contains("00112233445566778899aabbccddeeff"));
+        // Everything else is still there to debug with
+        assert!(logged.contains("alice"));
+        assert!(logged.contains("https://example.org/card"));
     }
 }
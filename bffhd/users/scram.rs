@@ -0,0 +1,100 @@
+//! SCRAM-SHA-256 credentials of users, stored next to the argon2 password hash
+//!
+//! SCRAM needs keys derived from the plain text password with PBKDF2, which can't be computed
+//! from the argon2 hash. They are derived whenever the plain text password is known instead, i.e.
+//! when it is set and on every successful PLAIN authentication of users that don't have them yet.
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+/// Key in [`UserData::kv`](super::db::UserData::kv) holding the credentials
+///
+/// Kept in the key-value store so existing user records stay readable.
+pub const SCRAM_KEY: &str = "scram-sha-256";
+
+/// PBKDF2 iterations for newly derived credentials, the minimum RFC 7677 asks for
+pub const DEFAULT_ITERATIONS: u32 = 4096;
+
+const PREFIX: &str = "SCRAM-SHA-256$";
+
+#[derive(Clone, PartialEq, Eq)]
+/// Salt, iteration count and keys the server needs to verify a SCRAM-SHA-256 authentication
+///
+/// Encoded in the format of RFC 5803: `SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>`
+/// with all binary values in base64.
+pub struct ScramCredentials {
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub stored_key: [u8; 32],
+    pub server_key: [u8; 32],
+}
+
+impl ScramCredentials {
+    /// Derive credentials for `password` with a new random salt
+    pub fn derive(password: &[u8]) -> Self {
+        let salt: [u8; 16] = rand::random();
+        Self::derive_with(password, &salt, DEFAULT_ITERATIONS)
+    }
+
+    pub fn derive_with(password: &[u8], salt: &[u8], iterations: u32) -> Self {
+        let mut salted = [0; 32];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(password, salt, iterations, &mut salted);
+        let hmac = |message: &[u8]| {
+            let mut mac =
+                Hmac::<Sha256>::new_from_slice(&salted).expect("HMAC accepts keys of any length");
+            mac.update(message);
+            <[u8; 32]>::from(mac.finalize().into_bytes())
+        };
+        let client_key = hmac(b"Client Key");
+        Self {
+            iterations,
+            salt: salt.to_vec(),
+            stored_key: Sha256::digest(client_key).into(),
+            server_key: hmac(b"Server Key"),
+        }
+    }
+
+    pub fn parse(encoded: &str) -> Option<Self> {
+        let (params, keys) = encoded.strip_prefix(PREFIX)?.split_once('$')?;
+        let (iterations, salt) = params.split_once(':')?;
+        let (stored_key, server_key) = keys.split_once(':')?;
+        let key = |encoded: &str| <[u8; 32]>::try_from(base64::decode(encoded).ok()?).ok();
+        Some(Self {
+            iterations: iterations.parse().ok()?,
+            salt: base64::decode(salt).ok()?,
+            stored_key: key(stored_key)?,
+            server_key: key(server_key)?,
+        })
+    }
+
+    pub fn encode(&self) -> String {
+        format!(
+            "{}{}:{}${}:{}",
+            PREFIX,
+            self.iterations,
+            base64::encode(&self.salt),
+            base64::encode(self.stored_key),
+            base64::encode(self.server_key),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_match_rfc7677_example() {
+        let salt = base64::decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let credentials = ScramCredentials::derive_with(b"pencil", &salt, 4096);
+        let encoded = credentials.encode();
+        assert_eq!(
+            encoded,
+            "SCRAM-SHA-256$4096:W22ZaJ0SNY7soEsUEjb6gQ==\
+             $WG5d8oPm3OtcPnkdi4Uo7BkeZkBFzpcXkuLmtbsT4qY=\
+             :wfPLwcE6nTWhTAmQ7tl2KeoiWGPlZqQxSrmfPwDl2dU="
+        );
+        assert!(ScramCredentials::parse(&encoded) == Some(credentials));
+        assert!(ScramCredentials::parse("$argon2i$v=19$m=4096,t=3,p=1$c2FsdA$aGFzaA").is_none());
+    }
+}
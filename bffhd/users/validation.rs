@@ -0,0 +1,214 @@
+use miette::Diagnostic;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error, Diagnostic)]
+pub enum InvalidUsername {
+    #[error("username must not be empty")]
+    #[diagnostic(code(bffh::users::username::empty))]
+    Empty,
+    #[error("username is longer than {max} characters")]
+    #[diagnostic(code(bffh::users::username::length))]
+    TooLong { max: usize },
+    #[error("username contains disallowed character {0:?}")]
+    #[diagnostic(
+        code(bffh::users::username::charset),
+        help("Usernames must not contain control characters. With `usernames.strict` set they may only contain ASCII letters, digits and the characters configured in `usernames.extra_chars`")
+    )]
+    InvalidChar(char),
+}
+
+fn default_max_length() -> usize {
+    256
+}
+
+fn default_extra_chars() -> String {
+    "._-".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Rules new usernames have to follow
+///
+/// Usernames end up in log and audit lines, so control characters are never allowed. Anything
+/// else, e.g. email addresses or non-ASCII names, is accepted unless `strict` is set.
+pub struct UsernamePolicy {
+    /// Maximum length in characters
+    #[serde(default = "default_max_length")]
+    pub max_length: usize,
+    /// Only allow ASCII letters, digits and `extra_chars`
+    #[serde(default)]
+    pub strict: bool,
+    /// Characters allowed in addition to ASCII letters and digits if `strict` is set
+    #[serde(default = "default_extra_chars")]
+    pub extra_chars: String,
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        Self {
+            max_length: default_max_length(),
+            strict: false,
+            extra_chars: default_extra_chars(),
+        }
+    }
+}
+
+impl UsernamePolicy {
+    pub fn check(&self, username: &str) -> Result<(), InvalidUsername> {
+        if username.is_empty() {
+            return Err(InvalidUsername::Empty);
+        }
+        let allowed = |c: char| {
+            if self.strict {
+                c.is_ascii_alphanumeric() || self.extra_chars.contains(c)
+            } else {
+                !c.is_control()
+            }
+        };
+        if let Some(c) = username.chars().find(|c| !allowed(*c)) {
+            return Err(InvalidUsername::InvalidChar(c));
+        }
+        if username.chars().count() > self.max_length {
+            return Err(InvalidUsername::TooLong {
+                max: self.max_length,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error, Diagnostic)]
+pub enum WeakPassword {
+    #[error("password is shorter than {min} characters")]
+    #[diagnostic(code(bffh::users::password::length))]
+    TooShort { min: usize },
+    #[error("password uses fewer than three kinds of characters")]
+    #[diagnostic(
+        code(bffh::users::password::classes),
+        help("Mix lower case letters, upper case letters, digits and other characters")
+    )]
+    NotMixed,
+    #[error("password is on the deny list")]
+    #[diagnostic(code(bffh::users::password::denied))]
+    Denied,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+/// Rules plain text passwords have to follow when users are loaded
+///
+/// Nothing is enforced by default. Passwords that are already hashed can't be checked and are
+/// accepted as they are.
+pub struct PasswordPolicy {
+    /// Minimum length in characters
+    #[serde(default)]
+    pub min_length: usize,
+    /// Require at least three of lower case letters, upper case letters, digits and other
+    /// characters
+    #[serde(default)]
+    pub require_mixed_classes: bool,
+    /// Passwords that are refused regardless of the other rules, compared ignoring case
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny_list: Vec<String>,
+}
+
+impl PasswordPolicy {
+    pub fn check(&self, password: &str) -> Result<(), WeakPassword> {
+        if self
+            .deny_list
+            .iter()
+            .any(|denied| denied.to_lowercase() == password.to_lowercase())
+        {
+            return Err(WeakPassword::Denied);
+        }
+        if password.chars().count() < self.min_length {
+            return Err(WeakPassword::TooShort {
+                min: self.min_length,
+            });
+        }
+        if self.require_mixed_classes {
+            let classes = [
+                password.chars().any(|c| c.is_lowercase()),
+                password.chars().any(|c| c.is_uppercase()),
+                password.chars().any(|c| c.is_numeric()),
+                password.chars().any(|c| !c.is_alphanumeric()),
+            ];
+            if classes.iter().filter(|present| **present).count() < 3 {
+                return Err(WeakPassword::NotMixed);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_usernames() {
+        let policy = UsernamePolicy::default();
+        for name in ["alice", "Bob42", "first.last", "jörg", "user@example.org"] {
+            assert_eq!(policy.check(name), Ok(()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn invalid_usernames() {
+        let policy = UsernamePolicy::default();
+        assert_eq!(policy.check(""), Err(InvalidUsername::Empty));
+        assert_eq!(
+            policy.check("evil\nINFO forged line"),
+            Err(InvalidUsername::InvalidChar('\n'))
+        );
+        assert_eq!(
+            policy.check(&"a".repeat(257)),
+            Err(InvalidUsername::TooLong { max: 256 })
+        );
+    }
+
+    #[test]
+    fn strict_policy() {
+        let policy = UsernamePolicy {
+            strict: true,
+            ..UsernamePolicy::default()
+        };
+        assert_eq!(policy.check("some_user-2"), Ok(()));
+        assert_eq!(
+            policy.check("with space"),
+            Err(InvalidUsername::InvalidChar(' '))
+        );
+        assert_eq!(policy.check("jörg"), Err(InvalidUsername::InvalidChar('ö')));
+    }
+
+    #[test]
+    fn policy_is_configurable() {
+        let policy = UsernamePolicy {
+            max_length: 4,
+            strict: true,
+            extra_chars: "@".to_string(),
+        };
+        assert_eq!(policy.check("a@b"), Ok(()));
+        assert_eq!(policy.check("a.b"), Err(InvalidUsername::InvalidChar('.')));
+        assert_eq!(
+            policy.check("abcde"),
+            Err(InvalidUsername::TooLong { max: 4 })
+        );
+    }
+
+    #[test]
+    fn password_policy() {
+        let policy = PasswordPolicy {
+            min_length: 8,
+            require_mixed_classes: true,
+            deny_list: vec!["Passw0rd!".to_string()],
+        };
+        assert_eq!(policy.check("correct-Horse"), Ok(()));
+        assert_eq!(
+            policy.check("Sh0rt"),
+            Err(WeakPassword::TooShort { min: 8 })
+        );
+        assert_eq!(policy.check("alllowercase"), Err(WeakPassword::NotMixed));
+        assert_eq!(policy.check("PASSW0RD!"), Err(WeakPassword::Denied));
+        assert_eq!(PasswordPolicy::default().check("secret"), Ok(()));
+    }
+}
@@ -0,0 +1,385 @@
+//! A small HTTP/1.1 client for webhooks, calendars and audit collectors
+//!
+//! Every request opens a new connection that is closed after the response, which is all these
+//! occasional requests need. Both `http://` and `https://` URLs are supported, the latter verified
+//! against the certificates of the system. Responses may be sent with a `Content-Length`, chunked
+//! or delimited by closing the connection, and are refused if their body is larger than allowed.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_io::Timer;
+use async_net::TcpStream;
+use futures_lite::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use futures_lite::{AsyncRead, AsyncWrite, FutureExt};
+use miette::Diagnostic;
+use once_cell::sync::Lazy;
+use rustls::{ClientConfig, RootCertStore, ServerName};
+use thiserror::Error;
+use url::Url;
+
+/// Time a request may take if not set otherwise
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Size of a response body allowed if not set otherwise
+const DEFAULT_MAX_BODY: u64 = 1024 * 1024;
+/// Size of the status line and headers of a response
+const MAX_HEAD: u64 = 64 * 1024;
+
+/// Certificates of the system, used to verify TLS servers
+pub static ROOT_CERTS: Lazy<RootCertStore> = Lazy::new(|| {
+    let span = tracing::info_span!("loading system certificates");
+    let _guard = span.enter();
+    let mut store = RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            let certs: Vec<Vec<u8>> = certs.into_iter().map(|c| c.0).collect();
+            let (loaded, ignored) = store.add_parsable_certificates(&certs[..]);
+            if ignored != 0 {
+                tracing::info!(loaded, ignored, "certificates loaded, some ignored");
+            } else {
+                tracing::info!(loaded, "certificates loaded");
+            }
+        }
+        Err(error) => {
+            tracing::error!(%error, "failed to load system certificates");
+        }
+    }
+    store
+});
+
+static TLS: Lazy<Arc<ClientConfig>> = Lazy::new(|| {
+    Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(ROOT_CERTS.clone())
+            .with_no_client_auth(),
+    )
+});
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("only http:// and https:// URLs with a host are supported, not {0}")]
+    #[diagnostic(code(bffh::http::url))]
+    UnsupportedUrl(Url),
+    #[error("request timed out")]
+    TimedOut,
+    #[error("server answered `{0}`")]
+    Status(String),
+    #[error("response body is larger than {0} bytes")]
+    TooLarge(u64),
+    #[error("malformed response: {0}")]
+    Malformed(&'static str),
+}
+
+/// Check that `url` is one requests can be sent to
+pub fn check_url(url: &Url) -> Result<(), Error> {
+    match url.scheme() {
+        "http" | "https" if url.host_str().is_some() => Ok(()),
+        _ => Err(Error::UnsupportedUrl(url.clone())),
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// A request to be sent with [`Request::send`]
+pub struct Request<'a> {
+    method: &'static str,
+    url: &'a Url,
+    headers: Vec<(&'static str, String)>,
+    body: &'a [u8],
+    timeout: Duration,
+    max_body: u64,
+}
+
+impl<'a> Request<'a> {
+    pub fn get(url: &'a Url) -> Self {
+        Self {
+            method: "GET",
+            url,
+            headers: Vec::new(),
+            body: &[],
+            timeout: DEFAULT_TIMEOUT,
+            max_body: DEFAULT_MAX_BODY,
+        }
+    }
+
+    pub fn post(url: &'a Url, content_type: &str, body: &'a [u8]) -> Self {
+        let mut request = Self::get(url);
+        request.method = "POST";
+        request.body = body;
+        request.header("Content-Type", content_type)
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    /// Send `token` as bearer token
+    pub fn bearer(self, token: &str) -> Self {
+        self.header("Authorization", format!("Bearer {}", token))
+    }
+
+    /// Give up on the request after `timeout`, including connecting and reading the response
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Refuse responses with a body larger than `max_body` bytes
+    pub fn max_body(mut self, max_body: u64) -> Self {
+        self.max_body = max_body;
+        self
+    }
+
+    /// Send the request, returning the body of the response if its status is 2xx
+    pub async fn send(self) -> Result<Vec<u8>, Error> {
+        let timeout = async {
+            Timer::after(self.timeout).await;
+            Err(Error::TimedOut)
+        };
+        self.exchange().or(timeout).await
+    }
+
+    async fn exchange(&self) -> Result<Vec<u8>, Error> {
+        check_url(self.url)?;
+        let host = self.url.host_str().unwrap_or_default();
+        let port = self.url.port_or_known_default().unwrap_or(80);
+        let tcp = TcpStream::connect((host, port)).await?;
+        let mut stream: Box<dyn Stream> = if self.url.scheme() == "https" {
+            let name = ServerName::try_from(host)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+            let connector = futures_rustls::TlsConnector::from(TLS.clone());
+            Box::new(connector.connect(name, tcp).await?)
+        } else {
+            Box::new(tcp)
+        };
+
+        let path = &self.url[url::Position::BeforePath..url::Position::AfterQuery];
+        let authority = &self.url[url::Position::BeforeHost..url::Position::AfterPort];
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\n",
+            self.method, path, authority
+        );
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if self.method != "GET" {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(self.body).await?;
+        stream.flush().await?;
+
+        let mut reader = BufReader::new(stream);
+        let response = read_head(&mut reader).await?;
+        if !response.status.starts_with('2') {
+            return Err(Error::Status(response.status_line));
+        }
+        if response.chunked {
+            read_chunked(&mut reader, self.max_body).await
+        } else {
+            let limit = match response.length {
+                Some(length) if length > self.max_body => {
+                    return Err(Error::TooLarge(self.max_body))
+                }
+                Some(length) => length,
+                None => self.max_body + 1,
+            };
+            let mut body = Vec::new();
+            (&mut reader).take(limit).read_to_end(&mut body).await?;
+            if body.len() as u64 > self.max_body {
+                return Err(Error::TooLarge(self.max_body));
+            }
+            Ok(body)
+        }
+    }
+}
+
+struct Head {
+    status_line: String,
+    status: String,
+    length: Option<u64>,
+    chunked: bool,
+}
+
+/// Read the status line and headers, skipping informational responses
+async fn read_head(reader: &mut (impl AsyncBufReadExt + Unpin)) -> Result<Head, Error> {
+    loop {
+        let mut lines = Vec::new();
+        let mut read = 0;
+        loop {
+            let mut line = String::new();
+            let n = reader.read_line(&mut line).await?;
+            read += n as u64;
+            if n == 0 {
+                return Err(Error::Malformed("incomplete response"));
+            } else if read > MAX_HEAD {
+                return Err(Error::Malformed("headers are too large"));
+            }
+            let line = line.trim_end().to_string();
+            if line.is_empty() {
+                break;
+            }
+            lines.push(line);
+        }
+
+        let mut lines = lines.into_iter();
+        let status_line = lines.next().unwrap_or_default();
+        let status = match status_line.split_whitespace().nth(1) {
+            Some(status) if status_line.starts_with("HTTP/1.") => status.to_string(),
+            _ => return Err(Error::Malformed("invalid status line")),
+        };
+        if status.starts_with('1') {
+            continue;
+        }
+
+        let mut head = Head {
+            status_line,
+            status,
+            length: None,
+            chunked: false,
+        };
+        for line in lines {
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+                None => return Err(Error::Malformed("invalid header")),
+            };
+            if name == "content-length" {
+                let length = value
+                    .parse()
+                    .map_err(|_| Error::Malformed("invalid content length"))?;
+                head.length = Some(length);
+            } else if name == "transfer-encoding" {
+                head.chunked = value.to_ascii_lowercase().contains("chunked");
+            }
+        }
+        return Ok(head);
+    }
+}
+
+/// Read a chunked body of at most `max_body` bytes
+async fn read_chunked(
+    reader: &mut (impl AsyncBufReadExt + Unpin),
+    max_body: u64,
+) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size =
+            u64::from_str_radix(size, 16).map_err(|_| Error::Malformed("invalid chunk size"))?;
+        if size == 0 {
+            // Trailers aren't of interest and the connection is closed anyway
+            return Ok(body);
+        }
+        if body.len() as u64 + size > max_body {
+            return Err(Error::TooLarge(max_body));
+        }
+        let start = body.len();
+        body.resize(start + size as usize, 0);
+        reader.read_exact(&mut body[start..]).await?;
+        let mut end = [0; 2];
+        reader.read_exact(&mut end).await?;
+        if &end != b"\r\n" {
+            return Err(Error::Malformed("chunk not terminated"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_net::TcpListener;
+
+    /// Answer a single request with `response`, returning the request
+    async fn serve(listener: TcpListener, response: &str) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(stream.clone());
+        let mut request = String::new();
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.trim().parse().unwrap();
+            }
+            request.push_str(&line);
+            if line == "\r\n" {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).await.unwrap();
+        request.push_str(&String::from_utf8(body).unwrap());
+        stream.write_all(response.as_bytes()).await.unwrap();
+        request
+    }
+
+    fn exchange(
+        response: &str,
+        request: impl FnOnce(&Url) -> Request<'_>,
+    ) -> (String, Result<Vec<u8>, Error>) {
+        async_io::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = Url::parse(&format!(
+                "http://{}/path?q=1",
+                listener.local_addr().unwrap()
+            ))
+            .unwrap();
+            let (received, result) =
+                futures_lite::future::zip(serve(listener, response), request(&url).send()).await;
+            (received, result)
+        })
+    }
+
+    #[test]
+    fn bodies_are_read_whatever_their_framing() {
+        let (request, body) = exchange(
+            "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n",
+            |url| Request::post(url, "application/json", b"{}").bearer("hunter2"),
+        );
+        assert!(request.starts_with("POST /path?q=1 HTTP/1.1\r\n"));
+        assert!(request.contains("Authorization: Bearer hunter2\r\n"));
+        assert!(request.ends_with("\r\n\r\n{}"));
+        assert_eq!(body.unwrap(), b"hello world");
+
+        let (_, body) = exchange(
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello and more",
+            |url| Request::get(url),
+        );
+        assert_eq!(body.unwrap(), b"hello");
+
+        let (_, body) = exchange("HTTP/1.1 204 No Content\r\n\r\n", |url| Request::get(url));
+        assert_eq!(body.unwrap(), b"");
+    }
+
+    #[test]
+    fn failures_are_reported() {
+        let (_, result) = exchange("HTTP/1.1 503 Unavailable\r\n\r\n", |url| Request::get(url));
+        assert!(
+            matches!(result, Err(Error::Status(status)) if status == "HTTP/1.1 503 Unavailable")
+        );
+
+        let (_, result) = exchange("HTTP/1.1 200 OK\r\n\r\nmore than four", |url| {
+            Request::get(url).max_body(4)
+        });
+        assert!(matches!(result, Err(Error::TooLarge(4))));
+
+        let (_, result) = exchange(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n9\r\nmore than\r\n0\r\n\r\n",
+            |url| Request::get(url).max_body(4),
+        );
+        assert!(matches!(result, Err(Error::TooLarge(4))));
+
+        let url = Url::parse("ftp://example.org/file").unwrap();
+        assert!(matches!(check_url(&url), Err(Error::UnsupportedUrl(_))));
+    }
+}
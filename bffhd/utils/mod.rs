@@ -10,3 +10,12 @@ pub mod l10nstring;
 pub mod uuid;
 
 pub mod linebuffer;
+
+/// Collapsing of repeated log messages
+pub mod ratelimit;
+
+/// Bounded formatting of logged payloads
+pub mod truncate;
+
+/// Client for the few HTTP requests bffh sends itself
+pub mod http;
@@ -187,6 +187,51 @@ impl ObjectIdentifier {
     pub const fn as_bytes(&self) -> &[u8] {
         &self.nodes
     }
+
+    /// Returns a new OID with `node` appended, e.g. `1.3.6.1.4.1.61783.612.2.4` from `...612.2`
+    pub fn child(&self, node: Node) -> Self {
+        let var: VarNode = node.into();
+        let mut vec = Vec::with_capacity(self.nodes.len() + var.as_bytes().len());
+        vec.extend_from_slice(&self.nodes);
+        vec.extend_from_slice(var.as_bytes());
+        Self {
+            nodes: vec.into_boxed_slice(),
+        }
+    }
+
+    /// Whether `other` is this OID or lies below it, e.g. `1.3.6.1` is a prefix of `1.3.6.1.4.1`
+    pub fn is_prefix_of(&self, other: &ObjectIdentifier) -> bool {
+        other.strip_prefix(self).is_some()
+    }
+
+    /// The encoded nodes following `prefix`, or `None` if this OID doesn't start with `prefix`
+    ///
+    /// Comparing the encoded bytes never matches part of a node: the last byte of every node has
+    /// the high bit clear and all others have it set, so one node can't be a prefix of another.
+    /// A `prefix` that itself ends in the middle of a node never matches.
+    pub fn strip_prefix(&self, prefix: &ObjectIdentifier) -> Option<&[u8]> {
+        match prefix.nodes.last() {
+            Some(last) if last & 0x80 == 0 => self.nodes.strip_prefix(&prefix.nodes[..]),
+            _ => None,
+        }
+    }
+
+    /// Whether this OID lies exactly one node below `parent`
+    pub fn is_child_of(&self, parent: &ObjectIdentifier) -> bool {
+        // A single node, i.e. only the last byte has the high bit clear
+        self.strip_prefix(parent).map_or(false, |rest| {
+            rest.last().map_or(false, |last| last & 0x80 == 0)
+                && rest.iter().filter(|byte| *byte & 0x80 == 0).count() == 1
+        })
+    }
+
+    /// The OIDs among `oids` lying exactly one node below this one, in the order given
+    pub fn children_in<'a>(
+        &'a self,
+        oids: impl IntoIterator<Item = &'a ObjectIdentifier> + 'a,
+    ) -> impl Iterator<Item = &'a ObjectIdentifier> + 'a {
+        oids.into_iter().filter(move |oid| oid.is_child_of(self))
+    }
 }
 
 impl Deref for ObjectIdentifier {
@@ -497,18 +542,50 @@ mod serde_support {
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
     use std::convert::TryInto;
 
-    pub(crate) fn gen_random() -> ObjectIdentifier {
-        let amt: u8 = rand::random::<u8>() % 10 + 1;
+    pub(crate) fn gen_random(rng: &mut impl Rng) -> ObjectIdentifier {
+        let amt: u8 = rng.gen::<u8>() % 10 + 1;
         let mut children = Vec::new();
         for _ in 0..amt {
-            children.push(rand::random());
+            children.push(rng.gen());
         }
 
         ObjectIdentifier::build(ObjectIdentifierRoot::JointIsoItuT, 25, children).unwrap()
     }
 
+    /// RNG for randomized tests, seeded from `BFFH_TEST_SEED` if set to replay a failed run
+    ///
+    /// The seed is returned so tests can include it in their assertion messages.
+    pub(crate) fn seeded_rng() -> (u64, StdRng) {
+        let seed = std::env::var("BFFH_TEST_SEED")
+            .ok()
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or_else(rand::random);
+        (seed, StdRng::seed_from_u64(seed))
+    }
+
+    #[test]
+    fn random_oids_roundtrip() {
+        let (seed, mut rng) = seeded_rng();
+        for _ in 0..100 {
+            let oid = gen_random(&mut rng);
+
+            let parsed = ObjectIdentifier::from_str(&oid.to_string()).ok();
+            assert_eq!(parsed.as_ref(), Some(&oid), "BFFH_TEST_SEED={}", seed);
+
+            let bytes: Vec<u8> = oid.clone().into();
+            let decoded: Option<ObjectIdentifier> = bytes.try_into().ok();
+            assert_eq!(decoded.as_ref(), Some(&oid), "BFFH_TEST_SEED={}", seed);
+
+            let archived = rkyv::to_bytes::<_, 256>(&oid).unwrap();
+            let archived = unsafe { rkyv::archived_root::<ObjectIdentifier>(&archived) };
+            assert_eq!(&archived[..], oid.as_bytes(), "BFFH_TEST_SEED={}", seed);
+        }
+    }
+
     #[test]
     fn encode_binary_root_node_0() {
         let expected: Vec<u8> = vec![0];
@@ -842,6 +919,27 @@ pub(crate) mod tests {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn child_appends_node() {
+        let parent = ObjectIdentifier::build(ObjectIdentifierRoot::Iso, 3, vec![6, 1]).unwrap();
+
+        let expected =
+            ObjectIdentifier::build(ObjectIdentifierRoot::Iso, 3, vec![6, 1, 4]).unwrap();
+        assert_eq!(parent.child(4), expected);
+        assert_eq!(parent.child(4).as_bytes(), &[0x2B, 0x06, 0x01, 0x04]);
+
+        let expected =
+            ObjectIdentifier::build(ObjectIdentifierRoot::Iso, 3, vec![6, 1, 61783, 2147483647])
+                .unwrap();
+        let actual = parent.child(61783).child(2147483647);
+        assert_eq!(actual, expected);
+        assert_eq!(
+            actual.as_bytes(),
+            &[0x2B, 0x06, 0x01, 0x83, 0xE2, 0x57, 0x87, 0xFF, 0xFF, 0xFF, 0x7F]
+        );
+        assert_eq!(actual.to_string(), "1.3.6.1.61783.2147483647");
+    }
+
     #[test]
     fn encode_to_bytes() {
         let expected = vec![0x2A, 0x03, 0x04];
@@ -850,4 +948,100 @@ pub(crate) mod tests {
             .into();
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn prefix_matches_whole_nodes() {
+        let parent = ObjectIdentifier::build(ObjectIdentifierRoot::Iso, 3, vec![6, 1]).unwrap();
+        let child = parent.child(61783).child(2147483647);
+        assert!(parent.is_prefix_of(&child));
+        assert!(parent.is_prefix_of(&parent));
+        assert!(!child.is_prefix_of(&parent));
+        assert_eq!(
+            child.strip_prefix(&parent),
+            Some(&[0x83, 0xE2, 0x57, 0x87, 0xFF, 0xFF, 0xFF, 0x7F][..])
+        );
+        assert_eq!(parent.strip_prefix(&parent), Some(&[][..]));
+
+        // `.2` is not a prefix of `.2501`, `.42` only of itself
+        let example = ObjectIdentifier::build(
+            ObjectIdentifierRoot::JointIsoItuT,
+            39,
+            vec![42, 2501, 65535, 2147483647, 1235, 2352],
+        )
+        .unwrap();
+        let two =
+            ObjectIdentifier::build(ObjectIdentifierRoot::JointIsoItuT, 39, vec![42, 2]).unwrap();
+        assert!(!two.is_prefix_of(&example));
+        let head = ObjectIdentifier::build(ObjectIdentifierRoot::JointIsoItuT, 39, vec![42, 2501])
+            .unwrap();
+        assert_eq!(
+            example.strip_prefix(&head),
+            Some(&[0x83, 0xFF, 0x7F, 0x87, 0xFF, 0xFF, 0xFF, 0x7F, 0x89, 0x53, 0x92, 0x30][..])
+        );
+    }
+
+    #[test]
+    fn children_are_one_whole_node_below() {
+        let parent =
+            ObjectIdentifier::build(ObjectIdentifierRoot::JointIsoItuT, 39, vec![42]).unwrap();
+        let small = parent.child(2);
+        let large = parent.child(2147483647);
+        let grandchild = large.child(1235);
+        let other =
+            ObjectIdentifier::build(ObjectIdentifierRoot::JointIsoItuT, 39, vec![43, 1]).unwrap();
+        let oids = [
+            small.clone(),
+            grandchild.clone(),
+            parent.clone(),
+            other,
+            large.clone(),
+        ];
+
+        let children: Vec<_> = parent.children_in(&oids).collect();
+        assert_eq!(children, [&small, &large]);
+        assert!(grandchild.is_child_of(&large));
+        assert!(!grandchild.is_child_of(&parent));
+        assert!(!parent.is_child_of(&parent));
+
+        // Children of a large node aren't confused with the bytes of the node itself
+        let big: ObjectIdentifier = "2.25.190754093376743485973207716749546715206"
+            .try_into()
+            .unwrap();
+        let root =
+            ObjectIdentifier::build(ObjectIdentifierRoot::JointIsoItuT, 25, Vec::<Node>::new())
+                .unwrap();
+        assert!(big.is_child_of(&root));
+        let partial = ObjectIdentifier::new_unchecked(big[..3].into());
+        assert!(!big.child(1).is_child_of(&partial));
+        assert_eq!(
+            root.children_in([&big, &big.child(7)]).collect::<Vec<_>>(),
+            [&big]
+        );
+    }
+
+    #[test]
+    fn prefix_never_splits_large_nodes() {
+        let full: ObjectIdentifier = "2.25.190754093376743485973207716749546715206.\
+                                      255822649272987943607843257596365752308"
+            .try_into()
+            .unwrap();
+        let first: ObjectIdentifier = "2.25.190754093376743485973207716749546715206"
+            .try_into()
+            .unwrap();
+        assert!(first.is_prefix_of(&full));
+        let rest = full.strip_prefix(&first).unwrap();
+        let second = ObjectIdentifier::build(
+            ObjectIdentifierRoot::JointIsoItuT,
+            25,
+            vec![255822649272987943607843257596365752308],
+        )
+        .unwrap();
+        assert_eq!(rest, second.child_nodes());
+
+        // Every byte boundary within the first large node, none of them a node boundary
+        for end in 2..first.len() {
+            let partial = ObjectIdentifier::new_unchecked(first[..end].into());
+            assert!(!partial.is_prefix_of(&full), "matched {} bytes", end);
+        }
+    }
 }
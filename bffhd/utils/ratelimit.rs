@@ -0,0 +1,190 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Time identical messages are collapsed for before a summary is logged
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What to do with a single occurrence of a message
+pub enum Occurrence {
+    /// Log it as usual
+    Log,
+    /// Drop it, the same message was logged recently
+    Suppress,
+    /// Log it, mentioning how often it happened since the last time it was logged
+    Summary { count: usize, period: Duration },
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    suppressed: usize,
+}
+
+#[derive(Debug)]
+/// Rate limit collapsing repeated identical log messages into periodic summaries
+///
+/// The first occurrence of a message is logged right away. Further occurrences within `interval`
+/// are only counted, and the first one after that is logged together with the count. Messages
+/// are told apart by a key, e.g. the formatted error.
+pub struct LogLimiter {
+    interval: Duration,
+    windows: HashMap<String, Window>,
+}
+
+impl LogLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            windows: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, key: &str) -> Occurrence {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&mut self, key: &str, now: Instant) -> Occurrence {
+        let interval = self.interval;
+        // Forget messages that stopped happening so the map doesn't grow without bounds
+        self.windows.retain(|_, window| {
+            window.suppressed > 0 || now.duration_since(window.start) < interval
+        });
+
+        let window = match self.windows.entry(key.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(Window {
+                    start: now,
+                    suppressed: 0,
+                });
+                return Occurrence::Log;
+            }
+        };
+
+        let period = now.duration_since(window.start);
+        if period < interval {
+            window.suppressed += 1;
+            Occurrence::Suppress
+        } else {
+            // Windows without suppressed messages were removed above, so there is something to sum up
+            let count = std::mem::take(&mut window.suppressed) + 1;
+            window.start = now;
+            Occurrence::Summary { count, period }
+        }
+    }
+
+    /// Take the summaries of all messages suppressed since they were last logged
+    fn flush_at(&mut self, now: Instant) -> Vec<(String, Occurrence)> {
+        self.windows
+            .drain()
+            .filter(|(_, window)| window.suppressed > 0)
+            .map(|(key, window)| {
+                let count = window.suppressed;
+                let period = now.duration_since(window.start);
+                (key, Occurrence::Summary { count, period })
+            })
+            .collect()
+    }
+}
+
+impl Drop for LogLimiter {
+    /// Log what was suppressed in the last window, it would be lost otherwise
+    fn drop(&mut self) {
+        for (key, occurrence) in self.flush_at(Instant::now()) {
+            if let Occurrence::Summary { count, period } = occurrence {
+                tracing::warn!(occurrences = count, seconds = period.as_secs(), "{}", key);
+            }
+        }
+    }
+}
+
+impl Default for LogLimiter {
+    fn default() -> Self {
+        Self::new(DEFAULT_INTERVAL)
+    }
+}
+
+#[macro_export]
+/// Log an event through a [`LogLimiter`](crate::utils::ratelimit::LogLimiter)
+///
+/// Takes the limiter, the key identifying the message, the level and then the arguments of the
+/// matching `tracing` macro, e.g. `log_limited!(limiter, &key, error, ?error, "publish failed")`.
+/// Summaries carry the number of `occurrences` over the last `seconds` as additional fields.
+macro_rules! log_limited {
+    ($limiter:expr, $key:expr, $level:ident, $($arg:tt)+) => {
+        match $limiter.check($key) {
+            $crate::utils::ratelimit::Occurrence::Log => tracing::$level!($($arg)+),
+            $crate::utils::ratelimit::Occurrence::Summary { count, period } => {
+                tracing::$level!(occurrences = count, seconds = period.as_secs(), $($arg)+)
+            }
+            $crate::utils::ratelimit::Occurrence::Suppress => {}
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identical_errors_are_collapsed() {
+        let mut limiter = LogLimiter::new(Duration::from_secs(10));
+        let start = Instant::now();
+
+        // One error every 10ms for a minute
+        let logged: Vec<_> = (0..6000)
+            .map(|i| limiter.check_at("broker down", start + Duration::from_millis(i * 10)))
+            .filter(|occurrence| *occurrence != Occurrence::Suppress)
+            .collect();
+
+        assert!(logged.len() <= 7, "{} lines logged", logged.len());
+        assert_eq!(logged[0], Occurrence::Log);
+        assert_eq!(
+            logged[1],
+            Occurrence::Summary {
+                count: 1000,
+                period: Duration::from_secs(10)
+            }
+        );
+    }
+
+    #[test]
+    fn different_errors_are_limited_separately() {
+        let mut limiter = LogLimiter::new(Duration::from_secs(10));
+        let now = Instant::now();
+        assert_eq!(limiter.check_at("timeout", now), Occurrence::Log);
+        assert_eq!(limiter.check_at("refused", now), Occurrence::Log);
+        assert_eq!(limiter.check_at("timeout", now), Occurrence::Suppress);
+
+        // A message that stopped repeating is logged as usual again
+        let later = now + Duration::from_secs(30);
+        assert_eq!(limiter.check_at("refused", later), Occurrence::Log);
+    }
+
+    #[test]
+    fn suppressed_messages_are_flushed() {
+        let mut limiter = LogLimiter::new(Duration::from_secs(10));
+        let now = Instant::now();
+        assert_eq!(limiter.check_at("timeout", now), Occurrence::Log);
+        assert_eq!(limiter.check_at("refused", now), Occurrence::Log);
+        for i in 1..=3 {
+            let at = now + Duration::from_secs(i);
+            assert_eq!(limiter.check_at("timeout", at), Occurrence::Suppress);
+        }
+
+        let flushed = limiter.flush_at(now + Duration::from_secs(5));
+        assert_eq!(
+            flushed,
+            [(
+                "timeout".to_string(),
+                Occurrence::Summary {
+                    count: 3,
+                    period: Duration::from_secs(5)
+                }
+            )]
+        );
+        assert!(limiter.flush_at(now + Duration::from_secs(6)).is_empty());
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Characters of a payload logged unless configured otherwise
+pub const DEFAULT_MAX_PAYLOAD_LOG: usize = 128;
+
+static MAX_PAYLOAD_LOG: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_PAYLOAD_LOG);
+
+/// Set how many characters of a payload wrapped in [`Truncated`] are logged
+pub fn set_max_payload_log(max: usize) {
+    MAX_PAYLOAD_LOG.store(max, Ordering::Relaxed);
+}
+
+/// Formats the wrapped value cut off after the configured number of characters
+///
+/// Meant for logging data received from clients or cards, e.g. APDUs, which can be large and
+/// shouldn't end up in the logs in full. Cut off output ends in `…` and the number of characters
+/// left out.
+pub struct Truncated<T>(pub T);
+
+impl<T> Truncated<T> {
+    fn write(
+        f: &mut fmt::Formatter<'_>,
+        format: impl FnOnce(&mut Bounded) -> fmt::Result,
+    ) -> fmt::Result {
+        let mut bounded = Bounded {
+            out: String::new(),
+            left: MAX_PAYLOAD_LOG.load(Ordering::Relaxed),
+            skipped: 0,
+        };
+        format(&mut bounded)?;
+        f.write_str(&bounded.out)?;
+        if bounded.skipped > 0 {
+            write!(f, "…({} more)", bounded.skipped)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Truncated<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Self::write(f, |bounded| {
+            fmt::write(bounded, format_args!("{:?}", self.0))
+        })
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Truncated<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Self::write(f, |bounded| fmt::write(bounded, format_args!("{}", self.0)))
+    }
+}
+
+/// Keeps the first `left` characters written to it and counts the rest
+struct Bounded {
+    out: String,
+    left: usize,
+    skipped: usize,
+}
+
+impl fmt::Write for Bounded {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut chars = s.chars();
+        for c in chars.by_ref().take(self.left) {
+            self.out.push(c);
+            self.left -= 1;
+        }
+        self.skipped += chars.count();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_payloads_are_cut_off() {
+        let payload = "ä".repeat(DEFAULT_MAX_PAYLOAD_LOG + 10);
+        assert_eq!(
+            format!("{}", Truncated(&payload)),
+            format!("{}…(10 more)", "ä".repeat(DEFAULT_MAX_PAYLOAD_LOG))
+        );
+        assert_eq!(format!("{:?}", Truncated([1u8, 2, 3])), "[1, 2, 3]");
+    }
+}
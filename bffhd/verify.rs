@@ -0,0 +1,124 @@
+//! Deep check that every value stored in the databases can still be read
+//!
+//! Unlike normal operation, which trusts the stored bytes, every entry is validated before it
+//! would be accessed. This finds corruption e.g. after an upgrade changed a stored type.
+
+use std::fmt;
+use std::path::Path;
+
+use bytecheck::CheckBytes;
+use lmdb::{Environment, EnvironmentFlags, Transaction};
+use rkyv::Archive;
+
+use crate::db;
+use crate::db::{ArchivedValue, RawDB};
+use crate::resources::state::State;
+use crate::users::db::User;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A stored value that failed validation
+pub struct Corrupt {
+    pub db: &'static str,
+    pub key: String,
+}
+
+impl fmt::Display for Corrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} entry '{}' is corrupt", self.db, self.key)
+    }
+}
+
+/// Validate all users and machine states in the database at `path`
+///
+/// The database is opened read-only so this can't make things worse. Returns the entries that
+/// failed validation.
+pub fn verify_db(path: impl AsRef<Path>) -> Result<Vec<Corrupt>, db::Error> {
+    let env = Environment::new()
+        .set_flags(
+            EnvironmentFlags::READ_ONLY
+                | EnvironmentFlags::NO_SUB_DIR
+                | EnvironmentFlags::NO_TLS
+                | EnvironmentFlags::NO_READAHEAD,
+        )
+        .set_max_dbs(8)
+        .open(path.as_ref())?;
+
+    let mut corrupt = Vec::new();
+    corrupt.extend(verify::<State>(&env, "state")?);
+    corrupt.extend(verify::<User>(&env, "user")?);
+    Ok(corrupt)
+}
+
+fn verify<T>(env: &Environment, name: &'static str) -> Result<Vec<Corrupt>, db::Error>
+where
+    T: Archive,
+    T::Archived: for<'a> CheckBytes<rkyv::validation::validators::DefaultValidator<'a>>,
+{
+    let db = match RawDB::open(env, Some(name)) {
+        Ok(db) => db,
+        // Nothing was ever stored
+        Err(lmdb::Error::NotFound) => return Ok(Vec::new()),
+        Err(error) => return Err(error.into()),
+    };
+
+    let txn = env.begin_ro_txn()?;
+    let mut cursor = db.open_ro_cursor(&txn)?;
+    let mut corrupt = Vec::new();
+    let mut checked = 0;
+    for entry in db.iter(&mut cursor) {
+        let (key, value) = entry?;
+        // Copied since values in the db don't have to be aligned the way rkyv needs them
+        let value = ArchivedValue::<T>::build(value);
+        if rkyv::check_archived_root::<T>(value.as_slice()).is_err() {
+            corrupt.push(Corrupt {
+                db: name,
+                key: String::from_utf8_lossy(key).into_owned(),
+            });
+        }
+        checked += 1;
+    }
+    tracing::info!(db = name, checked, corrupt = corrupt.len(), "verified db");
+    Ok(corrupt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::modules::fabaccess::MachineState;
+    use crate::resources::state::db::StateDB;
+    use crate::users::db::UserDB;
+    use rkyv::ser::serializers::AllocSerializer;
+    use rkyv::ser::Serializer;
+
+    #[test]
+    fn corrupt_entries_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        {
+            let env = StateDB::open_env(&path).unwrap();
+            let states = StateDB::create_with_env(env.clone()).unwrap();
+            let mut serializer = AllocSerializer::<1024>::default();
+            serializer
+                .serialize_value(&MachineState::free(None).to_state())
+                .unwrap();
+            let good = ArchivedValue::new(serializer.into_serializer().into_inner());
+            states.put(&"Good", &good).unwrap();
+            states
+                .put(&"Broken", &ArchivedValue::build(&[0xff; 64]))
+                .unwrap();
+
+            let users = unsafe { UserDB::create(env) }.unwrap();
+            users
+                .put("alice", &User::new_with_plain_pw("alice", "secret"))
+                .unwrap();
+        }
+
+        assert_eq!(
+            verify_db(&path).unwrap(),
+            vec![Corrupt {
+                db: "state",
+                key: "Broken".to_string(),
+            }]
+        );
+    }
+}
@@ -1,11 +1,22 @@
 use clap::{Arg, Command, ValueHint};
-use difluoroborane::{config, Difluoroborane};
+use difluoroborane::users::invites::DEFAULT_VALIDITY;
+use difluoroborane::resources::state::value;
+use difluoroborane::db::Dump;
+use difluoroborane::stress::Stress;
+use difluoroborane::users::UserEdit;
+use difluoroborane::{config, verify, Difluoroborane};
+use miette::IntoDiagnostic;
 
+use std::io::IsTerminal;
 use std::str::FromStr;
-use std::{env, io, io::Write, path::PathBuf};
+use std::time::Duration;
+use std::{env, io, io::Write, path::Path, path::PathBuf};
 
 use nix::NixPath;
 
+/// Environment variable `--set-password` takes the password from
+const PASSWORD_VAR: &str = "BFFH_PASSWORD";
+
 fn main() -> miette::Result<()> {
     // Argument parsing
     // values for the name, description and version are pulled from `Cargo.toml`.
@@ -16,7 +27,7 @@ fn main() -> miette::Result<()> {
             \t[{build_kind} build built on {build_time}]\n\
             \t  {rustc_version}\n\t  {cargo_version}",
             version=difluoroborane::env::PKG_VERSION,
-            apiver="0.3",
+            apiver=difluoroborane::capnp::version::API_VERSION,
             rustc_version=difluoroborane::env::RUST_VERSION,
             cargo_version=difluoroborane::env::CARGO_VERSION,
             build_time=difluoroborane::env::BUILD_TIME_3339,
@@ -52,14 +63,26 @@ fn main() -> miette::Result<()> {
             Arg::new("print default")
                 .help("Print a default config to stdout instead of running")
                 .long("print-default"))
+        .arg(
+            Arg::new("print value types")
+                .help("Print the catalog of known state value types as JSON instead of running")
+                .long("print-value-types"))
         .arg(
             Arg::new("check config")
                 .help("Check config for validity")
                 .long("check"))
+        .arg(
+            Arg::new("verify-db")
+                .help("Check that every user and machine state stored in the database can be read")
+                .long("verify-db"))
         .arg(
             Arg::new("dump")
-                .help("Dump all internal databases")
+                .help("Dump all users and machine states to the given file as TOML")
                 .long("dump")
+                .takes_value(true)
+                .value_name("FILE")
+                .value_hint(ValueHint::AnyPath)
+                .default_missing_value("bffh-dump.toml")
                 .conflicts_with("load"))
         .arg(
             Arg::new("dump-users")
@@ -77,10 +100,71 @@ fn main() -> miette::Result<()> {
         )
         .arg(
             Arg::new("load")
-                .help("Load values into the internal databases")
+                .help("Load a dump made with `--dump`, or a users file made with `--dump-users`, into the internal databases")
                 .long("load")
                 .takes_value(true)
                 .conflicts_with("dump"))
+        .arg(
+            Arg::new("add-user")
+                .help("Add the user NAME, or merge the changes into an existing one with `--force`")
+                .long("add-user")
+                .value_name("NAME")
+                .takes_value(true)
+                .conflicts_with_all(&["dump", "dump-users", "load"]))
+        .arg(
+            Arg::new("set-password")
+                .help("Set the password of the user given with `--add-user`, read from $BFFH_PASSWORD, a prompt or the first line of stdin")
+                .long("set-password")
+                .requires("add-user"))
+        .arg(
+            Arg::new("add-role")
+                .help("Give the user given with `--add-user` ROLE, can be repeated")
+                .long("add-role")
+                .value_name("ROLE")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .requires("add-user"))
+        .arg(
+            Arg::new("remove-role")
+                .help("Take ROLE from the user given with `--add-user`, can be repeated")
+                .long("remove-role")
+                .value_name("ROLE")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .requires("add-user"))
+        .arg(
+            Arg::new("issue-invite")
+                .help("Issue an invite token for self-registration, optionally granting ROLE")
+                .long("issue-invite")
+                .value_name("ROLE")
+                .takes_value(true)
+                .max_values(1)
+                .min_values(0)
+                .default_missing_value(""))
+        .arg(
+            Arg::new("stress")
+                .help("Measure the executor by running TASKS synthetic tasks instead of the server")
+                .long("stress")
+                .value_name("TASKS")
+                .takes_value(true)
+                .max_values(1)
+                .min_values(0)
+                .default_missing_value("10000")
+                .hide(true))
+        .arg(
+            Arg::new("stress-poll-us")
+                .help("How many microseconds every poll of a `--stress` task keeps the worker busy")
+                .long("stress-poll-us")
+                .takes_value(true)
+                .requires("stress")
+                .hide(true))
+        .arg(
+            Arg::new("stress-polls")
+                .help("How often every `--stress` task is polled before it finishes")
+                .long("stress-polls")
+                .takes_value(true)
+                .requires("stress")
+                .hide(true))
         .arg(Arg::new("keylog")
             .help("log TLS keys into PATH. If no path is specified the value of the envvar SSLKEYLOGFILE is used.")
             .long("tls-key-log")
@@ -114,6 +198,27 @@ fn main() -> miette::Result<()> {
 
         // Early return to exit.
         return Ok(());
+    } else if matches.is_present("print value types") {
+        let catalog = value::value_types();
+        let encoded = serde_json::to_string_pretty(&catalog).into_diagnostic()?;
+        println!("{}", encoded);
+        return Ok(());
+    } else if matches.is_present("stress") {
+        let defaults = Stress::default();
+        let stress = Stress {
+            tasks: matches.value_of_t("stress").into_diagnostic()?,
+            poll: match matches.value_of("stress-poll-us") {
+                Some(us) => Duration::from_micros(us.parse().into_diagnostic()?),
+                None => defaults.poll,
+            },
+            polls: match matches.value_of("stress-polls") {
+                Some(polls) => polls.parse().into_diagnostic()?,
+                None => defaults.polls,
+            },
+        };
+        let report = stress.run(&executor::pool::Executor::new());
+        println!("{}", report);
+        return Ok(());
     } else if matches.is_present("check config") {
         match config::read(&PathBuf::from_str(configpath).unwrap()) {
             Ok(c) => {
@@ -129,7 +234,7 @@ fn main() -> miette::Result<()> {
                 return Ok(());
             }
             Err(e) => {
-                eprintln!("{}", e);
+                eprintln!("{:?}", miette::Report::new(e));
                 std::process::exit(-1);
             }
         }
@@ -137,8 +242,37 @@ fn main() -> miette::Result<()> {
 
     let mut config = config::read(&PathBuf::from_str(configpath).unwrap())?;
 
-    if matches.is_present("dump") {
-        return Err(miette::miette!("DB Dumping is currently not implemented, except for the users db, using `--dump-users`"));
+    if matches.is_present("verify-db") {
+        // Doesn't start bffh so the database is only ever opened read-only
+        let corrupt = verify::verify_db(&config.db_path)?;
+        for entry in corrupt.iter() {
+            eprintln!("{}", entry);
+        }
+        if !corrupt.is_empty() {
+            return Err(miette::miette!("found {} corrupt entries", corrupt.len()));
+        }
+        println!("all entries in {} are readable", config.db_path.display());
+        return Ok(());
+    } else if matches.is_present("dump") {
+        let path = matches.value_of("dump").unwrap();
+        if Path::new(path).exists() && !matches.is_present("force") {
+            return Err(miette::miette!(
+                "{} already exists, add `--force` to overwrite it",
+                path
+            ));
+        }
+        let bffh = Difluoroborane::new(config)?;
+
+        let dump = Dump::new(&bffh.users, &bffh.statedb)?;
+        dump.write_file(path)?;
+
+        tracing::info!(
+            users = dump.users.len(),
+            states = dump.states.len(),
+            "successfully dumped databases"
+        );
+
+        return Ok(());
     } else if matches.is_present("dump-users") {
         let bffh = Difluoroborane::new(config)?;
 
@@ -151,11 +285,73 @@ fn main() -> miette::Result<()> {
 
         return Ok(());
     } else if matches.is_present("load") {
+        let unknown_oids = config.unknown_oids;
         let bffh = Difluoroborane::new(config)?;
+        let path = matches.value_of("load").unwrap();
 
-        bffh.users.load_file(matches.value_of("load").unwrap())?;
+        // Full dumps have nothing but a `users` and a `states` table, anything else is a users file
+        let dump = std::fs::read(path)
+            .ok()
+            .and_then(|bytes| Dump::decode(&bytes).ok());
+        match dump {
+            Some(dump) => {
+                dump.restore(&bffh.users, &bffh.statedb, unknown_oids)?;
+                tracing::info!("loaded users and machine states from {}", path);
+            }
+            None => {
+                bffh.users.load_file(path)?;
+                tracing::info!("loaded users from {}", path);
+            }
+        }
 
-        tracing::info!("loaded users from {}", matches.value_of("load").unwrap());
+        return Ok(());
+    } else if matches.is_present("add-user") {
+        let uid = matches.value_of("add-user").unwrap();
+        let roles = |arg: &str| -> Vec<String> {
+            matches
+                .values_of(arg)
+                .map(|roles| roles.map(str::to_string).collect())
+                .unwrap_or_default()
+        };
+        let password = if matches.is_present("set-password") {
+            Some(read_password(uid)?)
+        } else {
+            None
+        };
+        let edit = UserEdit {
+            password,
+            add_roles: roles("add-role"),
+            remove_roles: roles("remove-role"),
+        };
+        let unknown: Vec<&String> = edit
+            .add_roles
+            .iter()
+            .filter(|role| !config.roles.contains_key(*role))
+            .collect();
+
+        // Logging is only set up by `Difluoroborane::new`
+        let bffh = Difluoroborane::new(config)?;
+        for role in unknown {
+            tracing::warn!(%role, "role is not defined in the config");
+        }
+        let user = bffh
+            .users
+            .add_user(uid, &edit, matches.is_present("force"))?;
+        tracing::info!(uid, roles = ?user.userdata.roles, "successfully stored user");
+
+        return Ok(());
+    } else if matches.is_present("issue-invite") {
+        let bffh = Difluoroborane::new(config)?;
+
+        let invites = bffh.invites.ok_or_else(|| {
+            miette::miette!("Self-registration is disabled, set `self_registration = True` in the config")
+        })?;
+        let role = match matches.value_of("issue-invite") {
+            Some("") | None => None,
+            Some(role) => Some(role.to_string()),
+        };
+        let token = invites.issue(role, DEFAULT_VALIDITY)?;
+        println!("{}", token);
 
         return Ok(());
     } else {
@@ -179,9 +375,30 @@ fn main() -> miette::Result<()> {
         }
         config.logging.format = matches.value_of("log format").unwrap_or("full").to_string();
 
-        let mut bffh = Difluoroborane::new(config)?;
+        // Resolved before bffhd changes its working directory so SIGHUP can still find it
+        let configpath = PathBuf::from_str(configpath).unwrap();
+        let configpath = configpath.canonicalize().unwrap_or(configpath);
+        let mut bffh = Difluoroborane::new(config)?.with_config_path(configpath);
         bffh.run()?;
     }
 
     Ok(())
 }
+
+/// The new password for `uid`, kept off the command line where other users could read it
+fn read_password(uid: &str) -> miette::Result<String> {
+    if let Ok(password) = env::var(PASSWORD_VAR) {
+        return Ok(password);
+    }
+    let password = if io::stdin().is_terminal() {
+        rpassword::prompt_password(format!("New password for {}: ", uid)).into_diagnostic()?
+    } else {
+        let mut line = String::new();
+        io::stdin().read_line(&mut line).into_diagnostic()?;
+        line.trim_end_matches(&['\r', '\n'][..]).to_string()
+    };
+    if password.is_empty() {
+        return Err(miette::miette!("no password given for `--set-password`"));
+    }
+    Ok(password)
+}
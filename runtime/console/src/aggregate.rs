@@ -4,7 +4,8 @@ use crate::stats::{TimeAnchor, Unsent};
 use crate::{server, stats};
 use crate::{Event, Shared};
 use console_api::{async_ops, instrument, resources, tasks};
-use crossbeam_channel::{Receiver, TryRecvError};
+use crate::buffer::EventBuffer;
+use crossbeam_channel::TryRecvError;
 use futures_util::{FutureExt, StreamExt};
 use std::collections::HashMap;
 use std::num::NonZeroU64;
@@ -131,7 +132,7 @@ pub(crate) enum Include {
 #[derive(Debug)]
 pub struct Aggregator {
     shared: Arc<Shared>,
-    events: Receiver<Event>,
+    events: Arc<EventBuffer<Event>>,
     rpcs: async_channel::Receiver<server::Command>,
     watchers: Vec<Watch<instrument::Update>>,
     details_watchers: HashMap<span::Id, Vec<Watch<tasks::TaskDetails>>>,
@@ -152,7 +153,7 @@ pub struct Aggregator {
 impl Aggregator {
     pub(crate) fn new(
         shared: Arc<Shared>,
-        events: Receiver<Event>,
+        events: Arc<EventBuffer<Event>>,
         rpcs: async_channel::Receiver<server::Command>,
     ) -> Self {
         Self {
@@ -238,7 +239,7 @@ impl Aggregator {
         tasks::TaskUpdate {
             new_tasks: self.tasks.as_proto_list(include, &self.base_time),
             stats_update: self.task_stats.as_proto(include, &self.base_time),
-            dropped_events: self.shared.dropped_tasks.swap(0, Ordering::AcqRel) as u64,
+            dropped_events: self.shared.dropped_tasks.take() as u64,
         }
     }
 
@@ -251,7 +252,7 @@ impl Aggregator {
             new_resources: self.resources.as_proto_list(include, &self.base_time),
             stats_update: self.resource_stats.as_proto(include, &self.base_time),
             new_poll_ops,
-            dropped_events: self.shared.dropped_resources.swap(0, Ordering::AcqRel) as u64,
+            dropped_events: self.shared.dropped_resources.take() as u64,
         }
     }
 
@@ -259,7 +260,7 @@ impl Aggregator {
         async_ops::AsyncOpUpdate {
             new_async_ops: self.async_ops.as_proto_list(include, &self.base_time),
             stats_update: self.async_op_stats.as_proto(include, &self.base_time),
-            dropped_events: self.shared.dropped_async_ops.swap(0, Ordering::AcqRel) as u64,
+            dropped_events: self.shared.dropped_async_ops.take() as u64,
         }
     }
 
@@ -0,0 +1,185 @@
+//! Event channel whose capacity can be changed while the layer is running
+
+use crossbeam_channel::{Receiver, Sender, TryRecvError, TrySendError};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::Event;
+
+#[derive(Debug)]
+struct Channel<T> {
+    tx: Sender<T>,
+    rx: Receiver<T>,
+}
+
+impl<T> Channel<T> {
+    fn bounded(capacity: usize) -> Self {
+        let (tx, rx) = crossbeam_channel::bounded(capacity);
+        Self { tx, rx }
+    }
+}
+
+#[derive(Debug)]
+/// Bounded channel shared between the layer sending events and the aggregator receiving them
+///
+/// Resizing replaces the underlying channel. Events still buffered in the old one are handed
+/// out before any event sent after the resize, so nothing is lost or reordered.
+pub(crate) struct EventBuffer<T> {
+    current: RwLock<Channel<T>>,
+    /// Receivers of channels replaced by a resize, oldest first
+    retired: Mutex<VecDeque<Receiver<T>>>,
+    /// Set once the sending side is gone
+    closed: AtomicBool,
+}
+
+impl<T> EventBuffer<T> {
+    pub(crate) fn bounded(capacity: usize) -> (EventSender<T>, Arc<Self>) {
+        let buffer = Arc::new(Self {
+            current: RwLock::new(Channel::bounded(capacity)),
+            retired: Mutex::new(VecDeque::new()),
+            closed: AtomicBool::new(false),
+        });
+        (EventSender(buffer.clone()), buffer)
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        let current = self.current.read().unwrap();
+        current.tx.capacity().unwrap_or(usize::MAX)
+    }
+
+    pub(crate) fn resize(&self, capacity: usize) {
+        // Lock order is retired → current everywhere
+        let mut retired = self.retired.lock().unwrap();
+        // Holding the write lock guarantees no send to the old channel is in flight anymore
+        let mut current = self.current.write().unwrap();
+        let old = std::mem::replace(&mut *current, Channel::bounded(capacity));
+        retired.push_back(old.rx);
+    }
+
+    pub(crate) fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut retired = self.retired.lock().unwrap();
+        while let Some(rx) = retired.front() {
+            match rx.try_recv() {
+                Ok(event) => return Ok(event),
+                // The sender was dropped by the resize so no new events can show up
+                Err(_) => {
+                    retired.pop_front();
+                }
+            }
+        }
+
+        // Checked first: once closed no more events can arrive, so an empty channel stays empty
+        let closed = self.closed.load(Ordering::Acquire);
+        let current = self.current.read().unwrap();
+        match current.rx.try_recv() {
+            Err(TryRecvError::Empty) if closed => Err(TryRecvError::Disconnected),
+            result => result,
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Sending half of an [`EventBuffer`], closing it when dropped
+pub(crate) struct EventSender<T>(Arc<EventBuffer<T>>);
+
+impl<T> EventSender<T> {
+    pub(crate) fn is_full(&self) -> bool {
+        self.0.current.read().unwrap().tx.is_full()
+    }
+
+    pub(crate) fn try_send(&self, event: T) -> Result<(), TrySendError<T>> {
+        self.0.current.read().unwrap().tx.try_send(event)
+    }
+}
+
+impl<T> Drop for EventSender<T> {
+    fn drop(&mut self) {
+        self.0.closed.store(true, Ordering::Release);
+    }
+}
+
+#[derive(Debug, Clone)]
+/// Handle to change the number of events buffered between the layer and the aggregator
+pub struct EventBufferHandle(pub(crate) Arc<EventBuffer<Event>>);
+
+impl EventBufferHandle {
+    pub fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+
+    /// Set the number of events that can be buffered
+    ///
+    /// Events already buffered are kept even if there are more of them than the new capacity.
+    pub fn resize(&self, capacity: usize) {
+        tracing::info!(
+            old = self.0.capacity(),
+            new = capacity,
+            "resizing console event buffer"
+        );
+        self.0.resize(capacity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn resizing_keeps_buffered_events() {
+        let (tx, buffer) = EventBuffer::bounded(4);
+        for i in 0..4 {
+            tx.try_send(i).unwrap();
+        }
+        assert!(tx.is_full());
+
+        buffer.resize(2);
+        assert_eq!(buffer.capacity(), 2);
+        tx.try_send(4).unwrap();
+        tx.try_send(5).unwrap();
+        assert!(tx.try_send(6).is_err());
+
+        let received: Vec<_> = std::iter::from_fn(|| buffer.try_recv().ok()).collect();
+        assert_eq!(received, [0, 1, 2, 3, 4, 5]);
+
+        drop(tx);
+        assert_eq!(buffer.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn no_events_lost_while_resizing_concurrently() {
+        const EVENTS: usize = 10_000;
+        let (tx, buffer) = EventBuffer::bounded(16);
+
+        let sender = thread::spawn(move || {
+            for i in 0..EVENTS {
+                let mut event = i;
+                // Retry instead of dropping so every event has to arrive
+                while let Err(TrySendError::Full(e)) = tx.try_send(event) {
+                    event = e;
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut received = Vec::with_capacity(EVENTS);
+        let mut capacity = 16;
+        loop {
+            match buffer.try_recv() {
+                Ok(event) => {
+                    received.push(event);
+                    if received.len() % 500 == 0 {
+                        capacity = if capacity == 16 { 1024 } else { 16 };
+                        buffer.resize(capacity);
+                    }
+                }
+                Err(TryRecvError::Empty) => thread::yield_now(),
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+        sender.join().unwrap();
+
+        assert_eq!(received, (0..EVENTS).collect::<Vec<_>>());
+    }
+}
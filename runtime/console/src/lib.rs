@@ -1,6 +1,7 @@
-use crossbeam_channel::{Sender, TrySendError};
+use crossbeam_channel::TrySendError;
 use std::borrow::Borrow;
 use std::cell::RefCell;
+use std::fmt;
 use std::net::IpAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
@@ -15,21 +16,27 @@ use tracing_subscriber::Layer;
 
 mod aggregate;
 mod attribute;
+mod buffer;
 mod callsites;
 mod event;
 mod id_map;
+mod metrics;
 mod server;
 mod stack;
 mod stats;
 mod visitors;
 
 use crate::aggregate::Aggregator;
+use crate::buffer::{EventBuffer, EventSender};
 use crate::callsites::Callsites;
+use crate::metrics::{DropCounter, PollDurations};
 use crate::visitors::{
     AsyncOpVisitor, PollOpVisitor, ResourceVisitor, ResourceVisitorResult, StateUpdateVisitor,
     TaskVisitor, WakerVisitor,
 };
 use event::Event;
+pub use buffer::EventBufferHandle;
+pub use metrics::{MetricsHandle, MetricsSnapshot, POLL_BUCKETS};
 pub use server::Server;
 use stack::SpanStack;
 
@@ -37,7 +44,7 @@ use stack::SpanStack;
 pub struct ConsoleLayer {
     current_spans: ThreadLocal<RefCell<SpanStack>>,
 
-    tx: Sender<Event>,
+    tx: EventSender<Event>,
     shared: Arc<Shared>,
 
     spawn_callsites: Callsites<8>,
@@ -70,6 +77,9 @@ pub struct ConsoleLayer {
     async_op_state_update_callsites: Callsites<32>,
 
     max_poll_duration_nanos: u64,
+
+    /// Upper bounds of the configured poll duration buckets in nanoseconds
+    poll_buckets_nanos: Option<Arc<[u64]>>,
 }
 
 #[derive(Debug)]
@@ -82,16 +92,42 @@ pub struct Builder {
     /// Number of events that can be buffered before events are dropped.
     ///
     /// A smaller number will reduce the memory footprint but may lead to more events being dropped
-    /// during activity bursts.
+    /// during activity bursts. Can be changed at runtime through [`Server::event_buffer`].
     event_buffer_capacity: usize,
 
     client_buffer_capacity: usize,
 
     poll_duration_max: Duration,
+
+    /// Upper bounds of the poll duration buckets, [`POLL_BUCKETS`] if unset
+    poll_buckets: Option<Vec<Duration>>,
 }
 impl Builder {
-    pub fn build(self) -> (ConsoleLayer, Server) {
-        ConsoleLayer::build(self)
+    /// Count task polls in buckets with these upper bounds instead of [`POLL_BUCKETS`]
+    ///
+    /// Polls longer than the last bound are counted in an extra bucket. The per-task poll
+    /// duration histograms record each poll up to the last bound as the bound of its bucket and
+    /// their maximum is raised to the last bound if needed. Bounds have to be larger than zero
+    /// and strictly increasing, otherwise [`Builder::build`] fails.
+    pub fn poll_duration_buckets(mut self, bounds: impl Into<Vec<Duration>>) -> Self {
+        self.poll_buckets = Some(bounds.into());
+        self
+    }
+
+    pub fn build(self) -> Result<(ConsoleLayer, Server), BuildError> {
+        if let Some(bounds) = self.poll_buckets.as_ref() {
+            if bounds.is_empty() {
+                return Err(BuildError::NoPollBuckets);
+            }
+            let mut previous = Duration::ZERO;
+            for (index, bound) in bounds.iter().enumerate() {
+                if *bound <= previous {
+                    return Err(BuildError::UnorderedPollBuckets { index });
+                }
+                previous = *bound;
+            }
+        }
+        Ok(ConsoleLayer::build(self))
     }
 }
 impl Default for Builder {
@@ -103,20 +139,50 @@ impl Default for Builder {
             event_buffer_capacity: ConsoleLayer::DEFAULT_EVENT_BUFFER_CAPACITY,
             client_buffer_capacity: 1024,
             poll_duration_max: ConsoleLayer::DEFAULT_POLL_DURATION_MAX,
+            poll_buckets: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Invalid configuration given to a [`Builder`]
+pub enum BuildError {
+    /// An empty list of poll duration buckets was given
+    NoPollBuckets,
+    /// The poll duration bucket bound at `index` is zero or not larger than the one before it
+    UnorderedPollBuckets { index: usize },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoPollBuckets => f.write_str("at least one poll duration bucket is required"),
+            Self::UnorderedPollBuckets { index } => write!(
+                f,
+                "poll duration bucket {} must be larger than zero and the bucket before it",
+                index
+            ),
         }
     }
 }
 
+impl std::error::Error for BuildError {}
+
 #[derive(Debug, Default)]
 struct Shared {
-    dropped_tasks: AtomicUsize,
-    dropped_resources: AtomicUsize,
-    dropped_async_ops: AtomicUsize,
+    dropped_tasks: DropCounter,
+    dropped_resources: DropCounter,
+    dropped_async_ops: DropCounter,
+    live_tasks: AtomicUsize,
+    live_resources: AtomicUsize,
+    live_async_ops: AtomicUsize,
+    poll_durations: PollDurations,
 }
 
 impl ConsoleLayer {
     pub fn new() -> (Self, Server) {
-        Self::builder().build()
+        // The default configuration is always valid
+        Self::build(Self::builder())
     }
     pub fn builder() -> Builder {
         Builder::default()
@@ -128,11 +194,33 @@ impl ConsoleLayer {
             "configured console subscriber"
         );
 
-        let (tx, events) = crossbeam_channel::bounded(config.event_buffer_capacity);
-        let shared = Arc::new(Shared::default());
+        let (tx, events) = EventBuffer::bounded(config.event_buffer_capacity);
+        let event_buffer = EventBufferHandle(events.clone());
+        // Polls longer than the last bucket still have to be recorded in the histograms
+        let max_poll_duration = match config.poll_buckets.as_ref().and_then(|b| b.last()) {
+            Some(last) => (*last).max(config.poll_duration_max),
+            None => config.poll_duration_max,
+        };
+        let poll_buckets_nanos = config
+            .poll_buckets
+            .as_ref()
+            .map(|bounds| bounds.iter().map(|bound| bound.as_nanos() as u64).collect());
+        let shared = Arc::new(Shared {
+            poll_durations: config
+                .poll_buckets
+                .map(PollDurations::new)
+                .unwrap_or_default(),
+            ..Shared::default()
+        });
         let (subscribe, rpcs) = async_channel::bounded(config.client_buffer_capacity);
         let aggregator = Aggregator::new(shared.clone(), events, rpcs);
-        let server = Server::new(aggregator, config.client_buffer_capacity, subscribe);
+        let server = Server::new(
+            aggregator,
+            MetricsHandle(shared.clone()),
+            config.client_buffer_capacity,
+            subscribe,
+            event_buffer,
+        );
         let layer = Self {
             current_spans: ThreadLocal::new(),
             tx,
@@ -145,7 +233,8 @@ impl ConsoleLayer {
             poll_op_callsites: Callsites::default(),
             resource_state_update_callsites: Callsites::default(),
             async_op_state_update_callsites: Callsites::default(),
-            max_poll_duration_nanos: config.poll_duration_max.as_nanos() as u64,
+            max_poll_duration_nanos: max_poll_duration.as_nanos() as u64,
+            poll_buckets_nanos,
         };
 
         (layer, server)
@@ -222,11 +311,11 @@ impl ConsoleLayer {
 
     fn send_stats<S>(
         &self,
-        dropped: &AtomicUsize,
+        dropped: &DropCounter,
         mkEvent: impl FnOnce() -> (Event, S),
     ) -> Option<S> {
         if self.tx.is_full() {
-            dropped.fetch_add(1, Ordering::Release);
+            dropped.add();
             return None;
         }
 
@@ -234,14 +323,14 @@ impl ConsoleLayer {
         match self.tx.try_send(event) {
             Ok(()) => Some(stats),
             Err(TrySendError::Full(_)) => {
-                dropped.fetch_add(1, Ordering::Release);
+                dropped.add();
                 None
             }
             Err(TrySendError::Disconnected(_)) => None,
         }
     }
 
-    fn send_metadata(&self, dropped: &AtomicUsize, event: Event) -> bool {
+    fn send_metadata(&self, dropped: &DropCounter, event: Event) -> bool {
         self.send_stats(dropped, || (event, ())).is_some()
     }
 }
@@ -300,7 +389,11 @@ where
             attrs.record(&mut task_visitor);
             let (fields, location) = task_visitor.result();
             if let Some(stats) = self.send_stats(&self.shared.dropped_tasks, move || {
-                let stats = Arc::new(stats::TaskStats::new(self.max_poll_duration_nanos, at));
+                let stats = Arc::new(stats::TaskStats::new(
+                    self.max_poll_duration_nanos,
+                    self.poll_buckets_nanos.clone(),
+                    at,
+                ));
                 let event = Event::Spawn {
                     id: id.clone(),
                     stats: stats.clone(),
@@ -314,6 +407,7 @@ where
                     .expect("`on_new_span` called with nonexistent span. This is a tracing bug.")
                     .extensions_mut()
                     .insert(stats);
+                self.shared.live_tasks.fetch_add(1, Ordering::Relaxed);
             }
         } else if self.is_resource(metadata) {
             let at = Instant::now();
@@ -352,6 +446,7 @@ where
                         .expect("if `on_new_span` was called, the span must exist; this is a `tracing` bug!")
                         .extensions_mut()
                         .insert(stats);
+                    self.shared.live_resources.fetch_add(1, Ordering::Relaxed);
                 }
             }
         } else if self.is_async_op(metadata) {
@@ -390,6 +485,7 @@ where
                             .expect("if `on_new_span` was called, the span must exist; this is a `tracing` bug!")
                             .extensions_mut()
                             .insert(stats);
+                        self.shared.live_async_ops.fetch_add(1, Ordering::Relaxed);
                     }
                 }
             }
@@ -466,13 +562,16 @@ where
         fn update<S: Subscriber + for<'a> LookupSpan<'a>>(
             span: &SpanRef<S>,
             at: Option<Instant>,
+            polls: &PollDurations,
         ) -> Option<Instant> {
             let exts = span.extensions();
             // if the span we are entering is a task or async op, record the
             // poll stats.
             if let Some(stats) = exts.get::<Arc<stats::TaskStats>>() {
                 let at = at.unwrap_or_else(Instant::now);
-                stats.end_poll(at);
+                if let Some(duration) = stats.end_poll(at) {
+                    polls.record(duration);
+                }
                 Some(at)
             } else if let Some(stats) = exts.get::<Arc<stats::AsyncOpStats>>() {
                 let at = at.unwrap_or_else(Instant::now);
@@ -489,9 +588,10 @@ where
         }
 
         if let Some(span) = cx.span(id) {
-            if let Some(now) = update(&span, None) {
+            let polls = &self.shared.poll_durations;
+            if let Some(now) = update(&span, None, polls) {
                 if let Some(parent) = span.parent() {
-                    update(&parent, Some(now));
+                    update(&parent, Some(now), polls);
                 }
                 self.current_spans.get_or_default().borrow_mut().pop(id);
             }
@@ -504,11 +604,54 @@ where
             let exts = span.extensions();
             if let Some(stats) = exts.get::<Arc<stats::TaskStats>>() {
                 stats.drop_task(now);
+                self.shared.live_tasks.fetch_sub(1, Ordering::Relaxed);
             } else if let Some(stats) = exts.get::<Arc<stats::AsyncOpStats>>() {
                 stats.drop_async_op(now);
+                self.shared.live_async_ops.fetch_sub(1, Ordering::Relaxed);
             } else if let Some(stats) = exts.get::<Arc<stats::ResourceStats>>() {
                 stats.drop_resource(now);
+                self.shared.live_resources.fetch_sub(1, Ordering::Relaxed);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poll_buckets_must_increase() {
+        let micros = |us: &[u64]| -> Vec<Duration> {
+            us.iter().copied().map(Duration::from_micros).collect()
+        };
+
+        let (_, server) = ConsoleLayer::builder()
+            .poll_duration_buckets(micros(&[1, 2, 5, 10]))
+            .build()
+            .unwrap();
+        let snapshot = server.metrics().snapshot();
+        assert_eq!(snapshot.poll_buckets, micros(&[1, 2, 5, 10]));
+        assert_eq!(snapshot.poll_durations, [0; 5]);
+
+        let error = |bounds: Vec<Duration>| {
+            ConsoleLayer::builder()
+                .poll_duration_buckets(bounds)
+                .build()
+                .err()
+        };
+        assert_eq!(error(micros(&[])), Some(BuildError::NoPollBuckets));
+        assert_eq!(
+            error(micros(&[1, 5, 5])),
+            Some(BuildError::UnorderedPollBuckets { index: 2 })
+        );
+        assert_eq!(
+            error(micros(&[10, 2])),
+            Some(BuildError::UnorderedPollBuckets { index: 1 })
+        );
+        assert_eq!(
+            error(micros(&[0, 2])),
+            Some(BuildError::UnorderedPollBuckets { index: 0 })
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! Aggregated counts that can be exported without a console client connected
+//!
+//! Everything is kept in atomics updated by the layer as events happen, so taking a snapshot
+//! from any thread is a handful of loads no matter how many tasks exist.
+
+use crate::Shared;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Upper bounds of the poll duration buckets unless configured otherwise with
+/// [`Builder::poll_duration_buckets`](crate::Builder::poll_duration_buckets). Longer polls are
+/// counted in an extra last bucket.
+pub const POLL_BUCKETS: [Duration; 6] = [
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+#[derive(Debug, Default)]
+/// Events dropped because the event buffer was full
+pub(crate) struct DropCounter {
+    /// Reset whenever an update is sent to console clients
+    since_publish: AtomicUsize,
+    total: AtomicU64,
+}
+
+impl DropCounter {
+    pub(crate) fn add(&self) {
+        self.since_publish.fetch_add(1, Ordering::Release);
+        self.total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number dropped since the last call
+    pub(crate) fn take(&self) -> usize {
+        self.since_publish.swap(0, Ordering::AcqRel)
+    }
+
+    fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+/// Number of task polls per bucket
+pub(crate) struct PollDurations {
+    /// Upper bound of each bucket, increasing
+    bounds: Vec<Duration>,
+    /// One more than there are bounds, for polls longer than all of them
+    counts: Vec<AtomicU64>,
+}
+
+impl PollDurations {
+    pub(crate) fn new(bounds: Vec<Duration>) -> Self {
+        let counts = (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect();
+        Self { bounds, counts }
+    }
+
+    pub(crate) fn record(&self, duration: Duration) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| duration <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> Vec<u64> {
+        self.counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+impl Default for PollDurations {
+    fn default() -> Self {
+        Self::new(POLL_BUCKETS.to_vec())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Counts of the instrumented runtime at one point in time
+///
+/// The counters are read one after the other, so they may be off by the events that happened
+/// while the snapshot was taken.
+pub struct MetricsSnapshot {
+    pub live_tasks: usize,
+    pub live_resources: usize,
+    pub live_async_ops: usize,
+    /// Events about tasks dropped since startup because the event buffer was full
+    pub dropped_tasks: u64,
+    pub dropped_resources: u64,
+    pub dropped_async_ops: u64,
+    /// Upper bounds of the poll duration buckets, [`POLL_BUCKETS`] unless configured otherwise
+    pub poll_buckets: Vec<Duration>,
+    /// Number of task polls that took at most the `poll_buckets` bound at the same index and
+    /// longer than the previous one. The last entry counts polls longer than all bounds.
+    pub poll_durations: Vec<u64>,
+}
+
+#[derive(Clone, Debug)]
+/// Handle to take [`MetricsSnapshot`]s from, e.g. to export them to Prometheus
+pub struct MetricsHandle(pub(crate) Arc<Shared>);
+
+impl MetricsHandle {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let shared = &self.0;
+        MetricsSnapshot {
+            live_tasks: shared.live_tasks.load(Ordering::Relaxed),
+            live_resources: shared.live_resources.load(Ordering::Relaxed),
+            live_async_ops: shared.live_async_ops.load(Ordering::Relaxed),
+            dropped_tasks: shared.dropped_tasks.total(),
+            dropped_resources: shared.dropped_resources.total(),
+            dropped_async_ops: shared.dropped_async_ops.total(),
+            poll_buckets: shared.poll_durations.bounds.clone(),
+            poll_durations: shared.poll_durations.load(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn polls_are_counted_in_their_bucket() {
+        let durations = PollDurations::default();
+        durations.record(Duration::from_micros(5));
+        durations.record(Duration::from_micros(10));
+        durations.record(Duration::from_millis(5));
+        durations.record(Duration::from_secs(5));
+        assert_eq!(durations.load(), [2, 0, 0, 1, 0, 0, 1]);
+    }
+
+    #[test]
+    fn polls_are_counted_in_configured_buckets() {
+        let durations = PollDurations::new(vec![
+            Duration::from_micros(1),
+            Duration::from_micros(2),
+            Duration::from_micros(5),
+        ]);
+        durations.record(Duration::from_nanos(500));
+        durations.record(Duration::from_nanos(1500));
+        durations.record(Duration::from_micros(3));
+        durations.record(Duration::from_micros(4));
+        durations.record(Duration::from_millis(1));
+        assert_eq!(durations.load(), [1, 1, 2, 1]);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::Aggregator;
+use crate::{Aggregator, EventBufferHandle, MetricsHandle};
 use async_channel::{Receiver, Sender};
 use async_compat::CompatExt;
 use console_api::instrument;
@@ -73,8 +73,10 @@ impl<T: TokioAsyncRead + Unpin> TokioAsyncRead for StreamWrapper<T> {
 #[derive(Debug)]
 pub struct Server {
     pub aggregator: Option<Aggregator>,
+    metrics: MetricsHandle,
     client_buffer_size: usize,
     subscribe: Sender<Command>,
+    event_buffer: EventBufferHandle,
 }
 
 impl Server {
@@ -84,16 +86,30 @@ impl Server {
 
     pub(crate) fn new(
         aggregator: Aggregator,
+        metrics: MetricsHandle,
         client_buffer_size: usize,
         subscribe: Sender<Command>,
+        event_buffer: EventBufferHandle,
     ) -> Self {
         Self {
             aggregator: Some(aggregator),
+            metrics,
             client_buffer_size,
             subscribe,
+            event_buffer,
         }
     }
 
+    /// Handle to resize the buffer between instrumented code and the aggregator at runtime
+    pub fn event_buffer(&self) -> EventBufferHandle {
+        self.event_buffer.clone()
+    }
+
+    /// Handle to read task counts and poll durations from, also while the aggregator runs
+    pub fn metrics(&self) -> MetricsHandle {
+        self.metrics.clone()
+    }
+
     pub async fn serve(
         mut self, /*, incoming: I */
     ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
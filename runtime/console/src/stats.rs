@@ -114,10 +114,11 @@ impl<H: RecordPoll> PollStats<H> {
         }
     }
 
-    fn end_poll(&self, at: Instant) {
+    /// Returns how long the poll took if it was the outermost one
+    fn end_poll(&self, at: Instant) -> Option<Duration> {
         // Are we ending the last current poll?
         if self.current_polls.fetch_sub(1, Ordering::AcqRel) > 1 {
-            return;
+            return None;
         }
 
         let mut timestamps = self.timestamps.lock().unwrap();
@@ -128,7 +129,7 @@ impl<H: RecordPoll> PollStats<H> {
                     "a poll ended, but start timestamp was recorded. \
                      this is probably a `console-subscriber` bug"
                 );
-                return;
+                return None;
             }
         };
 
@@ -141,7 +142,7 @@ impl<H: RecordPoll> PollStats<H> {
                     was before its start timestamp\nstart = {:?}\n  end = {:?}",
                     started, at
                 );
-                return;
+                return None;
             }
         };
 
@@ -149,6 +150,7 @@ impl<H: RecordPoll> PollStats<H> {
         timestamps.histogram.record_poll_duration(elapsed);
 
         timestamps.busy_time += elapsed;
+        Some(elapsed)
     }
 }
 
@@ -191,7 +193,11 @@ pub(crate) struct TaskStats {
 }
 
 impl TaskStats {
-    pub(crate) fn new(poll_duration_max: u64, created_at: Instant) -> Self {
+    pub(crate) fn new(
+        poll_duration_max: u64,
+        poll_buckets: Option<Arc<[u64]>>,
+        created_at: Instant,
+    ) -> Self {
         Self {
             is_dirty: AtomicBool::new(true),
             is_dropped: AtomicBool::new(false),
@@ -199,7 +205,7 @@ impl TaskStats {
             timestamps: Mutex::new(TaskTimestamps::default()),
             poll_stats: PollStats {
                 timestamps: Mutex::new(PollTimestamps {
-                    histogram: Histogram::new(poll_duration_max),
+                    histogram: Histogram::new(poll_duration_max, poll_buckets),
                     first_poll: None,
                     last_poll_started: None,
                     last_poll_ended: None,
@@ -261,9 +267,11 @@ impl TaskStats {
         self.make_dirty();
     }
 
-    pub(crate) fn end_poll(&self, at: Instant) {
-        self.poll_stats.end_poll(at);
+    /// Returns how long the task was polled if this ended its outermost poll
+    pub(crate) fn end_poll(&self, at: Instant) -> Option<Duration> {
+        let elapsed = self.poll_stats.end_poll(at);
         self.make_dirty();
+        elapsed
     }
 
     pub(crate) fn drop_task(&self, dropped_at: Instant) {
@@ -556,10 +564,12 @@ struct Histogram {
     max: u64,
     outliers: u64,
     max_outlier: Option<u64>,
+    /// Upper bounds of the configured poll duration buckets in nanoseconds
+    buckets: Option<Arc<[u64]>>,
 }
 
 impl Histogram {
-    fn new(max: u64) -> Self {
+    fn new(max: u64, buckets: Option<Arc<[u64]>>) -> Self {
         // significant figures should be in the [0-5] range and memory usage
         // grows exponentially with higher a sigfig
         let histogram = hdrhistogram::Histogram::new_with_max(max, 2).unwrap();
@@ -568,6 +578,7 @@ impl Histogram {
             max,
             max_outlier: None,
             outliers: 0,
+            buckets,
         }
     }
 
@@ -597,6 +608,13 @@ impl RecordPoll for Histogram {
             duration_ns = self.max;
         }
 
+        // polls within the configured buckets are recorded as the bound of their bucket
+        if let Some(buckets) = self.buckets.as_ref() {
+            if let Some(bound) = buckets.iter().find(|bound| duration_ns <= **bound) {
+                duration_ns = *bound;
+            }
+        }
+
         self.histogram
             .record(duration_ns)
             .expect("duration has already been clamped to histogram max value")
@@ -608,3 +626,27 @@ impl RecordPoll for () {
         // do nothing
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_records_polls_as_their_bucket() {
+        let mut histogram = Histogram::new(10_000, Some(vec![1_000, 2_000, 5_000].into()));
+        histogram.record_poll_duration(Duration::from_nanos(1_500));
+        histogram.record_poll_duration(Duration::from_nanos(2_000));
+        histogram.record_poll_duration(Duration::from_nanos(3_000));
+        let hist = &histogram.histogram;
+        assert_eq!(hist.count_at(2_000), 2);
+        assert_eq!(hist.count_at(5_000), 1);
+        assert_eq!(hist.count_at(1_500) + hist.count_at(3_000), 0);
+
+        // Longer polls are recorded as they are, up to the maximum
+        histogram.record_poll_duration(Duration::from_nanos(7_000));
+        histogram.record_poll_duration(Duration::from_nanos(20_000));
+        assert_eq!(histogram.histogram.count_at(7_000), 1);
+        assert_eq!(histogram.histogram.count_at(10_000), 1);
+        assert_eq!(histogram.outliers, 1);
+    }
+}
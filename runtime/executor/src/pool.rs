@@ -108,6 +108,25 @@ impl<'a, 'executor: 'a> Executor<'executor> {
     /// ```
     #[track_caller]
     pub fn spawn<F, R>(&self, future: F) -> RecoverableHandle<R>
+    where
+        F: Future<Output = R> + Send + 'a,
+        R: Send + 'a,
+    {
+        self.spawn_inner(None, future)
+    }
+
+    /// Spawn a process like [`Executor::spawn`], giving it a name shown in diagnostics
+    #[track_caller]
+    pub fn spawn_named<F, R>(&self, name: &str, future: F) -> RecoverableHandle<R>
+    where
+        F: Future<Output = R> + Send + 'a,
+        R: Send + 'a,
+    {
+        self.spawn_inner(Some(name), future)
+    }
+
+    #[track_caller]
+    fn spawn_inner<F, R>(&self, name: Option<&str>, future: F) -> RecoverableHandle<R>
     where
         F: Future<Output = R> + Send + 'a,
         R: Send + 'a,
@@ -118,6 +137,7 @@ impl<'a, 'executor: 'a> Executor<'executor> {
         let span = tracing::trace_span!(
             target: "executor::task",
             "runtime.spawn",
+            task.name = name,
             loc.file = location.file(),
             loc.line = location.line(),
             loc.col = location.column(),
@@ -158,6 +178,36 @@ impl<'a, 'executor: 'a> Executor<'executor> {
 
     #[track_caller]
     pub fn spawn_local_cgroup<F, R>(&self, future: F, cgroup: GroupId) -> RecoverableHandle<R>
+    where
+        F: Future<Output = R> + 'a,
+        R: Send + 'a,
+    {
+        self.spawn_local_cgroup_inner(None, future, cgroup)
+    }
+
+    /// Spawn a local process like [`Executor::spawn_local_cgroup`], giving it a name shown in
+    /// diagnostics
+    #[track_caller]
+    pub fn spawn_local_cgroup_named<F, R>(
+        &self,
+        name: &str,
+        future: F,
+        cgroup: GroupId,
+    ) -> RecoverableHandle<R>
+    where
+        F: Future<Output = R> + 'a,
+        R: Send + 'a,
+    {
+        self.spawn_local_cgroup_inner(Some(name), future, cgroup)
+    }
+
+    #[track_caller]
+    fn spawn_local_cgroup_inner<F, R>(
+        &self,
+        name: Option<&str>,
+        future: F,
+        cgroup: GroupId,
+    ) -> RecoverableHandle<R>
     where
         F: Future<Output = R> + 'a,
         R: Send + 'a,
@@ -166,6 +216,7 @@ impl<'a, 'executor: 'a> Executor<'executor> {
         let span = tracing::trace_span!(
             target: "executor::task",
             "runtime.spawn",
+            task.name = name,
             loc.file = location.file(),
             loc.line = location.line(),
             loc.col = location.column(),
@@ -287,3 +338,51 @@ fn schedule_local() -> impl Fn(LightProc) {
         unparker.unpark();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tracing::field::{Field, Visit};
+    use tracing::span;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
+
+    #[derive(Clone, Default)]
+    struct SpawnNames(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for SpawnNames {
+        fn record_debug(&mut self, _: &Field, _: &dyn std::fmt::Debug) {}
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == "task.name" {
+                self.0.lock().unwrap().push(value.to_string());
+            }
+        }
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for SpawnNames {
+        fn on_new_span(&self, attrs: &span::Attributes<'_>, _: &span::Id, _: Context<'_, S>) {
+            if attrs.metadata().name() == "runtime.spawn" {
+                attrs.record(&mut self.clone());
+            }
+        }
+    }
+
+    #[test]
+    fn spawn_records_task_name() {
+        let names = SpawnNames::default();
+        let subscriber = tracing_subscriber::registry().with(names.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let executor = Executor::new();
+            let named = executor.spawn_named("test:named", async {});
+            let anonymous = executor.spawn(async {});
+            executor.run(async {
+                named.await;
+                anonymous.await;
+            });
+        });
+
+        assert_eq!(*names.0.lock().unwrap(), vec!["test:named".to_string()]);
+    }
+}
@@ -2,17 +2,33 @@ use crossbeam_deque::{Injector, Steal, Stealer, Worker};
 use crossbeam_queue::SegQueue;
 use crossbeam_utils::sync::{Parker, Unparker};
 use lightproc::prelude::LightProc;
+use std::any::Any;
+use std::cell::RefCell;
 use std::marker::PhantomData;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tracing::Span;
+
+/// Polls taking longer than this are reported as stalling the worker thread
+pub const DEFAULT_POLL_DURATION_MAX: Duration = Duration::from_millis(100);
 
 pub trait Runnable {
     fn run(self);
+
+    /// Span identifying the task in logs
+    fn span(&self) -> Span {
+        Span::none()
+    }
 }
 impl Runnable for LightProc {
     fn run(self) {
         LightProc::run(self)
     }
+
+    fn span(&self) -> Span {
+        LightProc::span(self)
+    }
 }
 
 #[derive(Debug)]
@@ -36,6 +52,13 @@ pub(crate) struct WorkerThread<'a, Task> {
     /// unparked by either a local task being woken up or by the Executor owning the Injector queue.
     parker: Parker,
 
+    /// A single poll taking longer than this is logged as a stall. Futures blocking in poll keep
+    /// every other task queued on this thread from making progress.
+    poll_duration_max: Duration,
+
+    /// Span of the task currently being polled, used to give context to panics escaping a task.
+    running: RefCell<Option<Span>>,
+
     _marker: PhantomData<&'a ()>,
 }
 
@@ -66,6 +89,8 @@ impl<'a, T: Runnable + 'a> WorkerThread<'a, T> {
                 tasks,
                 local_tasks,
                 parker,
+                poll_duration_max: DEFAULT_POLL_DURATION_MAX,
+                running: RefCell::new(None),
                 _marker,
             },
             Sleeper { stealer, unparker },
@@ -81,7 +106,8 @@ impl<'a, T: Runnable + 'a> WorkerThread<'a, T> {
         let fences: Vec<Stealer<T>> = fences.map(|stealer| stealer.clone()).collect();
 
         loop {
-            self.run_inner(&fences);
+            self.run_guarded(&fences);
+            tracing::trace!("worker heartbeat, parking");
             self.parker.park();
         }
     }
@@ -94,7 +120,8 @@ impl<'a, T: Runnable + 'a> WorkerThread<'a, T> {
         let fences: Vec<Stealer<T>> = fences.map(|stealer| stealer.clone()).collect();
 
         loop {
-            self.run_inner(&fences);
+            self.run_guarded(&fences);
+            tracing::trace!(?timeout, "worker heartbeat, parking");
             self.parker.park_timeout(timeout);
         }
     }
@@ -102,7 +129,29 @@ impl<'a, T: Runnable + 'a> WorkerThread<'a, T> {
     pub fn run_once(&self, fences: impl Iterator<Item = &'a Stealer<T>>) {
         let fences: Vec<Stealer<T>> = fences.map(|stealer| stealer.clone()).collect();
 
-        self.run_inner(fences);
+        self.run_guarded(&fences);
+    }
+
+    /// Work until there is nothing left to do, restarting the worker if a task panics.
+    ///
+    /// A panic escaping a task would otherwise unwind the whole worker thread, silently shrinking
+    /// the pool and dropping every `!Send` task queued on it. Instead the panic is logged and the
+    /// worker picks up again with its queues intact.
+    fn run_guarded(&self, fences: &[Stealer<T>]) {
+        while let Err(payload) = catch_unwind(AssertUnwindSafe(|| self.run_inner(fences))) {
+            let thread = std::thread::current();
+            let thread = thread.name().unwrap_or("<unnamed>");
+            let panic = panic_message(payload.as_ref());
+            match self.running.borrow_mut().take() {
+                Some(span) => tracing::error!(
+                    parent: &span,
+                    thread,
+                    panic,
+                    "task panicked on worker thread, restarting worker"
+                ),
+                None => tracing::error!(thread, panic, "worker thread panicked, restarting worker"),
+            }
+        }
     }
 
     fn run_inner<F: AsRef<[Stealer<T>]>>(&self, fences: F) {
@@ -110,10 +159,10 @@ impl<'a, T: Runnable + 'a> WorkerThread<'a, T> {
         'work: while {
             // Always run local tasks first since they can't be done by anybody else.
             if let Some(task) = self.local_tasks.pop() {
-                task.run();
+                self.run_task(task);
                 continue 'work;
             } else if let Some(task) = self.tasks.pop() {
-                task.run();
+                self.run_task(task);
                 continue 'work;
             } else {
                 // If we were woken up by the global scheduler `should_steal` is set to true,
@@ -125,7 +174,7 @@ impl<'a, T: Runnable + 'a> WorkerThread<'a, T> {
                     match self.task_queue.steal_batch_and_pop(&self.tasks) {
                         // If we could steal from the global queue do more work.
                         Steal::Success(task) => {
-                            task.run();
+                            self.run_task(task);
                             continue 'work;
                         }
 
@@ -149,7 +198,7 @@ impl<'a, T: Runnable + 'a> WorkerThread<'a, T> {
                 while let Some(fence) = select_fence(fences.as_ref().iter()) {
                     match fence.steal_batch_and_pop(&self.tasks) {
                         Steal::Success(task) => {
-                            task.run();
+                            self.run_task(task);
                             continue 'work;
                         }
 
@@ -169,12 +218,165 @@ impl<'a, T: Runnable + 'a> WorkerThread<'a, T> {
         } {}
     }
 
+    /// Poll `task` once, warning if that took longer than `poll_duration_max`
+    fn run_task(&self, task: T) {
+        let span = task.span();
+        self.running.replace(Some(span.clone()));
+        let start = Instant::now();
+        task.run();
+        let elapsed = start.elapsed();
+        self.running.replace(None);
+        if elapsed > self.poll_duration_max {
+            tracing::warn!(
+                parent: &span,
+                ?elapsed,
+                max = ?self.poll_duration_max,
+                "polling a task stalled the worker thread"
+            );
+        }
+    }
+
     pub fn schedule_local(&self, task: T) {
         self.local_tasks.push(task);
     }
 }
 
+/// Extract the message of a panic payload if it was raised with one
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&'static str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.as_str()
+    } else {
+        "<non-string panic payload>"
+    }
+}
+
 #[inline(always)]
 fn select_fence<'a, T>(fences: impl Iterator<Item = &'a Stealer<T>>) -> Option<&'a Stealer<T>> {
     fences.max_by_key(|fence| fence.len())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use tracing::field::{Field, Visit};
+    use tracing::{Event, Level};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+
+    /// Task sleeping instead of yielding
+    struct Blocking(Duration, Span);
+
+    impl Runnable for Blocking {
+        fn run(self) {
+            std::thread::sleep(self.0)
+        }
+
+        fn span(&self) -> Span {
+            self.1.clone()
+        }
+    }
+
+    /// Task panicking when polled
+    struct Panicking(Span);
+
+    impl Runnable for Panicking {
+        fn run(self) {
+            panic!("task exploded")
+        }
+
+        fn span(&self) -> Span {
+            self.0.clone()
+        }
+    }
+
+    /// Task counting how often it was run
+    struct Counting(Arc<AtomicUsize>);
+
+    impl Runnable for Counting {
+        fn run(self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Records the message and span of events at or above a level
+    #[derive(Clone)]
+    struct Warnings(Arc<Mutex<Vec<(String, Option<String>)>>>, Level);
+
+    impl Default for Warnings {
+        fn default() -> Self {
+            Self(Arc::default(), Level::WARN)
+        }
+    }
+
+    struct Message(String);
+
+    impl Visit for Message {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.0 = format!("{:?}", value);
+            }
+        }
+    }
+
+    impl<S: tracing::Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Warnings {
+        fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+            if *event.metadata().level() <= self.1 {
+                let mut message = Message(String::new());
+                event.record(&mut message);
+                let span = ctx.event_span(event).map(|span| span.name().to_string());
+                self.0.lock().unwrap().push((message.0, span));
+            }
+        }
+    }
+
+    #[test]
+    fn long_poll_is_reported_as_stall() {
+        let warnings = Warnings::default();
+        let subscriber = tracing_subscriber::registry().with(warnings.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let (mut worker, _) = WorkerThread::new(Arc::new(Injector::new()));
+            worker.poll_duration_max = Duration::from_millis(10);
+            let span = tracing::info_span!("stuck_task");
+            worker.schedule_local(Blocking(Duration::from_millis(50), span));
+            worker.schedule_local(Blocking(Duration::ZERO, Span::none()));
+            worker.run_once(std::iter::empty());
+        });
+
+        assert_eq!(
+            *warnings.0.lock().unwrap(),
+            vec![(
+                "polling a task stalled the worker thread".to_string(),
+                Some("stuck_task".to_string())
+            )]
+        );
+    }
+
+    #[test]
+    fn worker_recovers_from_panicking_task() {
+        let errors = Warnings(Arc::default(), Level::ERROR);
+        let subscriber = tracing_subscriber::registry().with(errors.clone());
+        let ran = Arc::new(AtomicUsize::new(0));
+        tracing::subscriber::with_default(subscriber, || {
+            let (worker, _) = WorkerThread::new(Arc::new(Injector::new()));
+            worker.schedule_local(Counting(ran.clone()));
+            worker.schedule_local(Panicking(tracing::info_span!("exploding_task")));
+            worker.schedule_local(Counting(ran.clone()));
+            worker.schedule_local(Counting(ran.clone()));
+            worker.run_once(std::iter::empty());
+        });
+
+        assert_eq!(ran.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            *errors.0.lock().unwrap(),
+            vec![(
+                "task panicked on worker thread, restarting worker".to_string(),
+                Some("exploding_task".to_string())
+            )]
+        );
+    }
+}
@@ -167,6 +167,14 @@ impl LightProc {
         }
     }
 
+    /// The span this process was created with
+    pub fn span(&self) -> Span {
+        let ptr = self.raw_proc.as_ptr();
+        let pdata = ptr as *const ProcData;
+
+        unsafe { (*pdata).span.clone() }
+    }
+
     /// Cancel polling the lightproc's inner future, thus cancelling the proc itself.
     pub fn cancel(&self) {
         let ptr = self.raw_proc.as_ptr();